@@ -0,0 +1,69 @@
+use std::time::Instant;
+
+use burn_rl_example::{
+    trainer::{storage::StorageBackend, uniform::UniformReplayMemory, ReplayMemory as _},
+    Action, DeepQNetworkState, Experience, FlatState, FrameStackState, ObservationState as _,
+    State,
+};
+use rand::Rng as _;
+use serde::{de::DeserializeOwned, Serialize};
+
+const BATCH_SIZE: usize = 32;
+const BATCHES: usize = 200;
+
+fn experience<S: State>(state: S) -> Experience<S> {
+    Experience::new(state, Action::Discrete(1), 1.0, false, false)
+}
+
+// pushes the memory to capacity, then draws batches
+fn bench<S: FlatState + Serialize + DeserializeOwned + Sync + 'static>(
+    name: &str,
+    backend: StorageBackend,
+    capacity: usize,
+    mut make_state: impl FnMut() -> S,
+) -> anyhow::Result<()> {
+    let mut memory = UniformReplayMemory::<S>::new(capacity, BATCH_SIZE, backend.clone())?;
+
+    let start = Instant::now();
+    for _ in 0..capacity {
+        memory.push(experience(make_state()))?;
+    }
+    let push = start.elapsed();
+
+    // the samplers wait for a batch before they start
+    memory.sample()?;
+    let start = Instant::now();
+    for _ in 0..BATCHES {
+        memory.sample()?;
+    }
+    let sample = start.elapsed();
+
+    println!(
+        "{:<12} {:<8} push {:>10.2?}/experience sample {:>10.2?}/batch",
+        name,
+        format!("{:?}", backend),
+        push / capacity as u32,
+        sample / BATCHES as u32,
+    );
+    Ok(())
+}
+
+fn main() -> anyhow::Result<()> {
+    let mut rng = rand::thread_rng();
+    let mut observation = |len: usize| (0..len).map(|_| rng.gen()).collect::<Vec<f32>>();
+
+    for backend in [StorageBackend::InMemory, StorageBackend::RocksDb] {
+        bench("cart pole", backend, 10000, || {
+            DeepQNetworkState::new(observation(4)).next_state(&observation(4))
+        })?;
+    }
+    // 84x84 frames stacked by 4 along an episode, each frame is stored once
+    for backend in [StorageBackend::InMemory, StorageBackend::RocksDb] {
+        let mut state = FrameStackState::<4>::new(observation(84 * 84));
+        bench("atari", backend, 1000, || {
+            state = state.next_state(&observation(84 * 84));
+            state.clone()
+        })?;
+    }
+    Ok(())
+}
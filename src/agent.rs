@@ -1,12 +1,467 @@
+use std::{fs::File, path::Path};
+
+use anyhow::Context as _;
+use burn::{
+    module::{AutodiffModule, Module, ParamId},
+    optim::{
+        adaptor::OptimizerAdaptor,
+        record::{AdaptorRecord, AdaptorRecordItem},
+        Optimizer as _, SimpleOptimizer,
+    },
+    record::{CompactRecorder, HalfPrecisionSettings, Record as _, Recorder as _},
+    tensor::{
+        backend::{AutodiffBackend, Backend},
+        Shape, Tensor, TensorData,
+    },
+};
 use clap::ValueEnum;
+use rand::{rngs::StdRng, SeedableRng as _};
 use serde::{Deserialize, Serialize};
 
+use crate::{env::wrapper::ObservationNormalizer, layers::Noisy, Action, ObservationSpace};
+
 pub mod categorical;
 pub mod expectation;
+pub mod fully_parameterized_quantile;
+pub mod implicit_quantile;
+pub mod ppo;
 pub mod quantile;
+pub mod recurrent;
+pub mod sac;
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Serialize, Deserialize)]
 pub enum LossFunction {
     Huber,
     Squared,
 }
+
+// how a distributional agent turns a return distribution into an action value
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum RiskMeasure {
+    Mean,
+    // expectation of the worst alpha fraction of the returns
+    ConditionalValueAtRisk { alpha: f32 },
+    MeanMinusStd { k: f32 },
+    // eta < 0 is risk averse, as in the IQN paper
+    Wang { eta: f32 },
+}
+
+impl RiskMeasure {
+    // `values` and `probs` are [batch, action, support], returns [batch, action]
+    pub fn risk_values<B: Backend>(
+        &self,
+        values: Tensor<B, 3>,
+        probs: Tensor<B, 3>,
+    ) -> Tensor<B, 2> {
+        let [batch_size, action_num, _] = values.dims();
+        let probs = probs.clone() / probs.sum_dim(2);
+        let mean = (values.clone() * probs.clone()).sum_dim(2);
+        let risk_values = match *self {
+            RiskMeasure::Mean => mean,
+            RiskMeasure::MeanMinusStd { k } => {
+                let variance = ((values - mean.clone()).powf_scalar(2.0) * probs).sum_dim(2);
+                mean - variance.sqrt().mul_scalar(k)
+            }
+            RiskMeasure::ConditionalValueAtRisk { alpha } => {
+                distorted_expectation(values, probs, |u| u.div_scalar(alpha).clamp_max(1.0))
+            }
+            RiskMeasure::Wang { eta } => distorted_expectation(values, probs, |u| {
+                normal_cdf(normal_quantile(u).sub_scalar(eta))
+            }),
+        };
+        risk_values.reshape([batch_size, action_num])
+    }
+
+    // the taus an implicit quantile agent samples at to act under a distortion risk measure,
+    // None when the measure is no distortion of the taus
+    pub fn distort_taus<B: Backend>(&self, taus: Tensor<B, 2>) -> Option<Tensor<B, 2>> {
+        match *self {
+            RiskMeasure::Mean => Some(taus),
+            RiskMeasure::ConditionalValueAtRisk { alpha } => Some(taus.mul_scalar(alpha)),
+            RiskMeasure::Wang { eta } => Some(normal_cdf(normal_quantile(taus).add_scalar(eta))),
+            RiskMeasure::MeanMinusStd { .. } => None,
+        }
+    }
+}
+
+// agents trained on envs with an observation normalizer save and load its statistics
+pub trait ObservationNormalized: Sized {
+    fn observation_normalizer(&self) -> Option<&ObservationNormalizer>;
+    fn observation_normalizer_mut(&mut self) -> &mut Option<ObservationNormalizer>;
+
+    fn with_observation_normalizer(
+        mut self,
+        observation_normalizer: ObservationNormalizer,
+    ) -> Self {
+        *self.observation_normalizer_mut() = Some(observation_normalizer);
+        self
+    }
+
+    fn save_observation_normalizer<P: AsRef<Path>>(&self, artifacts_dir: P) -> anyhow::Result<()> {
+        if let Some(observation_normalizer) = self.observation_normalizer() {
+            observation_normalizer
+                .save(artifacts_dir)
+                .with_context(|| "save observation normalizer")?;
+        }
+        Ok(())
+    }
+
+    fn load_observation_normalizer<P: AsRef<Path>>(&self, restore_dir: P) -> anyhow::Result<()> {
+        if let Some(observation_normalizer) = self.observation_normalizer() {
+            observation_normalizer
+                .load(restore_dir)
+                .with_context(|| "load observation normalizer")?;
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn save_optimizer<
+    B: AutodiffBackend,
+    M: AutodiffModule<B>,
+    O: SimpleOptimizer<B::InnerBackend>,
+>(
+    optimizer: &OptimizerAdaptor<O, M, B>,
+    path: &Path,
+) -> anyhow::Result<()> {
+    let optimizer_record = optimizer
+        .to_record()
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.into_item()))
+        .collect::<hashbrown::HashMap<String, AdaptorRecordItem<O, B, HalfPrecisionSettings>>>();
+    let mut optimizer_file =
+        File::create(path).with_context(|| format!("create optimizer file {:?}", path))?;
+    rmp_serde::encode::write(&mut optimizer_file, &optimizer_record)
+        .with_context(|| "Failed to write optimizer record")?;
+    Ok(())
+}
+
+pub(crate) fn load_optimizer<
+    B: AutodiffBackend,
+    M: AutodiffModule<B>,
+    O: SimpleOptimizer<B::InnerBackend>,
+>(
+    optimizer: OptimizerAdaptor<O, M, B>,
+    path: &Path,
+    device: &B::Device,
+) -> anyhow::Result<OptimizerAdaptor<O, M, B>> {
+    let optimizer_file =
+        File::open(path).with_context(|| format!("open optimizer file {:?}", path))?;
+    let record: hashbrown::HashMap<String, AdaptorRecordItem<O, B, HalfPrecisionSettings>> =
+        rmp_serde::decode::from_read(optimizer_file)
+            .with_context(|| "Failed to read optimizer record")?;
+    let record = record
+        .into_iter()
+        .map(|(k, v)| {
+            (
+                ParamId::deserialize(k.as_str()),
+                AdaptorRecord::from_item(v, device),
+            )
+        })
+        .collect::<hashbrown::HashMap<_, _>>();
+    Ok(optimizer.load_record(record))
+}
+
+pub(crate) fn save_teacher_model<B: Backend, M: Module<B>>(
+    teacher_model: &M,
+    artifacts_dir: &Path,
+) -> anyhow::Result<()> {
+    teacher_model
+        .clone()
+        .save_file(artifacts_dir.join("teacher_model"), &CompactRecorder::new())
+        .with_context(|| "fail to save teacher model")?;
+    Ok(())
+}
+
+// checkpoints without a teacher start it from the model
+pub(crate) fn load_teacher_model<B: Backend, M: Module<B>>(
+    model: &M,
+    teacher_model: M,
+    restore_dir: &Path,
+    device: &B::Device,
+) -> anyhow::Result<M> {
+    let teacher_model_file = restore_dir.join("teacher_model.mpk");
+    if !teacher_model_file.exists() {
+        return Ok(model.clone().fork(device));
+    }
+    let record = CompactRecorder::new()
+        .load(teacher_model_file, device)
+        .with_context(|| "Failed to load teacher model")?;
+    Ok(teacher_model.load_record(record))
+}
+
+// the rng of the noisy layers of a model and its teacher
+#[derive(Clone)]
+pub(crate) struct ModelNoise {
+    rng: StdRng,
+}
+
+impl Default for ModelNoise {
+    fn default() -> Self {
+        Self {
+            rng: StdRng::from_entropy(),
+        }
+    }
+}
+
+impl ModelNoise {
+    pub fn seed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    pub fn resample<B: Backend, M: Noisy<B> + Clone>(
+        &mut self,
+        model: &mut M,
+        teacher_model: &mut M,
+    ) {
+        *model = model.clone().resample_noise(&mut self.rng);
+        *teacher_model = teacher_model.clone().resample_noise(&mut self.rng);
+    }
+}
+
+pub(crate) fn set_models_training<B: Backend, M: Noisy<B> + Clone>(
+    model: &mut M,
+    teacher_model: &mut M,
+    training: bool,
+) {
+    *model = model.clone().train(training);
+    *teacher_model = teacher_model.clone().train(training);
+}
+
+// greedy discrete actions for a batch of observations, `q_value` maps the
+// [batch, ...] observation tensor to [batch, action] scores, e.g. Estimator::predict
+pub(crate) fn batch_greedy_policy<B: Backend, const D: usize>(
+    observations: &[Vec<f32>],
+    observation_space: &ObservationSpace<D>,
+    device: &B::Device,
+    q_value: impl FnOnce(Tensor<B, D>) -> Tensor<B, 2>,
+) -> Vec<Action> {
+    let mut shape = *observation_space.shape();
+    shape[0] = observations.len();
+    let feature = Tensor::from_data(
+        TensorData::new(observations.concat(), Shape::new(shape)).convert::<B::FloatElem>(),
+        device,
+    );
+    q_value(feature)
+        .argmax(1)
+        .flatten::<1>(0, 1)
+        .into_data()
+        .iter::<i64>()
+        .map(Action::Discrete)
+        .collect()
+}
+
+// expectation under the probability weights g(F(v_i)) - g(F(v_{i-1})) of the sorted outcomes,
+// `probs` are normalized, returns [batch, action, 1]
+fn distorted_expectation<B: Backend>(
+    values: Tensor<B, 3>,
+    probs: Tensor<B, 3>,
+    g: impl Fn(Tensor<B, 3>) -> Tensor<B, 3>,
+) -> Tensor<B, 3> {
+    let [batch_size, action_num, support] = values.dims();
+    let (values, indices) = values.sort_with_indices(2);
+    let probs = probs.gather(2, indices);
+    // cumulative sums along the support as a product with an upper triangular matrix of ones
+    let upper = Tensor::<B, 2>::ones([support, support], &values.device()).triu(0);
+    let cumulative = probs
+        .reshape([batch_size * action_num, support])
+        .matmul(upper)
+        .reshape([batch_size, action_num, support]);
+    // g(1) is 1 whatever the rounding of the cumulative sum
+    let weights = g(cumulative.clamp_max(1.0)).slice_assign(
+        [0..batch_size, 0..action_num, support - 1..support],
+        Tensor::ones([batch_size, action_num, 1], &values.device()),
+    );
+    let previous = Tensor::cat(
+        vec![
+            weights
+                .zeros_like()
+                .slice([0..batch_size, 0..action_num, 0..1]),
+            weights
+                .clone()
+                .slice([0..batch_size, 0..action_num, 0..support - 1]),
+        ],
+        2,
+    );
+    ((weights - previous) * values).sum_dim(2)
+}
+
+fn normal_cdf<B: Backend, const D: usize>(x: Tensor<B, D>) -> Tensor<B, D> {
+    (x.div_scalar(std::f32::consts::SQRT_2).erf() + 1.0) * 0.5
+}
+
+// Acklam's rational approximation of the inverse normal cdf, p is clamped away from 0 and 1
+fn normal_quantile<B: Backend, const D: usize>(p: Tensor<B, D>) -> Tensor<B, D> {
+    const CENTRAL_NUMERATOR: [f32; 6] = [
+        -3.969683e+01,
+        2.209461e+02,
+        -2.759285e+02,
+        1.3835775e+02,
+        -3.0664798e+01,
+        2.5066283e+00,
+    ];
+    const CENTRAL_DENOMINATOR: [f32; 6] = [
+        -5.4476099e+01,
+        1.6158584e+02,
+        -1.5569898e+02,
+        6.6801312e+01,
+        -1.3280682e+01,
+        1.0,
+    ];
+    const TAIL_NUMERATOR: [f32; 6] = [
+        -7.784894e-03,
+        -3.2239646e-01,
+        -2.4007583e+00,
+        -2.5497325e+00,
+        4.3746641e+00,
+        2.938164e+00,
+    ];
+    const TAIL_DENOMINATOR: [f32; 5] = [
+        7.784696e-03,
+        3.2246713e-01,
+        2.4451342e+00,
+        3.7544087e+00,
+        1.0,
+    ];
+    const P_LOW: f32 = 0.02425;
+
+    let horner = |coefficients: &[f32], x: Tensor<B, D>| {
+        coefficients
+            .iter()
+            .fold(x.zeros_like(), |acc, c| acc * x.clone() + *c)
+    };
+    let tail = |q: Tensor<B, D>| horner(&TAIL_NUMERATOR, q.clone()) / horner(&TAIL_DENOMINATOR, q);
+
+    let p = p.clamp(1e-7, 1.0 - 1e-7);
+    let q = p.clone() - 0.5;
+    let r = q.clone() * q.clone();
+    let central = horner(&CENTRAL_NUMERATOR, r.clone()) * q / horner(&CENTRAL_DENOMINATOR, r);
+    let low = tail((p.clone().log() * -2.0).sqrt());
+    let high = -tail(((-p.clone() + 1.0).log() * -2.0).sqrt());
+    central
+        .mask_where(p.clone().lower_elem(P_LOW), low)
+        .mask_where(p.greater_elem(1.0 - P_LOW), high)
+}
+
+#[cfg(test)]
+mod tests {
+    use burn::backend::{libtorch::LibTorchDevice, LibTorch};
+
+    use super::*;
+
+    fn risk_value(risk_measure: RiskMeasure, values: &[f32], probs: &[f32]) -> f32 {
+        let device = LibTorchDevice::Cpu;
+        let shape = Shape::new([1, 1, values.len()]);
+        let values = Tensor::<LibTorch, 3>::from_data(
+            TensorData::new(values.to_vec(), shape.clone()),
+            &device,
+        );
+        let probs = Tensor::from_data(TensorData::new(probs.to_vec(), shape), &device);
+        risk_measure.risk_values(values, probs).into_scalar()
+    }
+
+    #[test]
+    fn test_risk_measure() {
+        let values = [3.0, -1.0, 2.0, 0.0];
+        let probs = [0.25; 4];
+
+        approx::assert_abs_diff_eq!(
+            risk_value(RiskMeasure::Mean, &values, &probs),
+            1.0,
+            epsilon = 1e-6
+        );
+        // the worst half of the outcomes is {-1, 0}
+        approx::assert_abs_diff_eq!(
+            risk_value(
+                RiskMeasure::ConditionalValueAtRisk { alpha: 0.5 },
+                &values,
+                &probs
+            ),
+            -0.5,
+            epsilon = 1e-6
+        );
+        approx::assert_abs_diff_eq!(
+            risk_value(
+                RiskMeasure::ConditionalValueAtRisk { alpha: 1.0 },
+                &values,
+                &probs
+            ),
+            1.0,
+            epsilon = 1e-6
+        );
+        approx::assert_abs_diff_eq!(
+            risk_value(RiskMeasure::MeanMinusStd { k: 2.0 }, &values, &probs),
+            1.0 - 2.0 * 2.5f32.sqrt(),
+            epsilon = 1e-5
+        );
+        approx::assert_abs_diff_eq!(
+            risk_value(RiskMeasure::Wang { eta: 0.0 }, &values, &probs),
+            1.0,
+            epsilon = 1e-4
+        );
+        let averse = risk_value(RiskMeasure::Wang { eta: -0.75 }, &values, &probs);
+        let seeking = risk_value(RiskMeasure::Wang { eta: 0.75 }, &values, &probs);
+        assert!(averse < 1.0 && 1.0 < seeking);
+
+        // the weights are normalized
+        approx::assert_abs_diff_eq!(
+            risk_value(
+                RiskMeasure::ConditionalValueAtRisk { alpha: 0.5 },
+                &[1.0, 5.0],
+                &[2.0, 2.0]
+            ),
+            1.0,
+            epsilon = 1e-6
+        );
+
+        // each action of each batch row has its own distribution
+        let device = LibTorchDevice::Cpu;
+        let values = Tensor::<LibTorch, 3>::from_data(
+            TensorData::new(
+                vec![3.0, -1.0, 2.0, 0.0, 1.0, 1.0, 1.0, 1.0],
+                Shape::new([2, 1, 4]),
+            ),
+            &device,
+        );
+        let risk_values = RiskMeasure::ConditionalValueAtRisk { alpha: 0.5 }
+            .risk_values(values.clone(), values.ones_like())
+            .into_data()
+            .to_vec::<f32>()
+            .unwrap();
+        approx::assert_abs_diff_eq!(risk_values[0], -0.5, epsilon = 1e-6);
+        approx::assert_abs_diff_eq!(risk_values[1], 1.0, epsilon = 1e-6);
+
+        let p = Tensor::<LibTorch, 1>::from_floats([0.001, 0.1, 0.5, 0.7, 0.999], &device);
+        let roundtrip = normal_cdf(normal_quantile(p.clone()));
+        assert!((roundtrip - p).abs().max().into_scalar() < 1e-5);
+    }
+
+    #[test]
+    fn test_distort_taus() {
+        let device = LibTorchDevice::Cpu;
+        let taus = Tensor::<LibTorch, 2>::from_floats([[0.1, 0.5, 0.9]], &device);
+
+        let cvar = RiskMeasure::ConditionalValueAtRisk { alpha: 0.25 }
+            .distort_taus(taus.clone())
+            .unwrap();
+        assert!(
+            (cvar - taus.clone().mul_scalar(0.25))
+                .abs()
+                .max()
+                .into_scalar()
+                < 1e-6
+        );
+        let neutral = RiskMeasure::Wang { eta: 0.0 }
+            .distort_taus(taus.clone())
+            .unwrap();
+        assert!((neutral - taus.clone()).abs().max().into_scalar() < 1e-5);
+        // a risk averse distortion samples lower taus
+        let averse = RiskMeasure::Wang { eta: -0.75 }
+            .distort_taus(taus.clone())
+            .unwrap();
+        assert!((averse - taus.clone()).max().into_scalar() < 0.0);
+        assert!(RiskMeasure::MeanMinusStd { k: 1.0 }
+            .distort_taus(taus)
+            .is_none());
+    }
+}
@@ -0,0 +1,580 @@
+use std::{fmt::Display, fs::File, path::Path};
+
+use anyhow::{ensure, Context as _};
+use burn::{
+    config::Config,
+    data::dataloader::batcher::Batcher as _,
+    lr_scheduler::LrScheduler,
+    module::AutodiffModule,
+    nn::loss::{HuberLossConfig, MseLoss},
+    optim::{adaptor::OptimizerAdaptor, GradientsParams, Optimizer as _, SimpleOptimizer},
+    record::{CompactRecorder, HalfPrecisionSettings, Record, Recorder as _},
+    tensor::{
+        backend::{AutodiffBackend, Backend},
+        ElementConversion as _, Shape, Tensor, TensorData,
+    },
+};
+
+use crate::{
+    batch::DeepQNetworkBathcer, env::wrapper::ObservationNormalizer, layers::Noisy,
+    model::FullyParameterizedQuantileLayer, Action, ActionSpace, Agent, Estimator, Experience,
+    FractionProposal, ImplicitQuantile, ObservationSpace, ObservationState, PrioritizedReplay,
+    PrioritizedReplayAgent,
+};
+
+use super::{
+    batch_greedy_policy, load_optimizer, load_teacher_model, save_optimizer, save_teacher_model,
+    set_models_training, LossFunction, ModelNoise, ObservationNormalized, RiskMeasure,
+};
+
+#[derive(Debug, Config)]
+pub struct FullyParameterizedQuantileAgentConfig {
+    teacher_update_freq: usize,
+    n_step: usize,
+    double_dqn: bool,
+    loss_function: LossFunction,
+    #[config(default = "RiskMeasure::Mean")]
+    risk_measure: RiskMeasure,
+}
+
+#[derive(Clone)]
+pub struct FullyParameterizedQuantileAgent<
+    B: AutodiffBackend,
+    const D: usize,
+    M: AutodiffModule<B>,
+    O: SimpleOptimizer<B::InnerBackend>,
+    S: LrScheduler,
+> {
+    model: M,
+    teacher_model: M,
+    optimizer: OptimizerAdaptor<O, M, B>,
+    lr_scheduler: S,
+    observation_space: ObservationSpace<D>,
+    action_space: ActionSpace,
+    device: B::Device,
+    observation_normalizer: Option<ObservationNormalizer>,
+    noise: ModelNoise,
+    update_counter: usize,
+
+    config: FullyParameterizedQuantileAgentConfig,
+}
+
+impl<
+        B: AutodiffBackend,
+        const D: usize,
+        M: AutodiffModule<B> + Estimator<B> + ImplicitQuantile<B> + FractionProposal<B>,
+        O: SimpleOptimizer<B::InnerBackend>,
+        S: LrScheduler,
+    > FullyParameterizedQuantileAgent<B, D, M, O, S>
+{
+    pub fn new(
+        model: M,
+        optimizer: OptimizerAdaptor<O, M, B>,
+        lr_scheduler: S,
+        observation_space: ObservationSpace<D>,
+        action_space: ActionSpace,
+        device: B::Device,
+
+        config: FullyParameterizedQuantileAgentConfig,
+    ) -> anyhow::Result<Self> {
+        ensure!(
+            matches!(action_space, ActionSpace::Discrete(..)),
+            "FQF needs a discrete action space, got {:?}",
+            action_space
+        );
+        let teacher_model = model.clone().fork(&device);
+        Ok(Self {
+            model,
+            teacher_model,
+            optimizer,
+            lr_scheduler,
+            observation_space,
+            action_space,
+            device,
+            observation_normalizer: None,
+            noise: ModelNoise::default(),
+            update_counter: 0,
+            config,
+        })
+    }
+
+    fn risk_q_value<BB: Backend, MM: ImplicitQuantile<BB> + FractionProposal<BB>>(
+        &self,
+        model: &MM,
+        observation: Tensor<BB, D>,
+    ) -> Tensor<BB, 2> {
+        let taus = model.propose_fractions(observation.clone());
+        let [batch_size, num_fraction] = taus.dims();
+        let quantiles = model.get_quantiles(
+            observation,
+            FullyParameterizedQuantileLayer::tau_hats(taus.clone()),
+        );
+        let action_num = quantiles.dims()[1];
+        let probs = (taus.clone().slice([0..batch_size, 1..num_fraction])
+            - taus.slice([0..batch_size, 0..num_fraction - 1]))
+        .reshape([batch_size, 1, num_fraction - 1])
+        .repeat_dim(1, action_num);
+        self.config.risk_measure.risk_values(quantiles, probs)
+    }
+}
+
+impl<
+        B: AutodiffBackend,
+        const D: usize,
+        M: AutodiffModule<B>,
+        O: SimpleOptimizer<B::InnerBackend>,
+        S: LrScheduler,
+    > ObservationNormalized for FullyParameterizedQuantileAgent<B, D, M, O, S>
+{
+    fn observation_normalizer(&self) -> Option<&ObservationNormalizer> {
+        self.observation_normalizer.as_ref()
+    }
+
+    fn observation_normalizer_mut(&mut self) -> &mut Option<ObservationNormalizer> {
+        &mut self.observation_normalizer
+    }
+}
+
+impl<B, const D: usize, M, O, S, T> PrioritizedReplay<T>
+    for FullyParameterizedQuantileAgent<B, D, M, O, S>
+where
+    B: AutodiffBackend,
+    M: AutodiffModule<B> + Display + Estimator<B> + ImplicitQuantile<B> + FractionProposal<B>,
+    M::InnerModule: Estimator<B::InnerBackend>
+        + ImplicitQuantile<B::InnerBackend>
+        + FractionProposal<B::InnerBackend>,
+    O: SimpleOptimizer<B::InnerBackend>,
+    S: LrScheduler + Clone,
+    T: ObservationState,
+{
+    fn temporaral_difference_error(
+        &self,
+        gamma: f32,
+        experiences: &[Experience<T>],
+    ) -> anyhow::Result<Vec<f32>> {
+        let batcher = DeepQNetworkBathcer::new(self.device.clone(), self.action_space.clone());
+
+        let mut shape = *self.observation_space.shape();
+        shape[0] = experiences.len();
+
+        let model = self.model.clone();
+        let item = batcher.batch(experiences.to_vec());
+        let observation = item.observation.clone();
+        let q_value = model.predict(observation.reshape(shape));
+        let next_target_q_value = self
+            .teacher_model
+            .valid()
+            .predict(item.next_observation.clone().inner().reshape(shape));
+        let num_class = self.action_space.size();
+        let next_observation = item.next_observation.clone().inner().reshape(shape);
+        let next_actions = if self.config.double_dqn {
+            self.risk_q_value(&model.valid(), next_observation)
+                .argmax(1)
+        } else {
+            self.risk_q_value(&self.teacher_model.valid(), next_observation)
+                .argmax(1)
+        };
+        let next_target_q_value = next_target_q_value
+            .gather(1, next_actions)
+            .repeat_dim(1, num_class);
+        let next_target_q_value: Tensor<B, 2> =
+            Tensor::from_inner(next_target_q_value).to_device(&self.device);
+        let targets = next_target_q_value
+            .clone()
+            .inner()
+            .mul_scalar(gamma.powi(self.config.n_step as i32))
+            * (item.done.ones_like().inner() - item.done.clone().inner())
+            + item.reward.clone().inner();
+        let targets = q_value.clone().inner()
+            * (item.action.ones_like().inner() - item.action.clone().inner())
+            + targets * item.action.clone().inner();
+        let td: Vec<f32> = (q_value.inner() - targets)
+            .abs()
+            .sum_dim(1)
+            .into_data()
+            .to_vec()
+            .map_err(|e| anyhow::anyhow!("{:?}", e))?;
+        Ok(td)
+    }
+}
+
+impl<B, const D: usize, M, O, S, T> Agent<T> for FullyParameterizedQuantileAgent<B, D, M, O, S>
+where
+    B: AutodiffBackend,
+    M: AutodiffModule<B>
+        + Display
+        + Estimator<B>
+        + ImplicitQuantile<B>
+        + FractionProposal<B>
+        + Noisy<B>,
+    M::InnerModule: Estimator<B::InnerBackend>
+        + ImplicitQuantile<B::InnerBackend>
+        + FractionProposal<B::InnerBackend>,
+    O: SimpleOptimizer<B::InnerBackend>,
+    S: LrScheduler + Clone,
+    T: ObservationState,
+{
+    fn supports_action_space(action_space: &ActionSpace) -> bool {
+        matches!(action_space, ActionSpace::Discrete(..))
+    }
+
+    fn policy(&self, observation: &[f32]) -> Action {
+        let shape = *self.observation_space.shape();
+        let feature: Tensor<<B as AutodiffBackend>::InnerBackend, D> = Tensor::from_data(
+            TensorData::new(observation.to_vec(), Shape::new(shape)).convert::<B::FloatElem>(),
+            &self.device,
+        );
+        let scores = self.risk_q_value(&self.model.valid(), feature);
+        let scores = scores.argmax(1);
+        let scores = scores.flatten::<1>(0, 1).into_scalar();
+        Action::Discrete(scores.elem())
+    }
+
+    fn batch_policy(&self, observations: &[Vec<f32>]) -> Vec<Action> {
+        batch_greedy_policy(
+            observations,
+            &self.observation_space,
+            &self.device,
+            |feature| self.risk_q_value(&self.model.valid(), feature),
+        )
+    }
+
+    fn batch_state_policy(&self, _observations: &[Vec<f32>], states: &[T]) -> Vec<Action> {
+        let observations = states
+            .iter()
+            .map(|state| state.next_observation())
+            .collect::<Vec<_>>();
+        Agent::<T>::batch_policy(self, &observations)
+    }
+
+    fn update(
+        &mut self,
+        gamma: f32,
+        experiences: &[Experience<T>],
+        weights: &[f32],
+    ) -> anyhow::Result<()> {
+        let batcher = DeepQNetworkBathcer::new(self.device.clone(), self.action_space.clone());
+
+        let batch_size = experiences.len();
+        let mut shape = *self.observation_space.shape();
+        shape[0] = batch_size;
+
+        let model = self.model.clone();
+        let item = batcher.batch(experiences.to_vec());
+
+        let next_observation = item.next_observation.clone().inner().reshape(shape);
+        let next_q_value = if self.config.double_dqn {
+            self.risk_q_value(&model.valid(), next_observation.clone())
+        } else {
+            self.risk_q_value(&self.teacher_model.valid(), next_observation.clone())
+        };
+        let next_taus = self
+            .teacher_model
+            .valid()
+            .propose_fractions(next_observation.clone());
+        let num_quantile = next_taus.dims()[1] - 1;
+        let next_actions = next_q_value
+            .argmax(1)
+            .reshape([batch_size, 1, 1])
+            .repeat_dim(2, num_quantile);
+        let next_quantiles = self.teacher_model.valid().get_quantiles(
+            next_observation,
+            FullyParameterizedQuantileLayer::tau_hats(next_taus),
+        );
+        let next_quantiles = next_quantiles.gather(1, next_actions); // [batch_size, 1, num_quantile]
+
+        let reward = item
+            .reward
+            .clone()
+            .mean_dim(1)
+            .inner()
+            .reshape([batch_size, 1, 1]);
+        let done = item
+            .done
+            .clone()
+            .mean_dim(1)
+            .inner()
+            .reshape([batch_size, 1, 1]);
+
+        let target_quantiles = reward
+            + next_quantiles.mul_scalar(gamma.powi(self.config.n_step as i32))
+                * (done.ones_like() - done); // [batch_size, 1, num_quantile]
+        let target_quantiles = Tensor::from_inner(target_quantiles);
+
+        let observation = item.observation.clone().reshape(shape);
+        let taus = model.propose_fractions(observation.clone()); // [batch_size, num_quantile + 1]
+        let tau_hats = FullyParameterizedQuantileLayer::tau_hats(taus.clone().detach());
+        let actions = item
+            .action
+            .clone()
+            .argmax(1)
+            .reshape([batch_size, 1, 1])
+            .repeat_dim(2, num_quantile);
+        let quantile_values = model
+            .get_quantiles(observation.clone(), tau_hats.clone())
+            .gather(1, actions.clone()); // [batch_size, 1, num_quantile]
+
+        // 1-Wasserstein gradient w.r.t. the inner fractions tau_1..tau_{N-1}
+        let quantile_hats = quantile_values
+            .clone()
+            .inner()
+            .reshape([batch_size, num_quantile]);
+        let inner_taus = taus.clone().inner().slice([0..batch_size, 1..num_quantile]);
+        let inner_quantiles = model
+            .valid()
+            .get_quantiles(observation.inner(), inner_taus)
+            .gather(
+                1,
+                actions
+                    .inner()
+                    .slice([0..batch_size, 0..1, 0..num_quantile - 1]),
+            )
+            .reshape([batch_size, num_quantile - 1]);
+        let fraction_grads = inner_quantiles.mul_scalar(2.0)
+            - quantile_hats
+                .clone()
+                .slice([0..batch_size, 0..num_quantile - 1])
+            - quantile_hats.slice([0..batch_size, 1..num_quantile]);
+        let fraction_loss = (Tensor::from_inner(fraction_grads)
+            * taus.slice([0..batch_size, 1..num_quantile]))
+        .sum_dim(1);
+
+        let quantile_values = quantile_values.permute([0, 2, 1]); // [batch_size, num_quantile, 1]
+        let loss = match self.config.loss_function {
+            LossFunction::Huber => HuberLossConfig::new(1.0)
+                .init()
+                .forward_no_reduction(quantile_values.clone(), target_quantiles.clone()),
+            LossFunction::Squared => MseLoss::new()
+                .forward_no_reduction(quantile_values.clone(), target_quantiles.clone()),
+        };
+
+        let td_errors = (target_quantiles - quantile_values).inner();
+        let is_negative = td_errors.clone().lower(td_errors.zeros_like()).float();
+        let tau_hats = tau_hats.inner().reshape([batch_size, num_quantile, 1]);
+        let quantile_weights = (tau_hats - is_negative).abs();
+        let quantile_weights = Tensor::from_inner(quantile_weights);
+        let loss = (loss * quantile_weights)
+            .mean_dim(2)
+            .reshape([batch_size, num_quantile])
+            .sum_dim(1)
+            + fraction_loss;
+        let weights = Tensor::from_data(
+            TensorData::new(weights.to_vec(), Shape::new([weights.len(), 1]))
+                .convert::<B::FloatElem>(),
+            &self.device,
+        );
+        let loss = loss * weights;
+        let loss = loss.mean();
+        let grads: <B as AutodiffBackend>::Gradients = loss.backward();
+        let grads = GradientsParams::from_grads(grads, &model);
+        self.model = self.optimizer.step(self.lr_scheduler.step(), model, grads);
+
+        self.update_counter += 1;
+        if self.update_counter % self.config.teacher_update_freq == 0 {
+            self.teacher_model = self.model.clone().fork(&self.device);
+        }
+
+        Ok(())
+    }
+
+    fn set_training(&mut self, training: bool) {
+        set_models_training(&mut self.model, &mut self.teacher_model, training);
+    }
+
+    fn resample_noise(&mut self) {
+        self.noise
+            .resample(&mut self.model, &mut self.teacher_model);
+    }
+
+    fn set_noise_seed(&mut self, seed: u64) {
+        self.noise.seed(seed);
+    }
+
+    fn update_counter(&self) -> usize {
+        self.update_counter
+    }
+
+    fn set_update_counter(&mut self, update_counter: usize) {
+        self.update_counter = update_counter;
+    }
+
+    fn make_state(&self, next_observation: &[f32], state: &T) -> T {
+        state.next_state(next_observation)
+    }
+
+    fn save<P: AsRef<Path>>(&self, artifacts_dir: P) -> anyhow::Result<()> {
+        let artifacts_dir = artifacts_dir.as_ref().to_path_buf();
+        std::fs::create_dir_all(&artifacts_dir)
+            .with_context(|| format!("fail to create {:?}", artifacts_dir))?;
+        self.model
+            .clone()
+            .save_file(artifacts_dir.join("model"), &CompactRecorder::new())
+            .with_context(|| "fail to save model")?;
+        save_teacher_model(&self.teacher_model, &artifacts_dir)?;
+        save_optimizer(&self.optimizer, &artifacts_dir.join("optimizer.mpk"))?;
+
+        let scheduler_record = self.lr_scheduler.to_record();
+        let scheduler_record: <<S as LrScheduler>::Record<B> as Record<_>>::Item<
+            HalfPrecisionSettings,
+        > = scheduler_record.into_item();
+        let mut scheduler_file = File::create(artifacts_dir.join("scheduler.mpk"))
+            .with_context(|| "create scheduler file")?;
+        rmp_serde::encode::write(&mut scheduler_file, &scheduler_record)
+            .with_context(|| "Failed to write scheduler record")?;
+        self.save_observation_normalizer(&artifacts_dir)?;
+        Ok(())
+    }
+
+    fn load<P: AsRef<Path>>(&mut self, restore_dir: P) -> anyhow::Result<()> {
+        let restore_dir = restore_dir.as_ref().to_path_buf();
+        let model_file = restore_dir.join("model.mpk");
+        if model_file.exists() {
+            let record = CompactRecorder::new()
+                .load(model_file, &self.device)
+                .with_context(|| "Failed to load model")?;
+            self.model = self.model.clone().load_record(record);
+        }
+        self.teacher_model = load_teacher_model(
+            &self.model,
+            self.teacher_model.clone(),
+            &restore_dir,
+            &self.device,
+        )?;
+        let optimizer_file = restore_dir.join("optimizer.mpk");
+        if optimizer_file.exists() {
+            self.optimizer = load_optimizer(self.optimizer.clone(), &optimizer_file, &self.device)?;
+        }
+        let scheduler_file = restore_dir.join("scheduler.mpk");
+        if scheduler_file.exists() {
+            let scheduler_file =
+                File::open(scheduler_file).with_context(|| "open scheduler file")?;
+            let record: <<S as LrScheduler>::Record<B> as Record<_>>::Item<HalfPrecisionSettings> =
+                rmp_serde::decode::from_read(scheduler_file)
+                    .with_context(|| "Failed to read scheduler record")?;
+            let record =
+                <<S as LrScheduler>::Record<B> as Record<_>>::from_item(record, &self.device);
+            self.lr_scheduler = self.lr_scheduler.clone().load_record(record);
+        }
+        self.load_observation_normalizer(&restore_dir)?;
+
+        Ok(())
+    }
+}
+
+impl<B, const D: usize, M, O, S, T> PrioritizedReplayAgent<T>
+    for FullyParameterizedQuantileAgent<B, D, M, O, S>
+where
+    B: AutodiffBackend,
+    M: AutodiffModule<B>
+        + Display
+        + Estimator<B>
+        + ImplicitQuantile<B>
+        + FractionProposal<B>
+        + Noisy<B>,
+    M::InnerModule: Estimator<B::InnerBackend>
+        + ImplicitQuantile<B::InnerBackend>
+        + FractionProposal<B::InnerBackend>,
+    O: SimpleOptimizer<B::InnerBackend>,
+    S: LrScheduler + Clone,
+    T: ObservationState,
+{
+}
+
+#[cfg(test)]
+mod tests {
+    use burn::{
+        backend::{libtorch::LibTorchDevice, Autodiff, LibTorch},
+        lr_scheduler::constant::ConstantLr,
+        optim::AdamConfig,
+    };
+    use tempfile::TempDir;
+
+    use crate::{
+        env::classic::CartPole,
+        model::{DeepQNetworkModel, HeadConfig, OutputLayerConfig, TorsoConfig},
+        trainer::{
+            replay::ReplayTrainer, storage::StorageBackend, uniform::UniformReplayMemory,
+            RandomPolicy, RewardMapping,
+        },
+        DeepQNetworkState, Env, State,
+    };
+
+    use super::*;
+
+    type Backend = Autodiff<LibTorch>;
+
+    #[test]
+    fn test_train_loop_fully_parameterized_quantile() -> anyhow::Result<()> {
+        let device = LibTorchDevice::Cpu;
+        let mut env = CartPole::with_seed(0);
+        let model = DeepQNetworkModel::<Backend>::new(
+            &device,
+            env.observation_space(),
+            env.action_space(),
+            &TorsoConfig::default_for::<2>(),
+            &HeadConfig::new(),
+            false,
+            false,
+            OutputLayerConfig::FullyParameterizedQuantile {
+                quantiles: 8,
+                embedding_dim: 16,
+            },
+        )?;
+        let taus = model
+            .propose_fractions(Tensor::<Backend, 2>::zeros([3, 4], &device))
+            .into_data()
+            .to_vec::<f32>()
+            .map_err(|e| anyhow::anyhow!("{:?}", e))?;
+        for taus in taus.chunks(9) {
+            approx::assert_abs_diff_eq!(taus[0], 0.0, epsilon = 1e-6);
+            approx::assert_abs_diff_eq!(taus[8], 1.0, epsilon = 1e-5);
+            assert!(taus.windows(2).all(|w| w[0] <= w[1]));
+        }
+
+        let mut agent = FullyParameterizedQuantileAgent::new(
+            model,
+            AdamConfig::new().init(),
+            ConstantLr::new(0.00025),
+            env.observation_space().clone(),
+            env.action_space().clone(),
+            device,
+            FullyParameterizedQuantileAgentConfig::new(100, 1, true, LossFunction::Huber),
+        )?;
+        let mut state = DeepQNetworkState::new(env.reset()?);
+        let mut experiences = Vec::new();
+        for i in 0..8 {
+            let action = Action::Discrete(i % 2);
+            let result = env.step(&action)?;
+            state = agent.make_state(&result.observation, &state);
+            experiences.push(Experience {
+                state: state.clone(),
+                action,
+                reward: result.reward,
+                terminated: result.terminated,
+                truncated: result.truncated,
+            });
+        }
+        agent.update(0.99, &experiences, &[1.0; 8])?;
+        assert_eq!(
+            agent.temporaral_difference_error(0.99, &experiences)?.len(),
+            8
+        );
+
+        let mut memory =
+            UniformReplayMemory::<DeepQNetworkState>::new(1024, 8, StorageBackend::InMemory)?;
+
+        let artifacts_dir = TempDir::new()?;
+        let trainer = ReplayTrainer::new(
+            2,
+            0.99,
+            1,
+            RewardMapping::Identity,
+            artifacts_dir.path().to_path_buf(),
+            false,
+        )?;
+        let random_policy = Some(RandomPolicy::new(1.0, 0.01, 0.99));
+        trainer.train_loop(&mut agent, &mut env, &mut memory, &random_policy, &mut None)?;
+        assert!(artifacts_dir.path().join("1").join("model.mpk").exists());
+        Ok(())
+    }
+}
@@ -0,0 +1,467 @@
+use std::{f32::consts::PI, fs::File, path::Path};
+
+use anyhow::{anyhow, Context};
+use burn::{
+    config::Config,
+    lr_scheduler::LrScheduler,
+    module::AutodiffModule,
+    optim::{adaptor::OptimizerAdaptor, GradientsParams, Optimizer, SimpleOptimizer},
+    record::{CompactRecorder, HalfPrecisionSettings, Record, Recorder},
+    tensor::{
+        activation::log_softmax,
+        backend::{AutodiffBackend, Backend},
+        Distribution, Shape, Tensor, TensorData,
+    },
+};
+
+use crate::{
+    env::wrapper::ObservationNormalizer, Action, ActionSpace, ActorCritic, Agent,
+    DeepQNetworkState, Experience, ObservationSpace, OnPolicy, OnPolicyAgent, RolloutSample,
+};
+
+use super::{load_optimizer, save_optimizer, ObservationNormalized};
+
+#[derive(Debug, Config)]
+pub struct ProximalPolicyOptimizationAgentConfig {
+    #[config(default = 0.2)]
+    clip_epsilon: f32,
+    #[config(default = 0.5)]
+    value_coefficient: f32,
+    #[config(default = 0.01)]
+    entropy_coefficient: f32,
+}
+
+#[derive(Clone)]
+pub struct ProximalPolicyOptimizationAgent<
+    B: AutodiffBackend,
+    const D: usize,
+    M: AutodiffModule<B>,
+    O: SimpleOptimizer<B::InnerBackend>,
+    S: LrScheduler,
+> {
+    model: M,
+    optimizer: OptimizerAdaptor<O, M, B>,
+    lr_scheduler: S,
+    observation_space: ObservationSpace<D>,
+    action_space: ActionSpace,
+    device: B::Device,
+    observation_normalizer: Option<ObservationNormalizer>,
+    update_counter: usize,
+
+    config: ProximalPolicyOptimizationAgentConfig,
+}
+
+impl<
+        B: AutodiffBackend,
+        const D: usize,
+        M: AutodiffModule<B> + ActorCritic<B>,
+        O: SimpleOptimizer<B::InnerBackend>,
+        S: LrScheduler,
+    > ProximalPolicyOptimizationAgent<B, D, M, O, S>
+where
+    M::InnerModule: ActorCritic<B::InnerBackend>,
+{
+    pub fn new(
+        model: M,
+        optimizer: OptimizerAdaptor<O, M, B>,
+        lr_scheduler: S,
+        observation_space: ObservationSpace<D>,
+        action_space: ActionSpace,
+        device: B::Device,
+        config: ProximalPolicyOptimizationAgentConfig,
+    ) -> Self {
+        Self {
+            model,
+            optimizer,
+            lr_scheduler,
+            observation_space,
+            action_space,
+            device,
+            observation_normalizer: None,
+            update_counter: 0,
+            config,
+        }
+    }
+
+    fn feature<BB: Backend<Device = B::Device>>(&self, observations: &[Vec<f32>]) -> Tensor<BB, D> {
+        let mut shape = *self.observation_space.shape();
+        shape[0] = observations.len();
+        Tensor::from_data(
+            TensorData::new(observations.concat(), Shape::new(shape)).convert::<BB::FloatElem>(),
+            &self.device,
+        )
+    }
+}
+
+impl<
+        B: AutodiffBackend,
+        const D: usize,
+        M: AutodiffModule<B>,
+        O: SimpleOptimizer<B::InnerBackend>,
+        S: LrScheduler,
+    > ObservationNormalized for ProximalPolicyOptimizationAgent<B, D, M, O, S>
+{
+    fn observation_normalizer(&self) -> Option<&ObservationNormalizer> {
+        self.observation_normalizer.as_ref()
+    }
+
+    fn observation_normalizer_mut(&mut self) -> &mut Option<ObservationNormalizer> {
+        &mut self.observation_normalizer
+    }
+}
+
+// log probability of `action` under the policy head output and the policy entropy, both [batch, 1]
+fn log_prob_and_entropy<B: Backend>(
+    policy: Tensor<B, 2>,
+    action: Tensor<B, 2>,
+    action_space: &ActionSpace,
+) -> (Tensor<B, 2>, Tensor<B, 2>) {
+    match action_space {
+        ActionSpace::Discrete(..) => {
+            let log_probs = log_softmax(policy, 1);
+            let entropy = (log_probs.clone().exp() * log_probs.clone())
+                .sum_dim(1)
+                .neg();
+            let log_prob = log_probs.gather(1, action.int());
+            (log_prob, entropy)
+        }
+        ActionSpace::Box { .. } => {
+            let [batch_size, width] = policy.dims();
+            let mean = policy.clone().slice([0..batch_size, 0..width / 2]);
+            let log_std = policy.slice([0..batch_size, width / 2..width]);
+            let log_prob = ((action - mean) / log_std.clone().exp())
+                .powf_scalar(2.0)
+                .mul_scalar(-0.5)
+                - log_std.clone().add_scalar(0.5 * (2.0 * PI).ln());
+            let entropy = log_std.add_scalar(0.5 + 0.5 * (2.0 * PI).ln());
+            (log_prob.sum_dim(1), entropy.sum_dim(1))
+        }
+    }
+}
+
+impl<B, const D: usize, M, O, S> OnPolicy for ProximalPolicyOptimizationAgent<B, D, M, O, S>
+where
+    B: AutodiffBackend,
+    M: AutodiffModule<B> + ActorCritic<B>,
+    M::InnerModule: ActorCritic<B::InnerBackend>,
+    O: SimpleOptimizer<B::InnerBackend>,
+    S: LrScheduler + Clone,
+{
+    fn evaluate(&self, observations: &[Vec<f32>]) -> Vec<(Action, f32, f32)> {
+        let (policy, value) = self
+            .model
+            .valid()
+            .actor_critic(self.feature::<B::InnerBackend>(observations));
+        let action = match self.action_space {
+            // gumbel-max trick samples from the categorical distribution
+            ActionSpace::Discrete(..) => {
+                let gumbel = Tensor::random(
+                    policy.shape(),
+                    Distribution::Uniform(1e-10, 1.0),
+                    &self.device,
+                )
+                .log()
+                .neg()
+                .log()
+                .neg();
+                (policy.clone() + gumbel).argmax(1).float()
+            }
+            ActionSpace::Box { .. } => {
+                let [batch_size, width] = policy.dims();
+                let mean = policy.clone().slice([0..batch_size, 0..width / 2]);
+                let std = policy
+                    .clone()
+                    .slice([0..batch_size, width / 2..width])
+                    .exp();
+                let noise =
+                    Tensor::random(mean.shape(), Distribution::Normal(0.0, 1.0), &self.device);
+                mean + std * noise
+            }
+        };
+        let (log_prob, _) = log_prob_and_entropy(policy, action.clone(), &self.action_space);
+
+        let action_dim = action.dims()[1];
+        let actions = action.into_data().iter::<f32>().collect::<Vec<_>>();
+        let actions = actions
+            .chunks(action_dim)
+            .map(|action| match self.action_space {
+                ActionSpace::Discrete(..) => Action::Discrete(action[0] as i64),
+                ActionSpace::Box { .. } => Action::Continuous(action.to_vec()),
+            });
+        actions
+            .zip(log_prob.into_data().iter::<f32>())
+            .zip(value.into_data().iter::<f32>())
+            .map(|((action, log_prob), value)| (action, log_prob, value))
+            .collect()
+    }
+
+    fn value(&self, observations: &[Vec<f32>]) -> Vec<f32> {
+        let (_, value) = self
+            .model
+            .valid()
+            .actor_critic(self.feature::<B::InnerBackend>(observations));
+        value.into_data().iter::<f32>().collect()
+    }
+
+    fn update_rollout(&mut self, samples: &[RolloutSample]) -> anyhow::Result<()> {
+        let batch_size = samples.len();
+        let observations = samples
+            .iter()
+            .map(|sample| sample.observation.clone())
+            .collect::<Vec<_>>();
+        let actions = samples
+            .iter()
+            .map(|sample| match &sample.action {
+                Action::Discrete(action) => vec![*action as f32],
+                Action::Continuous(action) => action.clone(),
+            })
+            .collect::<Vec<_>>();
+        let action_dim = actions[0].len();
+        let column = |values: Vec<f32>| -> Tensor<B, 2> {
+            Tensor::from_data(
+                TensorData::new(values, Shape::new([batch_size, 1])).convert::<B::FloatElem>(),
+                &self.device,
+            )
+        };
+
+        let advantages = samples
+            .iter()
+            .map(|sample| sample.advantage)
+            .collect::<Vec<_>>();
+        let mean = advantages.iter().sum::<f32>() / batch_size as f32;
+        let std =
+            (advantages.iter().map(|x| (x - mean).powi(2)).sum::<f32>() / batch_size as f32).sqrt();
+        let advantages = column(
+            advantages
+                .into_iter()
+                .map(|x| (x - mean) / (std + 1e-8))
+                .collect(),
+        );
+        let old_log_prob = column(samples.iter().map(|sample| sample.log_prob).collect());
+        let value_target = column(samples.iter().map(|sample| sample.value_target).collect());
+        let action: Tensor<B, 2> = Tensor::from_data(
+            TensorData::new(actions.concat(), Shape::new([batch_size, action_dim]))
+                .convert::<B::FloatElem>(),
+            &self.device,
+        );
+
+        let model = self.model.clone();
+        let (policy, value) = model.actor_critic(self.feature::<B>(&observations));
+        let (log_prob, entropy) = log_prob_and_entropy(policy, action, &self.action_space);
+        let ratio = (log_prob - old_log_prob).exp();
+        let surrogate = (ratio.clone() * advantages.clone()).min_pair(
+            ratio.clamp(
+                1.0 - self.config.clip_epsilon,
+                1.0 + self.config.clip_epsilon,
+            ) * advantages,
+        );
+        let value_loss = (value - value_target).powf_scalar(2.0).mean();
+        let loss = surrogate.mean().neg() + value_loss.mul_scalar(self.config.value_coefficient)
+            - entropy.mean().mul_scalar(self.config.entropy_coefficient);
+
+        let grads = GradientsParams::from_grads(loss.backward(), &model);
+        self.model = self.optimizer.step(self.lr_scheduler.step(), model, grads);
+        self.update_counter += 1;
+
+        Ok(())
+    }
+}
+
+impl<B, const D: usize, M, O, S> Agent<DeepQNetworkState>
+    for ProximalPolicyOptimizationAgent<B, D, M, O, S>
+where
+    B: AutodiffBackend,
+    M: AutodiffModule<B> + ActorCritic<B>,
+    M::InnerModule: ActorCritic<B::InnerBackend>,
+    O: SimpleOptimizer<B::InnerBackend>,
+    S: LrScheduler + Clone,
+{
+    fn supports_action_space(_action_space: &ActionSpace) -> bool {
+        true
+    }
+
+    fn policy(&self, observation: &[f32]) -> Action {
+        let (action, _, _) = self
+            .evaluate(&[observation.to_vec()])
+            .pop()
+            .expect("one action per observation");
+        action
+    }
+
+    fn batch_policy(&self, observations: &[Vec<f32>]) -> Vec<Action> {
+        self.evaluate(observations)
+            .into_iter()
+            .map(|(action, _, _)| action)
+            .collect()
+    }
+
+    fn update(
+        &mut self,
+        _gamma: f32,
+        _experiences: &[Experience<DeepQNetworkState>],
+        _weights: &[f32],
+    ) -> anyhow::Result<()> {
+        Err(anyhow!(
+            "PPO learns from rollouts, train it with RolloutTrainer"
+        ))
+    }
+
+    fn make_state(&self, next_observation: &[f32], state: &DeepQNetworkState) -> DeepQNetworkState {
+        DeepQNetworkState {
+            observation: state.next_observation.clone(),
+            next_observation: next_observation.to_vec(),
+        }
+    }
+
+    fn save<P: AsRef<Path>>(&self, artifacts_dir: P) -> anyhow::Result<()> {
+        let artifacts_dir = artifacts_dir.as_ref().to_path_buf();
+        std::fs::create_dir_all(&artifacts_dir)
+            .with_context(|| format!("fail to create {:?}", artifacts_dir))?;
+        self.model
+            .clone()
+            .save_file(artifacts_dir.join("model"), &CompactRecorder::new())
+            .with_context(|| "fail to save model")?;
+        save_optimizer(&self.optimizer, &artifacts_dir.join("optimizer.mpk"))?;
+
+        let scheduler_record = self.lr_scheduler.to_record();
+        let scheduler_record: <<S as LrScheduler>::Record<B> as Record<_>>::Item<
+            HalfPrecisionSettings,
+        > = scheduler_record.into_item();
+        let mut scheduler_file = File::create(artifacts_dir.join("scheduler.mpk"))
+            .with_context(|| "create scheduler file")?;
+        rmp_serde::encode::write(&mut scheduler_file, &scheduler_record)
+            .with_context(|| "Failed to write scheduler record")?;
+        self.save_observation_normalizer(&artifacts_dir)?;
+        Ok(())
+    }
+
+    fn load<P: AsRef<Path>>(&mut self, restore_dir: P) -> anyhow::Result<()> {
+        let restore_dir = restore_dir.as_ref().to_path_buf();
+        let model_file = restore_dir.join("model.mpk");
+        if model_file.exists() {
+            let record = CompactRecorder::new()
+                .load(model_file, &self.device)
+                .with_context(|| "Failed to load model")?;
+            self.model = self.model.clone().load_record(record);
+        }
+        let optimizer_file = restore_dir.join("optimizer.mpk");
+        if optimizer_file.exists() {
+            self.optimizer = load_optimizer(self.optimizer.clone(), &optimizer_file, &self.device)?;
+        }
+        let scheduler_file = restore_dir.join("scheduler.mpk");
+        if scheduler_file.exists() {
+            let scheduler_file =
+                File::open(scheduler_file).with_context(|| "open scheduler file")?;
+            let record: <<S as LrScheduler>::Record<B> as Record<_>>::Item<HalfPrecisionSettings> =
+                rmp_serde::decode::from_read(scheduler_file)
+                    .with_context(|| "Failed to read scheduler record")?;
+            let record =
+                <<S as LrScheduler>::Record<B> as Record<_>>::from_item(record, &self.device);
+            self.lr_scheduler = self.lr_scheduler.clone().load_record(record);
+        }
+        self.load_observation_normalizer(&restore_dir)?;
+
+        Ok(())
+    }
+}
+
+impl<B, const D: usize, M, O, S> OnPolicyAgent<DeepQNetworkState>
+    for ProximalPolicyOptimizationAgent<B, D, M, O, S>
+where
+    B: AutodiffBackend,
+    M: AutodiffModule<B> + ActorCritic<B>,
+    M::InnerModule: ActorCritic<B::InnerBackend>,
+    O: SimpleOptimizer<B::InnerBackend>,
+    S: LrScheduler + Clone,
+{
+}
+
+#[cfg(test)]
+mod tests {
+    use burn::{
+        backend::{libtorch::LibTorchDevice, Autodiff, LibTorch},
+        lr_scheduler::constant::ConstantLr,
+        optim::AdamConfig,
+    };
+    use tempfile::TempDir;
+
+    use crate::{
+        env::classic::{CartPole, Pendulum},
+        model::ActorCriticModel,
+        trainer::{rollout::RolloutTrainer, RewardMapping, TrainingState},
+        Env,
+    };
+
+    use super::*;
+
+    type Backend = Autodiff<LibTorch>;
+
+    fn train(env: &mut impl Env<2>) -> anyhow::Result<()> {
+        let episode = 2;
+        let device = LibTorchDevice::Cpu;
+        let mut agent = ProximalPolicyOptimizationAgent::new(
+            ActorCriticModel::<Backend>::new(&device, env.observation_space(), env.action_space()),
+            AdamConfig::new().init(),
+            ConstantLr::new(0.0003),
+            env.observation_space().clone(),
+            env.action_space().clone(),
+            device,
+            ProximalPolicyOptimizationAgentConfig::new(),
+        );
+
+        let artifacts_dir = TempDir::new()?;
+        let trainer = RolloutTrainer::new(
+            episode,
+            0.99,
+            0.95,
+            32,
+            2,
+            16,
+            RewardMapping::Identity,
+            artifacts_dir.path().to_path_buf(),
+            false,
+        )?;
+        trainer.train_loop(&mut agent, env)?;
+
+        for epi in 0..episode {
+            let episode_artifacts_dir = artifacts_dir.path().join(format!("{}", epi));
+            assert!(episode_artifacts_dir.join("train.jsonl").exists());
+            assert!(episode_artifacts_dir.join("model.mpk").exists());
+            assert!(episode_artifacts_dir.join("optimizer.mpk").exists());
+        }
+        agent.load(artifacts_dir.path().join("0"))?;
+
+        // a restored run continues from the episode after the checkpoint
+        let checkpoint = artifacts_dir.path().join("1");
+        let state = TrainingState::load(&checkpoint)?.unwrap();
+        assert_eq!(state.finished_episode, episode);
+        assert!(state.total_step > 0);
+        let restored_dir = TempDir::new()?;
+        RolloutTrainer::new(
+            episode + 1,
+            0.99,
+            0.95,
+            32,
+            2,
+            16,
+            RewardMapping::Identity,
+            restored_dir.path().to_path_buf(),
+            false,
+        )?
+        .with_training_state(Some(state.clone()))
+        .train_loop(&mut agent, env)?;
+        assert!(!restored_dir.path().join("0").exists());
+        let resumed =
+            TrainingState::load(&restored_dir.path().join(format!("{}", episode)))?.unwrap();
+        assert_eq!(resumed.finished_episode, episode + 1);
+        assert!(resumed.total_step > state.total_step);
+        Ok(())
+    }
+
+    #[test]
+    fn test_train_loop_ppo() -> anyhow::Result<()> {
+        train(&mut CartPole::with_seed(0))?;
+        train(&mut Pendulum::with_seed(0))?;
+        Ok(())
+    }
+}
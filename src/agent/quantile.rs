@@ -1,27 +1,30 @@
 use std::{fmt::Display, fs::File, path::Path};
 
-use anyhow::Context as _;
+use anyhow::{ensure, Context as _};
 use burn::{
     config::Config,
     data::dataloader::batcher::Batcher as _,
     lr_scheduler::LrScheduler,
-    module::{AutodiffModule, ParamId},
+    module::AutodiffModule,
     nn::loss::{HuberLossConfig, MseLoss},
-    optim::{
-        adaptor::OptimizerAdaptor,
-        record::{AdaptorRecord, AdaptorRecordItem},
-        GradientsParams, Optimizer as _, SimpleOptimizer,
-    },
+    optim::{adaptor::OptimizerAdaptor, GradientsParams, Optimizer as _, SimpleOptimizer},
     record::{CompactRecorder, HalfPrecisionSettings, Record, Recorder as _},
-    tensor::{backend::AutodiffBackend, ElementConversion as _, Shape, Tensor, TensorData},
+    tensor::{
+        backend::{AutodiffBackend, Backend},
+        ElementConversion as _, Shape, Tensor, TensorData,
+    },
 };
 
 use crate::{
-    batch::DeepQNetworkBathcer, Action, ActionSpace, Agent, DeepQNetworkState, Distributional,
-    Estimator, Experience, ObservationSpace, PrioritizedReplay, PrioritizedReplayAgent,
+    batch::DeepQNetworkBathcer, env::wrapper::ObservationNormalizer, layers::Noisy, Action,
+    ActionSpace, Agent, Distributional, Estimator, Experience, ObservationSpace, ObservationState,
+    PrioritizedReplay, PrioritizedReplayAgent,
 };
 
-use super::LossFunction;
+use super::{
+    batch_greedy_policy, load_optimizer, load_teacher_model, save_optimizer, save_teacher_model,
+    set_models_training, LossFunction, ModelNoise, ObservationNormalized, RiskMeasure,
+};
 
 #[derive(Debug, Config)]
 pub struct QuantileRegressionAgentConfig {
@@ -29,6 +32,8 @@ pub struct QuantileRegressionAgentConfig {
     n_step: usize,
     double_dqn: bool,
     loss_function: LossFunction,
+    #[config(default = "RiskMeasure::Mean")]
+    risk_measure: RiskMeasure,
 }
 
 #[derive(Clone)]
@@ -46,6 +51,8 @@ pub struct QuantileRegressionAgent<
     observation_space: ObservationSpace<D>,
     action_space: ActionSpace,
     device: B::Device,
+    observation_normalizer: Option<ObservationNormalizer>,
+    noise: ModelNoise,
     update_counter: usize,
 
     config: QuantileRegressionAgentConfig,
@@ -68,9 +75,14 @@ impl<
         device: B::Device,
 
         config: QuantileRegressionAgentConfig,
-    ) -> Self {
+    ) -> anyhow::Result<Self> {
+        ensure!(
+            matches!(action_space, ActionSpace::Discrete(..)),
+            "QR-DQN needs a discrete action space, got {:?}",
+            action_space
+        );
         let teacher_model = model.clone().fork(&device);
-        Self {
+        Ok(Self {
             model,
             teacher_model,
             optimizer,
@@ -78,27 +90,57 @@ impl<
             observation_space,
             action_space,
             device,
+            observation_normalizer: None,
+            noise: ModelNoise::default(),
             update_counter: 0,
             config,
-        }
+        })
+    }
+
+    fn risk_q_value<BB: Backend, MM: Distributional<BB>>(
+        &self,
+        model: &MM,
+        observation: Tensor<BB, D>,
+    ) -> Tensor<BB, 2> {
+        let quantiles = model.get_distribution(observation);
+        let num_quantile = quantiles.dims()[2];
+        let probs = quantiles.ones_like() / num_quantile as f32;
+        self.config.risk_measure.risk_values(quantiles, probs)
     }
 }
 
-impl<B, const D: usize, M, O, S> PrioritizedReplay<DeepQNetworkState>
-    for QuantileRegressionAgent<B, D, M, O, S>
+impl<
+        B: AutodiffBackend,
+        const D: usize,
+        M: AutodiffModule<B>,
+        O: SimpleOptimizer<B::InnerBackend>,
+        S: LrScheduler,
+    > ObservationNormalized for QuantileRegressionAgent<B, D, M, O, S>
+{
+    fn observation_normalizer(&self) -> Option<&ObservationNormalizer> {
+        self.observation_normalizer.as_ref()
+    }
+
+    fn observation_normalizer_mut(&mut self) -> &mut Option<ObservationNormalizer> {
+        &mut self.observation_normalizer
+    }
+}
+
+impl<B, const D: usize, M, O, S, T> PrioritizedReplay<T> for QuantileRegressionAgent<B, D, M, O, S>
 where
     B: AutodiffBackend,
     M: AutodiffModule<B> + Display + Estimator<B> + Distributional<B>,
     M::InnerModule: Estimator<B::InnerBackend> + Distributional<B::InnerBackend>,
     O: SimpleOptimizer<B::InnerBackend>,
     S: LrScheduler + Clone,
+    T: ObservationState,
 {
     fn temporaral_difference_error(
         &self,
         gamma: f32,
-        experiences: &[Experience<DeepQNetworkState>],
+        experiences: &[Experience<T>],
     ) -> anyhow::Result<Vec<f32>> {
-        let batcher = DeepQNetworkBathcer::new(self.device.clone(), self.action_space);
+        let batcher = DeepQNetworkBathcer::new(self.device.clone(), self.action_space.clone());
 
         let mut shape = *self.observation_space.shape();
         shape[0] = experiences.len();
@@ -111,23 +153,18 @@ where
             .teacher_model
             .valid()
             .predict(item.next_observation.clone().inner().reshape(shape));
-        let next_target_q_value = match self.action_space {
-            ActionSpace::Discrete(num_class) => {
-                if self.config.double_dqn {
-                    let next_q_value = model
-                        .valid()
-                        .predict(item.next_observation.clone().inner().reshape(shape));
-                    let next_actions = next_q_value.argmax(1);
-                    next_target_q_value
-                        .gather(1, next_actions)
-                        .repeat_dim(1, num_class as usize)
-                } else {
-                    next_target_q_value
-                        .max_dim(1)
-                        .repeat_dim(1, num_class as usize)
-                }
-            }
+        let num_class = self.action_space.size();
+        let next_observation = item.next_observation.clone().inner().reshape(shape);
+        let next_actions = if self.config.double_dqn {
+            self.risk_q_value(&model.valid(), next_observation)
+                .argmax(1)
+        } else {
+            self.risk_q_value(&self.teacher_model.valid(), next_observation)
+                .argmax(1)
         };
+        let next_target_q_value = next_target_q_value
+            .gather(1, next_actions)
+            .repeat_dim(1, num_class);
         let next_target_q_value: Tensor<B, 2> =
             Tensor::from_inner(next_target_q_value).to_device(&self.device);
         let targets = next_target_q_value
@@ -149,38 +186,55 @@ where
     }
 }
 
-impl<B, const D: usize, M, O, S> Agent<DeepQNetworkState> for QuantileRegressionAgent<B, D, M, O, S>
+impl<B, const D: usize, M, O, S, T> Agent<T> for QuantileRegressionAgent<B, D, M, O, S>
 where
     B: AutodiffBackend,
-    M: AutodiffModule<B> + Display + Estimator<B> + Distributional<B>,
+    M: AutodiffModule<B> + Display + Estimator<B> + Distributional<B> + Noisy<B>,
     M::InnerModule: Estimator<B::InnerBackend> + Distributional<B::InnerBackend>,
     O: SimpleOptimizer<B::InnerBackend>,
     S: LrScheduler + Clone,
+    T: ObservationState,
 {
+    fn supports_action_space(action_space: &ActionSpace) -> bool {
+        matches!(action_space, ActionSpace::Discrete(..))
+    }
+
     fn policy(&self, observation: &[f32]) -> Action {
         let shape = *self.observation_space.shape();
         let feature: Tensor<<B as AutodiffBackend>::InnerBackend, D> = Tensor::from_data(
             TensorData::new(observation.to_vec(), Shape::new(shape)).convert::<B::FloatElem>(),
             &self.device,
         );
-        let scores = self.model.valid().predict(feature);
-        println!("score: {:?}", scores.to_data().to_vec::<f32>());
-        match self.action_space {
-            ActionSpace::Discrete(..) => {
-                let scores = scores.argmax(1);
-                let scores = scores.flatten::<1>(0, 1).into_scalar();
-                Action::Discrete(scores.elem())
-            }
-        }
+        let scores = self.risk_q_value(&self.model.valid(), feature);
+        let scores = scores.argmax(1);
+        let scores = scores.flatten::<1>(0, 1).into_scalar();
+        Action::Discrete(scores.elem())
+    }
+
+    fn batch_policy(&self, observations: &[Vec<f32>]) -> Vec<Action> {
+        batch_greedy_policy(
+            observations,
+            &self.observation_space,
+            &self.device,
+            |feature| self.risk_q_value(&self.model.valid(), feature),
+        )
+    }
+
+    fn batch_state_policy(&self, _observations: &[Vec<f32>], states: &[T]) -> Vec<Action> {
+        let observations = states
+            .iter()
+            .map(|state| state.next_observation())
+            .collect::<Vec<_>>();
+        Agent::<T>::batch_policy(self, &observations)
     }
 
     fn update(
         &mut self,
         gamma: f32,
-        experiences: &[Experience<DeepQNetworkState>],
+        experiences: &[Experience<T>],
         weights: &[f32],
     ) -> anyhow::Result<()> {
-        let batcher = DeepQNetworkBathcer::new(self.device.clone(), self.action_space);
+        let batcher = DeepQNetworkBathcer::new(self.device.clone(), self.action_space.clone());
 
         let batch_size = experiences.len();
         let mut shape = *self.observation_space.shape();
@@ -201,88 +255,83 @@ where
             quantiles.push((i as f32 + 0.5) / num_quantile as f32);
         }
 
-        let loss = match self.action_space {
-            ActionSpace::Discrete(..) => {
-                let next_actions = if self.config.double_dqn {
-                    let next_q_value = model
-                        .valid()
-                        .predict(item.next_observation.clone().inner().reshape(shape));
-
-                    next_q_value
-                        .argmax(1)
-                        .reshape([batch_size, 1, 1])
-                        .repeat_dim(2, num_quantile)
-                } else {
-                    let next_q_value = self
-                        .teacher_model
-                        .valid()
-                        .predict(item.next_observation.clone().inner().reshape(shape));
-
-                    next_q_value
-                        .argmax(1)
-                        .reshape([batch_size, 1, 1])
-                        .repeat_dim(2, num_quantile)
-                };
-                let next_quantiles = next_quantiles.clone().gather(1, next_actions).reshape([
-                    batch_size,
-                    1,
-                    num_quantile,
-                ]);
-
-                let reward = item
-                    .reward
-                    .clone()
-                    .mean_dim(1)
-                    .inner()
-                    .reshape([batch_size, 1, 1]);
-                let done = item
-                    .done
-                    .clone()
-                    .mean_dim(1)
-                    .inner()
-                    .reshape([batch_size, 1, 1]);
-
-                let target_quantiles = reward
-                    + next_quantiles.mul_scalar(gamma.powi(self.config.n_step as i32))
-                        * (done.ones_like() - done); // [batch_size, 1, num_quantile]
-                let target_quantiles = Tensor::from_inner(target_quantiles);
-
-                let quantile_values = self
-                    .model
-                    .get_distribution(item.observation.clone().reshape(shape));
-                let quantile_values = quantile_values.gather(
-                    1,
-                    item.action
-                        .clone()
-                        .argmax(1)
-                        .reshape([batch_size, 1, 1])
-                        .repeat_dim(2, num_quantile),
-                ); // [batch_size, 1, num_quantile]
-                let quantile_values = quantile_values.permute([0, 2, 1]); // [batch_size, num_quantile, 1]
-                let loss = match self.config.loss_function {
-                    LossFunction::Huber => HuberLossConfig::new(1.0)
-                        .init()
-                        .forward_no_reduction(quantile_values.clone(), target_quantiles.clone()),
-                    LossFunction::Squared => MseLoss::new()
-                        .forward_no_reduction(quantile_values.clone(), target_quantiles.clone()),
-                };
-
-                let td_errors = (target_quantiles - quantile_values).inner();
-                let is_negative = td_errors.clone().lower(td_errors.zeros_like()).float();
-                let quantiles = Tensor::from_data(
-                    TensorData::new(quantiles, Shape::new([1, num_quantile, 1]))
-                        .convert::<B::FloatElem>(),
-                    &self.device,
-                ); // [1, num_quantile, 1]
-                let quantile_weights = (quantiles - is_negative).abs();
-                let quantile_weights = Tensor::from_inner(quantile_weights);
-
-                (loss * quantile_weights)
-                    .mean_dim(2)
-                    .reshape([batch_size, num_quantile])
-                    .sum_dim(1)
-            }
+        let next_actions = if self.config.double_dqn {
+            let next_q_value = self.risk_q_value(
+                &model.valid(),
+                item.next_observation.clone().inner().reshape(shape),
+            );
+
+            next_q_value
+                .argmax(1)
+                .reshape([batch_size, 1, 1])
+                .repeat_dim(2, num_quantile)
+        } else {
+            let next_q_value = self.risk_q_value(
+                &self.teacher_model.valid(),
+                item.next_observation.clone().inner().reshape(shape),
+            );
+
+            next_q_value
+                .argmax(1)
+                .reshape([batch_size, 1, 1])
+                .repeat_dim(2, num_quantile)
+        };
+        let next_quantiles =
+            next_quantiles
+                .clone()
+                .gather(1, next_actions)
+                .reshape([batch_size, 1, num_quantile]);
+
+        let reward = item
+            .reward
+            .clone()
+            .mean_dim(1)
+            .inner()
+            .reshape([batch_size, 1, 1]);
+        let done = item
+            .done
+            .clone()
+            .mean_dim(1)
+            .inner()
+            .reshape([batch_size, 1, 1]);
+
+        let target_quantiles = reward
+            + next_quantiles.mul_scalar(gamma.powi(self.config.n_step as i32))
+                * (done.ones_like() - done); // [batch_size, 1, num_quantile]
+        let target_quantiles = Tensor::from_inner(target_quantiles);
+
+        let quantile_values = self
+            .model
+            .get_distribution(item.observation.clone().reshape(shape));
+        let quantile_values = quantile_values.gather(
+            1,
+            item.action
+                .clone()
+                .argmax(1)
+                .reshape([batch_size, 1, 1])
+                .repeat_dim(2, num_quantile),
+        ); // [batch_size, 1, num_quantile]
+        let quantile_values = quantile_values.permute([0, 2, 1]); // [batch_size, num_quantile, 1]
+        let loss = match self.config.loss_function {
+            LossFunction::Huber => HuberLossConfig::new(1.0)
+                .init()
+                .forward_no_reduction(quantile_values.clone(), target_quantiles.clone()),
+            LossFunction::Squared => MseLoss::new()
+                .forward_no_reduction(quantile_values.clone(), target_quantiles.clone()),
         };
+
+        let td_errors = (target_quantiles - quantile_values).inner();
+        let is_negative = td_errors.clone().lower(td_errors.zeros_like()).float();
+        let quantiles = Tensor::from_data(
+            TensorData::new(quantiles, Shape::new([1, num_quantile, 1])).convert::<B::FloatElem>(),
+            &self.device,
+        ); // [1, num_quantile, 1]
+        let quantile_weights = (quantiles - is_negative).abs();
+        let quantile_weights = Tensor::from_inner(quantile_weights);
+        let loss = (loss * quantile_weights)
+            .mean_dim(2)
+            .reshape([batch_size, num_quantile])
+            .sum_dim(1);
         let weights = Tensor::from_data(
             TensorData::new(weights.to_vec(), Shape::new([weights.len(), 1]))
                 .convert::<B::FloatElem>(),
@@ -302,11 +351,29 @@ where
         Ok(())
     }
 
-    fn make_state(&self, next_observation: &[f32], state: &DeepQNetworkState) -> DeepQNetworkState {
-        DeepQNetworkState {
-            observation: state.next_observation.clone(),
-            next_observation: next_observation.to_vec(),
-        }
+    fn set_training(&mut self, training: bool) {
+        set_models_training(&mut self.model, &mut self.teacher_model, training);
+    }
+
+    fn resample_noise(&mut self) {
+        self.noise
+            .resample(&mut self.model, &mut self.teacher_model);
+    }
+
+    fn set_noise_seed(&mut self, seed: u64) {
+        self.noise.seed(seed);
+    }
+
+    fn update_counter(&self) -> usize {
+        self.update_counter
+    }
+
+    fn set_update_counter(&mut self, update_counter: usize) {
+        self.update_counter = update_counter;
+    }
+
+    fn make_state(&self, next_observation: &[f32], state: &T) -> T {
+        state.next_state(next_observation)
     }
 
     fn save<P: AsRef<Path>>(&self, artifacts_dir: P) -> anyhow::Result<()> {
@@ -317,18 +384,8 @@ where
             .clone()
             .save_file(artifacts_dir.join("model"), &CompactRecorder::new())
             .with_context(|| "fail to save model")?;
-        let optimizer_record = self.optimizer.to_record();
-        let optimizer_record = optimizer_record
-            .into_iter()
-            .map(|(k, v)| (k.to_string(), v.into_item()))
-            .collect::<hashbrown::HashMap<String, AdaptorRecordItem<O, B, HalfPrecisionSettings>>>(
-            );
-
-        let mut optimizer_file = File::create(artifacts_dir.join("optimizer.mpk"))
-            .with_context(|| "create optimizer file")?;
-
-        rmp_serde::encode::write(&mut optimizer_file, &optimizer_record)
-            .with_context(|| "Failed to write optimizer record")?;
+        save_teacher_model(&self.teacher_model, &artifacts_dir)?;
+        save_optimizer(&self.optimizer, &artifacts_dir.join("optimizer.mpk"))?;
 
         let scheduler_record = self.lr_scheduler.to_record();
         let scheduler_record: <<S as LrScheduler>::Record<B> as Record<_>>::Item<
@@ -338,6 +395,7 @@ where
             .with_context(|| "create scheduler file")?;
         rmp_serde::encode::write(&mut scheduler_file, &scheduler_record)
             .with_context(|| "Failed to write scheduler record")?;
+        self.save_observation_normalizer(&artifacts_dir)?;
         Ok(())
     }
 
@@ -350,23 +408,15 @@ where
                 .with_context(|| "Failed to load model")?;
             self.model = self.model.clone().load_record(record);
         }
+        self.teacher_model = load_teacher_model(
+            &self.model,
+            self.teacher_model.clone(),
+            &restore_dir,
+            &self.device,
+        )?;
         let optimizer_file = restore_dir.join("optimizer.mpk");
         if optimizer_file.exists() {
-            let optimizer_file =
-                File::open(optimizer_file).with_context(|| "open optimizer file")?;
-            let record: hashbrown::HashMap<String, AdaptorRecordItem<O, B, HalfPrecisionSettings>> =
-                rmp_serde::decode::from_read(optimizer_file)
-                    .with_context(|| "Failed to read optimizer record")?;
-            let record = record
-                .into_iter()
-                .map(|(k, v)| {
-                    (
-                        ParamId::deserialize(k.as_str()),
-                        AdaptorRecord::from_item(v, &self.device),
-                    )
-                })
-                .collect::<hashbrown::HashMap<_, _>>();
-            self.optimizer = self.optimizer.clone().load_record(record);
+            self.optimizer = load_optimizer(self.optimizer.clone(), &optimizer_file, &self.device)?;
         }
         let scheduler_file = restore_dir.join("scheduler.mpk");
         if scheduler_file.exists() {
@@ -379,18 +429,20 @@ where
                 <<S as LrScheduler>::Record<B> as Record<_>>::from_item(record, &self.device);
             self.lr_scheduler = self.lr_scheduler.clone().load_record(record);
         }
+        self.load_observation_normalizer(&restore_dir)?;
 
         Ok(())
     }
 }
 
-impl<B, const D: usize, M, O, S> PrioritizedReplayAgent<DeepQNetworkState>
+impl<B, const D: usize, M, O, S, T> PrioritizedReplayAgent<T>
     for QuantileRegressionAgent<B, D, M, O, S>
 where
     B: AutodiffBackend,
-    M: AutodiffModule<B> + Display + Estimator<B> + Distributional<B>,
+    M: AutodiffModule<B> + Display + Estimator<B> + Distributional<B> + Noisy<B>,
     M::InnerModule: Estimator<B::InnerBackend> + Distributional<B::InnerBackend>,
     O: SimpleOptimizer<B::InnerBackend>,
     S: LrScheduler + Clone,
+    T: ObservationState,
 {
 }
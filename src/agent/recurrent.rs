@@ -0,0 +1,643 @@
+use std::{fmt::Display, fs::File, ops::Range, path::Path};
+
+use anyhow::{anyhow, bail, ensure, Context as _};
+use burn::{
+    config::Config,
+    lr_scheduler::LrScheduler,
+    module::AutodiffModule,
+    nn::loss::{HuberLossConfig, MseLoss},
+    optim::{adaptor::OptimizerAdaptor, GradientsParams, Optimizer as _, SimpleOptimizer},
+    record::{CompactRecorder, HalfPrecisionSettings, Record, Recorder as _},
+    tensor::{backend::AutodiffBackend, Int, Shape, Tensor, TensorData},
+};
+
+use crate::{
+    env::wrapper::ObservationNormalizer, layers::Noisy, Action, ActionSpace, Agent, Experience,
+    ObservationSpace, Recurrent, RecurrentState, Sequence, SequenceReplay, SequenceReplayAgent,
+    State,
+};
+
+use super::{
+    load_optimizer, load_teacher_model, save_optimizer, save_teacher_model, set_models_training,
+    LossFunction, ModelNoise, ObservationNormalized,
+};
+
+#[derive(Debug, Config)]
+pub struct RecurrentDeepQNetworkAgentConfig {
+    teacher_update_freq: usize,
+    n_step: usize,
+    double_dqn: bool,
+    loss_function: LossFunction,
+    // sequence priority is eta * max |td| + (1 - eta) * mean |td|
+    #[config(default = 0.9)]
+    priority_eta: f32,
+}
+
+#[derive(Clone)]
+pub struct RecurrentDeepQNetworkAgent<
+    B: AutodiffBackend,
+    const D: usize,
+    M: AutodiffModule<B>,
+    O: SimpleOptimizer<B::InnerBackend>,
+    S: LrScheduler,
+> {
+    model: M,
+    teacher_model: M,
+    optimizer: OptimizerAdaptor<O, M, B>,
+    lr_scheduler: S,
+    observation_space: ObservationSpace<D>,
+    action_space: ActionSpace,
+    device: B::Device,
+    observation_normalizer: Option<ObservationNormalizer>,
+    noise: ModelNoise,
+    update_counter: usize,
+
+    config: RecurrentDeepQNetworkAgentConfig,
+}
+
+impl<
+        B: AutodiffBackend,
+        const D: usize,
+        M: AutodiffModule<B> + Recurrent<B>,
+        O: SimpleOptimizer<B::InnerBackend>,
+        S: LrScheduler,
+    > RecurrentDeepQNetworkAgent<B, D, M, O, S>
+{
+    pub fn new(
+        model: M,
+        optimizer: OptimizerAdaptor<O, M, B>,
+        lr_scheduler: S,
+        observation_space: ObservationSpace<D>,
+        action_space: ActionSpace,
+        device: B::Device,
+        config: RecurrentDeepQNetworkAgentConfig,
+    ) -> anyhow::Result<Self> {
+        ensure!(
+            matches!(action_space, ActionSpace::Discrete(..)),
+            "R2D2 needs a discrete action space, got {:?}",
+            action_space
+        );
+        let teacher_model = model.clone().fork(&device);
+        Ok(Self {
+            model,
+            teacher_model,
+            optimizer,
+            lr_scheduler,
+            observation_space,
+            action_space,
+            device,
+            observation_normalizer: None,
+            noise: ModelNoise::default(),
+            update_counter: 0,
+            config,
+        })
+    }
+
+    fn observation_tensor(
+        &self,
+        observations: Vec<f32>,
+        batch_size: usize,
+    ) -> Tensor<B::InnerBackend, D> {
+        let mut shape = *self.observation_space.shape();
+        shape[0] = batch_size;
+        Tensor::from_data(
+            TensorData::new(observations, Shape::new(shape)).convert::<B::FloatElem>(),
+            &self.device,
+        )
+    }
+
+    // an empty recurrent state is the initial one
+    fn recurrent_state_tensor(&self, recurrent_states: &[&Vec<f32>]) -> Tensor<B::InnerBackend, 2> {
+        let size = self.model.recurrent_state_size();
+        let recurrent_state = recurrent_states
+            .iter()
+            .flat_map(|recurrent_state| {
+                if recurrent_state.is_empty() {
+                    vec![0.0; size]
+                } else {
+                    recurrent_state.to_vec()
+                }
+            })
+            .collect::<Vec<_>>();
+        Tensor::from_data(
+            TensorData::new(recurrent_state, Shape::new([recurrent_states.len(), size]))
+                .convert::<B::FloatElem>(),
+            &self.device,
+        )
+    }
+}
+
+impl<
+        B: AutodiffBackend,
+        const D: usize,
+        M: AutodiffModule<B>,
+        O: SimpleOptimizer<B::InnerBackend>,
+        S: LrScheduler,
+    > ObservationNormalized for RecurrentDeepQNetworkAgent<B, D, M, O, S>
+{
+    fn observation_normalizer(&self) -> Option<&ObservationNormalizer> {
+        self.observation_normalizer.as_ref()
+    }
+
+    fn observation_normalizer_mut(&mut self) -> &mut Option<ObservationNormalizer> {
+        &mut self.observation_normalizer
+    }
+}
+
+impl<B, const D: usize, M, O, S> SequenceReplay<RecurrentState>
+    for RecurrentDeepQNetworkAgent<B, D, M, O, S>
+where
+    B: AutodiffBackend,
+    M: AutodiffModule<B> + Display + Recurrent<B>,
+    M::InnerModule: Recurrent<B::InnerBackend>,
+    O: SimpleOptimizer<B::InnerBackend>,
+    S: LrScheduler + Clone,
+{
+    fn update_sequences(
+        &mut self,
+        gamma: f32,
+        sequences: &[Sequence<RecurrentState>],
+        weights: &[f32],
+    ) -> anyhow::Result<Vec<f32>> {
+        let batch_size = sequences.len();
+        let burn_in = sequences.first().map(|s| s.burn_in).unwrap_or_default();
+        ensure!(
+            sequences
+                .iter()
+                .all(|s| s.burn_in == burn_in && s.experiences.len() > burn_in),
+            "sequences must share the burn in and be longer than it"
+        );
+        let sequence_length = sequences
+            .iter()
+            .map(|s| s.experiences.len())
+            .max()
+            .unwrap_or_default();
+        let unroll_length = sequence_length - burn_in;
+        let action_num = self.action_space.size();
+
+        // the observations of each sequence followed by its last next observation, zero padded
+        let observation_size = self.observation_space.shape()[1..]
+            .iter()
+            .product::<usize>();
+        let padding = vec![0.0; observation_size];
+        let inputs = sequences
+            .iter()
+            .map(|sequence| {
+                let mut inputs = sequence
+                    .experiences
+                    .iter()
+                    .map(|e| e.state.observation.as_slice())
+                    .collect::<Vec<_>>();
+                inputs.extend(
+                    sequence
+                        .experiences
+                        .last()
+                        .map(|e| e.state.next_observation.as_slice()),
+                );
+                inputs.resize(sequence_length + 1, padding.as_slice());
+                inputs
+            })
+            .collect::<Vec<_>>();
+        let observation = |range: Range<usize>| {
+            self.observation_tensor(
+                inputs
+                    .iter()
+                    .flat_map(|inputs| inputs[range.clone()].concat())
+                    .collect(),
+                batch_size * range.len(),
+            )
+        };
+
+        // the unroll starts from the stored recurrent state refreshed by the burn in
+        let stored_state = self.recurrent_state_tensor(
+            &sequences
+                .iter()
+                .map(|s| &s.experiences[0].state.recurrent_state)
+                .collect::<Vec<_>>(),
+        );
+        let online_state = if burn_in > 0 {
+            self.model
+                .valid()
+                .predict_sequence(observation(0..burn_in), burn_in, stored_state.clone())
+                .1
+        } else {
+            stored_state.clone()
+        };
+
+        let model = self.model.clone();
+        let (q_value, _) = model.predict_sequence(
+            Tensor::from_inner(observation(burn_in..sequence_length + 1)),
+            unroll_length + 1,
+            Tensor::from_inner(online_state),
+        );
+        let (target_q_value, _) = self.teacher_model.valid().predict_sequence(
+            observation(0..sequence_length + 1),
+            sequence_length + 1,
+            stored_state,
+        );
+        let target_q_value =
+            target_q_value.slice([0..batch_size, burn_in..sequence_length + 1, 0..action_num]);
+        let next_actions = if self.config.double_dqn {
+            q_value.clone().inner().argmax(2)
+        } else {
+            target_q_value.clone().argmax(2)
+        };
+        let bootstrap = target_q_value
+            .gather(2, next_actions)
+            .into_data()
+            .iter::<f32>()
+            .collect::<Vec<_>>();
+
+        let mut targets = Vec::with_capacity(batch_size * unroll_length);
+        let mut masks = Vec::with_capacity(batch_size * unroll_length);
+        let mut actions = Vec::with_capacity(batch_size * unroll_length);
+        for (i, sequence) in sequences.iter().enumerate() {
+            let length = sequence.experiences.len();
+            for t in burn_in..sequence_length {
+                if t >= length {
+                    targets.push(0.0);
+                    masks.push(0.0);
+                    actions.push(0);
+                    continue;
+                }
+                let Action::Discrete(action) = sequence.experiences[t].action else {
+                    bail!("R2D2 trains only on discrete actions");
+                };
+                // n-step return inside the sequence, shorter at its end
+                let steps = self.config.n_step.clamp(1, length - t);
+                let mut target = 0.0;
+                let mut terminated = false;
+                for (k, experience) in sequence.experiences[t..t + steps].iter().enumerate() {
+                    target += gamma.powi(k as i32) * experience.reward;
+                    if experience.is_done() {
+                        terminated = experience.is_terminated();
+                        break;
+                    }
+                }
+                // a truncated episode still bootstraps from its last state
+                if !terminated {
+                    target += gamma.powi(steps as i32)
+                        * bootstrap[i * (unroll_length + 1) + t - burn_in + steps];
+                }
+                targets.push(target);
+                masks.push(1.0);
+                actions.push(action);
+            }
+        }
+        let targets = Tensor::<B, 2>::from_data(
+            TensorData::new(targets, Shape::new([batch_size, unroll_length]))
+                .convert::<B::FloatElem>(),
+            &self.device,
+        );
+        let masks = Tensor::<B, 2>::from_data(
+            TensorData::new(masks, Shape::new([batch_size, unroll_length]))
+                .convert::<B::FloatElem>(),
+            &self.device,
+        );
+        let actions = Tensor::<B, 3, Int>::from_data(
+            TensorData::new(actions, Shape::new([batch_size, unroll_length, 1]))
+                .convert::<B::IntElem>(),
+            &self.device,
+        );
+        let q_value = q_value
+            .slice([0..batch_size, 0..unroll_length, 0..action_num])
+            .gather(2, actions)
+            .reshape([batch_size, unroll_length]);
+
+        let td_errors = ((targets.clone() - q_value.clone()) * masks.clone())
+            .inner()
+            .abs()
+            .into_data()
+            .iter::<f32>()
+            .collect::<Vec<_>>();
+        let priorities = td_errors
+            .chunks(unroll_length)
+            .zip(sequences)
+            .map(|(td_errors, sequence)| {
+                let td_errors = &td_errors[..sequence.experiences.len() - burn_in];
+                let max = td_errors.iter().copied().fold(0.0, f32::max);
+                let mean = td_errors.iter().sum::<f32>() / td_errors.len() as f32;
+                self.config.priority_eta * max + (1.0 - self.config.priority_eta) * mean
+            })
+            .collect();
+
+        let loss = match self.config.loss_function {
+            LossFunction::Huber => HuberLossConfig::new(1.0)
+                .init()
+                .forward_no_reduction(q_value, targets),
+            LossFunction::Squared => MseLoss::new().forward_no_reduction(q_value, targets),
+        };
+        let weights = Tensor::from_data(
+            TensorData::new(weights.to_vec(), Shape::new([weights.len(), 1]))
+                .convert::<B::FloatElem>(),
+            &self.device,
+        );
+        let loss = (loss * masks.clone()).sum_dim(1) / masks.sum_dim(1) * weights;
+        let loss = loss.mean();
+        let grads: <B as AutodiffBackend>::Gradients = loss.backward();
+        let grads = GradientsParams::from_grads(grads, &model);
+        self.model = self.optimizer.step(self.lr_scheduler.step(), model, grads);
+
+        self.update_counter += 1;
+        if self.update_counter % self.config.teacher_update_freq == 0 {
+            self.teacher_model = self.model.clone().fork(&self.device);
+        }
+
+        Ok(priorities)
+    }
+}
+
+impl<B, const D: usize, M, O, S> Agent<RecurrentState> for RecurrentDeepQNetworkAgent<B, D, M, O, S>
+where
+    B: AutodiffBackend,
+    M: AutodiffModule<B> + Display + Recurrent<B> + Noisy<B>,
+    M::InnerModule: Recurrent<B::InnerBackend>,
+    O: SimpleOptimizer<B::InnerBackend>,
+    S: LrScheduler + Clone,
+{
+    fn supports_action_space(action_space: &ActionSpace) -> bool {
+        matches!(action_space, ActionSpace::Discrete(..))
+    }
+
+    // without a state the policy acts from the initial recurrent state
+    fn policy(&self, observation: &[f32]) -> Action {
+        self.batch_state_policy(&[], &[RecurrentState::new(observation.to_vec())])
+            .remove(0)
+    }
+
+    fn batch_policy(&self, observations: &[Vec<f32>]) -> Vec<Action> {
+        let states = observations
+            .iter()
+            .map(|observation| RecurrentState::new(observation.clone()))
+            .collect::<Vec<_>>();
+        self.batch_state_policy(observations, &states)
+    }
+
+    fn batch_state_policy(
+        &self,
+        _observations: &[Vec<f32>],
+        states: &[RecurrentState],
+    ) -> Vec<Action> {
+        let observation = self.observation_tensor(
+            states
+                .iter()
+                .flat_map(|state| state.next_observation.clone())
+                .collect(),
+            states.len(),
+        );
+        let recurrent_state = self.recurrent_state_tensor(
+            &states
+                .iter()
+                .map(|state| &state.next_recurrent_state)
+                .collect::<Vec<_>>(),
+        );
+        let (scores, _) = self
+            .model
+            .valid()
+            .predict_sequence(observation, 1, recurrent_state);
+        scores
+            .argmax(2)
+            .flatten::<1>(0, 2)
+            .into_data()
+            .iter::<i64>()
+            .map(Action::Discrete)
+            .collect()
+    }
+
+    fn update(
+        &mut self,
+        _gamma: f32,
+        _experiences: &[Experience<RecurrentState>],
+        _weights: &[f32],
+    ) -> anyhow::Result<()> {
+        Err(anyhow!(
+            "R2D2 learns from sequences, train it with SequenceReplayTrainer"
+        ))
+    }
+
+    fn set_training(&mut self, training: bool) {
+        set_models_training(&mut self.model, &mut self.teacher_model, training);
+    }
+
+    fn resample_noise(&mut self) {
+        self.noise
+            .resample(&mut self.model, &mut self.teacher_model);
+    }
+
+    fn set_noise_seed(&mut self, seed: u64) {
+        self.noise.seed(seed);
+    }
+
+    fn update_counter(&self) -> usize {
+        self.update_counter
+    }
+
+    fn set_update_counter(&mut self, update_counter: usize) {
+        self.update_counter = update_counter;
+    }
+
+    fn make_state(&self, next_observation: &[f32], state: &RecurrentState) -> RecurrentState {
+        let observation = self.observation_tensor(state.next_observation.clone(), 1);
+        let recurrent_state = self.recurrent_state_tensor(&[&state.next_recurrent_state]);
+        let (_, next_recurrent_state) =
+            self.model
+                .valid()
+                .predict_sequence(observation, 1, recurrent_state);
+        RecurrentState {
+            observation: state.next_observation.clone(),
+            next_observation: next_observation.to_vec(),
+            recurrent_state: state.next_recurrent_state.clone(),
+            next_recurrent_state: next_recurrent_state.into_data().iter::<f32>().collect(),
+        }
+    }
+
+    fn save<P: AsRef<Path>>(&self, artifacts_dir: P) -> anyhow::Result<()> {
+        let artifacts_dir = artifacts_dir.as_ref().to_path_buf();
+        std::fs::create_dir_all(&artifacts_dir)
+            .with_context(|| format!("fail to create {:?}", artifacts_dir))?;
+        self.model
+            .clone()
+            .save_file(artifacts_dir.join("model"), &CompactRecorder::new())
+            .with_context(|| "fail to save model")?;
+        save_teacher_model(&self.teacher_model, &artifacts_dir)?;
+        save_optimizer(&self.optimizer, &artifacts_dir.join("optimizer.mpk"))?;
+
+        let scheduler_record = self.lr_scheduler.to_record();
+        let scheduler_record: <<S as LrScheduler>::Record<B> as Record<_>>::Item<
+            HalfPrecisionSettings,
+        > = scheduler_record.into_item();
+        let mut scheduler_file = File::create(artifacts_dir.join("scheduler.mpk"))
+            .with_context(|| "create scheduler file")?;
+        rmp_serde::encode::write(&mut scheduler_file, &scheduler_record)
+            .with_context(|| "Failed to write scheduler record")?;
+        self.save_observation_normalizer(&artifacts_dir)?;
+        Ok(())
+    }
+
+    fn load<P: AsRef<Path>>(&mut self, restore_dir: P) -> anyhow::Result<()> {
+        let restore_dir = restore_dir.as_ref().to_path_buf();
+        let model_file = restore_dir.join("model.mpk");
+        if model_file.exists() {
+            let record = CompactRecorder::new()
+                .load(model_file, &self.device)
+                .with_context(|| "Failed to load model")?;
+            self.model = self.model.clone().load_record(record);
+        }
+        self.teacher_model = load_teacher_model(
+            &self.model,
+            self.teacher_model.clone(),
+            &restore_dir,
+            &self.device,
+        )?;
+        let optimizer_file = restore_dir.join("optimizer.mpk");
+        if optimizer_file.exists() {
+            self.optimizer = load_optimizer(self.optimizer.clone(), &optimizer_file, &self.device)?;
+        }
+        let scheduler_file = restore_dir.join("scheduler.mpk");
+        if scheduler_file.exists() {
+            let scheduler_file =
+                File::open(scheduler_file).with_context(|| "open scheduler file")?;
+            let record: <<S as LrScheduler>::Record<B> as Record<_>>::Item<HalfPrecisionSettings> =
+                rmp_serde::decode::from_read(scheduler_file)
+                    .with_context(|| "Failed to read scheduler record")?;
+            let record =
+                <<S as LrScheduler>::Record<B> as Record<_>>::from_item(record, &self.device);
+            self.lr_scheduler = self.lr_scheduler.clone().load_record(record);
+        }
+        self.load_observation_normalizer(&restore_dir)?;
+
+        Ok(())
+    }
+}
+
+impl<B, const D: usize, M, O, S> SequenceReplayAgent<RecurrentState>
+    for RecurrentDeepQNetworkAgent<B, D, M, O, S>
+where
+    B: AutodiffBackend,
+    M: AutodiffModule<B> + Display + Recurrent<B> + Noisy<B>,
+    M::InnerModule: Recurrent<B::InnerBackend>,
+    O: SimpleOptimizer<B::InnerBackend>,
+    S: LrScheduler + Clone,
+{
+}
+
+#[cfg(test)]
+mod tests {
+    use burn::{
+        backend::{libtorch::LibTorchDevice, Autodiff, LibTorch},
+        lr_scheduler::constant::ConstantLr,
+        optim::AdamConfig,
+    };
+    use tempfile::TempDir;
+
+    use crate::{
+        env::classic::CartPole,
+        model::{HeadConfig, RecurrentCell, RecurrentDeepQNetworkModel, TorsoConfig},
+        trainer::{
+            prioritized::PrioritizedReplayConfig,
+            sequence::{SequenceBuffer, SequenceReplayMemory, SequenceReplayTrainer},
+            storage::StorageBackend,
+            RandomPolicy, RewardMapping,
+        },
+        Env,
+    };
+
+    use super::*;
+
+    type Backend = Autodiff<LibTorch>;
+
+    #[test]
+    fn test_train_loop_recurrent() -> anyhow::Result<()> {
+        let device = LibTorchDevice::Cpu;
+        for (cell, recurrent_state_size) in [(RecurrentCell::Lstm, 32), (RecurrentCell::Gru, 16)] {
+            let mut env = CartPole::with_seed(0);
+            let model = RecurrentDeepQNetworkModel::<Backend>::new(
+                &device,
+                env.observation_space(),
+                env.action_space(),
+                &TorsoConfig::default_for::<2>(),
+                &HeadConfig::new(),
+                cell,
+                16,
+                true,
+                false,
+            )?;
+            assert_eq!(model.recurrent_state_size(), recurrent_state_size);
+            let (q_value, recurrent_state) = model.predict_sequence(
+                Tensor::<Backend, 2>::zeros([3 * 5, 4], &device),
+                5,
+                Tensor::zeros([3, recurrent_state_size], &device),
+            );
+            assert_eq!(q_value.dims(), [3, 5, 2]);
+            assert_eq!(recurrent_state.dims(), [3, recurrent_state_size]);
+
+            let mut agent = RecurrentDeepQNetworkAgent::new(
+                model,
+                AdamConfig::new().init(),
+                ConstantLr::new(0.00025),
+                env.observation_space().clone(),
+                env.action_space().clone(),
+                device,
+                RecurrentDeepQNetworkAgentConfig::new(100, 3, true, LossFunction::Huber),
+            )?;
+
+            // the recurrent state is carried along the episode
+            let mut state = RecurrentState::new(env.reset()?);
+            let mut buffer = SequenceBuffer::new(6, 2, 4);
+            let mut sequences = Vec::new();
+            for _ in 0..12 {
+                let action = agent.batch_state_policy(&[], &[state.clone()]).remove(0);
+                let result = env.step(&action)?;
+                state = agent.make_state(&result.observation, &state);
+                assert_eq!(state.next_recurrent_state.len(), recurrent_state_size);
+                sequences.extend(buffer.push(Experience {
+                    state: state.clone(),
+                    action,
+                    reward: result.reward,
+                    terminated: result.terminated,
+                    truncated: result.truncated,
+                }));
+                if result.is_done() {
+                    break;
+                }
+            }
+            sequences.extend(buffer.finish());
+            assert!(sequences[0].experiences[0].state.recurrent_state.is_empty());
+            assert_eq!(
+                sequences[1].experiences[0].state.recurrent_state.len(),
+                recurrent_state_size
+            );
+
+            let priorities =
+                agent.update_sequences(0.99, &sequences, &vec![1.0; sequences.len()])?;
+            assert_eq!(priorities.len(), sequences.len());
+            assert!(priorities.iter().all(|p| p.is_finite() && *p >= 0.0));
+            assert!(agent
+                .update(0.99, &sequences[0].experiences, &[1.0])
+                .is_err());
+
+            let mut memory = SequenceReplayMemory::<RecurrentState>::new(
+                1024,
+                4,
+                8,
+                PrioritizedReplayConfig::new(),
+                StorageBackend::InMemory,
+            )?;
+            let artifacts_dir = TempDir::new()?;
+            let trainer = SequenceReplayTrainer::new(
+                2,
+                0.99,
+                8,
+                2,
+                4,
+                RewardMapping::Identity,
+                artifacts_dir.path().to_path_buf(),
+                false,
+            )?;
+            let random_policy = Some(RandomPolicy::new(1.0, 0.01, 0.99));
+            trainer.train_loop(&mut agent, &mut env, &mut memory, &random_policy)?;
+            assert!(artifacts_dir.path().join("1").join("model.mpk").exists());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,630 @@
+use std::{collections::HashMap, f32::consts::PI, fs::File, path::Path};
+
+use anyhow::{anyhow, bail, Context};
+use burn::{
+    config::Config,
+    data::dataloader::batcher::Batcher,
+    lr_scheduler::LrScheduler,
+    module::{AutodiffModule, ModuleMapper, ModuleVisitor, ParamId},
+    optim::{adaptor::OptimizerAdaptor, GradientsParams, Optimizer, SimpleOptimizer},
+    record::{CompactRecorder, HalfPrecisionSettings, Record, Recorder},
+    tensor::{
+        backend::{AutodiffBackend, Backend},
+        Distribution, ElementConversion, Shape, Tensor, TensorData,
+    },
+};
+
+use crate::{
+    batch::{DeepQNetworkBatch, DeepQNetworkBathcer},
+    env::wrapper::ObservationNormalizer,
+    Action, ActionSpace, ActionValue, Agent, DeepQNetworkState, Experience, GaussianPolicy,
+    ObservationSpace, PrioritizedReplay, PrioritizedReplayAgent,
+};
+
+use super::{load_optimizer, save_optimizer, ObservationNormalized};
+
+#[derive(Debug, Config)]
+pub struct SoftActorCriticAgentConfig {
+    n_step: usize,
+    #[config(default = 0.005)]
+    tau: f32,
+    #[config(default = 0.0003)]
+    alpha_learning_rate: f32,
+    #[config(default = 1.0)]
+    init_alpha: f32,
+    // defaults to -dim(action space)
+    target_entropy: Option<f32>,
+}
+
+#[derive(Clone)]
+pub struct SoftActorCriticAgent<
+    B: AutodiffBackend,
+    const D: usize,
+    A: AutodiffModule<B>,
+    C: AutodiffModule<B>,
+    O: SimpleOptimizer<B::InnerBackend>,
+    S: LrScheduler,
+> {
+    actor: A,
+    critic1: C,
+    critic2: C,
+    target_critic1: C,
+    target_critic2: C,
+    actor_optimizer: OptimizerAdaptor<O, A, B>,
+    critic1_optimizer: OptimizerAdaptor<O, C, B>,
+    critic2_optimizer: OptimizerAdaptor<O, C, B>,
+    lr_scheduler: S,
+    log_alpha: f32,
+    target_entropy: f32,
+    // affine map from the tanh squashed actions in [-1, 1] to the action space bounds
+    action_scale: Vec<f32>,
+    action_bias: Vec<f32>,
+    observation_space: ObservationSpace<D>,
+    action_space: ActionSpace,
+    device: B::Device,
+    observation_normalizer: Option<ObservationNormalizer>,
+    update_counter: usize,
+    // eval mode acts on the mean of the policy
+    training: bool,
+
+    config: SoftActorCriticAgentConfig,
+}
+
+impl<
+        B: AutodiffBackend,
+        const D: usize,
+        A: AutodiffModule<B> + GaussianPolicy<B>,
+        C: AutodiffModule<B> + ActionValue<B>,
+        O: SimpleOptimizer<B::InnerBackend>,
+        S: LrScheduler,
+    > SoftActorCriticAgent<B, D, A, C, O, S>
+where
+    A::InnerModule: GaussianPolicy<B::InnerBackend>,
+    C::InnerModule: ActionValue<B::InnerBackend>,
+{
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        actor: A,
+        critic1: C,
+        critic2: C,
+        actor_optimizer: OptimizerAdaptor<O, A, B>,
+        critic_optimizer: OptimizerAdaptor<O, C, B>,
+        lr_scheduler: S,
+        observation_space: ObservationSpace<D>,
+        action_space: ActionSpace,
+        device: B::Device,
+        config: SoftActorCriticAgentConfig,
+    ) -> anyhow::Result<Self> {
+        let ActionSpace::Box { low, high } = &action_space else {
+            bail!("SAC needs a Box action space, got {:?}", action_space);
+        };
+        let (action_scale, action_bias) = low
+            .iter()
+            .zip(high)
+            .map(|(low, high)| ((high - low) / 2.0, (high + low) / 2.0))
+            .unzip();
+        let target_critic1 = critic1.clone().fork(&device);
+        let target_critic2 = critic2.clone().fork(&device);
+        let target_entropy = config
+            .target_entropy
+            .unwrap_or(-(action_space.size() as f32));
+        Ok(Self {
+            actor,
+            critic1,
+            critic2,
+            target_critic1,
+            target_critic2,
+            actor_optimizer,
+            critic1_optimizer: critic_optimizer.clone(),
+            critic2_optimizer: critic_optimizer,
+            lr_scheduler,
+            log_alpha: config.init_alpha.ln(),
+            target_entropy,
+            action_scale,
+            action_bias,
+            observation_space,
+            action_space,
+            device,
+            observation_normalizer: None,
+            update_counter: 0,
+            training: true,
+            config,
+        })
+    }
+
+    fn sample_actions(&self, observations: &[Vec<f32>]) -> Vec<Action> {
+        let mut shape = *self.observation_space.shape();
+        shape[0] = observations.len();
+        let feature: Tensor<B::InnerBackend, D> = Tensor::from_data(
+            TensorData::new(observations.concat(), Shape::new(shape)).convert::<B::FloatElem>(),
+            &self.device,
+        );
+        let (mean, log_std) = self.actor.valid().gaussian(feature);
+        let action = if self.training {
+            sample_squashed_gaussian(mean, log_std).0
+        } else {
+            mean.tanh()
+        };
+        let action = action * action_tensor(&self.action_scale, &self.device)
+            + action_tensor(&self.action_bias, &self.device);
+        let action_dim = self.action_space.size();
+        action
+            .into_data()
+            .iter::<f32>()
+            .collect::<Vec<_>>()
+            .chunks(action_dim)
+            .map(|action| Action::Continuous(action.to_vec()))
+            .collect()
+    }
+
+    // soft bellman target with the clipped double Q trick, shape [batch, 1]
+    fn soft_target(
+        &self,
+        gamma: f32,
+        item: &DeepQNetworkBatch<B>,
+        shape: [usize; D],
+    ) -> Tensor<B::InnerBackend, 2> {
+        let next_observation = item.next_observation.clone().inner().reshape(shape);
+        let (mean, log_std) = self.actor.valid().gaussian(next_observation.clone());
+        let (next_action, next_log_prob) = sample_squashed_gaussian(mean, log_std);
+        let next_q1 = self
+            .target_critic1
+            .valid()
+            .action_value(next_observation.clone(), next_action.clone());
+        let next_q2 = self
+            .target_critic2
+            .valid()
+            .action_value(next_observation, next_action);
+        let next_value = next_q1.min_pair(next_q2) - next_log_prob.mul_scalar(self.log_alpha.exp());
+        let reward = item.reward.clone().inner().mean_dim(1);
+        let done = item.done.clone().inner().mean_dim(1);
+        (done.ones_like() - done) * next_value.mul_scalar(gamma.powi(self.config.n_step as i32))
+            + reward
+    }
+
+    // replayed actions are stored in env scale, the critics work on [-1, 1]
+    fn normalized_action(&self, item: &DeepQNetworkBatch<B>) -> Tensor<B, 2> {
+        (item.action.clone() - action_tensor(&self.action_bias, &self.device))
+            / action_tensor(&self.action_scale, &self.device)
+    }
+}
+
+impl<
+        B: AutodiffBackend,
+        const D: usize,
+        A: AutodiffModule<B>,
+        C: AutodiffModule<B>,
+        O: SimpleOptimizer<B::InnerBackend>,
+        S: LrScheduler,
+    > ObservationNormalized for SoftActorCriticAgent<B, D, A, C, O, S>
+{
+    fn observation_normalizer(&self) -> Option<&ObservationNormalizer> {
+        self.observation_normalizer.as_ref()
+    }
+
+    fn observation_normalizer_mut(&mut self) -> &mut Option<ObservationNormalizer> {
+        &mut self.observation_normalizer
+    }
+}
+
+fn action_tensor<B: Backend>(values: &[f32], device: &B::Device) -> Tensor<B, 2> {
+    Tensor::from_data(
+        TensorData::new(values.to_vec(), Shape::new([1, values.len()])).convert::<B::FloatElem>(),
+        device,
+    )
+}
+
+fn sample_squashed_gaussian<B: Backend>(
+    mean: Tensor<B, 2>,
+    log_std: Tensor<B, 2>,
+) -> (Tensor<B, 2>, Tensor<B, 2>) {
+    let noise = Tensor::random(mean.shape(), Distribution::Normal(0.0, 1.0), &mean.device());
+    let action = (mean + log_std.clone().exp() * noise.clone()).tanh();
+    let log_prob = (noise.powf_scalar(2.0).mul_scalar(-0.5) - log_std)
+        .sub_scalar(0.5 * (2.0 * PI).ln())
+        - action
+            .clone()
+            .powf_scalar(2.0)
+            .neg()
+            .add_scalar(1.0 + 1e-6)
+            .log();
+    (action, log_prob.sum_dim(1))
+}
+
+struct ParamCollector<B: Backend> {
+    params: HashMap<ParamId, Tensor<B, 1>>,
+}
+
+impl<B: Backend> ModuleVisitor<B> for ParamCollector<B> {
+    fn visit_float<const D: usize>(&mut self, id: ParamId, tensor: &Tensor<B, D>) {
+        self.params
+            .insert(id, tensor.clone().detach().flatten(0, D - 1));
+    }
+}
+
+struct PolyakUpdater<B: Backend> {
+    params: HashMap<ParamId, Tensor<B, 1>>,
+    tau: f32,
+}
+
+impl<B: Backend> ModuleMapper<B> for PolyakUpdater<B> {
+    fn map_float<const D: usize>(&mut self, id: ParamId, tensor: Tensor<B, D>) -> Tensor<B, D> {
+        match self.params.remove(&id) {
+            Some(param) => {
+                let shape = tensor.shape();
+                (tensor.mul_scalar(1.0 - self.tau) + param.reshape(shape).mul_scalar(self.tau))
+                    .detach()
+            }
+            None => tensor,
+        }
+    }
+}
+
+// target and online modules share param ids since the target is forked from the online one
+fn soft_update<B: AutodiffBackend, C: AutodiffModule<B>>(target: C, online: &C, tau: f32) -> C {
+    let mut collector = ParamCollector {
+        params: HashMap::new(),
+    };
+    online.visit(&mut collector);
+    target.map(&mut PolyakUpdater {
+        params: collector.params,
+        tau,
+    })
+}
+
+impl<B, const D: usize, A, C, O, S> PrioritizedReplay<DeepQNetworkState>
+    for SoftActorCriticAgent<B, D, A, C, O, S>
+where
+    B: AutodiffBackend,
+    A: AutodiffModule<B> + GaussianPolicy<B>,
+    A::InnerModule: GaussianPolicy<B::InnerBackend>,
+    C: AutodiffModule<B> + ActionValue<B>,
+    C::InnerModule: ActionValue<B::InnerBackend>,
+    O: SimpleOptimizer<B::InnerBackend>,
+    S: LrScheduler + Clone,
+{
+    fn temporaral_difference_error(
+        &self,
+        gamma: f32,
+        experiences: &[Experience<DeepQNetworkState>],
+    ) -> anyhow::Result<Vec<f32>> {
+        let batcher = DeepQNetworkBathcer::new(self.device.clone(), self.action_space.clone());
+
+        let mut shape = *self.observation_space.shape();
+        shape[0] = experiences.len();
+
+        let item = batcher.batch(experiences.to_vec());
+        let targets = self.soft_target(gamma, &item, shape);
+        let q_value = self.critic1.valid().action_value(
+            item.observation.clone().inner().reshape(shape),
+            self.normalized_action(&item).inner(),
+        );
+        let td: Vec<f32> = (q_value - targets)
+            .abs()
+            .sum_dim(1)
+            .into_data()
+            .to_vec()
+            .map_err(|e| anyhow!("tensor data to_vec error: {:?}", e))?;
+        Ok(td)
+    }
+}
+
+impl<B, const D: usize, A, C, O, S> Agent<DeepQNetworkState>
+    for SoftActorCriticAgent<B, D, A, C, O, S>
+where
+    B: AutodiffBackend,
+    A: AutodiffModule<B> + GaussianPolicy<B>,
+    A::InnerModule: GaussianPolicy<B::InnerBackend>,
+    C: AutodiffModule<B> + ActionValue<B>,
+    C::InnerModule: ActionValue<B::InnerBackend>,
+    O: SimpleOptimizer<B::InnerBackend>,
+    S: LrScheduler + Clone,
+{
+    fn supports_action_space(action_space: &ActionSpace) -> bool {
+        matches!(action_space, ActionSpace::Box { .. })
+    }
+
+    fn policy(&self, observation: &[f32]) -> Action {
+        self.sample_actions(&[observation.to_vec()])
+            .pop()
+            .expect("one action per observation")
+    }
+
+    fn batch_policy(&self, observations: &[Vec<f32>]) -> Vec<Action> {
+        self.sample_actions(observations)
+    }
+
+    fn set_training(&mut self, training: bool) {
+        self.training = training;
+    }
+
+    fn update(
+        &mut self,
+        gamma: f32,
+        experiences: &[Experience<DeepQNetworkState>],
+        weights: &[f32],
+    ) -> anyhow::Result<()> {
+        let batcher = DeepQNetworkBathcer::new(self.device.clone(), self.action_space.clone());
+
+        let batch_size = experiences.len();
+        let mut shape = *self.observation_space.shape();
+        shape[0] = batch_size;
+
+        let item = batcher.batch(experiences.to_vec());
+        let targets: Tensor<B, 2> = Tensor::from_inner(self.soft_target(gamma, &item, shape));
+        let observation = item.observation.clone().reshape(shape);
+        let action = self.normalized_action(&item);
+        let weights: Tensor<B, 2> = Tensor::from_data(
+            TensorData::new(weights.to_vec(), Shape::new([weights.len(), 1]))
+                .convert::<B::FloatElem>(),
+            &self.device,
+        );
+        let lr = self.lr_scheduler.step();
+
+        let critic1 = self.critic1.clone();
+        let q_value = critic1.action_value(observation.clone(), action.clone());
+        let loss = ((q_value - targets.clone()).powf_scalar(2.0) * weights.clone()).mean();
+        let grads = GradientsParams::from_grads(loss.backward(), &critic1);
+        self.critic1 = self.critic1_optimizer.step(lr, critic1, grads);
+
+        let critic2 = self.critic2.clone();
+        let q_value = critic2.action_value(observation.clone(), action);
+        let loss = ((q_value - targets).powf_scalar(2.0) * weights.clone()).mean();
+        let grads = GradientsParams::from_grads(loss.backward(), &critic2);
+        self.critic2 = self.critic2_optimizer.step(lr, critic2, grads);
+
+        let actor = self.actor.clone();
+        let (mean, log_std) = actor.gaussian(observation.clone());
+        let (new_action, log_prob) = sample_squashed_gaussian(mean, log_std);
+        let q1 = self
+            .critic1
+            .action_value(observation.clone(), new_action.clone());
+        let q2 = self.critic2.action_value(observation, new_action);
+        let loss = ((log_prob.clone().mul_scalar(self.log_alpha.exp()) - q1.min_pair(q2))
+            * weights)
+            .mean();
+        let grads = GradientsParams::from_grads(loss.backward(), &actor);
+        self.actor = self.actor_optimizer.step(lr, actor, grads);
+
+        // gradient step on log(alpha) for J(alpha) = E[-alpha * (log_prob + target_entropy)]
+        let log_prob: f32 = log_prob.inner().mean().into_scalar().elem();
+        self.log_alpha += self.config.alpha_learning_rate * (log_prob + self.target_entropy);
+
+        self.target_critic1 =
+            soft_update(self.target_critic1.clone(), &self.critic1, self.config.tau);
+        self.target_critic2 =
+            soft_update(self.target_critic2.clone(), &self.critic2, self.config.tau);
+        self.update_counter += 1;
+
+        Ok(())
+    }
+
+    fn update_counter(&self) -> usize {
+        self.update_counter
+    }
+
+    fn set_update_counter(&mut self, update_counter: usize) {
+        self.update_counter = update_counter;
+    }
+
+    fn make_state(&self, next_observation: &[f32], state: &DeepQNetworkState) -> DeepQNetworkState {
+        DeepQNetworkState {
+            observation: state.next_observation.clone(),
+            next_observation: next_observation.to_vec(),
+        }
+    }
+
+    fn save<P: AsRef<Path>>(&self, artifacts_dir: P) -> anyhow::Result<()> {
+        let artifacts_dir = artifacts_dir.as_ref().to_path_buf();
+        std::fs::create_dir_all(&artifacts_dir)
+            .with_context(|| format!("fail to create {:?}", artifacts_dir))?;
+        self.actor
+            .clone()
+            .save_file(artifacts_dir.join("model"), &CompactRecorder::new())
+            .with_context(|| "fail to save model")?;
+        for (name, critic) in [
+            ("critic1", &self.critic1),
+            ("critic2", &self.critic2),
+            ("target_critic1", &self.target_critic1),
+            ("target_critic2", &self.target_critic2),
+        ] {
+            critic
+                .clone()
+                .save_file(artifacts_dir.join(name), &CompactRecorder::new())
+                .with_context(|| format!("fail to save {}", name))?;
+        }
+
+        save_optimizer(&self.actor_optimizer, &artifacts_dir.join("optimizer.mpk"))?;
+        save_optimizer(
+            &self.critic1_optimizer,
+            &artifacts_dir.join("critic1_optimizer.mpk"),
+        )?;
+        save_optimizer(
+            &self.critic2_optimizer,
+            &artifacts_dir.join("critic2_optimizer.mpk"),
+        )?;
+
+        let scheduler_record = self.lr_scheduler.to_record();
+        let scheduler_record: <<S as LrScheduler>::Record<B> as Record<_>>::Item<
+            HalfPrecisionSettings,
+        > = scheduler_record.into_item();
+        let mut scheduler_file = File::create(artifacts_dir.join("scheduler.mpk"))
+            .with_context(|| "create scheduler file")?;
+        rmp_serde::encode::write(&mut scheduler_file, &scheduler_record)
+            .with_context(|| "Failed to write scheduler record")?;
+
+        let mut alpha_file = File::create(artifacts_dir.join("log_alpha.mpk"))
+            .with_context(|| "create log_alpha file")?;
+        rmp_serde::encode::write(&mut alpha_file, &self.log_alpha)
+            .with_context(|| "Failed to write log_alpha")?;
+        self.save_observation_normalizer(&artifacts_dir)?;
+        Ok(())
+    }
+
+    fn load<P: AsRef<Path>>(&mut self, restore_dir: P) -> anyhow::Result<()> {
+        let restore_dir = restore_dir.as_ref().to_path_buf();
+        let model_file = restore_dir.join("model.mpk");
+        if model_file.exists() {
+            let record = CompactRecorder::new()
+                .load(model_file, &self.device)
+                .with_context(|| "Failed to load model")?;
+            self.actor = self.actor.clone().load_record(record);
+        }
+        for (name, critic) in [
+            ("critic1", &mut self.critic1),
+            ("critic2", &mut self.critic2),
+            ("target_critic1", &mut self.target_critic1),
+            ("target_critic2", &mut self.target_critic2),
+        ] {
+            let critic_file = restore_dir.join(format!("{}.mpk", name));
+            if critic_file.exists() {
+                let record = CompactRecorder::new()
+                    .load(critic_file, &self.device)
+                    .with_context(|| format!("Failed to load {}", name))?;
+                *critic = critic.clone().load_record(record);
+            }
+        }
+
+        let optimizer_file = restore_dir.join("optimizer.mpk");
+        if optimizer_file.exists() {
+            self.actor_optimizer =
+                load_optimizer(self.actor_optimizer.clone(), &optimizer_file, &self.device)?;
+        }
+        let optimizer_file = restore_dir.join("critic1_optimizer.mpk");
+        if optimizer_file.exists() {
+            self.critic1_optimizer = load_optimizer(
+                self.critic1_optimizer.clone(),
+                &optimizer_file,
+                &self.device,
+            )?;
+        }
+        let optimizer_file = restore_dir.join("critic2_optimizer.mpk");
+        if optimizer_file.exists() {
+            self.critic2_optimizer = load_optimizer(
+                self.critic2_optimizer.clone(),
+                &optimizer_file,
+                &self.device,
+            )?;
+        }
+
+        let scheduler_file = restore_dir.join("scheduler.mpk");
+        if scheduler_file.exists() {
+            let scheduler_file =
+                File::open(scheduler_file).with_context(|| "open scheduler file")?;
+            let record: <<S as LrScheduler>::Record<B> as Record<_>>::Item<HalfPrecisionSettings> =
+                rmp_serde::decode::from_read(scheduler_file)
+                    .with_context(|| "Failed to read scheduler record")?;
+            let record =
+                <<S as LrScheduler>::Record<B> as Record<_>>::from_item(record, &self.device);
+            self.lr_scheduler = self.lr_scheduler.clone().load_record(record);
+        }
+
+        let alpha_file = restore_dir.join("log_alpha.mpk");
+        if alpha_file.exists() {
+            let alpha_file = File::open(alpha_file).with_context(|| "open log_alpha file")?;
+            self.log_alpha = rmp_serde::decode::from_read(alpha_file)
+                .with_context(|| "Failed to read log_alpha")?;
+        }
+        self.load_observation_normalizer(&restore_dir)?;
+
+        Ok(())
+    }
+}
+
+impl<B, const D: usize, A, C, O, S> PrioritizedReplayAgent<DeepQNetworkState>
+    for SoftActorCriticAgent<B, D, A, C, O, S>
+where
+    B: AutodiffBackend,
+    A: AutodiffModule<B> + GaussianPolicy<B>,
+    A::InnerModule: GaussianPolicy<B::InnerBackend>,
+    C: AutodiffModule<B> + ActionValue<B>,
+    C::InnerModule: ActionValue<B::InnerBackend>,
+    O: SimpleOptimizer<B::InnerBackend>,
+    S: LrScheduler + Clone,
+{
+}
+
+#[cfg(test)]
+mod tests {
+    use burn::{
+        backend::{libtorch::LibTorchDevice, Autodiff, LibTorch},
+        lr_scheduler::constant::ConstantLr,
+        optim::AdamConfig,
+    };
+    use tempfile::TempDir;
+
+    use crate::{
+        env::classic::Pendulum,
+        model::{ActionValueModel, GaussianPolicyModel},
+        trainer::{
+            replay::ReplayTrainer, storage::StorageBackend, uniform::UniformReplayMemory,
+            RewardMapping,
+        },
+        Env,
+    };
+
+    use super::*;
+
+    type Backend = Autodiff<LibTorch>;
+
+    #[test]
+    fn test_train_loop_pendulum() -> anyhow::Result<()> {
+        let episode = 2;
+        let device = LibTorchDevice::Cpu;
+        let mut env = Pendulum::with_seed(0);
+        let observation_space = env.observation_space().clone();
+        let action_space = env.action_space().clone();
+        let mut agent = SoftActorCriticAgent::new(
+            GaussianPolicyModel::<Backend>::new(&device, &observation_space, &action_space),
+            ActionValueModel::<Backend>::new(&device, &observation_space, &action_space),
+            ActionValueModel::<Backend>::new(&device, &observation_space, &action_space),
+            AdamConfig::new().init(),
+            AdamConfig::new().init(),
+            ConstantLr::new(0.0003),
+            observation_space,
+            action_space,
+            device,
+            SoftActorCriticAgentConfig::new(1),
+        )?;
+        let mut memory =
+            UniformReplayMemory::<DeepQNetworkState>::new(1024, 8, StorageBackend::InMemory)?;
+
+        let artifacts_dir = TempDir::new()?;
+        let trainer = ReplayTrainer::new(
+            episode,
+            0.99,
+            1,
+            RewardMapping::Identity,
+            artifacts_dir.path().to_path_buf(),
+            false,
+        )?;
+        trainer.train_loop(&mut agent, &mut env, &mut memory, &None, &mut None)?;
+
+        let episode_artifacts_dir = artifacts_dir.path().join("0");
+        for file in [
+            "model.mpk",
+            "critic1.mpk",
+            "target_critic2.mpk",
+            "optimizer.mpk",
+            "critic2_optimizer.mpk",
+            "log_alpha.mpk",
+        ] {
+            assert!(episode_artifacts_dir.join(file).exists(), "{}", file);
+        }
+        agent.load(&episode_artifacts_dir)?;
+        let action = agent.policy(&env.reset()?);
+        match action {
+            Action::Continuous(action) => {
+                assert_eq!(action.len(), 1);
+                assert!((-2.0..=2.0).contains(&action[0]));
+            }
+            Action::Discrete(..) => panic!("expected a continuous action"),
+        }
+
+        let observation = env.reset()?;
+        assert_ne!(agent.policy(&observation), agent.policy(&observation));
+        agent.set_training(false);
+        assert_eq!(agent.policy(&observation), agent.policy(&observation));
+        Ok(())
+    }
+}
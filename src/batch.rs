@@ -3,7 +3,7 @@ use burn::{
     tensor::{backend::AutodiffBackend, Shape, Tensor, TensorData},
 };
 
-use crate::{Action, ActionSpace, DeepQNetworkState, Experience};
+use crate::{Action, ActionSpace, Experience, ObservationState};
 
 #[derive(Debug, Clone)]
 pub struct DeepQNetworkBathcer<B: AutodiffBackend> {
@@ -20,54 +20,69 @@ impl<B: AutodiffBackend> DeepQNetworkBathcer<B> {
     }
 }
 
-impl<B: AutodiffBackend> Batcher<Experience<DeepQNetworkState>, DeepQNetworkBatch<B>>
+impl<B: AutodiffBackend, S: ObservationState> Batcher<Experience<S>, DeepQNetworkBatch<B>>
     for DeepQNetworkBathcer<B>
 {
-    fn batch(&self, items: Vec<Experience<DeepQNetworkState>>) -> DeepQNetworkBatch<B> {
+    fn batch(&self, items: Vec<Experience<S>>) -> DeepQNetworkBatch<B> {
         let result = items
             .into_iter()
-            .filter(|x| !x.state.observation.is_empty() && !x.state.next_observation.is_empty())
             .map(|x| {
-                let obs_len = x.state.observation.len();
-                let next_obs_len = x.state.next_observation.len();
                 (
-                    Tensor::from_data(
-                        TensorData::new(x.state.observation, Shape::new([1, obs_len]))
-                            .convert::<B::FloatElem>(),
-                        &Default::default(),
-                    ),
-                    Tensor::from_data(
-                        TensorData::new(x.state.next_observation, Shape::new([1, next_obs_len]))
-                            .convert::<B::FloatElem>(),
-                        &Default::default(),
-                    ),
-                    match (x.action, self.action_space) {
-                        (Action::Discrete(value), ActionSpace::Discrete(num_class)) => {
-                            Tensor::<B, 2>::one_hot(
+                    x.state.observation(),
+                    x.state.next_observation(),
+                    x.action,
+                    x.reward,
+                    x.terminated,
+                )
+            })
+            .filter(|(observation, next_observation, ..)| {
+                !observation.is_empty() && !next_observation.is_empty()
+            })
+            .map(
+                |(observation, next_observation, action, reward, terminated)| {
+                    let obs_len = observation.len();
+                    let next_obs_len = next_observation.len();
+                    (
+                        Tensor::from_data(
+                            TensorData::new(observation, Shape::new([1, obs_len]))
+                                .convert::<B::FloatElem>(),
+                            &Default::default(),
+                        ),
+                        Tensor::from_data(
+                            TensorData::new(next_observation, Shape::new([1, next_obs_len]))
+                                .convert::<B::FloatElem>(),
+                            &Default::default(),
+                        ),
+                        match action {
+                            Action::Discrete(value) => Tensor::<B, 2>::one_hot(
                                 value as usize,
-                                num_class as usize,
+                                self.action_space.size(),
                                 &Default::default(),
-                            )
-                        }
-                    },
-                    match self.action_space {
-                        ActionSpace::Discrete(num_class) => Tensor::from_data(
-                            TensorData::new(vec![x.reward], Shape::new([1, 1]))
+                            ),
+                            Action::Continuous(value) => {
+                                let action_len = value.len();
+                                Tensor::from_data(
+                                    TensorData::new(value, Shape::new([1, action_len]))
+                                        .convert::<B::FloatElem>(),
+                                    &Default::default(),
+                                )
+                            }
+                        },
+                        Tensor::from_data(
+                            TensorData::new(vec![reward], Shape::new([1, 1]))
                                 .convert::<B::FloatElem>(),
                             &Default::default(),
                         )
-                        .repeat_dim(1, num_class as usize),
-                    },
-                    match self.action_space {
-                        ActionSpace::Discrete(num_class) => Tensor::from_data(
-                            TensorData::new(vec![x.is_done as i32], Shape::new([1, 1]))
+                        .repeat_dim(1, self.action_space.size()),
+                        Tensor::from_data(
+                            TensorData::new(vec![terminated as i32], Shape::new([1, 1]))
                                 .convert::<B::FloatElem>(),
                             &Default::default(),
                         )
-                        .repeat_dim(1, num_class as usize),
-                    },
-                )
-            })
+                        .repeat_dim(1, self.action_space.size()),
+                    )
+                },
+            )
             .fold(
                 (Vec::new(), Vec::new(), Vec::new(), Vec::new(), Vec::new()),
                 |(mut a, mut b, mut c, mut d, mut e), (v, w, x, y, z)| {
@@ -1,8 +1,9 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-use anyhow::Context;
+use anyhow::{anyhow, ensure, Context};
 use burn::{
     backend::{libtorch::LibTorchDevice, Autodiff, LibTorch},
+    config::Config as _,
     lr_scheduler::constant::ConstantLr,
     optim::AdamConfig,
 };
@@ -10,24 +11,44 @@ use burn_rl_example::{
     agent::{
         categorical::{CategoricalDeepQNetworkAgent, CategoricalDeepQNetworkAgentConfig},
         expectation::{DeepQNetworkAgent, DeepQNetworkAgentConfig},
+        fully_parameterized_quantile::{
+            FullyParameterizedQuantileAgent, FullyParameterizedQuantileAgentConfig,
+        },
+        implicit_quantile::{ImplicitQuantileAgent, ImplicitQuantileAgentConfig},
+        ppo::{ProximalPolicyOptimizationAgent, ProximalPolicyOptimizationAgentConfig},
         quantile::{QuantileRegressionAgent, QuantileRegressionAgentConfig},
-        LossFunction,
+        recurrent::{RecurrentDeepQNetworkAgent, RecurrentDeepQNetworkAgentConfig},
+        sac::{SoftActorCriticAgent, SoftActorCriticAgentConfig},
+        LossFunction, ObservationNormalized, RiskMeasure,
     },
     env::{
+        atari::{atari_evaluation_wrappers, atari_wrappers},
+        classic::{ClassicControlEnv, CLASSIC_CONTROL_ENV_NAMES},
         gym_super_mario_bros::GymSuperMarioBrosEnv,
         gymnasium::{GymnasiumEnv1D, GymnasiumEnv3D},
+        wrapper::{EnvExt as _, ObservationNormalizer},
+    },
+    model::{
+        ActionValueModel, ActorCriticModel, DeepQNetworkModel, GaussianPolicyModel, HeadConfig,
+        OutputLayerConfig, RecurrentCell, RecurrentDeepQNetworkModel, TorsoConfig,
     },
-    model::{DeepQNetworkModel, OutputLayerConfig},
     trainer::{
-        prioritized::{PrioritizedReplayMemory, PrioritizedReplayTrainer},
-        uniform::{UniformReplayMemory, UniformReplayTrainer},
-        RandomPolicy, RewardMapping,
+        prioritized::{PrioritizedReplayConfig, PrioritizedReplayMemory},
+        replay::ReplayTrainer,
+        rollout::RolloutTrainer,
+        sequence::{SequenceReplayMemory, SequenceReplayTrainer},
+        storage::StorageBackend,
+        uniform::UniformReplayMemory,
+        Evaluation, EvaluationInterval, RandomPolicy, RewardMapping, TrainingScheduleConfig,
+        TrainingState,
     },
-    Agent, Env,
+    Agent, DeepQNetworkState, Env, FlatState, FrameStackState, ObservationSpace, ObservationState,
+    PrioritizedReplayAgent, State, VecEnv,
 };
 use chrono::Local;
 use clap::{Parser, ValueEnum};
 use pyo3::Python;
+use serde::{de::DeserializeOwned, Serialize};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -38,6 +59,8 @@ struct Args {
     revenue_mapping: RevenueMapping,
     #[clap(value_enum)]
     loss_function: LossFunction,
+    #[arg(long, value_enum, default_value_t = Algorithm::Dqn)]
+    algorithm: Algorithm,
     #[arg(long)]
     artifacts_path: PathBuf,
     #[arg(long)]
@@ -54,12 +77,239 @@ struct Args {
     double_dqn: bool,
     #[arg(long)]
     noisy: bool,
+    // seeds the noise of the noisy layers for reproducible runs
+    #[arg(long)]
+    noise_seed: Option<u64>,
+    // seeds the exploration of a fresh run, a restored one continues from its checkpoint
+    #[arg(long)]
+    seed: Option<u64>,
     #[arg(long)]
     render: bool,
     #[arg(long)]
+    native: bool,
+    #[arg(long, default_value_t = 1)]
+    num_envs: usize,
+    #[arg(long)]
     n_step: usize,
     #[arg(long)]
     bellman_gamma: f32,
+    #[arg(long, value_enum, default_value_t = Risk::Mean)]
+    risk_measure: Risk,
+    #[arg(long)]
+    risk_parameter: Option<f32>,
+    #[arg(long, value_enum, default_value_t = RecurrentCell::Lstm)]
+    recurrent_cell: RecurrentCell,
+    #[arg(long, default_value_t = 80)]
+    sequence_length: usize,
+    #[arg(long, default_value_t = 40)]
+    burn_in: usize,
+    #[arg(long, default_value_t = 1)]
+    frame_stack: usize,
+    #[arg(long)]
+    normalize_observation: bool,
+    // layers like `conv:32:8:4,relu,linear:512,relu`
+    #[arg(long, conflicts_with_all = ["torso_config", "encoder"])]
+    torso: Option<TorsoConfig>,
+    // a json file of a `TorsoConfig`
+    #[arg(long, conflicts_with = "encoder")]
+    torso_config: Option<PathBuf>,
+    // a ready-made encoder for pixel observations
+    #[arg(long, value_enum)]
+    encoder: Option<Encoder>,
+    #[arg(long, default_value_t = 64)]
+    head_width: usize,
+    #[arg(long, default_value_t = 1)]
+    head_depth: usize,
+    // greedy evaluation runs every this many training episodes
+    #[arg(long, conflicts_with = "eval_every_steps")]
+    eval_every_episodes: Option<usize>,
+    // or every this many env steps
+    #[arg(long)]
+    eval_every_steps: Option<usize>,
+    #[arg(long, default_value_t = 10)]
+    eval_episodes: usize,
+    // agent steps an evaluation episode is cut at, 108k frames with the atari frame skip
+    #[arg(long, default_value_t = 27000)]
+    eval_max_steps: usize,
+    // env steps collected before the first update
+    #[arg(long, default_value_t = 0)]
+    learning_starts: usize,
+    // env steps between updates, summed over all envs
+    #[arg(long, default_value_t = 1)]
+    train_frequency: usize,
+    #[arg(long, default_value_t = 1)]
+    gradient_steps: usize,
+    #[arg(long)]
+    total_timesteps: Option<usize>,
+    // target network sync in env steps rather than updates
+    #[arg(long)]
+    target_update_steps: Option<usize>,
+    #[arg(long, default_value_t = 0.6)]
+    per_alpha: f32,
+    // importance sampling exponent, annealed from start to end over the training
+    #[arg(long, default_value_t = 0.4)]
+    per_beta_start: f32,
+    #[arg(long, default_value_t = 1.0)]
+    per_beta_end: f32,
+    #[arg(long, default_value_t = 0.001)]
+    per_epsilon: f32,
+    // where the replay memory keeps experiences, by observation size when unset
+    #[arg(long, value_enum)]
+    replay_storage: Option<ReplayStorage>,
+    // keeps the replay memory under the artifacts path and resumes it with the restore path
+    #[arg(long, conflicts_with = "replay_storage")]
+    persist_replay: bool,
+}
+
+impl Args {
+    fn head_config(&self) -> HeadConfig {
+        HeadConfig::new()
+            .with_hidden_width(self.head_width)
+            .with_hidden_layers(self.head_depth)
+    }
+
+    fn torso_config<const D: usize>(&self) -> anyhow::Result<TorsoConfig> {
+        if let Some(torso_config) = &self.torso_config {
+            TorsoConfig::load(torso_config)
+                .map_err(|err| anyhow!("fail to load torso config: {:?}", err))
+        } else if let Some(torso) = &self.torso {
+            Ok(torso.clone())
+        } else if let Some(encoder) = self.encoder {
+            if D != 4 {
+                return Err(anyhow!("{:?} encoder needs pixel observations", encoder));
+            }
+            Ok(match encoder {
+                Encoder::Nature => TorsoConfig::nature_dqn(),
+                Encoder::Impala => TorsoConfig::impala(),
+            })
+        } else {
+            Ok(TorsoConfig::default_for::<D>())
+        }
+    }
+
+    fn reward_mapping(&self) -> RewardMapping {
+        match self.revenue_mapping {
+            RevenueMapping::Identity => RewardMapping::Identity,
+            RevenueMapping::Clip => RewardMapping::Clip {
+                min: -1.0,
+                max: 1.0,
+            },
+            RevenueMapping::Rescaling => RewardMapping::Rescaling { epsilon: 0.001 },
+            RevenueMapping::SymLog => RewardMapping::SymLog,
+        }
+    }
+
+    // epsilon greedy exploration unless the noisy layers explore
+    fn random_policy(&self) -> Option<RandomPolicy> {
+        if self.noisy {
+            None
+        } else {
+            Some(RandomPolicy::new(1.0, 0.01, 0.99))
+        }
+    }
+
+    fn schedule(&self) -> TrainingScheduleConfig {
+        TrainingScheduleConfig::new()
+            .with_learning_starts(self.learning_starts)
+            .with_train_frequency(self.train_frequency)
+            .with_gradient_steps(self.gradient_steps)
+            .with_total_timesteps(self.total_timesteps)
+    }
+
+    // any of the schedule flags, only the replay trainer follows them
+    fn has_schedule(&self) -> bool {
+        self.learning_starts != 0
+            || self.train_frequency != 1
+            || self.gradient_steps != 1
+            || self.total_timesteps.is_some()
+            || self.target_update_steps.is_some()
+    }
+
+    fn prioritized_replay_config(&self) -> PrioritizedReplayConfig {
+        PrioritizedReplayConfig::new()
+            .with_alpha(self.per_alpha)
+            .with_beta_start(self.per_beta_start)
+            .with_beta_end(self.per_beta_end)
+            .with_priority_epsilon(self.per_epsilon)
+    }
+
+    fn storage_backend<const D: usize>(
+        &self,
+        observation_space: &ObservationSpace<D>,
+        replay_dir: Option<&Path>,
+    ) -> StorageBackend {
+        if let Some(replay_dir) = replay_dir {
+            return StorageBackend::PersistentRocksDb(replay_dir.join("experiences"));
+        }
+        match self.replay_storage {
+            Some(ReplayStorage::InMemory) => StorageBackend::InMemory,
+            Some(ReplayStorage::RocksDb) => StorageBackend::RocksDb,
+            None => {
+                StorageBackend::for_observation_size(observation_space.shape().iter().product())
+            }
+        }
+    }
+
+    // the replay memory of the restored run is copied to this one, the restored run keeps its own
+    fn replay_dir(&self, artifacts_path: &Path) -> anyhow::Result<Option<PathBuf>> {
+        if !self.persist_replay {
+            return Ok(None);
+        }
+        let replay_dir = artifacts_path.join("replay");
+        let restored_dir = self
+            .restore_path
+            .as_ref()
+            .and_then(|restore_path| restore_path.parent())
+            .map(|run_path| run_path.join("replay"));
+        if let Some(restored_dir) = restored_dir.filter(|dir| dir.exists()) {
+            copy_dir(&restored_dir, &replay_dir)
+                .with_context(|| format!("copy replay memory from {}", restored_dir.display()))?;
+        }
+        Ok(Some(replay_dir))
+    }
+
+    fn training_state(&self) -> anyhow::Result<Option<TrainingState>> {
+        let Some(restore_path) = &self.restore_path else {
+            return Ok(None);
+        };
+        TrainingState::load(restore_path).with_context(|| "load training state")
+    }
+
+    fn teacher_update_freq(&self, default: usize) -> usize {
+        self.target_update_steps
+            .map_or(default, |steps| self.schedule().updates_in(steps).max(1))
+    }
+
+    fn evaluation<'a, const D: usize, E: Env<D> + 'a>(
+        &self,
+        make_env: impl FnOnce() -> anyhow::Result<E>,
+    ) -> anyhow::Result<Option<Evaluation<'a, D>>> {
+        let interval = if let Some(episode) = self.eval_every_episodes {
+            EvaluationInterval::Episode(episode)
+        } else if let Some(step) = self.eval_every_steps {
+            EvaluationInterval::Step(step)
+        } else {
+            return Ok(None);
+        };
+        let env = make_env().with_context(|| "create eval env")?;
+        Ok(Some(
+            Evaluation::new(env, interval, self.eval_episodes).with_max_steps(self.eval_max_steps),
+        ))
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum Encoder {
+    Nature,
+    Impala,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum Algorithm {
+    Dqn,
+    Sac,
+    Ppo,
+    R2d2,
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
@@ -67,6 +317,8 @@ enum Distributional {
     Expectation,
     Categorical,
     Quantile,
+    ImplicitQuantile,
+    FullyParameterizedQuantile,
 }
 
 impl Distributional {
@@ -79,10 +331,26 @@ impl Distributional {
                 max_value: 250.0,
             },
             Distributional::Quantile => OutputLayerConfig::QuantileRegression { quantiles: 51 },
+            Distributional::ImplicitQuantile => OutputLayerConfig::ImplicitQuantile {
+                quantiles: 64,
+                embedding_dim: 64,
+            },
+            Distributional::FullyParameterizedQuantile => {
+                OutputLayerConfig::FullyParameterizedQuantile {
+                    quantiles: 32,
+                    embedding_dim: 64,
+                }
+            }
         }
     }
 }
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum ReplayStorage {
+    InMemory,
+    RocksDb,
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
 enum RevenueMapping {
     Identity,
@@ -91,7 +359,78 @@ enum RevenueMapping {
     SymLog,
 }
 
-fn run<const D: usize>(env: &mut impl Env<D>, args: Args) -> anyhow::Result<()> {
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum Risk {
+    Mean,
+    Cvar,
+    MeanStd,
+    Wang,
+}
+
+impl Risk {
+    fn risk_measure(&self, parameter: Option<f32>) -> anyhow::Result<RiskMeasure> {
+        Ok(match self {
+            Risk::Mean => RiskMeasure::Mean,
+            Risk::Cvar => {
+                let alpha = parameter.unwrap_or(0.25);
+                ensure!(
+                    0.0 < alpha && alpha <= 1.0,
+                    "CVaR alpha must be in (0, 1], got {}",
+                    alpha
+                );
+                RiskMeasure::ConditionalValueAtRisk { alpha }
+            }
+            Risk::MeanStd => RiskMeasure::MeanMinusStd {
+                k: parameter.unwrap_or(1.0),
+            },
+            Risk::Wang => RiskMeasure::Wang {
+                eta: parameter.unwrap_or(-0.75),
+            },
+        })
+    }
+}
+
+fn copy_dir(from: &Path, to: &Path) -> anyhow::Result<()> {
+    std::fs::create_dir_all(to).with_context(|| format!("create {}", to.display()))?;
+    for entry in std::fs::read_dir(from).with_context(|| format!("read {}", from.display()))? {
+        let entry = entry?;
+        let path = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &path)?;
+        } else {
+            std::fs::copy(entry.path(), &path)
+                .with_context(|| format!("copy {}", entry.path().display()))?;
+        }
+    }
+    Ok(())
+}
+
+fn run_stacked<const D: usize, E: Env<D>>(
+    envs: &mut VecEnv<D, E>,
+    observation_normalizer: Option<ObservationNormalizer>,
+    evaluation: Option<Evaluation<'_, D>>,
+    args: Args,
+) -> anyhow::Result<()> {
+    match args.frame_stack {
+        1 => run::<D, E, DeepQNetworkState>(envs, observation_normalizer, evaluation, args),
+        4 => run::<D, E, FrameStackState<4>>(envs, observation_normalizer, evaluation, args),
+        frame_stack => Err(anyhow!(
+            "unsupported frame stack {}, use 1 or 4",
+            frame_stack
+        )),
+    }
+}
+
+fn run<
+    const D: usize,
+    E: Env<D>,
+    T: ObservationState + FlatState + Serialize + DeserializeOwned + Sync + 'static,
+>(
+    envs: &mut VecEnv<D, E>,
+    observation_normalizer: Option<ObservationNormalizer>,
+    evaluation: Option<Evaluation<'_, D>>,
+    args: Args,
+) -> anyhow::Result<()> {
     type Backend = LibTorch;
     type AutodiffBackend = Autodiff<Backend>;
     let device = if tch::utils::has_cuda() {
@@ -112,232 +451,366 @@ fn run<const D: usize>(env: &mut impl Env<D>, args: Args) -> anyhow::Result<()>
     let yyyymmdd_hhmmss = now.format("%Y%m%d_%H%M%S");
     let artifacts_path = artifacts_path.join(yyyymmdd_hhmmss.to_string());
 
+    if matches!(args.algorithm, Algorithm::Ppo | Algorithm::R2d2) {
+        ensure!(
+            evaluation.is_none(),
+            "{:?} does not support --eval-every-episodes or --eval-every-steps",
+            args.algorithm
+        );
+        ensure!(
+            !args.has_schedule(),
+            "{:?} does not support the training schedule flags",
+            args.algorithm
+        );
+    }
+
+    if args.algorithm == Algorithm::Ppo {
+        let agent = ProximalPolicyOptimizationAgent::new(
+            ActorCriticModel::<AutodiffBackend>::new(
+                &device,
+                envs.observation_space(),
+                envs.action_space(),
+            ),
+            AdamConfig::new().init(),
+            ConstantLr::new(0.0003),
+            envs.observation_space().clone(),
+            envs.action_space().clone(),
+            device,
+            ProximalPolicyOptimizationAgentConfig::new(),
+        );
+        let mut agent = prepare_agent(agent, &observation_normalizer, &args)?;
+
+        let trainer = RolloutTrainer::new(
+            10000,
+            args.bellman_gamma,
+            0.95,
+            2048 / args.num_envs,
+            10,
+            args.batch_size,
+            args.reward_mapping(),
+            artifacts_path,
+            true,
+        )?
+        .with_training_state(args.training_state()?)
+        .with_seed(args.seed);
+
+        trainer.vec_train_loop(&mut agent, envs)?;
+
+        return Ok(());
+    }
+
+    if args.algorithm == Algorithm::Sac {
+        let observation_space = envs.observation_space().clone();
+        let action_space = envs.action_space().clone();
+        let agent = SoftActorCriticAgent::new(
+            GaussianPolicyModel::<AutodiffBackend>::new(&device, &observation_space, &action_space),
+            ActionValueModel::<AutodiffBackend>::new(&device, &observation_space, &action_space),
+            ActionValueModel::<AutodiffBackend>::new(&device, &observation_space, &action_space),
+            AdamConfig::new().init(),
+            AdamConfig::new().init(),
+            ConstantLr::new(0.0003),
+            observation_space,
+            action_space,
+            device,
+            SoftActorCriticAgentConfig::new(args.n_step),
+        )?;
+
+        return train_replay::<D, E, DeepQNetworkState, _>(
+            agent,
+            envs,
+            observation_normalizer,
+            None,
+            evaluation,
+            artifacts_path,
+            &args,
+        );
+    }
+
+    if args.algorithm == Algorithm::R2d2 {
+        let agent = RecurrentDeepQNetworkAgent::new(
+            RecurrentDeepQNetworkModel::<AutodiffBackend>::new(
+                &device,
+                envs.observation_space(),
+                envs.action_space(),
+                &args.torso_config::<D>()?,
+                &args.head_config(),
+                args.recurrent_cell,
+                128,
+                args.dueling,
+                args.noisy,
+            )?,
+            AdamConfig::new()
+                .with_epsilon(0.01 / args.batch_size as f32)
+                .init(),
+            ConstantLr::new(0.0001),
+            envs.observation_space().clone(),
+            envs.action_space().clone(),
+            device,
+            RecurrentDeepQNetworkAgentConfig::new(
+                2500,
+                args.n_step,
+                args.double_dqn,
+                args.loss_function,
+            ),
+        )?;
+        let mut agent = prepare_agent(agent, &observation_normalizer, &args)?;
+
+        // an alpha of 0 samples uniformly
+        let mut replay_config = args.prioritized_replay_config();
+        if !args.prioritized {
+            replay_config = replay_config.with_alpha(0.0);
+        }
+        // the sequences hold the recurrent states too, they go to disk unless asked otherwise
+        let storage_backend = match args.replay_storage {
+            Some(ReplayStorage::InMemory) => StorageBackend::InMemory,
+            _ => StorageBackend::RocksDb,
+        };
+        let mut memory = SequenceReplayMemory::new(
+            2usize.pow(16),
+            args.batch_size,
+            args.sequence_length,
+            replay_config,
+            storage_backend,
+        )?;
+
+        let trainer = SequenceReplayTrainer::new(
+            10000,
+            args.bellman_gamma,
+            args.sequence_length,
+            args.burn_in,
+            args.sequence_length.saturating_sub(args.burn_in),
+            args.reward_mapping(),
+            artifacts_path,
+            true,
+        )?
+        .with_training_state(args.training_state()?)
+        .with_seed(args.seed);
+
+        trainer.vec_train_loop(&mut agent, envs, &mut memory, &args.random_policy())?;
+
+        return Ok(());
+    }
+
     let output_layer_config = args.distributional.output_layer_config(&args.env_name);
 
+    let observation_space = T::observation_space(envs.observation_space());
     let model = DeepQNetworkModel::<AutodiffBackend>::new(
         &device,
-        env.observation_space(),
-        env.action_space(),
+        &observation_space,
+        envs.action_space(),
+        &args.torso_config::<D>()?,
+        &args.head_config(),
         args.dueling,
         args.noisy,
         output_layer_config.clone(),
-    );
+    )?;
 
     let optimizer = AdamConfig::new()
         .with_epsilon(0.01 / args.batch_size as f32)
         .init();
-
-    let random_policy = if !args.noisy {
-        Some(RandomPolicy::new(1.0, 0.01, 0.99))
-    } else {
-        None
-    };
+    let lr_scheduler = ConstantLr::new(0.00025);
+    let action_space = envs.action_space().clone();
+    let random_policy = args.random_policy();
 
     match output_layer_config {
-        OutputLayerConfig::Expectation => {
-            let mut agent = DeepQNetworkAgent::new(
+        OutputLayerConfig::Expectation => train_replay::<D, E, T, _>(
+            DeepQNetworkAgent::new(
                 model,
                 optimizer,
-                ConstantLr::new(0.00025),
-                env.observation_space().clone(),
-                *env.action_space(),
+                lr_scheduler,
+                observation_space,
+                action_space,
                 device,
                 DeepQNetworkAgentConfig::new(
-                    1000,
+                    args.teacher_update_freq(1000),
                     args.n_step,
                     args.double_dqn,
                     args.loss_function,
                 ),
-            );
-
-            if let Some(restore_path) = &args.restore_path {
-                agent.load(restore_path).with_context(|| "load agent")?;
-            }
-
-            if args.prioritized {
-                let mut memory =
-                    PrioritizedReplayMemory::new(2usize.pow(20), args.batch_size, 0.6)?;
-
-                let trainer = PrioritizedReplayTrainer::new(
-                    10000,
-                    args.bellman_gamma,
-                    args.n_step,
-                    match args.revenue_mapping {
-                        RevenueMapping::Identity => RewardMapping::Identity,
-                        RevenueMapping::Clip => RewardMapping::Clip {
-                            min: -1.0,
-                            max: 1.0,
-                        },
-                        RevenueMapping::Rescaling => RewardMapping::Rescaling { epsilon: 0.001 },
-                        RevenueMapping::SymLog => RewardMapping::SymLog,
-                    },
-                    artifacts_path,
-                    true,
-                )?;
-
-                trainer.train_loop(&mut agent, env, &mut memory, &random_policy)?;
-            } else {
-                let mut memory = UniformReplayMemory::new(2usize.pow(20), args.batch_size)?;
-
-                let trainer = UniformReplayTrainer::new(
-                    10000,
-                    args.bellman_gamma,
-                    args.n_step,
-                    match args.revenue_mapping {
-                        RevenueMapping::Identity => RewardMapping::Identity,
-                        RevenueMapping::Clip => RewardMapping::Clip {
-                            min: -1.0,
-                            max: 1.0,
-                        },
-                        RevenueMapping::Rescaling => RewardMapping::Rescaling { epsilon: 0.001 },
-                        RevenueMapping::SymLog => RewardMapping::SymLog,
-                    },
-                    artifacts_path,
-                    true,
-                )?;
-
-                trainer.train_loop(&mut agent, env, &mut memory, &random_policy)?;
-            }
-        }
+            )?,
+            envs,
+            observation_normalizer,
+            random_policy,
+            evaluation,
+            artifacts_path,
+            &args,
+        ),
         OutputLayerConfig::CategoricalDistribution {
             min_value,
             max_value,
             ..
-        } => {
-            let mut agent = CategoricalDeepQNetworkAgent::new(
+        } => train_replay::<D, E, T, _>(
+            CategoricalDeepQNetworkAgent::new(
                 model,
                 optimizer,
-                ConstantLr::new(0.00025),
-                env.observation_space().clone(),
-                *env.action_space(),
+                lr_scheduler,
+                observation_space,
+                action_space,
                 device,
                 CategoricalDeepQNetworkAgentConfig::new(
-                    10000,
+                    args.teacher_update_freq(10000),
                     args.n_step,
                     args.double_dqn,
                     min_value,
                     max_value,
                     args.loss_function,
-                ),
-            );
-
-            if let Some(restore_path) = &args.restore_path {
-                agent.load(restore_path).with_context(|| "load agent")?;
-            }
-
-            if args.prioritized {
-                let mut memory =
-                    PrioritizedReplayMemory::new(2usize.pow(20), args.batch_size, 0.6)?;
-
-                let trainer = PrioritizedReplayTrainer::new(
-                    10000,
-                    args.bellman_gamma,
-                    args.n_step,
-                    match args.revenue_mapping {
-                        RevenueMapping::Identity => RewardMapping::Identity,
-                        RevenueMapping::Clip => RewardMapping::Clip {
-                            min: -1.0,
-                            max: 1.0,
-                        },
-                        RevenueMapping::Rescaling => RewardMapping::Rescaling { epsilon: 0.001 },
-                        RevenueMapping::SymLog => RewardMapping::SymLog,
-                    },
-                    artifacts_path,
-                    true,
-                )?;
-
-                trainer.train_loop(&mut agent, env, &mut memory, &random_policy)?;
-            } else {
-                let mut memory = UniformReplayMemory::new(2usize.pow(20), args.batch_size)?;
-
-                let trainer = UniformReplayTrainer::new(
-                    10000,
-                    args.bellman_gamma,
-                    args.n_step,
-                    match args.revenue_mapping {
-                        RevenueMapping::Identity => RewardMapping::Identity,
-                        RevenueMapping::Clip => RewardMapping::Clip {
-                            min: -1.0,
-                            max: 1.0,
-                        },
-                        RevenueMapping::Rescaling => RewardMapping::Rescaling { epsilon: 0.001 },
-                        RevenueMapping::SymLog => RewardMapping::SymLog,
-                    },
-                    artifacts_path,
-                    true,
-                )?;
-
-                trainer.train_loop(&mut agent, env, &mut memory, &random_policy)?;
-            }
-        }
-        OutputLayerConfig::QuantileRegression { .. } => {
-            let mut agent = QuantileRegressionAgent::new(
+                )
+                .with_risk_measure(args.risk_measure.risk_measure(args.risk_parameter)?),
+            )?,
+            envs,
+            observation_normalizer,
+            random_policy,
+            evaluation,
+            artifacts_path,
+            &args,
+        ),
+        OutputLayerConfig::QuantileRegression { .. } => train_replay::<D, E, T, _>(
+            QuantileRegressionAgent::new(
                 model,
                 optimizer,
-                ConstantLr::new(0.00025),
-                env.observation_space().clone(),
-                *env.action_space(),
+                lr_scheduler,
+                observation_space,
+                action_space,
                 device,
                 QuantileRegressionAgentConfig::new(
-                    1000,
+                    args.teacher_update_freq(1000),
                     args.n_step,
                     args.double_dqn,
                     args.loss_function,
-                ),
-            );
-
-            if let Some(restore_path) = &args.restore_path {
-                agent.load(restore_path).with_context(|| "load agent")?;
-            }
-
-            if args.prioritized {
-                let mut memory =
-                    PrioritizedReplayMemory::new(2usize.pow(20), args.batch_size, 0.6)?;
-
-                let trainer = PrioritizedReplayTrainer::new(
-                    10000,
-                    args.bellman_gamma,
+                )
+                .with_risk_measure(args.risk_measure.risk_measure(args.risk_parameter)?),
+            )?,
+            envs,
+            observation_normalizer,
+            random_policy,
+            evaluation,
+            artifacts_path,
+            &args,
+        ),
+        OutputLayerConfig::ImplicitQuantile { .. } => train_replay::<D, E, T, _>(
+            ImplicitQuantileAgent::new(
+                model,
+                optimizer,
+                lr_scheduler,
+                observation_space,
+                action_space,
+                device,
+                ImplicitQuantileAgentConfig::new(
+                    args.teacher_update_freq(1000),
                     args.n_step,
-                    match args.revenue_mapping {
-                        RevenueMapping::Identity => RewardMapping::Identity,
-                        RevenueMapping::Clip => RewardMapping::Clip {
-                            min: -1.0,
-                            max: 1.0,
-                        },
-                        RevenueMapping::Rescaling => RewardMapping::Rescaling { epsilon: 0.001 },
-                        RevenueMapping::SymLog => RewardMapping::SymLog,
-                    },
-                    artifacts_path,
-                    true,
-                )?;
-
-                trainer.train_loop(&mut agent, env, &mut memory, &random_policy)?;
-            } else {
-                let mut memory = UniformReplayMemory::new(2usize.pow(20), args.batch_size)?;
-
-                let trainer = UniformReplayTrainer::new(
-                    10000,
-                    args.bellman_gamma,
+                    args.double_dqn,
+                    args.loss_function,
+                )
+                .with_risk_measure(args.risk_measure.risk_measure(args.risk_parameter)?),
+            )?,
+            envs,
+            observation_normalizer,
+            random_policy,
+            evaluation,
+            artifacts_path,
+            &args,
+        ),
+        OutputLayerConfig::FullyParameterizedQuantile { .. } => train_replay::<D, E, T, _>(
+            FullyParameterizedQuantileAgent::new(
+                model,
+                optimizer,
+                lr_scheduler,
+                observation_space,
+                action_space,
+                device,
+                FullyParameterizedQuantileAgentConfig::new(
+                    args.teacher_update_freq(1000),
                     args.n_step,
-                    match args.revenue_mapping {
-                        RevenueMapping::Identity => RewardMapping::Identity,
-                        RevenueMapping::Clip => RewardMapping::Clip {
-                            min: -1.0,
-                            max: 1.0,
-                        },
-                        RevenueMapping::Rescaling => RewardMapping::Rescaling { epsilon: 0.001 },
-                        RevenueMapping::SymLog => RewardMapping::SymLog,
-                    },
-                    artifacts_path,
-                    true,
-                )?;
+                    args.double_dqn,
+                    args.loss_function,
+                )
+                .with_risk_measure(args.risk_measure.risk_measure(args.risk_parameter)?),
+            )?,
+            envs,
+            observation_normalizer,
+            random_policy,
+            evaluation,
+            artifacts_path,
+            &args,
+        ),
+    }
+}
 
-                trainer.train_loop(&mut agent, env, &mut memory, &random_policy)?;
-            }
-        }
+// the observation normalizer, noise seed and restored checkpoint shared by all agents
+fn prepare_agent<S: State, A: Agent<S> + ObservationNormalized>(
+    mut agent: A,
+    observation_normalizer: &Option<ObservationNormalizer>,
+    args: &Args,
+) -> anyhow::Result<A> {
+    if let Some(observation_normalizer) = observation_normalizer {
+        agent = agent.with_observation_normalizer(observation_normalizer.clone());
+    }
+    if let Some(noise_seed) = args.noise_seed {
+        agent.set_noise_seed(noise_seed);
     }
+    if let Some(restore_path) = &args.restore_path {
+        agent.load(restore_path).with_context(|| "load agent")?;
+    }
+    Ok(agent)
+}
 
-    Ok(())
+// trains an off-policy agent on a uniform or prioritized replay memory
+fn train_replay<
+    const D: usize,
+    E: Env<D>,
+    S: ObservationState + FlatState + Serialize + DeserializeOwned + Sync + 'static,
+    A: PrioritizedReplayAgent<S> + ObservationNormalized,
+>(
+    agent: A,
+    envs: &mut VecEnv<D, E>,
+    observation_normalizer: Option<ObservationNormalizer>,
+    random_policy: Option<RandomPolicy>,
+    mut evaluation: Option<Evaluation<'_, D>>,
+    artifacts_path: PathBuf,
+    args: &Args,
+) -> anyhow::Result<()> {
+    let mut agent = prepare_agent(agent, &observation_normalizer, args)?;
+    let observation_space = S::observation_space(envs.observation_space());
+    let replay_dir = args.replay_dir(&artifacts_path)?;
+    let storage_backend = args.storage_backend(&observation_space, replay_dir.as_deref());
+    let trainer = ReplayTrainer::new(
+        10000,
+        args.bellman_gamma,
+        args.n_step,
+        args.reward_mapping(),
+        artifacts_path,
+        true,
+    )?
+    .with_schedule(args.schedule())
+    .with_replay_checkpoint(replay_dir)
+    .with_training_state(args.training_state()?)
+    .with_seed(args.seed);
+
+    if args.prioritized {
+        let mut memory = PrioritizedReplayMemory::<S>::new(
+            2usize.pow(20),
+            args.batch_size,
+            args.prioritized_replay_config(),
+            storage_backend,
+        )?;
+        trainer.vec_prioritized_train_loop(
+            &mut agent,
+            envs,
+            &mut memory,
+            &random_policy,
+            &mut evaluation,
+        )
+    } else {
+        let mut memory =
+            UniformReplayMemory::<S>::new(2usize.pow(20), args.batch_size, storage_backend)?;
+        trainer.vec_train_loop(
+            &mut agent,
+            envs,
+            &mut memory,
+            &random_policy,
+            &mut evaluation,
+        )
+    }
 }
 
 fn main() -> anyhow::Result<()> {
@@ -346,10 +819,11 @@ fn main() -> anyhow::Result<()> {
             "CartPole-v1",
             "MountainCar-v0",
             "Acrobot-v1",
-            "Pendulum-v0",
+            "Pendulum-v1",
             "LunarLander-v2",
         ];
-        let env_3d = ["Breakout-v4"];
+        // the no frameskip variants, the wrappers skip the frames
+        let env_3d = ["BreakoutNoFrameskip-v4"];
 
         let super_mario_env = [
             "SuperMarioBros-v0",
@@ -360,19 +834,85 @@ fn main() -> anyhow::Result<()> {
 
         let args = Args::parse();
 
-        if super_mario_env.contains(&args.env_name.as_str()) {
-            let mut env = GymSuperMarioBrosEnv::new(py, &args.env_name, args.render)
+        if args.native && CLASSIC_CONTROL_ENV_NAMES.contains(&args.env_name.as_str()) {
+            let envs = (0..args.num_envs)
+                .map(|_| ClassicControlEnv::new(&args.env_name))
+                .collect::<anyhow::Result<Vec<_>>>()
+                .with_context(|| "create classic control env")?;
+            if args.normalize_observation {
+                let observation_normalizer = ObservationNormalizer::default();
+                let envs = envs
+                    .into_iter()
+                    .map(|env| env.map_observation(observation_normalizer.clone()))
+                    .collect();
+                let evaluation = args.evaluation(|| {
+                    Ok(ClassicControlEnv::new(&args.env_name)?
+                        .map_observation(observation_normalizer.frozen()))
+                })?;
+                run_stacked(
+                    &mut VecEnv::new(envs)?,
+                    Some(observation_normalizer),
+                    evaluation,
+                    args,
+                )?;
+            } else {
+                let evaluation = args.evaluation(|| ClassicControlEnv::new(&args.env_name))?;
+                run_stacked(&mut VecEnv::new(envs)?, None, evaluation, args)?;
+            }
+        } else if super_mario_env.contains(&args.env_name.as_str()) {
+            let envs = (0..args.num_envs)
+                .map(|_| {
+                    GymSuperMarioBrosEnv::new(py, &args.env_name, args.render)
+                        .and_then(|env| atari_wrappers(env, None))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()
                 .with_context(|| "create gymnasium env")?;
-            run(&mut env, args)?;
+            let evaluation = args.evaluation(|| {
+                GymSuperMarioBrosEnv::new(py, &args.env_name, false)
+                    .and_then(|env| atari_evaluation_wrappers(env, None))
+            })?;
+            run_stacked(&mut VecEnv::new(envs)?, None, evaluation, args)?;
         } else if env_1d.contains(&args.env_name.as_str()) {
-            let mut env = GymnasiumEnv1D::new(py, &args.env_name, args.render)
+            let envs = (0..args.num_envs)
+                .map(|_| GymnasiumEnv1D::new(py, &args.env_name, args.render))
+                .collect::<anyhow::Result<Vec<_>>>()
                 .with_context(|| "create gymnasium env")?;
-
-            run(&mut env, args)?;
+            if args.normalize_observation {
+                let observation_normalizer = ObservationNormalizer::default();
+                let envs = envs
+                    .into_iter()
+                    .map(|env| env.map_observation(observation_normalizer.clone()))
+                    .collect();
+                let evaluation = args.evaluation(|| {
+                    Ok(GymnasiumEnv1D::new(py, &args.env_name, false)?
+                        .map_observation(observation_normalizer.frozen()))
+                })?;
+                run_stacked(
+                    &mut VecEnv::new(envs)?,
+                    Some(observation_normalizer),
+                    evaluation,
+                    args,
+                )?;
+            } else {
+                let evaluation =
+                    args.evaluation(|| GymnasiumEnv1D::new(py, &args.env_name, false))?;
+                run_stacked(&mut VecEnv::new(envs)?, None, evaluation, args)?;
+            }
         } else if env_3d.contains(&args.env_name.as_str()) {
-            let mut env = GymnasiumEnv3D::new(py, &args.env_name, args.render)
+            let envs = (0..args.num_envs)
+                .map(|_| {
+                    let env = GymnasiumEnv3D::new(py, &args.env_name, args.render)?;
+                    let fire_action = env.fire_action()?;
+                    atari_wrappers(env, fire_action)
+                })
+                .collect::<anyhow::Result<Vec<_>>>()
                 .with_context(|| "create gymnasium env")?;
-            run(&mut env, args)?;
+            let evaluation = args.evaluation(|| {
+                let env = GymnasiumEnv3D::new(py, &args.env_name, false)?;
+                let fire_action = env.fire_action()?;
+                atari_evaluation_wrappers(env, fire_action)
+            })?;
+            run_stacked(&mut VecEnv::new(envs)?, None, evaluation, args)?;
         }
         Ok(())
     })?;
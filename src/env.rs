@@ -1,14 +1,73 @@
-use pyo3::{Py, PyAny, Python, ToPyObject as _};
+use std::collections::HashMap;
 
-use crate::Action;
+use anyhow::bail;
+use numpy::PyArray1;
+use pyo3::{
+    types::{PyAnyMethods as _, PyDict, PyDictMethods as _, PyTypeMethods as _},
+    Bound, Py, PyAny, Python, ToPyObject as _,
+};
+use serde_json::{json, Value};
 
+use crate::{Action, ActionSpace};
+
+pub mod atari;
+pub mod classic;
 pub mod gym_super_mario_bros;
 pub mod gymnasium;
+pub mod wrapper;
 
 impl Action {
     pub fn to_object(&self, py: Python) -> Py<PyAny> {
         match self {
             Action::Discrete(action) => action.to_object(py),
+            Action::Continuous(action) => PyArray1::from_vec_bound(py, action.clone())
+                .into_any()
+                .unbind(),
         }
     }
 }
+
+impl ActionSpace {
+    pub fn from_object(action_space: &Bound<PyAny>) -> anyhow::Result<Self> {
+        let action_type = action_space.get_type();
+        let name = action_type.name()?;
+        let action_space = match name.as_ref() {
+            "Discrete" => {
+                let n = action_space.getattr("n")?;
+                let action_space: i64 = n.extract()?;
+                ActionSpace::Discrete(action_space)
+            }
+            "Box" => {
+                let low = action_space.getattr("low")?;
+                let low: Vec<f32> = low.call_method("reshape", (-1,), None)?.extract()?;
+                let high = action_space.getattr("high")?;
+                let high: Vec<f32> = high.call_method("reshape", (-1,), None)?.extract()?;
+                ActionSpace::Box { low, high }
+            }
+            name => bail!("unsupported action space {}", name),
+        };
+        Ok(action_space)
+    }
+}
+
+// the scalar and string entries of a python info dict
+pub(crate) fn info_from_object(info: &Bound<PyAny>) -> HashMap<String, Value> {
+    let Ok(info) = info.downcast::<PyDict>() else {
+        return HashMap::new();
+    };
+    info.iter()
+        .filter_map(|(key, value)| {
+            let key = key.extract::<String>().ok()?;
+            let value = if let Ok(value) = value.extract::<bool>() {
+                json!(value)
+            } else if let Ok(value) = value.extract::<i64>() {
+                json!(value)
+            } else if let Ok(value) = value.extract::<f64>() {
+                json!(value)
+            } else {
+                json!(value.extract::<String>().ok()?)
+            };
+            Some((key, value))
+        })
+        .collect()
+}
@@ -0,0 +1,621 @@
+use anyhow::ensure;
+use rand::{rngs::StdRng, Rng as _, SeedableRng as _};
+
+use crate::{Action, ActionSpace, Env, ObservationSpace, StepResult};
+
+use super::wrapper::{EnvExt as _, ObservationEnv, ObservationWrapper};
+
+// the wrappers expect observations of shape [1, channel, height, width] flattened channel first
+
+// the standard DQN preprocessing, `fire_action` for the games that wait for FIRE after a reset
+#[allow(clippy::type_complexity)]
+pub fn atari_wrappers<E: Env<4>>(
+    env: E,
+    fire_action: Option<Action>,
+) -> anyhow::Result<FrameEnv<FireReset<EpisodicLife<MaxAndSkip<NoopReset<E>>>>>> {
+    let env = NoopReset::new(env, 30);
+    let env = MaxAndSkip::new(env, 4);
+    let env = EpisodicLife::new(env);
+    let env = FireReset::new(env, fire_action);
+    frame_wrappers(env)
+}
+
+// the same preprocessing without ending the episodes on a life loss, the returns are per game
+#[allow(clippy::type_complexity)]
+pub fn atari_evaluation_wrappers<E: Env<4>>(
+    env: E,
+    fire_action: Option<Action>,
+) -> anyhow::Result<FrameEnv<FireReset<FireLifeLoss<MaxAndSkip<NoopReset<E>>>>>> {
+    let env = NoopReset::new(env, 30);
+    let env = MaxAndSkip::new(env, 4);
+    let env = FireLifeLoss::new(env, fire_action.clone());
+    let env = FireReset::new(env, fire_action);
+    frame_wrappers(env)
+}
+
+pub type FrameEnv<E> = ObservationEnv<4, 4, ObservationEnv<4, 4, E, GrayScale>, Resize>;
+
+// 84x84 grayscale frames
+fn frame_wrappers<E: Env<4>>(env: E) -> anyhow::Result<FrameEnv<E>> {
+    let gray_scale = GrayScale::new(env.observation_space())?;
+    let env = env.map_observation(gray_scale);
+    let resize = Resize::new(env.observation_space(), 84, 84)?;
+    Ok(env.map_observation(resize))
+}
+
+pub struct GrayScale;
+
+impl GrayScale {
+    pub fn new(observation_space: &ObservationSpace<4>) -> anyhow::Result<Self> {
+        let channel = observation_space.shape()[1];
+        ensure!(
+            channel == 3,
+            "gray scale expects RGB observations, got {} channels",
+            channel
+        );
+        Ok(Self)
+    }
+}
+
+impl ObservationWrapper<4, 4> for GrayScale {
+    fn observation_space(&self, observation_space: &ObservationSpace<4>) -> ObservationSpace<4> {
+        let [batch, _, height, width] = *observation_space.shape();
+        // the weights are positive, the bounds map like the pixels
+        ObservationSpace::Box {
+            shape: [batch, 1, height, width],
+            low: gray_scale(observation_space.low()),
+            high: gray_scale(observation_space.high()),
+        }
+    }
+
+    fn observation(&mut self, observation: Vec<f32>) -> Vec<f32> {
+        gray_scale(&observation)
+    }
+}
+
+fn gray_scale(observation: &[f32]) -> Vec<f32> {
+    let size = observation.len() / 3;
+    let (red, rest) = observation.split_at(size);
+    let (green, blue) = rest.split_at(size);
+    red.iter()
+        .zip(green)
+        .zip(blue)
+        .map(|((r, g), b)| 0.299 * r + 0.587 * g + 0.114 * b)
+        .collect()
+}
+
+// area interpolation, each output pixel averages the input pixels it covers
+pub struct Resize {
+    height: usize,
+    width: usize,
+    input_height: usize,
+    input_width: usize,
+    row_weights: Vec<Vec<(usize, f32)>>,
+    column_weights: Vec<Vec<(usize, f32)>>,
+}
+
+impl Resize {
+    pub fn new(
+        observation_space: &ObservationSpace<4>,
+        height: usize,
+        width: usize,
+    ) -> anyhow::Result<Self> {
+        let [_, _, input_height, input_width] = *observation_space.shape();
+        ensure!(
+            height > 0 && width > 0,
+            "resize to {}x{} is empty",
+            height,
+            width
+        );
+        Ok(Self {
+            height,
+            width,
+            input_height,
+            input_width,
+            row_weights: area_weights(input_height, height),
+            column_weights: area_weights(input_width, width),
+        })
+    }
+}
+
+impl ObservationWrapper<4, 4> for Resize {
+    fn observation_space(&self, observation_space: &ObservationSpace<4>) -> ObservationSpace<4> {
+        let [batch, channel, _, _] = *observation_space.shape();
+        ObservationSpace::Box {
+            shape: [batch, channel, self.height, self.width],
+            low: self.resize(observation_space.low()),
+            high: self.resize(observation_space.high()),
+        }
+    }
+
+    fn observation(&mut self, observation: Vec<f32>) -> Vec<f32> {
+        self.resize(&observation)
+    }
+}
+
+impl Resize {
+    fn resize(&self, observation: &[f32]) -> Vec<f32> {
+        let input_size = self.input_height * self.input_width;
+        let mut resized =
+            Vec::with_capacity(observation.len() / input_size * self.height * self.width);
+        for input in observation.chunks(input_size) {
+            for rows in self.row_weights.iter() {
+                for columns in self.column_weights.iter() {
+                    let mut value = 0.0;
+                    for (row, row_weight) in rows {
+                        let input = &input[row * self.input_width..];
+                        for (column, column_weight) in columns {
+                            value += row_weight * column_weight * input[*column];
+                        }
+                    }
+                    resized.push(value);
+                }
+            }
+        }
+        resized
+    }
+}
+
+// weights of the input pixels overlapped by each output pixel
+fn area_weights(input: usize, output: usize) -> Vec<Vec<(usize, f32)>> {
+    let scale = input as f32 / output as f32;
+    (0..output)
+        .map(|i| {
+            let start = i as f32 * scale;
+            let end = start + scale;
+            (start.floor() as usize..(end.ceil() as usize).min(input))
+                .map(|j| {
+                    let overlap = end.min(j as f32 + 1.0) - start.max(j as f32);
+                    (j, overlap.max(0.0) / scale)
+                })
+                .collect()
+        })
+        .collect()
+}
+
+// repeats the action `skip` times, sums the rewards and returns the pixel-wise max of the last
+// two frames to remove the flickering of the sprites
+pub struct MaxAndSkip<E: Env<4>> {
+    env: E,
+    skip: usize,
+}
+
+impl<E: Env<4>> MaxAndSkip<E> {
+    pub fn new(env: E, skip: usize) -> Self {
+        Self {
+            env,
+            skip: skip.max(1),
+        }
+    }
+}
+
+impl<E: Env<4>> Env<4> for MaxAndSkip<E> {
+    fn action_space(&self) -> &ActionSpace {
+        self.env.action_space()
+    }
+
+    fn observation_space(&self) -> &ObservationSpace<4> {
+        self.env.observation_space()
+    }
+
+    fn reset(&mut self) -> anyhow::Result<Vec<f32>> {
+        self.env.reset()
+    }
+
+    fn step(&mut self, action: &Action) -> anyhow::Result<StepResult> {
+        let mut total_reward = 0.0;
+        let mut previous = None;
+        let mut result = self.env.step(action)?;
+        for _ in 1..self.skip {
+            if result.is_done() {
+                break;
+            }
+            total_reward += result.reward;
+            let next_result = self.env.step(action)?;
+            previous = Some(std::mem::replace(&mut result, next_result).observation);
+        }
+        result.reward += total_reward;
+        if let Some(previous) = previous {
+            result.observation = previous
+                .into_iter()
+                .zip(result.observation)
+                .map(|(p, l)| p.max(l))
+                .collect();
+        }
+        Ok(result)
+    }
+
+    fn render(&self) -> anyhow::Result<()> {
+        self.env.render()
+    }
+
+    fn lives(&self) -> Option<usize> {
+        self.env.lives()
+    }
+}
+
+// starts the episodes after a random number of no-op actions in 1..=noop_max
+pub struct NoopReset<E: Env<4>> {
+    env: E,
+    noop_max: usize,
+    rng: StdRng,
+}
+
+impl<E: Env<4>> NoopReset<E> {
+    pub fn new(env: E, noop_max: usize) -> Self {
+        Self::with_rng(env, noop_max, StdRng::from_entropy())
+    }
+
+    pub fn with_seed(env: E, noop_max: usize, seed: u64) -> Self {
+        Self::with_rng(env, noop_max, StdRng::seed_from_u64(seed))
+    }
+
+    fn with_rng(env: E, noop_max: usize, rng: StdRng) -> Self {
+        Self { env, noop_max, rng }
+    }
+}
+
+impl<E: Env<4>> Env<4> for NoopReset<E> {
+    fn action_space(&self) -> &ActionSpace {
+        self.env.action_space()
+    }
+
+    fn observation_space(&self) -> &ObservationSpace<4> {
+        self.env.observation_space()
+    }
+
+    fn reset(&mut self) -> anyhow::Result<Vec<f32>> {
+        let mut observation = self.env.reset()?;
+        if self.noop_max == 0 {
+            return Ok(observation);
+        }
+        let noops = self.rng.gen_range(1..=self.noop_max);
+        for _ in 0..noops {
+            let result = self.env.step(&Action::Discrete(0))?;
+            observation = if result.is_done() {
+                self.env.reset()?
+            } else {
+                result.observation
+            };
+        }
+        Ok(observation)
+    }
+
+    fn step(&mut self, action: &Action) -> anyhow::Result<StepResult> {
+        self.env.step(action)
+    }
+
+    fn render(&self) -> anyhow::Result<()> {
+        self.env.render()
+    }
+
+    fn lives(&self) -> Option<usize> {
+        self.env.lives()
+    }
+}
+
+// presses FIRE after each reset, resets are passed through without a `fire_action`
+pub struct FireReset<E: Env<4>> {
+    env: E,
+    fire_action: Option<Action>,
+}
+
+impl<E: Env<4>> FireReset<E> {
+    pub fn new(env: E, fire_action: Option<Action>) -> Self {
+        Self { env, fire_action }
+    }
+}
+
+impl<E: Env<4>> Env<4> for FireReset<E> {
+    fn action_space(&self) -> &ActionSpace {
+        self.env.action_space()
+    }
+
+    fn observation_space(&self) -> &ObservationSpace<4> {
+        self.env.observation_space()
+    }
+
+    fn reset(&mut self) -> anyhow::Result<Vec<f32>> {
+        let observation = self.env.reset()?;
+        let Some(fire_action) = &self.fire_action else {
+            return Ok(observation);
+        };
+        let result = self.env.step(fire_action)?;
+        if result.is_done() {
+            self.env.reset()
+        } else {
+            Ok(result.observation)
+        }
+    }
+
+    fn step(&mut self, action: &Action) -> anyhow::Result<StepResult> {
+        self.env.step(action)
+    }
+
+    fn render(&self) -> anyhow::Result<()> {
+        self.env.render()
+    }
+
+    fn lives(&self) -> Option<usize> {
+        self.env.lives()
+    }
+}
+
+// presses FIRE after each life loss, for the games played across lives
+pub struct FireLifeLoss<E: Env<4>> {
+    env: E,
+    fire_action: Option<Action>,
+    lives: usize,
+}
+
+impl<E: Env<4>> FireLifeLoss<E> {
+    pub fn new(env: E, fire_action: Option<Action>) -> Self {
+        Self {
+            env,
+            fire_action,
+            lives: 0,
+        }
+    }
+}
+
+impl<E: Env<4>> Env<4> for FireLifeLoss<E> {
+    fn action_space(&self) -> &ActionSpace {
+        self.env.action_space()
+    }
+
+    fn observation_space(&self) -> &ObservationSpace<4> {
+        self.env.observation_space()
+    }
+
+    fn reset(&mut self) -> anyhow::Result<Vec<f32>> {
+        let observation = self.env.reset()?;
+        self.lives = self.env.lives().unwrap_or_default();
+        Ok(observation)
+    }
+
+    fn step(&mut self, action: &Action) -> anyhow::Result<StepResult> {
+        let mut result = self.env.step(action)?;
+        let lives = self.env.lives().unwrap_or_default();
+        let life_lost = 0 < lives && lives < self.lives;
+        self.lives = lives;
+        if let Some(fire_action) = self.fire_action.as_ref().filter(|_| life_lost) {
+            if !result.is_done() {
+                let reward = result.reward;
+                result = self.env.step(fire_action)?;
+                result.reward += reward;
+                self.lives = self.env.lives().unwrap_or_default();
+            }
+        }
+        Ok(result)
+    }
+
+    fn render(&self) -> anyhow::Result<()> {
+        self.env.render()
+    }
+
+    fn lives(&self) -> Option<usize> {
+        self.env.lives()
+    }
+}
+
+// ends the episodes on each life loss but only resets the game once it is over
+pub struct EpisodicLife<E: Env<4>> {
+    env: E,
+    lives: usize,
+    game_over: bool,
+}
+
+impl<E: Env<4>> EpisodicLife<E> {
+    pub fn new(env: E) -> Self {
+        Self {
+            env,
+            lives: 0,
+            game_over: true,
+        }
+    }
+}
+
+impl<E: Env<4>> Env<4> for EpisodicLife<E> {
+    fn action_space(&self) -> &ActionSpace {
+        self.env.action_space()
+    }
+
+    fn observation_space(&self) -> &ObservationSpace<4> {
+        self.env.observation_space()
+    }
+
+    fn reset(&mut self) -> anyhow::Result<Vec<f32>> {
+        let observation = if self.game_over {
+            self.env.reset()?
+        } else {
+            // continue the game from the lost life with a no-op
+            let result = self.env.step(&Action::Discrete(0))?;
+            if result.is_done() {
+                self.env.reset()?
+            } else {
+                result.observation
+            }
+        };
+        self.game_over = false;
+        self.lives = self.env.lives().unwrap_or_default();
+        Ok(observation)
+    }
+
+    // a life loss terminates the episode
+    fn step(&mut self, action: &Action) -> anyhow::Result<StepResult> {
+        let mut result = self.env.step(action)?;
+        self.game_over = result.is_done();
+        let lives = self.env.lives().unwrap_or_default();
+        let life_lost = 0 < lives && lives < self.lives;
+        self.lives = lives;
+        result.terminated |= life_lost;
+        Ok(result)
+    }
+
+    fn render(&self) -> anyhow::Result<()> {
+        self.env.render()
+    }
+
+    fn lives(&self) -> Option<usize> {
+        self.env.lives()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // frames filled with the step count, a life is lost every 3 steps and the game is over
+    // after 3 lives
+    struct CountingEnv {
+        steps: usize,
+        resets: usize,
+        actions: Vec<Action>,
+        action_space: ActionSpace,
+        observation_space: ObservationSpace<4>,
+    }
+
+    impl CountingEnv {
+        fn new(channel: usize, height: usize, width: usize) -> Self {
+            Self {
+                steps: 0,
+                resets: 0,
+                actions: Vec::new(),
+                action_space: ActionSpace::Discrete(4),
+                observation_space: ObservationSpace::bounded(
+                    [1, channel, height, width],
+                    0.0,
+                    255.0,
+                ),
+            }
+        }
+
+        fn observation(&self) -> Vec<f32> {
+            vec![self.steps as f32; self.observation_space.shape()[1..].iter().product()]
+        }
+    }
+
+    impl Env<4> for CountingEnv {
+        fn action_space(&self) -> &ActionSpace {
+            &self.action_space
+        }
+
+        fn observation_space(&self) -> &ObservationSpace<4> {
+            &self.observation_space
+        }
+
+        fn reset(&mut self) -> anyhow::Result<Vec<f32>> {
+            self.steps = 0;
+            self.resets += 1;
+            Ok(self.observation())
+        }
+
+        fn step(&mut self, action: &Action) -> anyhow::Result<StepResult> {
+            self.steps += 1;
+            self.actions.push(action.clone());
+            Ok(StepResult::new(
+                self.observation(),
+                1.0,
+                self.steps >= 9,
+                false,
+            ))
+        }
+
+        fn render(&self) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn lives(&self) -> Option<usize> {
+            Some(3usize.saturating_sub(self.steps / 3))
+        }
+    }
+
+    #[test]
+    fn test_atari_wrappers() -> anyhow::Result<()> {
+        let env = CountingEnv::new(3, 210, 160);
+        let gray_scale = GrayScale::new(env.observation_space())?;
+        let env = env.map_observation(gray_scale);
+        let resize = Resize::new(env.observation_space(), 84, 84)?;
+        let mut env = env.map_observation(resize);
+        assert_eq!(env.observation_space().shape(), &[1, 1, 84, 84]);
+        let result = env.step(&Action::Discrete(0))?;
+        assert_eq!(result.observation.len(), 84 * 84);
+        for value in result.observation {
+            approx::assert_abs_diff_eq!(value, 1.0, epsilon = 1e-4);
+        }
+
+        assert!(GrayScale::new(CountingEnv::new(1, 2, 2).observation_space()).is_err());
+        // a red and a green pixel
+        assert_eq!(
+            GrayScale.observation(vec![255.0, 0.0, 0.0, 255.0, 0.0, 0.0]),
+            vec![0.299 * 255.0, 0.587 * 255.0]
+        );
+
+        let mut resize = Resize::new(CountingEnv::new(1, 4, 4).observation_space(), 2, 2)?;
+        let observation = resize.observation((0..16).map(|v| v as f32).collect());
+        assert_eq!(observation, vec![2.5, 4.5, 10.5, 12.5]);
+
+        let mut env = MaxAndSkip::new(CountingEnv::new(1, 2, 2), 4);
+        let result = env.step(&Action::Discrete(2))?;
+        assert_eq!(result.observation, vec![4.0; 4]);
+        assert_eq!((result.reward, result.terminated), (4.0, false));
+        env.step(&Action::Discrete(2))?;
+        // stops at the end of the game
+        let result = env.step(&Action::Discrete(2))?;
+        assert_eq!(result.observation, vec![9.0; 4]);
+        assert_eq!((result.reward, result.terminated), (1.0, true));
+
+        let mut env = NoopReset::with_seed(CountingEnv::new(1, 2, 2), 5, 0);
+        for _ in 0..10 {
+            let observation = env.reset()?;
+            assert!((1.0..=5.0).contains(&observation[0]));
+            assert_eq!(env.env.steps as f32, observation[0]);
+        }
+        assert!(env.env.actions.iter().all(|a| *a == Action::Discrete(0)));
+
+        let mut env = FireReset::new(CountingEnv::new(1, 2, 2), Some(Action::Discrete(1)));
+        assert_eq!(env.reset()?, vec![1.0; 4]);
+        assert_eq!(env.env.actions, vec![Action::Discrete(1)]);
+        let mut env = FireReset::new(CountingEnv::new(1, 2, 2), None);
+        assert_eq!(env.reset()?, vec![0.0; 4]);
+
+        let mut env = FireLifeLoss::new(CountingEnv::new(1, 2, 2), Some(Action::Discrete(1)));
+        env.reset()?;
+        for _ in 0..3 {
+            env.step(&Action::Discrete(2))?;
+        }
+        // the third step loses a life, FIRE follows it
+        assert_eq!(env.env.actions.last(), Some(&Action::Discrete(1)));
+        assert_eq!(env.env.steps, 4);
+
+        let mut env = EpisodicLife::new(CountingEnv::new(1, 2, 2));
+        env.reset()?;
+        let dones = (0..3)
+            .map(|_| {
+                env.step(&Action::Discrete(2))
+                    .map(|result| result.terminated)
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        assert_eq!(dones, vec![false, false, true]);
+        // the game goes on after a life loss
+        assert_eq!(env.reset()?, vec![4.0; 4]);
+        assert_eq!(env.env.resets, 1);
+        for _ in 0..5 {
+            env.step(&Action::Discrete(2))?;
+        }
+        env.reset()?;
+        assert_eq!(env.env.resets, 2);
+
+        let mut env = atari_wrappers(CountingEnv::new(3, 210, 160), Some(Action::Discrete(1)))?;
+        assert_eq!(env.observation_space().shape(), &[1, 1, 84, 84]);
+        assert_eq!(env.reset()?.len(), 84 * 84);
+
+        // the evaluation plays until the game is over
+        let mut env =
+            atari_evaluation_wrappers(CountingEnv::new(3, 210, 160), Some(Action::Discrete(1)))?;
+        env.reset()?;
+        while !env.step(&Action::Discrete(2))?.is_done() {}
+        assert_eq!(env.lives(), Some(0));
+        Ok(())
+    }
+}
@@ -0,0 +1,206 @@
+use anyhow::anyhow;
+
+use crate::{Action, ActionSpace, Env, ObservationSpace, StepResult};
+
+pub mod acrobot;
+pub mod cart_pole;
+pub mod mountain_car;
+pub mod pendulum;
+
+pub use acrobot::Acrobot;
+pub use cart_pole::CartPole;
+pub use mountain_car::MountainCar;
+pub use pendulum::Pendulum;
+
+pub const CLASSIC_CONTROL_ENV_NAMES: [&str; 4] =
+    ["CartPole-v1", "MountainCar-v0", "Acrobot-v1", "Pendulum-v1"];
+
+pub enum ClassicControlEnv {
+    CartPole(CartPole),
+    MountainCar(MountainCar),
+    Acrobot(Acrobot),
+    Pendulum(Pendulum),
+}
+
+impl ClassicControlEnv {
+    pub fn new(env_name: &str) -> anyhow::Result<Self> {
+        match env_name {
+            "CartPole-v1" => Ok(Self::CartPole(CartPole::new())),
+            "MountainCar-v0" => Ok(Self::MountainCar(MountainCar::new())),
+            "Acrobot-v1" => Ok(Self::Acrobot(Acrobot::new())),
+            "Pendulum-v1" => Ok(Self::Pendulum(Pendulum::new())),
+            _ => Err(anyhow!("unsupported classic control env: {}", env_name)),
+        }
+    }
+}
+
+impl Env<2> for ClassicControlEnv {
+    fn action_space(&self) -> &ActionSpace {
+        match self {
+            Self::CartPole(env) => env.action_space(),
+            Self::MountainCar(env) => env.action_space(),
+            Self::Acrobot(env) => env.action_space(),
+            Self::Pendulum(env) => env.action_space(),
+        }
+    }
+
+    fn observation_space(&self) -> &ObservationSpace<2> {
+        match self {
+            Self::CartPole(env) => env.observation_space(),
+            Self::MountainCar(env) => env.observation_space(),
+            Self::Acrobot(env) => env.observation_space(),
+            Self::Pendulum(env) => env.observation_space(),
+        }
+    }
+
+    fn reset(&mut self) -> anyhow::Result<Vec<f32>> {
+        match self {
+            Self::CartPole(env) => env.reset(),
+            Self::MountainCar(env) => env.reset(),
+            Self::Acrobot(env) => env.reset(),
+            Self::Pendulum(env) => env.reset(),
+        }
+    }
+
+    fn step(&mut self, action: &Action) -> anyhow::Result<StepResult> {
+        match self {
+            Self::CartPole(env) => env.step(action),
+            Self::MountainCar(env) => env.step(action),
+            Self::Acrobot(env) => env.step(action),
+            Self::Pendulum(env) => env.step(action),
+        }
+    }
+
+    fn render(&self) -> anyhow::Result<()> {
+        match self {
+            Self::CartPole(env) => env.render(),
+            Self::MountainCar(env) => env.render(),
+            Self::Acrobot(env) => env.render(),
+            Self::Pendulum(env) => env.render(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // returns the episode length, the cumulative reward and whether it terminated
+    fn run_episode(env: &mut impl Env<2>, action: &Action) -> anyhow::Result<(usize, f32, bool)> {
+        let observation = env.reset()?;
+        assert_eq!(observation.len(), env.observation_space().shape()[1]);
+        let mut steps = 0;
+        let mut cumulative_reward = 0.0;
+        loop {
+            let result = env.step(action)?;
+            assert_eq!(result.observation.len(), env.observation_space().shape()[1]);
+            assert!(!(result.terminated && result.truncated));
+            steps += 1;
+            cumulative_reward += result.reward;
+            if result.is_done() {
+                return Ok((steps, cumulative_reward, result.terminated));
+            }
+        }
+    }
+
+    #[test]
+    fn test_classic_control_env() -> anyhow::Result<()> {
+        for (env_name, action_space, shape) in [
+            ("CartPole-v1", ActionSpace::Discrete(2), [1, 4]),
+            ("MountainCar-v0", ActionSpace::Discrete(3), [1, 2]),
+            ("Acrobot-v1", ActionSpace::Discrete(3), [1, 6]),
+        ] {
+            let mut env = ClassicControlEnv::new(env_name)?;
+            assert_eq!(env.action_space(), &action_space, "{}", env_name);
+            assert_eq!(env.observation_space().shape(), &shape, "{}", env_name);
+            let observation = env.reset()?;
+            let observation_space = env.observation_space();
+            assert!(
+                observation
+                    .iter()
+                    .zip(observation_space.low().iter().zip(observation_space.high()))
+                    .all(|(x, (low, high))| (low..=high).contains(&x)),
+                "{}",
+                env_name
+            );
+            run_episode(&mut env, &Action::Discrete(0))?;
+            assert!(env.step(&Action::Discrete(100)).is_err(), "{}", env_name);
+            assert!(
+                env.step(&Action::Continuous(vec![0.0])).is_err(),
+                "{}",
+                env_name
+            );
+        }
+
+        let mut env = ClassicControlEnv::new("Pendulum-v1")?;
+        assert_eq!(
+            env.action_space(),
+            &ActionSpace::Box {
+                low: vec![-2.0],
+                high: vec![2.0]
+            }
+        );
+        assert_eq!(
+            env.observation_space(),
+            &ObservationSpace::Box {
+                shape: [1, 3],
+                low: vec![-1.0, -1.0, -8.0],
+                high: vec![1.0, 1.0, 8.0]
+            }
+        );
+        run_episode(&mut env, &Action::Continuous(vec![1.0]))?;
+        assert!(env.step(&Action::Discrete(0)).is_err());
+
+        let mut env = Pendulum::new().with_torque_bins(5);
+        assert_eq!(env.action_space(), &ActionSpace::Discrete(5));
+        run_episode(&mut env, &Action::Discrete(4))?;
+        assert!(ClassicControlEnv::new("Pendulum-v0").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_classic_control_episode_length() -> anyhow::Result<()> {
+        // pushing in one direction drops the pole long before the time limit
+        let (steps, reward, terminated) =
+            run_episode(&mut CartPole::with_seed(0), &Action::Discrete(1))?;
+        assert!(steps < 50);
+        assert!(terminated);
+        assert_eq!(reward, steps as f32);
+
+        // without any push the car never reaches the goal
+        let (steps, reward, terminated) =
+            run_episode(&mut MountainCar::with_seed(0), &Action::Discrete(1))?;
+        assert_eq!(steps, 200);
+        assert!(!terminated);
+        assert_eq!(reward, -200.0);
+
+        // without any torque the links never swing up
+        let (steps, reward, terminated) =
+            run_episode(&mut Acrobot::with_seed(0), &Action::Discrete(1))?;
+        assert_eq!(steps, 500);
+        assert!(!terminated);
+        assert_eq!(reward, -500.0);
+
+        let (steps, reward, terminated) =
+            run_episode(&mut Pendulum::with_seed(0), &Action::Continuous(vec![0.0]))?;
+        assert_eq!(steps, 200);
+        assert!(!terminated);
+        assert!(reward < 0.0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_classic_control_seed() -> anyhow::Result<()> {
+        let mut env1 = Acrobot::with_seed(42);
+        let mut env2 = Acrobot::with_seed(42);
+        assert_eq!(env1.reset()?, env2.reset()?);
+        for action in [0, 1, 2, 2, 1, 0] {
+            assert_eq!(
+                env1.step(&Action::Discrete(action))?,
+                env2.step(&Action::Discrete(action))?
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,177 @@
+use std::f64::consts::PI;
+
+use anyhow::bail;
+use rand::{rngs::StdRng, Rng as _, SeedableRng as _};
+
+use crate::{Action, ActionSpace, Env, ObservationSpace, StepResult};
+
+// https://github.com/Farama-Foundation/Gymnasium/blob/main/gymnasium/envs/classic_control/acrobot.py
+const DT: f64 = 0.2;
+const LINK_LENGTH_1: f64 = 1.0;
+const LINK_MASS_1: f64 = 1.0;
+const LINK_MASS_2: f64 = 1.0;
+const LINK_COM_POS_1: f64 = 0.5;
+const LINK_COM_POS_2: f64 = 0.5;
+const LINK_MOI: f64 = 1.0;
+const MAX_VEL_1: f64 = 4.0 * PI;
+const MAX_VEL_2: f64 = 9.0 * PI;
+const AVAIL_TORQUE: [f64; 3] = [-1.0, 0.0, 1.0];
+const GRAVITY: f64 = 9.8;
+const MAX_EPISODE_STEPS: usize = 500;
+
+pub struct Acrobot {
+    state: [f64; 4],
+    steps: usize,
+    rng: StdRng,
+    action_space: ActionSpace,
+    observation_space: ObservationSpace<2>,
+}
+
+impl Default for Acrobot {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Acrobot {
+    pub fn new() -> Self {
+        Self::with_rng(StdRng::from_entropy())
+    }
+
+    pub fn with_seed(seed: u64) -> Self {
+        Self::with_rng(StdRng::seed_from_u64(seed))
+    }
+
+    fn with_rng(rng: StdRng) -> Self {
+        Self {
+            state: [0.0; 4],
+            steps: 0,
+            rng,
+            action_space: ActionSpace::Discrete(3),
+            observation_space: ObservationSpace::Box {
+                shape: [1, 6],
+                low: vec![-1.0, -1.0, -1.0, -1.0, -MAX_VEL_1 as f32, -MAX_VEL_2 as f32],
+                high: vec![1.0, 1.0, 1.0, 1.0, MAX_VEL_1 as f32, MAX_VEL_2 as f32],
+            },
+        }
+    }
+
+    fn observation(&self) -> Vec<f32> {
+        let [theta1, theta2, dtheta1, dtheta2] = self.state;
+        [
+            theta1.cos(),
+            theta1.sin(),
+            theta2.cos(),
+            theta2.sin(),
+            dtheta1,
+            dtheta2,
+        ]
+        .iter()
+        .map(|x| *x as f32)
+        .collect()
+    }
+
+    fn is_terminal(&self) -> bool {
+        let [theta1, theta2, _, _] = self.state;
+        -theta1.cos() - (theta2 + theta1).cos() > 1.0
+    }
+
+    // "book" dynamics, i.e. Sutton & Barto, Reinforcement Learning: An Introduction
+    fn dsdt(s: [f64; 4], torque: f64) -> [f64; 4] {
+        let m1 = LINK_MASS_1;
+        let m2 = LINK_MASS_2;
+        let l1 = LINK_LENGTH_1;
+        let lc1 = LINK_COM_POS_1;
+        let lc2 = LINK_COM_POS_2;
+        let i1 = LINK_MOI;
+        let i2 = LINK_MOI;
+        let g = GRAVITY;
+        let [theta1, theta2, dtheta1, dtheta2] = s;
+
+        let d1 = m1 * lc1.powi(2)
+            + m2 * (l1.powi(2) + lc2.powi(2) + 2.0 * l1 * lc2 * theta2.cos())
+            + i1
+            + i2;
+        let d2 = m2 * (lc2.powi(2) + l1 * lc2 * theta2.cos()) + i2;
+        let phi2 = m2 * lc2 * g * (theta1 + theta2 - PI / 2.0).cos();
+        let phi1 = -m2 * l1 * lc2 * dtheta2.powi(2) * theta2.sin()
+            - 2.0 * m2 * l1 * lc2 * dtheta2 * dtheta1 * theta2.sin()
+            + (m1 * lc1 + m2 * l1) * g * (theta1 - PI / 2.0).cos()
+            + phi2;
+        let ddtheta2 =
+            (torque + d2 / d1 * phi1 - m2 * l1 * lc2 * dtheta1.powi(2) * theta2.sin() - phi2)
+                / (m2 * lc2.powi(2) + i2 - d2.powi(2) / d1);
+        let ddtheta1 = -(d2 * ddtheta2 + phi1) / d1;
+        [dtheta1, dtheta2, ddtheta1, ddtheta2]
+    }
+
+    fn rk4(s: [f64; 4], torque: f64, dt: f64) -> [f64; 4] {
+        let shift = |s: [f64; 4], k: [f64; 4], h: f64| std::array::from_fn(|i| s[i] + h * k[i]);
+        let k1 = Self::dsdt(s, torque);
+        let k2 = Self::dsdt(shift(s, k1, dt / 2.0), torque);
+        let k3 = Self::dsdt(shift(s, k2, dt / 2.0), torque);
+        let k4 = Self::dsdt(shift(s, k3, dt), torque);
+        std::array::from_fn(|i| s[i] + dt / 6.0 * (k1[i] + 2.0 * k2[i] + 2.0 * k3[i] + k4[i]))
+    }
+}
+
+fn wrap(x: f64, min: f64, max: f64) -> f64 {
+    let diff = max - min;
+    let mut x = x;
+    while x > max {
+        x -= diff;
+    }
+    while x < min {
+        x += diff;
+    }
+    x
+}
+
+impl Env<2> for Acrobot {
+    fn action_space(&self) -> &ActionSpace {
+        &self.action_space
+    }
+
+    fn observation_space(&self) -> &ObservationSpace<2> {
+        &self.observation_space
+    }
+
+    fn reset(&mut self) -> anyhow::Result<Vec<f32>> {
+        for x in self.state.iter_mut() {
+            *x = self.rng.gen_range(-0.1..0.1);
+        }
+        self.steps = 0;
+        Ok(self.observation())
+    }
+
+    fn step(&mut self, action: &Action) -> anyhow::Result<StepResult> {
+        let torque = match action {
+            Action::Discrete(action @ 0..=2) => AVAIL_TORQUE[*action as usize],
+            _ => bail!("invalid action for Acrobot: {:?}", action),
+        };
+
+        let [theta1, theta2, dtheta1, dtheta2] = Self::rk4(self.state, torque, DT);
+        self.state = [
+            wrap(theta1, -PI, PI),
+            wrap(theta2, -PI, PI),
+            dtheta1.clamp(-MAX_VEL_1, MAX_VEL_1),
+            dtheta2.clamp(-MAX_VEL_2, MAX_VEL_2),
+        ];
+        self.steps += 1;
+
+        let terminated = self.is_terminal();
+        let reward = if terminated { 0.0 } else { -1.0 };
+        let truncated = self.steps >= MAX_EPISODE_STEPS;
+        Ok(StepResult::new(
+            self.observation(),
+            reward,
+            terminated,
+            truncated,
+        ))
+    }
+
+    fn render(&self) -> anyhow::Result<()> {
+        println!("Acrobot: {:?}", self.state);
+        Ok(())
+    }
+}
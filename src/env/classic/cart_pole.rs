@@ -0,0 +1,127 @@
+use anyhow::bail;
+use rand::{rngs::StdRng, Rng as _, SeedableRng as _};
+
+use crate::{Action, ActionSpace, Env, ObservationSpace, StepResult};
+
+// https://github.com/Farama-Foundation/Gymnasium/blob/main/gymnasium/envs/classic_control/cartpole.py
+const GRAVITY: f64 = 9.8;
+const MASS_CART: f64 = 1.0;
+const MASS_POLE: f64 = 0.1;
+const TOTAL_MASS: f64 = MASS_CART + MASS_POLE;
+const LENGTH: f64 = 0.5;
+const POLE_MASS_LENGTH: f64 = MASS_POLE * LENGTH;
+const FORCE_MAG: f64 = 10.0;
+const TAU: f64 = 0.02;
+const THETA_THRESHOLD_RADIANS: f64 = 12.0 * 2.0 * std::f64::consts::PI / 360.0;
+const X_THRESHOLD: f64 = 2.4;
+const MAX_EPISODE_STEPS: usize = 500;
+
+pub struct CartPole {
+    state: [f64; 4],
+    steps: usize,
+    rng: StdRng,
+    action_space: ActionSpace,
+    observation_space: ObservationSpace<2>,
+}
+
+impl Default for CartPole {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CartPole {
+    pub fn new() -> Self {
+        Self::with_rng(StdRng::from_entropy())
+    }
+
+    pub fn with_seed(seed: u64) -> Self {
+        Self::with_rng(StdRng::seed_from_u64(seed))
+    }
+
+    fn with_rng(rng: StdRng) -> Self {
+        Self {
+            state: [0.0; 4],
+            steps: 0,
+            rng,
+            action_space: ActionSpace::Discrete(2),
+            observation_space: ObservationSpace::Box {
+                shape: [1, 4],
+                low: vec![
+                    -(X_THRESHOLD * 2.0) as f32,
+                    f32::MIN,
+                    -(THETA_THRESHOLD_RADIANS * 2.0) as f32,
+                    f32::MIN,
+                ],
+                high: vec![
+                    (X_THRESHOLD * 2.0) as f32,
+                    f32::MAX,
+                    (THETA_THRESHOLD_RADIANS * 2.0) as f32,
+                    f32::MAX,
+                ],
+            },
+        }
+    }
+
+    fn observation(&self) -> Vec<f32> {
+        self.state.iter().map(|x| *x as f32).collect()
+    }
+}
+
+impl Env<2> for CartPole {
+    fn action_space(&self) -> &ActionSpace {
+        &self.action_space
+    }
+
+    fn observation_space(&self) -> &ObservationSpace<2> {
+        &self.observation_space
+    }
+
+    fn reset(&mut self) -> anyhow::Result<Vec<f32>> {
+        for x in self.state.iter_mut() {
+            *x = self.rng.gen_range(-0.05..0.05);
+        }
+        self.steps = 0;
+        Ok(self.observation())
+    }
+
+    fn step(&mut self, action: &Action) -> anyhow::Result<StepResult> {
+        let force = match action {
+            Action::Discrete(0) => -FORCE_MAG,
+            Action::Discrete(1) => FORCE_MAG,
+            _ => bail!("invalid action for CartPole: {:?}", action),
+        };
+        let [x, x_dot, theta, theta_dot] = self.state;
+        let cos_theta = theta.cos();
+        let sin_theta = theta.sin();
+
+        let temp = (force + POLE_MASS_LENGTH * theta_dot.powi(2) * sin_theta) / TOTAL_MASS;
+        let theta_acc = (GRAVITY * sin_theta - cos_theta * temp)
+            / (LENGTH * (4.0 / 3.0 - MASS_POLE * cos_theta.powi(2) / TOTAL_MASS));
+        let x_acc = temp - POLE_MASS_LENGTH * theta_acc * cos_theta / TOTAL_MASS;
+
+        self.state = [
+            x + TAU * x_dot,
+            x_dot + TAU * x_acc,
+            theta + TAU * theta_dot,
+            theta_dot + TAU * theta_acc,
+        ];
+        self.steps += 1;
+
+        let [x, _, theta, _] = self.state;
+        let terminated = !(-X_THRESHOLD..=X_THRESHOLD).contains(&x)
+            || !(-THETA_THRESHOLD_RADIANS..=THETA_THRESHOLD_RADIANS).contains(&theta);
+        let truncated = self.steps >= MAX_EPISODE_STEPS;
+        Ok(StepResult::new(
+            self.observation(),
+            1.0,
+            terminated,
+            truncated,
+        ))
+    }
+
+    fn render(&self) -> anyhow::Result<()> {
+        println!("CartPole: {:?}", self.state);
+        Ok(())
+    }
+}
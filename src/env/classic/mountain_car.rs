@@ -0,0 +1,108 @@
+use anyhow::bail;
+use rand::{rngs::StdRng, Rng as _, SeedableRng as _};
+
+use crate::{Action, ActionSpace, Env, ObservationSpace, StepResult};
+
+// https://github.com/Farama-Foundation/Gymnasium/blob/main/gymnasium/envs/classic_control/mountain_car.py
+const MIN_POSITION: f64 = -1.2;
+const MAX_POSITION: f64 = 0.6;
+const MAX_SPEED: f64 = 0.07;
+const GOAL_POSITION: f64 = 0.5;
+const GOAL_VELOCITY: f64 = 0.0;
+const FORCE: f64 = 0.001;
+const GRAVITY: f64 = 0.0025;
+const MAX_EPISODE_STEPS: usize = 200;
+
+pub struct MountainCar {
+    position: f64,
+    velocity: f64,
+    steps: usize,
+    rng: StdRng,
+    action_space: ActionSpace,
+    observation_space: ObservationSpace<2>,
+}
+
+impl Default for MountainCar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MountainCar {
+    pub fn new() -> Self {
+        Self::with_rng(StdRng::from_entropy())
+    }
+
+    pub fn with_seed(seed: u64) -> Self {
+        Self::with_rng(StdRng::seed_from_u64(seed))
+    }
+
+    fn with_rng(rng: StdRng) -> Self {
+        Self {
+            position: 0.0,
+            velocity: 0.0,
+            steps: 0,
+            rng,
+            action_space: ActionSpace::Discrete(3),
+            observation_space: ObservationSpace::Box {
+                shape: [1, 2],
+                low: vec![MIN_POSITION as f32, -MAX_SPEED as f32],
+                high: vec![MAX_POSITION as f32, MAX_SPEED as f32],
+            },
+        }
+    }
+
+    fn observation(&self) -> Vec<f32> {
+        vec![self.position as f32, self.velocity as f32]
+    }
+}
+
+impl Env<2> for MountainCar {
+    fn action_space(&self) -> &ActionSpace {
+        &self.action_space
+    }
+
+    fn observation_space(&self) -> &ObservationSpace<2> {
+        &self.observation_space
+    }
+
+    fn reset(&mut self) -> anyhow::Result<Vec<f32>> {
+        self.position = self.rng.gen_range(-0.6..-0.4);
+        self.velocity = 0.0;
+        self.steps = 0;
+        Ok(self.observation())
+    }
+
+    fn step(&mut self, action: &Action) -> anyhow::Result<StepResult> {
+        let push = match action {
+            Action::Discrete(action @ 0..=2) => (*action - 1) as f64,
+            _ => bail!("invalid action for MountainCar: {:?}", action),
+        };
+
+        self.velocity += push * FORCE + (3.0 * self.position).cos() * (-GRAVITY);
+        self.velocity = self.velocity.clamp(-MAX_SPEED, MAX_SPEED);
+        self.position += self.velocity;
+        self.position = self.position.clamp(MIN_POSITION, MAX_POSITION);
+        if self.position == MIN_POSITION && self.velocity < 0.0 {
+            self.velocity = 0.0;
+        }
+        self.steps += 1;
+
+        let terminated = self.position >= GOAL_POSITION && self.velocity >= GOAL_VELOCITY;
+        let truncated = self.steps >= MAX_EPISODE_STEPS;
+        Ok(StepResult::new(
+            self.observation(),
+            -1.0,
+            terminated,
+            truncated,
+        ))
+    }
+
+    fn render(&self) -> anyhow::Result<()> {
+        println!(
+            "MountainCar: position {}, velocity {}",
+            self.position, self.velocity
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,142 @@
+use std::f64::consts::PI;
+
+use anyhow::bail;
+use rand::{rngs::StdRng, Rng as _, SeedableRng as _};
+
+use crate::{Action, ActionSpace, Env, ObservationSpace, StepResult};
+
+// https://github.com/Farama-Foundation/Gymnasium/blob/main/gymnasium/envs/classic_control/pendulum.py
+const MAX_SPEED: f64 = 8.0;
+const MAX_TORQUE: f64 = 2.0;
+const DT: f64 = 0.05;
+const GRAVITY: f64 = 10.0;
+const MASS: f64 = 1.0;
+const LENGTH: f64 = 1.0;
+const MAX_EPISODE_STEPS: usize = 200;
+
+pub struct Pendulum {
+    theta: f64,
+    theta_dot: f64,
+    steps: usize,
+    // evenly spaced torques when the action space is discretized
+    torques: Option<Vec<f64>>,
+    rng: StdRng,
+    action_space: ActionSpace,
+    observation_space: ObservationSpace<2>,
+}
+
+impl Default for Pendulum {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Pendulum {
+    pub fn new() -> Self {
+        Self::with_rng(StdRng::from_entropy())
+    }
+
+    pub fn with_seed(seed: u64) -> Self {
+        Self::with_rng(StdRng::seed_from_u64(seed))
+    }
+
+    // lets the discrete action agents control the pendulum
+    pub fn with_torque_bins(mut self, torque_bins: usize) -> Self {
+        assert!(torque_bins >= 2, "torque_bins must be at least 2");
+        let torques = (0..torque_bins)
+            .map(|i| -MAX_TORQUE + 2.0 * MAX_TORQUE * i as f64 / (torque_bins as f64 - 1.0))
+            .collect();
+        self.torques = Some(torques);
+        self.action_space = ActionSpace::Discrete(torque_bins as i64);
+        self
+    }
+
+    fn with_rng(rng: StdRng) -> Self {
+        Self {
+            theta: 0.0,
+            theta_dot: 0.0,
+            steps: 0,
+            torques: None,
+            rng,
+            action_space: ActionSpace::Box {
+                low: vec![-MAX_TORQUE as f32],
+                high: vec![MAX_TORQUE as f32],
+            },
+            observation_space: ObservationSpace::Box {
+                shape: [1, 3],
+                low: vec![-1.0, -1.0, -MAX_SPEED as f32],
+                high: vec![1.0, 1.0, MAX_SPEED as f32],
+            },
+        }
+    }
+
+    fn observation(&self) -> Vec<f32> {
+        vec![
+            self.theta.cos() as f32,
+            self.theta.sin() as f32,
+            self.theta_dot as f32,
+        ]
+    }
+}
+
+fn angle_normalize(x: f64) -> f64 {
+    (x + PI).rem_euclid(2.0 * PI) - PI
+}
+
+impl Env<2> for Pendulum {
+    fn action_space(&self) -> &ActionSpace {
+        &self.action_space
+    }
+
+    fn observation_space(&self) -> &ObservationSpace<2> {
+        &self.observation_space
+    }
+
+    fn reset(&mut self) -> anyhow::Result<Vec<f32>> {
+        self.theta = self.rng.gen_range(-PI..PI);
+        self.theta_dot = self.rng.gen_range(-1.0..1.0);
+        self.steps = 0;
+        Ok(self.observation())
+    }
+
+    fn step(&mut self, action: &Action) -> anyhow::Result<StepResult> {
+        let torque = match (action, &self.torques) {
+            (Action::Continuous(action), None) if action.len() == 1 => action[0] as f64,
+            (Action::Discrete(action), Some(torques))
+                if (0..torques.len() as i64).contains(action) =>
+            {
+                torques[*action as usize]
+            }
+            _ => bail!("invalid action for Pendulum: {:?}", action),
+        };
+        let torque = torque.clamp(-MAX_TORQUE, MAX_TORQUE);
+
+        let costs = angle_normalize(self.theta).powi(2)
+            + 0.1 * self.theta_dot.powi(2)
+            + 0.001 * torque.powi(2);
+
+        let theta_dot = self.theta_dot
+            + (3.0 * GRAVITY / (2.0 * LENGTH) * self.theta.sin()
+                + 3.0 / (MASS * LENGTH.powi(2)) * torque)
+                * DT;
+        self.theta_dot = theta_dot.clamp(-MAX_SPEED, MAX_SPEED);
+        self.theta += self.theta_dot * DT;
+        self.steps += 1;
+
+        let truncated = self.steps >= MAX_EPISODE_STEPS;
+        Ok(StepResult::new(
+            self.observation(),
+            -costs as f32,
+            false,
+            truncated,
+        ))
+    }
+
+    fn render(&self) -> anyhow::Result<()> {
+        println!(
+            "Pendulum: theta {}, theta_dot {}",
+            self.theta, self.theta_dot
+        );
+        Ok(())
+    }
+}
@@ -4,7 +4,9 @@ use pyo3::{
     Bound, PyAny, Python,
 };
 
-use crate::{Action, ActionSpace, Env, ObservationSpace};
+use crate::{Action, ActionSpace, Env, ObservationSpace, StepResult};
+
+use super::info_from_object;
 
 pub struct GymSuperMarioBrosEnv<'py> {
     py: Python<'py>,
@@ -37,21 +39,18 @@ impl<'py> GymSuperMarioBrosEnv<'py> {
             .getattr("action_space")
             .with_context(|| "fail to get action space")?;
 
-        let action_space = match action_space.get_type().name()?.as_ref() {
-            "Discrete" => {
-                let n = action_space.getattr("n")?;
-                let action_space: i64 = n.extract()?;
-                ActionSpace::Discrete(action_space)
-            }
-            _ => unimplemented!("Unsupported action space"),
-        };
+        let action_space = ActionSpace::from_object(&action_space)?;
         let observation_space = env.getattr("observation_space")?;
         let observation_space = match observation_space.get_type().name()?.as_ref() {
             "Box" => {
                 let shape = observation_space.getattr("shape")?;
                 let shape: Vec<i64> = shape.extract()?;
                 let shape = [1, shape[2] as usize, shape[0] as usize, shape[1] as usize];
-                ObservationSpace::Box { shape }
+                ObservationSpace::Box {
+                    shape,
+                    low: Self::ndarray_to_vec(observation_space.getattr("low")?)?,
+                    high: Self::ndarray_to_vec(observation_space.getattr("high")?)?,
+                }
             }
             _ => unimplemented!("Unsupported observation space"),
         };
@@ -65,7 +64,7 @@ impl<'py> GymSuperMarioBrosEnv<'py> {
     }
 
     fn ndarray_to_vec(array: Bound<PyAny>) -> anyhow::Result<Vec<f32>> {
-        let array = array.call_method("transpose", (1, 2, 0), None)?;
+        let array = array.call_method("transpose", (2, 0, 1), None)?;
         let array = array.call_method("reshape", (-1,), None)?;
         let array = array.extract()?;
         Ok(array)
@@ -86,13 +85,21 @@ impl<'py> Env<4> for GymSuperMarioBrosEnv<'py> {
         Self::ndarray_to_vec(result)
     }
 
-    fn step(&mut self, action: &Action) -> anyhow::Result<(Vec<f32>, f32, bool)> {
+    // the old gym api has no truncation
+    fn step(&mut self, action: &Action) -> anyhow::Result<StepResult> {
         let py_action = action.to_object(self.py);
         let step = self.env.call_method("step", (py_action,), None)?;
         let observation = Self::ndarray_to_vec(step.get_item(0)?)?;
         let reward: f32 = step.get_item(1)?.extract()?;
         let done: bool = step.get_item(2)?.extract()?;
-        Ok((observation, reward, done))
+        let info = info_from_object(&step.get_item(3)?);
+        Ok(StepResult {
+            observation,
+            reward,
+            terminated: done,
+            truncated: false,
+            info,
+        })
     }
 
     fn render(&self) -> anyhow::Result<()> {
@@ -105,6 +112,11 @@ impl<'py> Env<4> for GymSuperMarioBrosEnv<'py> {
         self.env.call_method("render", (), Some(&mode))?;
         Ok(())
     }
+
+    fn lives(&self) -> Option<usize> {
+        let unwrapped = self.env.getattr("unwrapped").ok()?;
+        unwrapped.getattr("_life").ok()?.extract().ok()
+    }
 }
 
 #[cfg(test)]
@@ -119,9 +131,7 @@ mod tests {
             let (env_name, action_space, observation_space) = (
                 "SuperMarioBros-v3",
                 ActionSpace::Discrete(12),
-                ObservationSpace::Box {
-                    shape: [1, 3, 240, 256],
-                },
+                ObservationSpace::bounded([1, 3, 240, 256], 0.0, 255.0),
             );
             let _result: anyhow::Result<()> = Python::with_gil(|py| {
                 let mut env = GymSuperMarioBrosEnv::new(py, env_name, true)?;
@@ -132,7 +142,11 @@ mod tests {
                     observation.len(),
                     observation_space.shape()[1..].iter().product::<usize>(),
                 );
-                let (observation, reward, _is_done) = env.step(&Action::Discrete(0))?;
+                let StepResult {
+                    observation,
+                    reward,
+                    ..
+                } = env.step(&Action::Discrete(0))?;
                 assert_eq!(
                     observation.len(),
                     observation_space.shape()[1..].iter().product::<usize>()
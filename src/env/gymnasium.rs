@@ -4,7 +4,9 @@ use pyo3::{
     Bound, PyAny, Python,
 };
 
-use crate::{Action, ActionSpace, Env, ObservationSpace};
+use crate::{Action, ActionSpace, Env, ObservationSpace, StepResult};
+
+use super::info_from_object;
 
 pub struct GymnasiumEnv1D<'py> {
     py: Python<'py>,
@@ -31,21 +33,18 @@ impl<'py> GymnasiumEnv1D<'py> {
             .getattr("action_space")
             .with_context(|| "fail to get action space")?;
 
-        let action_space = match action_space.get_type().name()?.as_ref() {
-            "Discrete" => {
-                let n = action_space.getattr("n")?;
-                let action_space: i64 = n.extract()?;
-                ActionSpace::Discrete(action_space)
-            }
-            _ => unimplemented!("Unsupported action space"),
-        };
+        let action_space = ActionSpace::from_object(&action_space)?;
         let observation_space = env.getattr("observation_space")?;
         let observation_space = match observation_space.get_type().name()?.as_ref() {
             "Box" => {
                 let shape = observation_space.getattr("shape")?;
                 let shape: Vec<i64> = shape.extract()?;
                 let shape = [1, shape[0] as usize];
-                ObservationSpace::Box { shape }
+                ObservationSpace::Box {
+                    shape,
+                    low: observation_space.getattr("low")?.extract()?,
+                    high: observation_space.getattr("high")?.extract()?,
+                }
             }
             _ => unimplemented!("Unsupported observation space"),
         };
@@ -83,15 +82,21 @@ impl<'py> Env<2> for GymnasiumEnv1D<'py> {
         Self::ndarray_to_vec(result)
     }
 
-    fn step(&mut self, action: &Action) -> anyhow::Result<(Vec<f32>, f32, bool)> {
+    fn step(&mut self, action: &Action) -> anyhow::Result<StepResult> {
         let py_action = action.to_object(self.py);
         let step = self.env.call_method("step", (py_action,), None)?;
         let observation = Self::ndarray_to_vec(step.get_item(0)?)?;
         let reward: f32 = step.get_item(1)?.extract()?;
         let terminated: bool = step.get_item(2)?.extract()?;
         let truncated: bool = step.get_item(3)?.extract()?;
-        let done = terminated || truncated;
-        Ok((observation, reward, done))
+        let info = info_from_object(&step.get_item(4)?);
+        Ok(StepResult {
+            observation,
+            reward,
+            terminated,
+            truncated,
+            info,
+        })
     }
 
     fn render(&self) -> anyhow::Result<()> {
@@ -125,21 +130,18 @@ impl<'py> GymnasiumEnv3D<'py> {
             .getattr("action_space")
             .with_context(|| "fail to get action space")?;
 
-        let action_space = match action_space.get_type().name()?.as_ref() {
-            "Discrete" => {
-                let n = action_space.getattr("n")?;
-                let action_space: i64 = n.extract()?;
-                ActionSpace::Discrete(action_space)
-            }
-            _ => unimplemented!("Unsupported action space"),
-        };
+        let action_space = ActionSpace::from_object(&action_space)?;
         let observation_space = env.getattr("observation_space")?;
         let observation_space = match observation_space.get_type().name()?.as_ref() {
             "Box" => {
                 let shape = observation_space.getattr("shape")?;
                 let shape: Vec<i64> = shape.extract()?;
                 let shape = [1, shape[2] as usize, shape[0] as usize, shape[1] as usize];
-                ObservationSpace::Box { shape }
+                ObservationSpace::Box {
+                    shape,
+                    low: Self::ndarray_to_vec(observation_space.getattr("low")?)?,
+                    high: Self::ndarray_to_vec(observation_space.getattr("high")?)?,
+                }
             }
             _ => unimplemented!("Unsupported observation space"),
         };
@@ -151,8 +153,24 @@ impl<'py> GymnasiumEnv3D<'py> {
         })
     }
 
+    // the action named FIRE by the game, None if the game has no such action
+    pub fn fire_action(&self) -> anyhow::Result<Option<Action>> {
+        let meanings: Vec<String> = self
+            .env
+            .getattr("unwrapped")?
+            .call_method0("get_action_meanings")
+            .with_context(|| "fail to get action meanings")?
+            .extract()?;
+        Ok(meanings
+            .iter()
+            .position(|meaning| meaning == "FIRE")
+            .map(|action| Action::Discrete(action as i64)))
+    }
+
+    // [height, width, channel] to channel first
     fn ndarray_to_vec(array: Bound<PyAny>) -> anyhow::Result<Vec<f32>> {
-        let result = array.call_method("reshape", (-1,), None)?;
+        let result = array.call_method("transpose", (2, 0, 1), None)?;
+        let result = result.call_method("reshape", (-1,), None)?;
         let result = result.extract()?;
         Ok(result)
     }
@@ -177,21 +195,32 @@ impl<'py> Env<4> for GymnasiumEnv3D<'py> {
         Self::ndarray_to_vec(result)
     }
 
-    fn step(&mut self, action: &Action) -> anyhow::Result<(Vec<f32>, f32, bool)> {
+    fn step(&mut self, action: &Action) -> anyhow::Result<StepResult> {
         let py_action = action.to_object(self.py);
         let step = self.env.call_method("step", (py_action,), None)?;
         let observation = Self::ndarray_to_vec(step.get_item(0)?)?;
         let reward: f32 = step.get_item(1)?.extract()?;
         let terminated: bool = step.get_item(2)?.extract()?;
         let truncated: bool = step.get_item(3)?.extract()?;
-        let done = terminated || truncated;
-        Ok((observation, reward, done))
+        let info = info_from_object(&step.get_item(4)?);
+        Ok(StepResult {
+            observation,
+            reward,
+            terminated,
+            truncated,
+            info,
+        })
     }
 
     fn render(&self) -> anyhow::Result<()> {
         self.env.call_method("render", (), None)?;
         Ok(())
     }
+
+    fn lives(&self) -> Option<usize> {
+        let ale = self.env.getattr("unwrapped").ok()?.getattr("ale").ok()?;
+        ale.call_method0("lives").ok()?.extract().ok()
+    }
 }
 
 #[cfg(test)]
@@ -204,31 +233,53 @@ mod tests {
 
     #[test]
     fn test_gym_env_discrete() -> anyhow::Result<()> {
-        for (env_name, action_space, observation_space) in [
-            (
-                "Acrobot-v1",
-                ActionSpace::Discrete(3),
-                ObservationSpace::Box { shape: [1, 6] },
-            ),
-            (
-                "CartPole-v1",
-                ActionSpace::Discrete(2),
-                ObservationSpace::Box { shape: [1, 4] },
-            ),
-            (
-                "MountainCar-v0",
-                ActionSpace::Discrete(3),
-                ObservationSpace::Box { shape: [1, 2] },
-            ),
+        for (env_name, action_space, shape) in [
+            ("Acrobot-v1", ActionSpace::Discrete(3), [1, 6]),
+            ("CartPole-v1", ActionSpace::Discrete(2), [1, 4]),
+            ("MountainCar-v0", ActionSpace::Discrete(3), [1, 2]),
         ] {
             let _result: anyhow::Result<()> = Python::with_gil(|py| {
                 let mut env = GymnasiumEnv1D::new(py, env_name, true)?;
                 assert_eq!(env.action_space(), &action_space);
-                assert_eq!(env.observation_space(), &observation_space);
+                assert_eq!(env.observation_space().shape(), &shape);
+                assert_eq!(env.observation_space().low().len(), shape[1]);
                 let observation = env.reset()?;
-                assert_eq!(observation.len(), observation_space.shape()[1]);
-                let (observation, reward, _is_done) = env.step(&Action::Discrete(0))?;
-                assert_eq!(observation.len(), observation_space.shape()[1]);
+                assert_eq!(observation.len(), shape[1]);
+                let StepResult {
+                    observation,
+                    reward,
+                    ..
+                } = env.step(&Action::Discrete(0))?;
+                assert_eq!(observation.len(), shape[1]);
+                // check that experience is printed
+                // because pyo3 failed silently
+                println!("{}", reward);
+                Ok(())
+            });
+        }
+
+        {
+            let (env_name, action_space, shape) = (
+                "Pendulum-v1",
+                ActionSpace::Box {
+                    low: vec![-2.0],
+                    high: vec![2.0],
+                },
+                [1, 3],
+            );
+            let _result: anyhow::Result<()> = Python::with_gil(|py| {
+                let mut env = GymnasiumEnv1D::new(py, env_name, true)?;
+                assert_eq!(env.action_space(), &action_space);
+                assert_eq!(env.observation_space().shape(), &shape);
+                assert_eq!(env.observation_space().low().len(), shape[1]);
+                let observation = env.reset()?;
+                assert_eq!(observation.len(), shape[1]);
+                let StepResult {
+                    observation,
+                    reward,
+                    ..
+                } = env.step(&Action::Continuous(vec![0.0]))?;
+                assert_eq!(observation.len(), shape[1]);
                 // check that experience is printed
                 // because pyo3 failed silently
                 println!("{}", reward);
@@ -240,9 +291,7 @@ mod tests {
             let (env_name, action_space, observation_space) = (
                 "Breakout-v4",
                 ActionSpace::Discrete(4),
-                ObservationSpace::Box {
-                    shape: [1, 3, 210, 160],
-                },
+                ObservationSpace::bounded([1, 3, 210, 160], 0.0, 255.0),
             );
             let _result: anyhow::Result<()> = Python::with_gil(|py| {
                 let mut env = GymnasiumEnv3D::new(py, env_name, true)?;
@@ -250,7 +299,11 @@ mod tests {
                 assert_eq!(env.observation_space(), &observation_space);
                 let observation = env.reset()?;
                 assert_eq!(observation.len(), observation_space.shape()[1]);
-                let (observation, reward, _is_done) = env.step(&Action::Discrete(0))?;
+                let StepResult {
+                    observation,
+                    reward,
+                    ..
+                } = env.step(&Action::Discrete(0))?;
                 assert_eq!(observation.len(), observation_space.shape()[1]);
                 // check that experience is printed
                 // because pyo3 failed silently
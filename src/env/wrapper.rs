@@ -0,0 +1,559 @@
+use std::{fs::File, path::Path, sync::Arc};
+
+use anyhow::{bail, Context as _};
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+
+use crate::{trainer::RewardMapping, Action, ActionSpace, Env, ObservationSpace, StepResult};
+
+// rewrites the observations of an `Env<D>` into the ones of an `Env<O>`
+pub trait ObservationWrapper<const D: usize, const O: usize> {
+    fn observation_space(&self, observation_space: &ObservationSpace<D>) -> ObservationSpace<O>;
+    fn observation(&mut self, observation: Vec<f32>) -> Vec<f32>;
+}
+
+pub trait RewardWrapper {
+    fn reward(&mut self, reward: f32) -> f32;
+}
+
+// maps the actions of the agent to the actions of the wrapped env
+pub trait ActionWrapper {
+    fn action_space(&self, action_space: &ActionSpace) -> ActionSpace;
+    fn action(&mut self, action: &Action) -> Action;
+}
+
+impl<F: FnMut(f32) -> f32> RewardWrapper for F {
+    fn reward(&mut self, reward: f32) -> f32 {
+        self(reward)
+    }
+}
+
+impl RewardWrapper for RewardMapping {
+    fn reward(&mut self, reward: f32) -> f32 {
+        self.apply(reward)
+    }
+}
+
+pub trait EnvExt<const D: usize>: Env<D> + Sized {
+    fn map_observation<const O: usize, W: ObservationWrapper<D, O>>(
+        self,
+        wrapper: W,
+    ) -> ObservationEnv<D, O, Self, W> {
+        ObservationEnv::new(self, wrapper)
+    }
+
+    fn map_reward<W: RewardWrapper>(self, wrapper: W) -> RewardEnv<D, Self, W> {
+        RewardEnv::new(self, wrapper)
+    }
+
+    fn map_action<W: ActionWrapper>(self, wrapper: W) -> ActionEnv<D, Self, W> {
+        ActionEnv::new(self, wrapper)
+    }
+
+    fn time_limit(self, max_episode_steps: usize) -> TimeLimit<D, Self> {
+        TimeLimit::new(self, max_episode_steps)
+    }
+}
+
+impl<const D: usize, E: Env<D>> EnvExt<D> for E {}
+
+pub struct ObservationEnv<const D: usize, const O: usize, E: Env<D>, W: ObservationWrapper<D, O>> {
+    env: E,
+    wrapper: W,
+    observation_space: ObservationSpace<O>,
+}
+
+impl<const D: usize, const O: usize, E: Env<D>, W: ObservationWrapper<D, O>>
+    ObservationEnv<D, O, E, W>
+{
+    pub fn new(env: E, wrapper: W) -> Self {
+        let observation_space = wrapper.observation_space(env.observation_space());
+        Self {
+            env,
+            wrapper,
+            observation_space,
+        }
+    }
+}
+
+impl<const D: usize, const O: usize, E: Env<D>, W: ObservationWrapper<D, O>> Env<O>
+    for ObservationEnv<D, O, E, W>
+{
+    fn action_space(&self) -> &ActionSpace {
+        self.env.action_space()
+    }
+
+    fn observation_space(&self) -> &ObservationSpace<O> {
+        &self.observation_space
+    }
+
+    fn reset(&mut self) -> anyhow::Result<Vec<f32>> {
+        let observation = self.env.reset()?;
+        Ok(self.wrapper.observation(observation))
+    }
+
+    fn step(&mut self, action: &Action) -> anyhow::Result<StepResult> {
+        let mut result = self.env.step(action)?;
+        result.observation = self.wrapper.observation(result.observation);
+        Ok(result)
+    }
+
+    fn render(&self) -> anyhow::Result<()> {
+        self.env.render()
+    }
+
+    fn lives(&self) -> Option<usize> {
+        self.env.lives()
+    }
+}
+
+pub struct RewardEnv<const D: usize, E: Env<D>, W: RewardWrapper> {
+    env: E,
+    wrapper: W,
+}
+
+impl<const D: usize, E: Env<D>, W: RewardWrapper> RewardEnv<D, E, W> {
+    pub fn new(env: E, wrapper: W) -> Self {
+        Self { env, wrapper }
+    }
+}
+
+impl<const D: usize, E: Env<D>, W: RewardWrapper> Env<D> for RewardEnv<D, E, W> {
+    fn action_space(&self) -> &ActionSpace {
+        self.env.action_space()
+    }
+
+    fn observation_space(&self) -> &ObservationSpace<D> {
+        self.env.observation_space()
+    }
+
+    fn reset(&mut self) -> anyhow::Result<Vec<f32>> {
+        self.env.reset()
+    }
+
+    fn step(&mut self, action: &Action) -> anyhow::Result<StepResult> {
+        let mut result = self.env.step(action)?;
+        result.reward = self.wrapper.reward(result.reward);
+        Ok(result)
+    }
+
+    fn render(&self) -> anyhow::Result<()> {
+        self.env.render()
+    }
+
+    fn lives(&self) -> Option<usize> {
+        self.env.lives()
+    }
+}
+
+pub struct ActionEnv<const D: usize, E: Env<D>, W: ActionWrapper> {
+    env: E,
+    wrapper: W,
+    action_space: ActionSpace,
+}
+
+impl<const D: usize, E: Env<D>, W: ActionWrapper> ActionEnv<D, E, W> {
+    pub fn new(env: E, wrapper: W) -> Self {
+        let action_space = wrapper.action_space(env.action_space());
+        Self {
+            env,
+            wrapper,
+            action_space,
+        }
+    }
+}
+
+impl<const D: usize, E: Env<D>, W: ActionWrapper> Env<D> for ActionEnv<D, E, W> {
+    fn action_space(&self) -> &ActionSpace {
+        &self.action_space
+    }
+
+    fn observation_space(&self) -> &ObservationSpace<D> {
+        self.env.observation_space()
+    }
+
+    fn reset(&mut self) -> anyhow::Result<Vec<f32>> {
+        self.env.reset()
+    }
+
+    fn step(&mut self, action: &Action) -> anyhow::Result<StepResult> {
+        let action = self.wrapper.action(action);
+        self.env.step(&action)
+    }
+
+    fn render(&self) -> anyhow::Result<()> {
+        self.env.render()
+    }
+
+    fn lives(&self) -> Option<usize> {
+        self.env.lives()
+    }
+}
+
+// ends the episodes after `max_episode_steps` steps
+pub struct TimeLimit<const D: usize, E: Env<D>> {
+    env: E,
+    max_episode_steps: usize,
+    steps: usize,
+}
+
+impl<const D: usize, E: Env<D>> TimeLimit<D, E> {
+    pub fn new(env: E, max_episode_steps: usize) -> Self {
+        Self {
+            env,
+            max_episode_steps,
+            steps: 0,
+        }
+    }
+}
+
+impl<const D: usize, E: Env<D>> Env<D> for TimeLimit<D, E> {
+    fn action_space(&self) -> &ActionSpace {
+        self.env.action_space()
+    }
+
+    fn observation_space(&self) -> &ObservationSpace<D> {
+        self.env.observation_space()
+    }
+
+    fn reset(&mut self) -> anyhow::Result<Vec<f32>> {
+        self.steps = 0;
+        self.env.reset()
+    }
+
+    // hitting the limit truncates the episodes that did not terminate
+    fn step(&mut self, action: &Action) -> anyhow::Result<StepResult> {
+        let mut result = self.env.step(action)?;
+        self.steps += 1;
+        result.truncated |= !result.terminated && self.steps >= self.max_episode_steps;
+        Ok(result)
+    }
+
+    fn render(&self) -> anyhow::Result<()> {
+        self.env.render()
+    }
+
+    fn lives(&self) -> Option<usize> {
+        self.env.lives()
+    }
+}
+
+// flattens the observations to [1, size]
+pub struct Flatten;
+
+impl<const D: usize> ObservationWrapper<D, 2> for Flatten {
+    fn observation_space(&self, observation_space: &ObservationSpace<D>) -> ObservationSpace<2> {
+        let shape = observation_space.shape();
+        ObservationSpace::Box {
+            shape: [shape[0], shape[1..].iter().product()],
+            low: observation_space.low().to_vec(),
+            high: observation_space.high().to_vec(),
+        }
+    }
+
+    fn observation(&mut self, observation: Vec<f32>) -> Vec<f32> {
+        observation
+    }
+}
+
+// box actions in [-1, 1] rescaled to the bounds of the wrapped env
+pub struct RescaleAction {
+    low: Vec<f32>,
+    high: Vec<f32>,
+}
+
+impl RescaleAction {
+    pub fn new(action_space: &ActionSpace) -> anyhow::Result<Self> {
+        let ActionSpace::Box { low, high } = action_space else {
+            bail!(
+                "rescale action needs a box action space, got {:?}",
+                action_space
+            );
+        };
+        Ok(Self {
+            low: low.clone(),
+            high: high.clone(),
+        })
+    }
+}
+
+impl ActionWrapper for RescaleAction {
+    fn action_space(&self, _action_space: &ActionSpace) -> ActionSpace {
+        ActionSpace::Box {
+            low: vec![-1.0; self.low.len()],
+            high: vec![1.0; self.high.len()],
+        }
+    }
+
+    fn action(&mut self, action: &Action) -> Action {
+        match action {
+            Action::Continuous(action) => Action::Continuous(
+                action
+                    .iter()
+                    .zip(self.low.iter().zip(&self.high))
+                    .map(|(a, (low, high))| low + (a.clamp(-1.0, 1.0) + 1.0) * (high - low) / 2.0)
+                    .collect(),
+            ),
+            // left to the wrapped env to reject
+            Action::Discrete(..) => action.clone(),
+        }
+    }
+}
+
+// running mean and variance of the observations, Welford's algorithm
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RunningMeanStd {
+    count: f64,
+    mean: Vec<f64>,
+    m2: Vec<f64>,
+}
+
+impl RunningMeanStd {
+    pub fn update(&mut self, observation: &[f32]) {
+        if self.mean.len() != observation.len() {
+            *self = Self {
+                count: 0.0,
+                mean: vec![0.0; observation.len()],
+                m2: vec![0.0; observation.len()],
+            };
+        }
+        self.count += 1.0;
+        for ((mean, m2), x) in self.mean.iter_mut().zip(&mut self.m2).zip(observation) {
+            let x = *x as f64;
+            let delta = x - *mean;
+            *mean += delta / self.count;
+            *m2 += delta * (x - *mean);
+        }
+    }
+
+    pub fn count(&self) -> f64 {
+        self.count
+    }
+
+    pub fn mean(&self) -> &[f64] {
+        &self.mean
+    }
+
+    pub fn variance(&self) -> Vec<f64> {
+        self.m2
+            .iter()
+            .map(|m2| {
+                if self.count > 0.0 {
+                    m2 / self.count
+                } else {
+                    1.0
+                }
+            })
+            .collect()
+    }
+}
+
+const OBSERVATION_NORMALIZER_FILE: &str = "observation_normalizer.mpk";
+
+// normalizes the observations with running statistics shared by its clones,
+// so that an agent can save and load the statistics of the envs it is trained on
+#[derive(Debug, Clone)]
+pub struct ObservationNormalizer {
+    statistics: Arc<RwLock<RunningMeanStd>>,
+    clip: f32,
+    epsilon: f32,
+    frozen: bool,
+}
+
+impl Default for ObservationNormalizer {
+    fn default() -> Self {
+        Self::new(10.0)
+    }
+}
+
+impl ObservationNormalizer {
+    pub fn new(clip: f32) -> Self {
+        Self {
+            statistics: Arc::new(RwLock::new(RunningMeanStd::default())),
+            clip,
+            epsilon: 1e-8,
+            frozen: false,
+        }
+    }
+
+    // a clone sharing the statistics without updating them, for evaluation
+    pub fn frozen(&self) -> Self {
+        Self {
+            frozen: true,
+            ..self.clone()
+        }
+    }
+
+    pub fn statistics(&self) -> RunningMeanStd {
+        self.statistics.read().clone()
+    }
+
+    pub fn update(&self, observation: &[f32]) {
+        self.statistics.write().update(observation);
+    }
+
+    pub fn normalize(&self, observation: &[f32]) -> Vec<f32> {
+        let statistics = self.statistics.read();
+        if statistics.mean.len() != observation.len() {
+            return observation.to_vec();
+        }
+        observation
+            .iter()
+            .zip(statistics.mean())
+            .zip(statistics.variance())
+            .map(|((x, mean), variance)| {
+                let x = (*x as f64 - mean) / (variance + self.epsilon as f64).sqrt();
+                (x as f32).clamp(-self.clip, self.clip)
+            })
+            .collect()
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, artifacts_dir: P) -> anyhow::Result<()> {
+        let mut file = File::create(artifacts_dir.as_ref().join(OBSERVATION_NORMALIZER_FILE))
+            .with_context(|| "create observation normalizer file")?;
+        rmp_serde::encode::write(&mut file, &*self.statistics.read())
+            .with_context(|| "Failed to write observation normalizer")?;
+        Ok(())
+    }
+
+    // the statistics are replaced in place, so the clones wrapping the envs see them too
+    pub fn load<P: AsRef<Path>>(&self, restore_dir: P) -> anyhow::Result<()> {
+        let normalizer_file = restore_dir.as_ref().join(OBSERVATION_NORMALIZER_FILE);
+        if normalizer_file.exists() {
+            let file =
+                File::open(normalizer_file).with_context(|| "open observation normalizer file")?;
+            let statistics = rmp_serde::decode::from_read(file)
+                .with_context(|| "Failed to read observation normalizer")?;
+            *self.statistics.write() = statistics;
+        }
+        Ok(())
+    }
+}
+
+impl<const D: usize> ObservationWrapper<D, D> for ObservationNormalizer {
+    // the normalized observations are clipped
+    fn observation_space(&self, observation_space: &ObservationSpace<D>) -> ObservationSpace<D> {
+        ObservationSpace::bounded(*observation_space.shape(), -self.clip, self.clip)
+    }
+
+    fn observation(&mut self, observation: Vec<f32>) -> Vec<f32> {
+        if !self.frozen {
+            self.update(&observation);
+        }
+        self.normalize(&observation)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use crate::env::classic::{CartPole, Pendulum};
+
+    use super::*;
+
+    #[test]
+    fn test_wrappers() -> anyhow::Result<()> {
+        let mut env = CartPole::with_seed(0)
+            .map_reward(|reward: f32| 2.0 * reward)
+            .map_reward(RewardMapping::Clip {
+                min: -1.5,
+                max: 1.5,
+            })
+            .time_limit(5);
+        env.reset()?;
+        let steps = (0..5)
+            .map(|_| env.step(&Action::Discrete(0)))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        assert!(steps.iter().all(|result| result.reward == 1.5));
+        assert!(steps[..4].iter().all(|result| !result.is_done()));
+        assert!(steps[4].truncated && !steps[4].terminated);
+        env.reset()?;
+        assert!(!env.step(&Action::Discrete(0))?.is_done());
+
+        struct Scale(f32);
+        impl ObservationWrapper<2, 4> for Scale {
+            fn observation_space(
+                &self,
+                observation_space: &ObservationSpace<2>,
+            ) -> ObservationSpace<4> {
+                let [batch, size] = *observation_space.shape();
+                ObservationSpace::unbounded([batch, 1, 1, size])
+            }
+
+            fn observation(&mut self, observation: Vec<f32>) -> Vec<f32> {
+                observation.into_iter().map(|o| o * self.0).collect()
+            }
+        }
+        let mut env = CartPole::with_seed(0).map_observation(Scale(0.0));
+        assert_eq!(env.observation_space().shape(), &[1, 1, 1, 4]);
+        assert_eq!(env.reset()?, vec![0.0; 4]);
+        let mut env = env.map_observation(Flatten);
+        assert_eq!(env.observation_space().shape(), &[1, 4]);
+        assert_eq!(env.step(&Action::Discrete(1))?.observation, vec![0.0; 4]);
+
+        let pendulum = Pendulum::with_seed(0);
+        let rescale = RescaleAction::new(pendulum.action_space())?;
+        let mut env = pendulum.map_action(rescale);
+        assert_eq!(
+            env.action_space(),
+            &ActionSpace::Box {
+                low: vec![-1.0],
+                high: vec![1.0]
+            }
+        );
+        assert_eq!(
+            env.wrapper.action(&Action::Continuous(vec![0.5])),
+            Action::Continuous(vec![1.0])
+        );
+        assert_eq!(
+            env.wrapper.action(&Action::Discrete(1)),
+            Action::Discrete(1)
+        );
+        env.reset()?;
+        env.step(&Action::Continuous(vec![-1.0]))?;
+        assert!(RescaleAction::new(CartPole::with_seed(0).action_space()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_observation_normalizer() -> anyhow::Result<()> {
+        let observations = [[1.0, 100.0], [2.0, 300.0], [3.0, 200.0], [6.0, 400.0]];
+        let mut statistics = RunningMeanStd::default();
+        for observation in &observations {
+            statistics.update(observation);
+        }
+        assert_eq!(statistics.count(), 4.0);
+        assert_eq!(statistics.mean(), &[3.0, 250.0]);
+        assert_eq!(statistics.variance(), vec![3.5, 12500.0]);
+
+        let normalizer = ObservationNormalizer::default();
+        let mut env = CartPole::with_seed(0).map_observation(normalizer.clone());
+        assert_eq!(
+            env.observation_space(),
+            &ObservationSpace::bounded([1, 4], -10.0, 10.0)
+        );
+        let mut observations = vec![env.reset()?];
+        for _ in 0..8 {
+            observations.push(env.step(&Action::Discrete(0))?.observation);
+        }
+        assert_eq!(normalizer.statistics().count(), 9.0);
+        // the first observation is the mean of the statistics it updated
+        assert_eq!(observations[0], vec![0.0; 4]);
+        assert!(observations.concat().iter().all(|o| o.abs() <= 10.0));
+
+        let artifacts_dir = TempDir::new()?;
+        normalizer.save(&artifacts_dir)?;
+        let restored = ObservationNormalizer::default();
+        let mut frozen = restored.frozen();
+        restored.load(&artifacts_dir)?;
+        assert_eq!(restored.statistics().mean(), normalizer.statistics().mean());
+        let observation = vec![0.1, 0.2, 0.3, 0.4];
+        assert_eq!(
+            ObservationWrapper::<2, 2>::observation(&mut frozen, observation.clone()),
+            normalizer.normalize(&observation)
+        );
+        assert_eq!(restored.statistics().count(), 9.0);
+        Ok(())
+    }
+}
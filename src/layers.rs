@@ -1,12 +1,51 @@
 use burn::{
     config::Config,
-    module::{Module, Param},
+    module::{Ignored, Module, Param},
     nn::Initializer,
     prelude::Backend,
     tensor::{Shape, Tensor, TensorData},
 };
+use rand::Rng;
 use rand_distr::{Distribution, Normal};
 
+// how the noisy layers draw their noise
+#[derive(Debug, Clone, Default)]
+pub enum NoiseMode {
+    // fresh noise on every forward pass
+    #[default]
+    PerForward,
+    // the same noise until it is resampled, usually once per step
+    Fixed {
+        epsilon_in: Vec<f32>,
+        epsilon_out: Vec<f32>,
+    },
+    // the mean weights only, for evaluation
+    Off,
+}
+
+// modules with noisy layers, consumed and returned like the other burn module updates
+pub trait Noisy<B: Backend>: Sized {
+    fn map_noisy<F: FnMut(NoisyLinear<B>) -> NoisyLinear<B>>(self, f: &mut F) -> Self;
+
+    // eval mode acts on the mean weights, train mode goes back to fresh noise on every forward
+    fn train(self, training: bool) -> Self {
+        let noise = if training {
+            NoiseMode::PerForward
+        } else {
+            NoiseMode::Off
+        };
+        self.map_noisy(&mut |layer| layer.with_noise(noise.clone()))
+    }
+
+    // fixes the noise of every noisy layer until the next call
+    fn resample_noise<R: Rng>(self, rng: &mut R) -> Self {
+        self.map_noisy(&mut |layer| {
+            let noise = layer.sample_noise(rng);
+            layer.with_noise(noise)
+        })
+    }
+}
+
 #[derive(Config, Debug)]
 pub struct NoisyLinearConfig {
     pub d_input: usize,
@@ -25,6 +64,7 @@ pub struct NoisyLinear<B: Backend> {
     pub weight_sigma: Param<Tensor<B, 2>>,
     pub bias_mu: Option<Param<Tensor<B, 1>>>,
     pub bias_sigma: Option<Param<Tensor<B, 1>>>,
+    pub noise: Ignored<NoiseMode>,
 }
 
 impl NoisyLinearConfig {
@@ -59,32 +99,67 @@ impl NoisyLinearConfig {
             weight_sigma,
             bias_mu,
             bias_sigma,
+            noise: Ignored(NoiseMode::default()),
         }
     }
 }
 
 impl<B: Backend> NoisyLinear<B> {
+    // factorized gaussian noise f(x) = sgn(x) sqrt(|x|)
+    fn sample_epsilon<R: Rng>(rng: &mut R, size: usize) -> Vec<f32> {
+        let normal = Normal::new(0.0, 1.0).unwrap();
+        (0..size)
+            .map(|_| normal.sample(rng))
+            .map(|x: f32| x.signum() * x.abs().sqrt())
+            .collect()
+    }
+
+    pub fn with_noise(self, noise: NoiseMode) -> Self {
+        Self {
+            noise: Ignored(noise),
+            ..self
+        }
+    }
+
+    pub fn sample_noise<R: Rng>(&self, rng: &mut R) -> NoiseMode {
+        let [d_input, d_output] = self.weight_mu.dims();
+        NoiseMode::Fixed {
+            epsilon_in: Self::sample_epsilon(rng, d_input),
+            epsilon_out: Self::sample_epsilon(rng, d_output),
+        }
+    }
+
     pub fn forward<const D: usize>(&self, input: Tensor<B, D>) -> Tensor<B, D> {
         if D == 1 {
             return Self::forward::<2>(self, input.unsqueeze()).flatten(0, 1);
         }
         let device = input.device();
-        let mut rng = rand::thread_rng();
-        let normal = Normal::new(0.0, 1.0).unwrap();
         let d_input = self.weight_mu.shape().dims[0];
         let d_output = self.weight_mu.shape().dims[1];
-        let epsilon_in = (0..d_input)
-            .map(|_| normal.sample(&mut rng))
-            .map(|x: f32| x.signum() * x.abs().sqrt())
-            .collect();
+        let (epsilon_in, epsilon_out) = match &self.noise.0 {
+            NoiseMode::Off => {
+                let output = input.matmul(self.weight_mu.val().unsqueeze());
+                return match &self.bias_mu {
+                    Some(bias_mu) => output + bias_mu.val().unsqueeze(),
+                    None => output,
+                };
+            }
+            NoiseMode::Fixed {
+                epsilon_in,
+                epsilon_out,
+            } => (epsilon_in.clone(), epsilon_out.clone()),
+            NoiseMode::PerForward => {
+                let mut rng = rand::thread_rng();
+                (
+                    Self::sample_epsilon(&mut rng, d_input),
+                    Self::sample_epsilon(&mut rng, d_output),
+                )
+            }
+        };
         let epsilon_in = Tensor::from_data(
             TensorData::new(epsilon_in, Shape::new([d_input, 1])).convert::<B::FloatElem>(),
             &device,
         );
-        let epsilon_out = (0..self.weight_mu.shape().dims[1])
-            .map(|_| normal.sample(&mut rng))
-            .map(|x: f32| x.signum() * x.abs().sqrt())
-            .collect();
         let epsilon_out = Tensor::from_data(
             TensorData::new(epsilon_out, Shape::new([1, d_output])).convert::<B::FloatElem>(),
             &device,
@@ -106,3 +181,9 @@ impl<B: Backend> NoisyLinear<B> {
         }
     }
 }
+
+impl<B: Backend> Noisy<B> for NoisyLinear<B> {
+    fn map_noisy<F: FnMut(NoisyLinear<B>) -> NoisyLinear<B>>(self, f: &mut F) -> Self {
+        f(self)
+    }
+}
@@ -5,8 +5,9 @@ pub mod layers;
 pub mod model;
 pub mod trainer;
 
-use std::{fmt::Debug, path::Path};
+use std::{collections::HashMap, fmt::Debug, path::Path};
 
+use anyhow::{anyhow, ensure};
 use burn::tensor::{backend::Backend, Tensor};
 use serde::{Deserialize, Serialize};
 
@@ -14,15 +15,40 @@ pub trait State: Clone + Debug + Send {
     fn new(observation: Vec<f32>) -> Self;
 }
 
+// states that are fed to the model as a single observation
+pub trait ObservationState: State {
+    // observation space of the model input for an env with `observation_space`
+    fn observation_space<const D: usize>(
+        observation_space: &ObservationSpace<D>,
+    ) -> ObservationSpace<D> {
+        observation_space.clone()
+    }
+    // empty until the first step of the episode
+    fn observation(&self) -> Vec<f32>;
+    fn next_observation(&self) -> Vec<f32>;
+    fn next_state(&self, next_observation: &[f32]) -> Self;
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Experience<S: State> {
     state: S,
     action: Action,
     reward: f32,
-    is_done: bool,
+    terminated: bool,
+    truncated: bool,
 }
 
 impl<S: State> Experience<S> {
+    pub fn new(state: S, action: Action, reward: f32, terminated: bool, truncated: bool) -> Self {
+        Self {
+            state,
+            action,
+            reward,
+            terminated,
+            truncated,
+        }
+    }
+
     pub fn state(&self) -> &S {
         &self.state
     }
@@ -35,48 +61,241 @@ impl<S: State> Experience<S> {
         self.reward
     }
 
+    // the episode ended, by termination or truncation
     pub fn is_done(&self) -> bool {
-        self.is_done
+        self.terminated || self.truncated
+    }
+
+    // only a termination stops the bootstrap
+    pub fn is_terminated(&self) -> bool {
+        self.terminated
+    }
+
+    pub fn is_truncated(&self) -> bool {
+        self.truncated
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum ActionSpace {
     Discrete(i64),
+    Box { low: Vec<f32>, high: Vec<f32> },
 }
 
 impl ActionSpace {
     pub fn size(&self) -> usize {
         match self {
             ActionSpace::Discrete(n) => *n as usize,
+            ActionSpace::Box { low, .. } => low.len(),
         }
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Action {
     Discrete(i64),
+    Continuous(Vec<f32>),
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum ObservationSpace<const D: usize> {
-    Box { shape: [usize; D] },
+    // `low` and `high` bound each element of an observation
+    Box {
+        shape: [usize; D],
+        low: Vec<f32>,
+        high: Vec<f32>,
+    },
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StepResult {
+    pub observation: Vec<f32>,
+    pub reward: f32,
+    // the episode reached a terminal state
+    pub terminated: bool,
+    // the episode was cut short, e.g. by a time limit
+    pub truncated: bool,
+    pub info: HashMap<String, serde_json::Value>,
+}
+
+impl StepResult {
+    pub fn new(observation: Vec<f32>, reward: f32, terminated: bool, truncated: bool) -> Self {
+        Self {
+            observation,
+            reward,
+            terminated,
+            truncated,
+            info: HashMap::new(),
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.terminated || self.truncated
+    }
 }
 
 pub trait Env<const D: usize> {
     fn action_space(&self) -> &ActionSpace;
     fn observation_space(&self) -> &ObservationSpace<D>;
     fn reset(&mut self) -> anyhow::Result<Vec<f32>>;
-    fn step(&mut self, action: &Action) -> anyhow::Result<(Vec<f32>, f32, bool)>;
+    fn step(&mut self, action: &Action) -> anyhow::Result<StepResult>;
     fn render(&self) -> anyhow::Result<()>;
+    // remaining lives of the games that have them
+    fn lives(&self) -> Option<usize> {
+        None
+    }
+}
+
+impl<const D: usize, E: Env<D> + ?Sized> Env<D> for &mut E {
+    fn action_space(&self) -> &ActionSpace {
+        (**self).action_space()
+    }
+
+    fn observation_space(&self) -> &ObservationSpace<D> {
+        (**self).observation_space()
+    }
+
+    fn reset(&mut self) -> anyhow::Result<Vec<f32>> {
+        (**self).reset()
+    }
+
+    fn step(&mut self, action: &Action) -> anyhow::Result<StepResult> {
+        (**self).step(action)
+    }
+
+    fn render(&self) -> anyhow::Result<()> {
+        (**self).render()
+    }
+
+    fn lives(&self) -> Option<usize> {
+        (**self).lives()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct VecEnvStep {
+    pub observation: Vec<f32>,
+    pub reward: f32,
+    pub terminated: bool,
+    pub truncated: bool,
+    pub info: HashMap<String, serde_json::Value>,
+    // set when the env finished and was reset, `observation` is then the terminal one
+    pub reset_observation: Option<Vec<f32>>,
+}
+
+impl VecEnvStep {
+    pub fn is_done(&self) -> bool {
+        self.terminated || self.truncated
+    }
+}
+
+pub struct VecEnv<const D: usize, E: Env<D>> {
+    envs: Vec<E>,
+}
+
+impl<const D: usize, E: Env<D>> VecEnv<D, E> {
+    pub fn new(envs: Vec<E>) -> anyhow::Result<Self> {
+        let first = envs
+            .first()
+            .ok_or(anyhow!("VecEnv needs at least one env"))?;
+        ensure!(
+            envs.iter()
+                .all(|env| env.action_space() == first.action_space()
+                    && env.observation_space() == first.observation_space()),
+            "all envs of VecEnv must share the same spaces"
+        );
+        Ok(Self { envs })
+    }
+
+    pub fn len(&self) -> usize {
+        self.envs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.envs.is_empty()
+    }
+
+    pub fn action_space(&self) -> &ActionSpace {
+        self.envs[0].action_space()
+    }
+
+    pub fn observation_space(&self) -> &ObservationSpace<D> {
+        self.envs[0].observation_space()
+    }
+
+    pub fn reset(&mut self) -> anyhow::Result<Vec<Vec<f32>>> {
+        self.envs.iter_mut().map(|env| env.reset()).collect()
+    }
+
+    pub fn step(&mut self, actions: &[Action]) -> anyhow::Result<Vec<VecEnvStep>> {
+        ensure!(
+            actions.len() == self.envs.len(),
+            "expected {} actions, got {}",
+            self.envs.len(),
+            actions.len()
+        );
+        self.envs
+            .iter_mut()
+            .zip(actions)
+            .map(|(env, action)| {
+                let result = env.step(action)?;
+                let reset_observation = if result.is_done() {
+                    Some(env.reset()?)
+                } else {
+                    None
+                };
+                Ok(VecEnvStep {
+                    observation: result.observation,
+                    reward: result.reward,
+                    terminated: result.terminated,
+                    truncated: result.truncated,
+                    info: result.info,
+                    reset_observation,
+                })
+            })
+            .collect()
+    }
+
+    pub fn render(&self) -> anyhow::Result<()> {
+        for env in self.envs.iter() {
+            env.render()?;
+        }
+        Ok(())
+    }
 }
 
 impl<const D: usize> ObservationSpace<D> {
+    // the same bounds for all the elements
+    pub fn bounded(shape: [usize; D], low: f32, high: f32) -> Self {
+        let size = shape[1..].iter().product();
+        ObservationSpace::Box {
+            shape,
+            low: vec![low; size],
+            high: vec![high; size],
+        }
+    }
+
+    pub fn unbounded(shape: [usize; D]) -> Self {
+        Self::bounded(shape, f32::NEG_INFINITY, f32::INFINITY)
+    }
+
     pub fn shape(&self) -> &[usize; D] {
         match self {
             ObservationSpace::Box { shape, .. } => shape,
         }
     }
+
+    pub fn low(&self) -> &[f32] {
+        match self {
+            ObservationSpace::Box { low, .. } => low,
+        }
+    }
+
+    pub fn high(&self) -> &[f32] {
+        match self {
+            ObservationSpace::Box { high, .. } => high,
+        }
+    }
 }
 
 pub trait Estimator<B: Backend> {
@@ -87,14 +306,83 @@ pub trait Distributional<B: Backend> {
     fn get_distribution<const D: usize>(&self, observation: Tensor<B, D>) -> Tensor<B, 3>;
 }
 
+pub trait ImplicitQuantile<B: Backend> {
+    // quantile values at `taus` of shape [batch, num_tau], returns [batch, action, num_tau]
+    fn get_quantiles<const D: usize>(
+        &self,
+        observation: Tensor<B, D>,
+        taus: Tensor<B, 2>,
+    ) -> Tensor<B, 3>;
+}
+
+pub trait FractionProposal<B: Backend> {
+    // quantile fractions 0 = tau_0 < ... < tau_N = 1, returns [batch, N + 1]
+    fn propose_fractions<const D: usize>(&self, observation: Tensor<B, D>) -> Tensor<B, 2>;
+}
+
+pub trait GaussianPolicy<B: Backend> {
+    // mean and log standard deviation of the action distribution before tanh squashing
+    fn gaussian<const D: usize>(&self, observation: Tensor<B, D>) -> (Tensor<B, 2>, Tensor<B, 2>);
+}
+
+pub trait ActionValue<B: Backend> {
+    fn action_value<const D: usize>(
+        &self,
+        observation: Tensor<B, D>,
+        action: Tensor<B, 2>,
+    ) -> Tensor<B, 2>;
+}
+
+pub trait ActorCritic<B: Backend> {
+    // policy logits for discrete actions, concatenated gaussian mean and log std for box actions,
+    // and the state value
+    fn actor_critic<const D: usize>(
+        &self,
+        observation: Tensor<B, D>,
+    ) -> (Tensor<B, 2>, Tensor<B, 2>);
+}
+
+pub trait Recurrent<B: Backend> {
+    fn recurrent_state_size(&self) -> usize;
+    // observations of shape [batch * sequence_length, ...] ordered by batch first,
+    // returns q values [batch, sequence_length, action] and the final recurrent state
+    fn predict_sequence<const D: usize>(
+        &self,
+        observation: Tensor<B, D>,
+        sequence_length: usize,
+        recurrent_state: Tensor<B, 2>,
+    ) -> (Tensor<B, 3>, Tensor<B, 2>);
+}
+
 pub trait Agent<S: State>: Clone + Send {
+    fn supports_action_space(action_space: &ActionSpace) -> bool;
     fn policy(&self, observation: &[f32]) -> Action;
+    fn batch_policy(&self, observations: &[Vec<f32>]) -> Vec<Action> {
+        observations
+            .iter()
+            .map(|observation| self.policy(observation))
+            .collect()
+    }
+    // agents that keep a memory in their state act on it, the others on the observations
+    fn batch_state_policy(&self, observations: &[Vec<f32>], _states: &[S]) -> Vec<Action> {
+        self.batch_policy(observations)
+    }
     fn update(
         &mut self,
         gamma: f32,
         experiences: &[Experience<S>],
         weights: &[f32],
     ) -> anyhow::Result<()>;
+    // noisy networks act on their mean weights outside of training
+    fn set_training(&mut self, _training: bool) {}
+    // draws the exploration noise kept until the next call
+    fn resample_noise(&mut self) {}
+    fn set_noise_seed(&mut self, _seed: u64) {}
+    // the number of updates so far, it sets the phase of the teacher sync
+    fn update_counter(&self) -> usize {
+        0
+    }
+    fn set_update_counter(&mut self, _update_counter: usize) {}
     fn make_state(&self, next_observation: &[f32], state: &S) -> S;
     fn save<P: AsRef<Path>>(&self, artifacts_dir: P) -> anyhow::Result<()>;
     fn load<P: AsRef<Path>>(&mut self, restore_dir: P) -> anyhow::Result<()>;
@@ -110,6 +398,43 @@ pub trait PrioritizedReplay<S: State> {
 
 pub trait PrioritizedReplayAgent<S: State>: Agent<S> + PrioritizedReplay<S> {}
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Sequence<S: State> {
+    pub experiences: Vec<Experience<S>>,
+    // leading experiences that only warm up the recurrent state
+    pub burn_in: usize,
+}
+
+pub trait SequenceReplay<S: State> {
+    // returns the new priorities of the sequences
+    fn update_sequences(
+        &mut self,
+        gamma: f32,
+        sequences: &[Sequence<S>],
+        weights: &[f32],
+    ) -> anyhow::Result<Vec<f32>>;
+}
+
+pub trait SequenceReplayAgent<S: State>: Agent<S> + SequenceReplay<S> {}
+
+#[derive(Debug, Clone)]
+pub struct RolloutSample {
+    pub observation: Vec<f32>,
+    pub action: Action,
+    pub log_prob: f32,
+    pub advantage: f32,
+    pub value_target: f32,
+}
+
+pub trait OnPolicy {
+    // sampled actions with their log probabilities and state value estimates
+    fn evaluate(&self, observations: &[Vec<f32>]) -> Vec<(Action, f32, f32)>;
+    fn value(&self, observations: &[Vec<f32>]) -> Vec<f32>;
+    fn update_rollout(&mut self, samples: &[RolloutSample]) -> anyhow::Result<()>;
+}
+
+pub trait OnPolicyAgent<S: State>: Agent<S> + OnPolicy {}
+
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct DeepQNetworkState {
     pub observation: Vec<f32>,
@@ -124,3 +449,299 @@ impl State for DeepQNetworkState {
         }
     }
 }
+
+impl ObservationState for DeepQNetworkState {
+    fn observation(&self) -> Vec<f32> {
+        self.observation.clone()
+    }
+
+    fn next_observation(&self) -> Vec<f32> {
+        self.next_observation.clone()
+    }
+
+    fn next_state(&self, next_observation: &[f32]) -> Self {
+        Self {
+            observation: self.next_observation.clone(),
+            next_observation: next_observation.to_vec(),
+        }
+    }
+}
+
+// the last K frames stacked along the channels, K + 1 frames are kept so that
+// `observation` and `next_observation` share their K - 1 common frames
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FrameStackState<const K: usize> {
+    // random per episode, a frame is keyed by it plus its step in the episode
+    episode: u64,
+    // step of the newest frame, K frames at step 0 and K + 1 after it
+    step: u64,
+    // empty while the replay storage keeps the frames
+    frames: Vec<Vec<f32>>,
+}
+
+impl<const K: usize> State for FrameStackState<K> {
+    // the first frame of an episode fills the whole stack
+    fn new(observation: Vec<f32>) -> Self {
+        Self {
+            episode: rand::random(),
+            step: 0,
+            frames: vec![observation; K],
+        }
+    }
+}
+
+impl<const K: usize> ObservationState for FrameStackState<K> {
+    fn observation_space<const D: usize>(
+        observation_space: &ObservationSpace<D>,
+    ) -> ObservationSpace<D> {
+        let mut shape = *observation_space.shape();
+        shape[1] *= K;
+        ObservationSpace::Box {
+            shape,
+            low: observation_space.low().repeat(K),
+            high: observation_space.high().repeat(K),
+        }
+    }
+
+    fn observation(&self) -> Vec<f32> {
+        if self.frames.len() > K {
+            self.frames[..K].concat()
+        } else {
+            Vec::new()
+        }
+    }
+
+    fn next_observation(&self) -> Vec<f32> {
+        self.frames[self.frames.len() - K..].concat()
+    }
+
+    fn next_state(&self, next_observation: &[f32]) -> Self {
+        let mut frames = self.frames[self.frames.len() - K..].to_vec();
+        frames.push(next_observation.to_vec());
+        Self {
+            episode: self.episode,
+            step: self.step + 1,
+            frames,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RecurrentState {
+    pub observation: Vec<f32>,
+    pub next_observation: Vec<f32>,
+    // recurrent states before `observation` and `next_observation` are fed, empty at the episode start
+    pub recurrent_state: Vec<f32>,
+    pub next_recurrent_state: Vec<f32>,
+}
+
+impl State for RecurrentState {
+    fn new(observation: Vec<f32>) -> Self {
+        Self {
+            observation: Vec::new(),
+            next_observation: observation,
+            recurrent_state: Vec::new(),
+            next_recurrent_state: Vec::new(),
+        }
+    }
+}
+
+impl<S: FlatState> Experience<S> {
+    pub(crate) fn without_frames(&self) -> Self {
+        Self {
+            state: self.state.without_frames(),
+            action: self.action.clone(),
+            reward: self.reward,
+            terminated: self.terminated,
+            truncated: self.truncated,
+        }
+    }
+
+    pub(crate) fn with_frames(self, frames: Vec<Vec<f32>>) -> Self {
+        Self {
+            state: self.state.with_frames(frames),
+            ..self
+        }
+    }
+}
+
+// states as the replay storage keeps them, as one row of f32 in memory, and with the frames
+// they share with the other states of their episode stored once
+pub trait FlatState: State {
+    fn to_flat(&self) -> Vec<f32>;
+    fn from_flat(flat: &[f32]) -> Self;
+    // keys of the shared frames, none by default
+    fn frame_keys(&self) -> Vec<u64> {
+        Vec::new()
+    }
+    // the shared frames in the order of their keys
+    fn frames(&self) -> Vec<&[f32]> {
+        Vec::new()
+    }
+    // the state without its shared frames, as the storage keeps it next to them
+    fn without_frames(&self) -> Self {
+        self.clone()
+    }
+    fn with_frames(self, _frames: Vec<Vec<f32>>) -> Self {
+        self
+    }
+}
+
+// each part preceded by its length
+fn flatten_parts(parts: &[&[f32]]) -> Vec<f32> {
+    let mut flat = Vec::with_capacity(parts.iter().map(|part| part.len() + 1).sum());
+    for part in parts {
+        flat.push(part.len() as f32);
+        flat.extend_from_slice(part);
+    }
+    flat
+}
+
+fn unflatten_parts(mut flat: &[f32]) -> Vec<Vec<f32>> {
+    let mut parts = Vec::new();
+    while let Some((len, rest)) = flat.split_first() {
+        let (part, rest) = rest.split_at(*len as usize);
+        parts.push(part.to_vec());
+        flat = rest;
+    }
+    parts
+}
+
+impl FlatState for DeepQNetworkState {
+    fn to_flat(&self) -> Vec<f32> {
+        flatten_parts(&[&self.observation, &self.next_observation])
+    }
+
+    fn from_flat(flat: &[f32]) -> Self {
+        let [observation, next_observation] =
+            <[_; 2]>::try_from(unflatten_parts(flat)).expect("flat DeepQNetworkState has 2 parts");
+        Self {
+            observation,
+            next_observation,
+        }
+    }
+}
+
+// a u64 as four exact 16 bit parts
+fn u64_to_flat(value: u64) -> [f32; 4] {
+    std::array::from_fn(|i| ((value >> (16 * i)) & 0xffff) as f32)
+}
+
+fn u64_from_flat(flat: &[f32]) -> u64 {
+    flat[..4]
+        .iter()
+        .enumerate()
+        .fold(0, |value, (i, part)| value | (*part as u64) << (16 * i))
+}
+
+impl<const K: usize> FlatState for FrameStackState<K> {
+    fn to_flat(&self) -> Vec<f32> {
+        let mut flat = u64_to_flat(self.episode).to_vec();
+        flat.extend(u64_to_flat(self.step));
+        flat.extend(flatten_parts(
+            &self.frames.iter().map(Vec::as_slice).collect::<Vec<_>>(),
+        ));
+        flat
+    }
+
+    fn from_flat(flat: &[f32]) -> Self {
+        Self {
+            episode: u64_from_flat(flat),
+            step: u64_from_flat(&flat[4..]),
+            frames: unflatten_parts(&flat[8..]),
+        }
+    }
+
+    // the frames before the episode start repeat its first one
+    fn frame_keys(&self) -> Vec<u64> {
+        let len = if self.step == 0 { K } else { K + 1 };
+        (0..len)
+            .map(|i| {
+                let step = self.step.saturating_sub((len - 1 - i) as u64);
+                self.episode.wrapping_add(step)
+            })
+            .collect()
+    }
+
+    fn frames(&self) -> Vec<&[f32]> {
+        self.frames.iter().map(Vec::as_slice).collect()
+    }
+
+    fn without_frames(&self) -> Self {
+        Self {
+            episode: self.episode,
+            step: self.step,
+            frames: Vec::new(),
+        }
+    }
+
+    fn with_frames(self, frames: Vec<Vec<f32>>) -> Self {
+        Self { frames, ..self }
+    }
+}
+
+impl FlatState for RecurrentState {
+    fn to_flat(&self) -> Vec<f32> {
+        flatten_parts(&[
+            &self.observation,
+            &self.next_observation,
+            &self.recurrent_state,
+            &self.next_recurrent_state,
+        ])
+    }
+
+    fn from_flat(flat: &[f32]) -> Self {
+        let [observation, next_observation, recurrent_state, next_recurrent_state] =
+            <[_; 4]>::try_from(unflatten_parts(flat)).expect("flat RecurrentState has 4 parts");
+        Self {
+            observation,
+            next_observation,
+            recurrent_state,
+            next_recurrent_state,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame_stack_state() {
+        let state = FrameStackState::<3>::new(vec![0.0, 0.5]);
+        assert!(state.observation().is_empty());
+        assert_eq!(state.next_observation(), vec![0.0, 0.5, 0.0, 0.5, 0.0, 0.5]);
+
+        let state = state.next_state(&[1.0, 1.5]).next_state(&[2.0, 2.5]);
+        assert_eq!(state.observation(), vec![0.0, 0.5, 0.0, 0.5, 1.0, 1.5]);
+        assert_eq!(state.next_observation(), vec![0.0, 0.5, 1.0, 1.5, 2.0, 2.5]);
+
+        assert_eq!(
+            FrameStackState::<4>::observation_space(&ObservationSpace::bounded(
+                [1, 3, 84, 84],
+                0.0,
+                255.0
+            )),
+            ObservationSpace::bounded([1, 12, 84, 84], 0.0, 255.0)
+        );
+
+        // consecutive states share the keys of their common frames
+        let first = FrameStackState::<3>::new(vec![0.0]);
+        let second = first.next_state(&[1.0]);
+        let third = second.next_state(&[2.0]);
+        let keys = [first.frame_keys(), second.frame_keys(), third.frame_keys()];
+        assert_eq!(keys[0], vec![first.episode; 3]);
+        assert_eq!(keys[1][..3], keys[0]);
+        assert_eq!(keys[2][..3], keys[1][1..]);
+        assert_eq!(keys[2][3], first.episode.wrapping_add(2));
+        assert_ne!(first.episode, FrameStackState::<3>::new(vec![0.0]).episode);
+
+        let stored = FrameStackState::<3>::from_flat(&third.without_frames().to_flat());
+        assert!(stored.frames().is_empty());
+        assert_eq!(stored.frame_keys(), keys[2]);
+        let restored =
+            stored.with_frames(third.frames().into_iter().map(<[f32]>::to_vec).collect());
+        assert_eq!(restored.observation(), third.observation());
+        assert_eq!(restored.next_observation(), third.next_observation());
+    }
+}
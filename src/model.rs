@@ -1,16 +1,26 @@
+use std::str::FromStr;
+
 use crate::{
-    layers::{NoisyLinear, NoisyLinearConfig},
-    ActionSpace, Distributional, Estimator, ObservationSpace,
+    layers::{Noisy, NoisyLinear, NoisyLinearConfig},
+    ActionSpace, ActionValue, ActorCritic, Distributional, Estimator, FractionProposal,
+    GaussianPolicy, ImplicitQuantile, ObservationSpace, Recurrent,
 };
+use anyhow::{bail, ensure};
 use burn::{
-    module::Module,
+    config::Config,
+    module::{Module, Param},
     nn::{
-        conv::{Conv2d, Conv2dConfig},
-        Linear, LinearConfig, Relu,
+        conv::{Conv1d, Conv1dConfig, Conv2d, Conv2dConfig},
+        gru::{Gru, GruConfig},
+        lstm::{Lstm, LstmConfig, LstmState},
+        pool::{MaxPool2d, MaxPool2dConfig},
+        LayerNorm, LayerNormConfig, Linear, LinearConfig, PaddingConfig2d, Relu, Tanh,
     },
     prelude::Backend,
-    tensor::{Shape, Tensor, TensorData},
+    tensor::{Distribution, Shape, Tensor, TensorData},
 };
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
 
 #[derive(Module, Debug)]
 pub enum LinearLayerType<B: Backend> {
@@ -19,6 +29,16 @@ pub enum LinearLayerType<B: Backend> {
 }
 
 impl<B: Backend> LinearLayerType<B> {
+    pub fn new(device: &B::Device, input_size: usize, output_size: usize, noisy: bool) -> Self {
+        if noisy {
+            LinearLayerType::NoisyLinear(
+                NoisyLinearConfig::new(input_size, output_size).init(device),
+            )
+        } else {
+            LinearLayerType::Linear(LinearConfig::new(input_size, output_size).init(device))
+        }
+    }
+
     pub fn forward(&self, x: Tensor<B, 2>) -> Tensor<B, 2> {
         match self {
             LinearLayerType::Linear(layer) => layer.forward(x),
@@ -27,45 +47,88 @@ impl<B: Backend> LinearLayerType<B> {
     }
 }
 
+// hidden layers of the value and advantage streams
+#[derive(Config, Debug)]
+pub struct HeadConfig {
+    #[config(default = 64)]
+    pub hidden_width: usize,
+    #[config(default = 1)]
+    pub hidden_layers: usize,
+}
+
 #[derive(Module, Debug)]
-pub struct LinearValueLayer<B: Backend> {
-    linear1: LinearLayerType<B>,
-    linear2: LinearLayerType<B>,
+pub struct MultiLayerPerceptron<B: Backend> {
+    hidden: Vec<LinearLayerType<B>>,
+    output: LinearLayerType<B>,
     activation: Relu,
 }
 
-impl<B: Backend> LinearValueLayer<B> {
-    pub fn new(device: &B::Device, input_size: usize, output_size: usize, noisy: bool) -> Self {
+impl<B: Backend> MultiLayerPerceptron<B> {
+    pub fn new(
+        device: &B::Device,
+        input_size: usize,
+        output_size: usize,
+        head_config: &HeadConfig,
+        noisy: bool,
+    ) -> Self {
+        let hidden = (0..head_config.hidden_layers)
+            .map(|i| {
+                let input_size = if i == 0 {
+                    input_size
+                } else {
+                    head_config.hidden_width
+                };
+                LinearLayerType::new(device, input_size, head_config.hidden_width, noisy)
+            })
+            .collect::<Vec<_>>();
+        let input_size = if hidden.is_empty() {
+            input_size
+        } else {
+            head_config.hidden_width
+        };
         Self {
-            linear1: if noisy {
-                LinearLayerType::NoisyLinear(
-                    NoisyLinearConfig::new(input_size, input_size).init(device),
-                )
-            } else {
-                LinearLayerType::Linear(LinearConfig::new(input_size, 64).init(device))
-            },
-            linear2: if noisy {
-                LinearLayerType::NoisyLinear(NoisyLinearConfig::new(64, output_size).init(device))
-            } else {
-                LinearLayerType::Linear(LinearConfig::new(64, output_size).init(device))
-            },
+            hidden,
+            output: LinearLayerType::new(device, input_size, output_size, noisy),
             activation: Relu::new(),
         }
     }
 
     pub fn forward(&self, x: Tensor<B, 2>) -> Tensor<B, 2> {
-        let x = self.activation.forward(self.linear1.forward(x));
-        self.linear2.forward(x)
+        let x = self
+            .hidden
+            .iter()
+            .fold(x, |x, layer| self.activation.forward(layer.forward(x)));
+        self.output.forward(x)
+    }
+}
+
+#[derive(Module, Debug)]
+pub struct LinearValueLayer<B: Backend> {
+    value: MultiLayerPerceptron<B>,
+}
+
+impl<B: Backend> LinearValueLayer<B> {
+    pub fn new(
+        device: &B::Device,
+        input_size: usize,
+        output_size: usize,
+        head_config: &HeadConfig,
+        noisy: bool,
+    ) -> Self {
+        Self {
+            value: MultiLayerPerceptron::new(device, input_size, output_size, head_config, noisy),
+        }
+    }
+
+    pub fn forward(&self, x: Tensor<B, 2>) -> Tensor<B, 2> {
+        self.value.forward(x)
     }
 }
 
 #[derive(Module, Debug)]
 pub struct DuelingLayer<B: Backend> {
-    value_linear1: LinearLayerType<B>,
-    value_linear2: LinearLayerType<B>,
-    advantage_linear1: LinearLayerType<B>,
-    advantage_linear2: LinearLayerType<B>,
-    activation: Relu,
+    value: MultiLayerPerceptron<B>,
+    advantage: MultiLayerPerceptron<B>,
     num_class: usize,
     atoms: usize,
 }
@@ -76,32 +139,18 @@ impl<B: Backend> DuelingLayer<B> {
         input_size: usize,
         num_class: usize,
         atoms: usize,
+        head_config: &HeadConfig,
         noisy: bool,
     ) -> Self {
         Self {
-            value_linear1: if noisy {
-                LinearLayerType::NoisyLinear(NoisyLinearConfig::new(input_size, 64).init(device))
-            } else {
-                LinearLayerType::Linear(LinearConfig::new(input_size, 64).init(device))
-            },
-            value_linear2: if noisy {
-                LinearLayerType::NoisyLinear(NoisyLinearConfig::new(64, atoms).init(device))
-            } else {
-                LinearLayerType::Linear(LinearConfig::new(64, atoms).init(device))
-            },
-            advantage_linear1: if noisy {
-                LinearLayerType::NoisyLinear(NoisyLinearConfig::new(input_size, 64).init(device))
-            } else {
-                LinearLayerType::Linear(LinearConfig::new(input_size, 64).init(device))
-            },
-            advantage_linear2: if noisy {
-                LinearLayerType::NoisyLinear(
-                    NoisyLinearConfig::new(64, num_class * atoms).init(device),
-                )
-            } else {
-                LinearLayerType::Linear(LinearConfig::new(64, num_class * atoms).init(device))
-            },
-            activation: Relu::new(),
+            value: MultiLayerPerceptron::new(device, input_size, atoms, head_config, noisy),
+            advantage: MultiLayerPerceptron::new(
+                device,
+                input_size,
+                num_class * atoms,
+                head_config,
+                noisy,
+            ),
             num_class,
             atoms,
         }
@@ -109,14 +158,8 @@ impl<B: Backend> DuelingLayer<B> {
 
     pub fn forward(&self, x: Tensor<B, 2>) -> Tensor<B, 2> {
         let batch_size = x.shape().dims[0];
-        let value = self
-            .activation
-            .forward(self.value_linear1.forward(x.clone()));
-        let value = self.value_linear2.forward(value);
-        let advantage = self
-            .activation
-            .forward(self.advantage_linear1.forward(x.clone()));
-        let advantage = self.advantage_linear2.forward(advantage);
+        let value = self.value.forward(x.clone());
+        let advantage = self.advantage.forward(x);
         let advantage = advantage.clone() - advantage.clone().mean_dim(1);
         let output = value.reshape([batch_size, 1, self.atoms])
             + advantage.reshape([batch_size, self.num_class, self.atoms]);
@@ -140,10 +183,395 @@ impl<B: Backend> ValueLayer<B> {
     }
 }
 
+impl<B: Backend> Noisy<B> for LinearLayerType<B> {
+    fn map_noisy<F: FnMut(NoisyLinear<B>) -> NoisyLinear<B>>(self, f: &mut F) -> Self {
+        match self {
+            LinearLayerType::Linear(layer) => LinearLayerType::Linear(layer),
+            LinearLayerType::NoisyLinear(layer) => LinearLayerType::NoisyLinear(f(layer)),
+        }
+    }
+}
+
+impl<B: Backend> Noisy<B> for MultiLayerPerceptron<B> {
+    fn map_noisy<F: FnMut(NoisyLinear<B>) -> NoisyLinear<B>>(self, f: &mut F) -> Self {
+        Self {
+            hidden: self
+                .hidden
+                .into_iter()
+                .map(|layer| layer.map_noisy(f))
+                .collect(),
+            output: self.output.map_noisy(f),
+            ..self
+        }
+    }
+}
+
+impl<B: Backend> Noisy<B> for ValueLayer<B> {
+    fn map_noisy<F: FnMut(NoisyLinear<B>) -> NoisyLinear<B>>(self, f: &mut F) -> Self {
+        match self {
+            ValueLayer::Linear(layer) => ValueLayer::Linear(LinearValueLayer {
+                value: layer.value.map_noisy(f),
+            }),
+            ValueLayer::Dueling(layer) => ValueLayer::Dueling(DuelingLayer {
+                value: layer.value.map_noisy(f),
+                advantage: layer.advantage.map_noisy(f),
+                ..layer
+            }),
+        }
+    }
+}
+
+// a layer of the observation encoder, the convolutions are 1d on rank 3 observations
+// and 2d on rank 4 ones, and the first linear layer flattens the features
+#[derive(Config, Debug, PartialEq)]
+pub enum TorsoLayerConfig {
+    Linear {
+        width: usize,
+    },
+    Conv {
+        channels: usize,
+        kernel_size: usize,
+        stride: usize,
+    },
+    // a 3x3 convolution, a 3x3 stride 2 max pooling and two residual blocks
+    ImpalaStage {
+        channels: usize,
+    },
+    Relu,
+    Tanh,
+    // over the features, or over the channels before flattening
+    LayerNorm,
+}
+
+#[derive(Config, Debug, PartialEq)]
+pub struct TorsoConfig {
+    pub layers: Vec<TorsoLayerConfig>,
+}
+
+impl TorsoConfig {
+    // a 64-64 mlp for vector observations, two 4x4 stride 4 convolutions otherwise
+    pub fn default_for<const D: usize>() -> Self {
+        let layers = if D == 2 {
+            vec![
+                TorsoLayerConfig::Linear { width: 64 },
+                TorsoLayerConfig::Relu,
+                TorsoLayerConfig::Linear { width: 64 },
+                TorsoLayerConfig::Relu,
+            ]
+        } else {
+            vec![
+                TorsoLayerConfig::Conv {
+                    channels: 16,
+                    kernel_size: 4,
+                    stride: 4,
+                },
+                TorsoLayerConfig::Relu,
+                TorsoLayerConfig::Conv {
+                    channels: 32,
+                    kernel_size: 4,
+                    stride: 4,
+                },
+                TorsoLayerConfig::Relu,
+            ]
+        };
+        Self { layers }
+    }
+
+    // the encoder of the Nature DQN paper for 84x84 frames
+    pub fn nature_dqn() -> Self {
+        let conv = |channels, kernel_size, stride| TorsoLayerConfig::Conv {
+            channels,
+            kernel_size,
+            stride,
+        };
+        Self {
+            layers: vec![
+                conv(32, 8, 4),
+                TorsoLayerConfig::Relu,
+                conv(64, 4, 2),
+                TorsoLayerConfig::Relu,
+                conv(64, 3, 1),
+                TorsoLayerConfig::Relu,
+                TorsoLayerConfig::Linear { width: 512 },
+                TorsoLayerConfig::Relu,
+            ],
+        }
+    }
+
+    // the residual encoder of IMPALA
+    pub fn impala() -> Self {
+        Self {
+            layers: vec![
+                TorsoLayerConfig::ImpalaStage { channels: 16 },
+                TorsoLayerConfig::ImpalaStage { channels: 32 },
+                TorsoLayerConfig::ImpalaStage { channels: 32 },
+                TorsoLayerConfig::Relu,
+                TorsoLayerConfig::Linear { width: 256 },
+                TorsoLayerConfig::Relu,
+            ],
+        }
+    }
+
+    // checks the layers against the rank and the size of the observations
+    pub fn init<B: Backend, const D: usize>(
+        &self,
+        device: &B::Device,
+        observation_space: &ObservationSpace<D>,
+    ) -> anyhow::Result<Torso<B>> {
+        ensure!(
+            (2..=4).contains(&D),
+            "the torso takes rank 2 to 4 observations, got rank {}",
+            D
+        );
+        // the shape of a single observation along the layers
+        let mut shape = observation_space.shape()[1..].to_vec();
+        let layers = self
+            .layers
+            .iter()
+            .map(|layer| {
+                let layer = match *layer {
+                    TorsoLayerConfig::Linear { width } => {
+                        let layer = LinearConfig::new(shape.iter().product(), width).init(device);
+                        shape = vec![width];
+                        TorsoLayer::Linear(layer)
+                    }
+                    TorsoLayerConfig::Conv {
+                        channels,
+                        kernel_size,
+                        stride,
+                    } => {
+                        ensure!(
+                            shape.len() > 1 && shape[1..].iter().all(|size| kernel_size <= *size),
+                            "{:?} needs spatial features of at least the kernel size, got {:?}",
+                            layer,
+                            shape
+                        );
+                        ensure!(stride > 0, "{:?} needs a positive stride", layer);
+                        match shape[..] {
+                            [in_channels, length] => {
+                                shape = vec![channels, (length - kernel_size) / stride + 1];
+                                TorsoLayer::Conv1d(
+                                    Conv1dConfig::new(in_channels, channels, kernel_size)
+                                        .with_stride(stride)
+                                        .init(device),
+                                )
+                            }
+                            [in_channels, height, width] => {
+                                shape = vec![
+                                    channels,
+                                    (height - kernel_size) / stride + 1,
+                                    (width - kernel_size) / stride + 1,
+                                ];
+                                TorsoLayer::Conv2d(
+                                    Conv2dConfig::new(
+                                        [in_channels, channels],
+                                        [kernel_size, kernel_size],
+                                    )
+                                    .with_stride([stride, stride])
+                                    .init(device),
+                                )
+                            }
+                            _ => unreachable!("the features are at most rank 3"),
+                        }
+                    }
+                    TorsoLayerConfig::ImpalaStage { channels } => match shape[..] {
+                        [in_channels, height, width] => {
+                            shape = vec![channels, (height - 1) / 2 + 1, (width - 1) / 2 + 1];
+                            TorsoLayer::ImpalaStage(ImpalaStage::new(device, in_channels, channels))
+                        }
+                        _ => bail!("{:?} needs image features, got {:?}", layer, shape),
+                    },
+                    TorsoLayerConfig::Relu => TorsoLayer::Relu(Relu::new()),
+                    TorsoLayerConfig::Tanh => TorsoLayer::Tanh(Tanh::new()),
+                    TorsoLayerConfig::LayerNorm => {
+                        TorsoLayer::LayerNorm(LayerNormConfig::new(shape[0]).init(device))
+                    }
+                };
+                Ok(layer)
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Torso {
+            layers,
+            output_dim: shape.iter().product(),
+        })
+    }
+}
+
+// `linear:64,relu,layer_norm` or `conv:32:8:4,relu,linear:512,tanh`, conv taking
+// the channels, the kernel size and the stride and impala the channels
+impl FromStr for TorsoConfig {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let layers = s
+            .split(',')
+            .map(|layer| {
+                let parts = layer.trim().split(':').collect::<Vec<_>>();
+                let layer = match parts[..] {
+                    ["linear", width] => TorsoLayerConfig::Linear {
+                        width: width.parse()?,
+                    },
+                    ["conv", channels, kernel_size, stride] => TorsoLayerConfig::Conv {
+                        channels: channels.parse()?,
+                        kernel_size: kernel_size.parse()?,
+                        stride: stride.parse()?,
+                    },
+                    ["impala", channels] => TorsoLayerConfig::ImpalaStage {
+                        channels: channels.parse()?,
+                    },
+                    ["relu"] => TorsoLayerConfig::Relu,
+                    ["tanh"] => TorsoLayerConfig::Tanh,
+                    ["layer_norm"] => TorsoLayerConfig::LayerNorm,
+                    _ => bail!("unknown torso layer {:?}", layer),
+                };
+                Ok(layer)
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(Self { layers })
+    }
+}
+
+#[derive(Module, Debug)]
+pub struct ResidualBlock<B: Backend> {
+    conv1: Conv2d<B>,
+    conv2: Conv2d<B>,
+    activation: Relu,
+}
+
+impl<B: Backend> ResidualBlock<B> {
+    pub fn new(device: &B::Device, channels: usize) -> Self {
+        let conv = || {
+            Conv2dConfig::new([channels, channels], [3, 3])
+                .with_padding(PaddingConfig2d::Explicit(1, 1))
+                .init(device)
+        };
+        Self {
+            conv1: conv(),
+            conv2: conv(),
+            activation: Relu::new(),
+        }
+    }
+
+    pub fn forward(&self, x: Tensor<B, 4>) -> Tensor<B, 4> {
+        let y = self.conv1.forward(self.activation.forward(x.clone()));
+        let y = self.conv2.forward(self.activation.forward(y));
+        x + y
+    }
+}
+
+#[derive(Module, Debug)]
+pub struct ImpalaStage<B: Backend> {
+    conv: Conv2d<B>,
+    pool: MaxPool2d,
+    block1: ResidualBlock<B>,
+    block2: ResidualBlock<B>,
+}
+
+impl<B: Backend> ImpalaStage<B> {
+    pub fn new(device: &B::Device, in_channels: usize, channels: usize) -> Self {
+        Self {
+            conv: Conv2dConfig::new([in_channels, channels], [3, 3])
+                .with_padding(PaddingConfig2d::Explicit(1, 1))
+                .init(device),
+            pool: MaxPool2dConfig::new([3, 3])
+                .with_strides([2, 2])
+                .with_padding(PaddingConfig2d::Explicit(1, 1))
+                .init(),
+            block1: ResidualBlock::new(device, channels),
+            block2: ResidualBlock::new(device, channels),
+        }
+    }
+
+    pub fn forward(&self, x: Tensor<B, 4>) -> Tensor<B, 4> {
+        let x = self.pool.forward(self.conv.forward(x));
+        self.block2.forward(self.block1.forward(x))
+    }
+}
+
 #[derive(Module, Debug)]
-pub enum FeatureExtractionLayer<B: Backend> {
+#[allow(clippy::large_enum_variant)]
+pub enum TorsoLayer<B: Backend> {
     Linear(Linear<B>),
+    Conv1d(Conv1d<B>),
     Conv2d(Conv2d<B>),
+    ImpalaStage(ImpalaStage<B>),
+    Relu(Relu),
+    Tanh(Tanh),
+    LayerNorm(LayerNorm<B>),
+}
+
+impl<B: Backend> TorsoLayer<B> {
+    // the layers that keep the rank of the features
+    fn forward_elementwise<const D: usize>(&self, x: Tensor<B, D>) -> Tensor<B, D> {
+        match self {
+            TorsoLayer::Relu(layer) => layer.forward(x),
+            TorsoLayer::Tanh(layer) => layer.forward(x),
+            TorsoLayer::LayerNorm(layer) if D == 2 => layer.forward(x),
+            TorsoLayer::LayerNorm(layer) => {
+                layer.forward(x.swap_dims(1, D - 1)).swap_dims(1, D - 1)
+            }
+            _ => unreachable!("not an elementwise layer"),
+        }
+    }
+}
+
+enum Features<B: Backend> {
+    Flat(Tensor<B, 2>),
+    Sequence(Tensor<B, 3>),
+    Image(Tensor<B, 4>),
+}
+
+impl<B: Backend> Features<B> {
+    fn flatten(self) -> Tensor<B, 2> {
+        match self {
+            Features::Flat(x) => x,
+            Features::Sequence(x) => x.flatten(1, 2),
+            Features::Image(x) => x.flatten(1, 3),
+        }
+    }
+}
+
+#[derive(Module, Debug)]
+pub struct Torso<B: Backend> {
+    layers: Vec<TorsoLayer<B>>,
+    output_dim: usize,
+}
+
+impl<B: Backend> Torso<B> {
+    pub fn output_dim(&self) -> usize {
+        self.output_dim
+    }
+
+    pub fn forward<const D: usize>(&self, observation: Tensor<B, D>) -> Tensor<B, 2> {
+        let shape = observation.dims();
+        let mut x = match D {
+            2 => Features::Flat(observation.reshape([shape[0], shape[1]])),
+            3 => Features::Sequence(observation.reshape([shape[0], shape[1], shape[2]])),
+            4 => Features::Image(observation.reshape([shape[0], shape[1], shape[2], shape[3]])),
+            _ => unreachable!("the torso config checks the observation rank"),
+        };
+        for layer in &self.layers {
+            x = match (layer, x) {
+                (TorsoLayer::Linear(layer), x) => Features::Flat(layer.forward(x.flatten())),
+                (TorsoLayer::Conv1d(layer), Features::Sequence(x)) => {
+                    Features::Sequence(layer.forward(x))
+                }
+                (TorsoLayer::Conv2d(layer), Features::Image(x)) => {
+                    Features::Image(layer.forward(x))
+                }
+                (TorsoLayer::ImpalaStage(layer), Features::Image(x)) => {
+                    Features::Image(layer.forward(x))
+                }
+                (TorsoLayer::Conv1d(_) | TorsoLayer::Conv2d(_) | TorsoLayer::ImpalaStage(_), _) => {
+                    unreachable!("the torso config checks the convolution inputs")
+                }
+                (layer, Features::Flat(x)) => Features::Flat(layer.forward_elementwise(x)),
+                (layer, Features::Sequence(x)) => Features::Sequence(layer.forward_elementwise(x)),
+                (layer, Features::Image(x)) => Features::Image(layer.forward_elementwise(x)),
+            };
+        }
+        x.flatten()
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -157,6 +585,15 @@ pub enum OutputLayerConfig {
     QuantileRegression {
         quantiles: usize,
     },
+    ImplicitQuantile {
+        // number of taus sampled per forward pass
+        quantiles: usize,
+        embedding_dim: usize,
+    },
+    FullyParameterizedQuantile {
+        quantiles: usize,
+        embedding_dim: usize,
+    },
 }
 
 impl OutputLayerConfig {
@@ -165,6 +602,8 @@ impl OutputLayerConfig {
             OutputLayerConfig::Expectation => 1,
             OutputLayerConfig::CategoricalDistribution { atoms, .. } => *atoms,
             OutputLayerConfig::QuantileRegression { quantiles } => *quantiles,
+            OutputLayerConfig::ImplicitQuantile { quantiles, .. } => *quantiles,
+            OutputLayerConfig::FullyParameterizedQuantile { quantiles, .. } => *quantiles,
         }
     }
 }
@@ -249,76 +688,169 @@ impl<B: Backend> QuantileRegressionLayer<B> {
     }
 }
 
+#[derive(Module, Debug)]
+pub struct ImplicitQuantileLayer<B: Backend> {
+    embedding: Linear<B>,
+    value_layer: ValueLayer<B>,
+    activation: Relu,
+    quantiles: usize,
+    embedding_dim: usize,
+    action_num: usize,
+}
+
+impl<B: Backend> ImplicitQuantileLayer<B> {
+    pub fn new(
+        device: &B::Device,
+        value_layer: ValueLayer<B>,
+        feature_dim: usize,
+        quantiles: usize,
+        embedding_dim: usize,
+        action_num: usize,
+    ) -> Self {
+        Self {
+            embedding: LinearConfig::new(embedding_dim, feature_dim).init(device),
+            value_layer,
+            activation: Relu::new(),
+            quantiles,
+            embedding_dim,
+            action_num,
+        }
+    }
+
+    pub fn forward(&self, x: Tensor<B, 2>) -> Tensor<B, 2> {
+        let shape = x.shape().dims;
+        let quantiles = self.forward_distribution(x);
+        let q_means = quantiles.mean_dim(2);
+        q_means.reshape([shape[0], self.action_num])
+    }
+
+    pub fn forward_distribution(&self, x: Tensor<B, 2>) -> Tensor<B, 3> {
+        let taus = Tensor::random(
+            [x.shape().dims[0], self.quantiles],
+            Distribution::Uniform(0.0, 1.0),
+            &x.device(),
+        );
+        self.forward_quantiles(x, taus)
+    }
+
+    pub fn forward_quantiles(&self, x: Tensor<B, 2>, taus: Tensor<B, 2>) -> Tensor<B, 3> {
+        let [batch_size, feature_dim] = x.dims();
+        let num_tau = taus.shape().dims[1];
+        // cos(pi * i * tau) for i in 0..embedding_dim
+        let i = Tensor::arange(0..self.embedding_dim as i64, &x.device())
+            .float()
+            .reshape([1, 1, self.embedding_dim]);
+        let embedding = (taus.reshape([batch_size, num_tau, 1]) * i)
+            .mul_scalar(std::f32::consts::PI)
+            .cos()
+            .reshape([batch_size * num_tau, self.embedding_dim]);
+        let embedding = self
+            .activation
+            .forward(self.embedding.forward(embedding))
+            .reshape([batch_size, num_tau, feature_dim]);
+        let x = (x.reshape([batch_size, 1, feature_dim]) * embedding)
+            .reshape([batch_size * num_tau, feature_dim]);
+        self.value_layer
+            .forward(x)
+            .reshape([batch_size, num_tau, self.action_num])
+            .swap_dims(1, 2)
+    }
+}
+
+#[derive(Module, Debug)]
+pub struct FullyParameterizedQuantileLayer<B: Backend> {
+    fraction: Linear<B>,
+    quantile_layer: ImplicitQuantileLayer<B>,
+    quantiles: usize,
+    action_num: usize,
+}
+
+impl<B: Backend> FullyParameterizedQuantileLayer<B> {
+    pub fn new(
+        device: &B::Device,
+        quantile_layer: ImplicitQuantileLayer<B>,
+        feature_dim: usize,
+        quantiles: usize,
+        action_num: usize,
+    ) -> Self {
+        Self {
+            fraction: LinearConfig::new(feature_dim, quantiles).init(device),
+            quantile_layer,
+            quantiles,
+            action_num,
+        }
+    }
+
+    pub fn forward(&self, x: Tensor<B, 2>) -> Tensor<B, 2> {
+        let batch_size = x.dims()[0];
+        let taus = self.forward_fractions(x.clone()).detach();
+        let tau_hats = Self::tau_hats(taus.clone());
+        let quantiles = self.quantile_layer.forward_quantiles(x, tau_hats);
+        let weights = taus.clone().slice([0..batch_size, 1..self.quantiles + 1])
+            - taus.slice([0..batch_size, 0..self.quantiles]);
+        (quantiles * weights.reshape([batch_size, 1, self.quantiles]))
+            .sum_dim(2)
+            .reshape([batch_size, self.action_num])
+    }
+
+    pub fn forward_distribution(&self, x: Tensor<B, 2>) -> Tensor<B, 3> {
+        let taus = self.forward_fractions(x.clone()).detach();
+        self.quantile_layer
+            .forward_quantiles(x, Self::tau_hats(taus))
+    }
+
+    pub fn forward_fractions(&self, x: Tensor<B, 2>) -> Tensor<B, 2> {
+        // the fraction proposal network does not train the feature extractor
+        let probs = burn::tensor::activation::softmax(self.fraction.forward(x.detach()), 1);
+        // tau_i = sum_{j < i} p_j
+        let cumsum = (0..self.quantiles)
+            .flat_map(|j| (0..self.quantiles + 1).map(move |i| if j < i { 1.0 } else { 0.0 }))
+            .collect::<Vec<f32>>();
+        let cumsum = Tensor::from_data(
+            TensorData::new(cumsum, Shape::new([self.quantiles, self.quantiles + 1]))
+                .convert::<B::FloatElem>(),
+            &probs.device(),
+        );
+        probs.matmul(cumsum)
+    }
+
+    pub fn tau_hats(taus: Tensor<B, 2>) -> Tensor<B, 2> {
+        let [batch_size, num_fraction] = taus.dims();
+        (taus.clone().slice([0..batch_size, 0..num_fraction - 1])
+            + taus.slice([0..batch_size, 1..num_fraction]))
+        .div_scalar(2.0)
+    }
+}
+
 #[derive(Module, Debug)]
 pub enum OutputLayer<B: Backend> {
     Expectation(ValueLayer<B>),
     CategoricalDistribution(CategoricalDistributionLayer<B>),
     QuantileRegression(QuantileRegressionLayer<B>),
+    ImplicitQuantile(ImplicitQuantileLayer<B>),
+    FullyParameterizedQuantile(FullyParameterizedQuantileLayer<B>),
 }
 
 #[derive(Module, Debug)]
 pub struct DeepQNetworkModel<B: Backend> {
-    layer1: FeatureExtractionLayer<B>,
-    layer2: FeatureExtractionLayer<B>,
+    torso: Torso<B>,
     output_layer: OutputLayer<B>,
-    activation: Relu,
 }
 
 impl<B: Backend> DeepQNetworkModel<B> {
+    #[allow(clippy::too_many_arguments)]
     pub fn new<const D: usize>(
         device: &B::Device,
         observation_space: &ObservationSpace<D>,
         action_space: &ActionSpace,
+        torso_config: &TorsoConfig,
+        head_config: &HeadConfig,
         dueling: bool,
         noisy: bool,
         output_layer_config: OutputLayerConfig,
-    ) -> Self {
-        let stride = 4;
-        let kernel_size = 4;
-        let value_layer_input_dim = if D == 2 {
-            64
-        } else if D == 4 {
-            let shape = observation_space.shape();
-            let shape = [shape[1], shape[2], shape[3]];
-            let shape = [
-                16,
-                (shape[1] - kernel_size) / stride + 1,
-                (shape[2] - kernel_size) / stride + 1,
-            ];
-            let shape = [
-                32,
-                (shape[1] - kernel_size) / stride + 1,
-                (shape[2] - kernel_size) / stride + 1,
-            ];
-            shape.iter().product()
-        } else {
-            unimplemented!()
-        };
-        let layer1 = if D == 2 {
-            FeatureExtractionLayer::Linear(
-                LinearConfig::new(observation_space.shape()[1], 64).init(device),
-            )
-        } else if D == 4 {
-            FeatureExtractionLayer::Conv2d(
-                Conv2dConfig::new([3, 16], [kernel_size, kernel_size])
-                    .with_stride([stride, stride])
-                    .init(device),
-            )
-        } else {
-            unimplemented!()
-        };
-
-        let layer2 = if D == 2 {
-            FeatureExtractionLayer::Linear(LinearConfig::new(64, 64).init(device))
-        } else if D == 4 {
-            FeatureExtractionLayer::Conv2d(
-                Conv2dConfig::new([16, 32], [kernel_size, kernel_size])
-                    .with_stride([stride, stride])
-                    .init(device),
-            )
-        } else {
-            unimplemented!()
-        };
+    ) -> anyhow::Result<Self> {
+        let torso = torso_config.init(device, observation_space)?;
+        let value_layer_input_dim = torso.output_dim();
 
         let output_layer = match output_layer_config {
             OutputLayerConfig::Expectation => {
@@ -328,6 +860,7 @@ impl<B: Backend> DeepQNetworkModel<B> {
                         value_layer_input_dim,
                         1,
                         action_space.size(),
+                        head_config,
                         noisy,
                     ))
                 } else {
@@ -335,6 +868,7 @@ impl<B: Backend> DeepQNetworkModel<B> {
                         device,
                         value_layer_input_dim,
                         action_space.size(),
+                        head_config,
                         noisy,
                     ))
                 };
@@ -351,6 +885,7 @@ impl<B: Backend> DeepQNetworkModel<B> {
                         value_layer_input_dim,
                         action_space.size(),
                         atoms,
+                        head_config,
                         noisy,
                     ))
                 } else {
@@ -358,6 +893,7 @@ impl<B: Backend> DeepQNetworkModel<B> {
                         device,
                         value_layer_input_dim,
                         atoms * action_space.size(),
+                        head_config,
                         noisy,
                     ))
                 };
@@ -377,6 +913,7 @@ impl<B: Backend> DeepQNetworkModel<B> {
                         value_layer_input_dim,
                         action_space.size(),
                         quantiles,
+                        head_config,
                         noisy,
                     ))
                 } else {
@@ -384,6 +921,7 @@ impl<B: Backend> DeepQNetworkModel<B> {
                         device,
                         value_layer_input_dim,
                         quantiles * action_space.size(),
+                        head_config,
                         noisy,
                     ))
                 };
@@ -393,39 +931,92 @@ impl<B: Backend> DeepQNetworkModel<B> {
                     action_space.size(),
                 ))
             }
+            OutputLayerConfig::ImplicitQuantile {
+                quantiles,
+                embedding_dim,
+            } => {
+                let value_layer = if dueling {
+                    ValueLayer::Dueling(DuelingLayer::new(
+                        device,
+                        value_layer_input_dim,
+                        action_space.size(),
+                        1,
+                        head_config,
+                        noisy,
+                    ))
+                } else {
+                    ValueLayer::Linear(LinearValueLayer::new(
+                        device,
+                        value_layer_input_dim,
+                        action_space.size(),
+                        head_config,
+                        noisy,
+                    ))
+                };
+                OutputLayer::ImplicitQuantile(ImplicitQuantileLayer::new(
+                    device,
+                    value_layer,
+                    value_layer_input_dim,
+                    quantiles,
+                    embedding_dim,
+                    action_space.size(),
+                ))
+            }
+            OutputLayerConfig::FullyParameterizedQuantile {
+                quantiles,
+                embedding_dim,
+            } => {
+                let value_layer = if dueling {
+                    ValueLayer::Dueling(DuelingLayer::new(
+                        device,
+                        value_layer_input_dim,
+                        action_space.size(),
+                        1,
+                        head_config,
+                        noisy,
+                    ))
+                } else {
+                    ValueLayer::Linear(LinearValueLayer::new(
+                        device,
+                        value_layer_input_dim,
+                        action_space.size(),
+                        head_config,
+                        noisy,
+                    ))
+                };
+                let quantile_layer = ImplicitQuantileLayer::new(
+                    device,
+                    value_layer,
+                    value_layer_input_dim,
+                    quantiles,
+                    embedding_dim,
+                    action_space.size(),
+                );
+                OutputLayer::FullyParameterizedQuantile(FullyParameterizedQuantileLayer::new(
+                    device,
+                    quantile_layer,
+                    value_layer_input_dim,
+                    quantiles,
+                    action_space.size(),
+                ))
+            }
         };
-        Self {
-            layer1,
-            layer2,
+        Ok(Self {
+            torso,
             output_layer,
-            activation: Relu::new(),
-        }
+        })
+    }
+}
+
+impl<B: Backend> DeepQNetworkModel<B> {
+    fn extract_features<const D: usize>(&self, observation: Tensor<B, D>) -> Tensor<B, 2> {
+        self.torso.forward(observation)
     }
 }
 
 impl<B: Backend> Estimator<B> for DeepQNetworkModel<B> {
     fn predict<const D: usize>(&self, observation: Tensor<B, D>) -> Tensor<B, 2> {
-        let x = observation;
-        let x = match (&self.layer1, &self.layer2) {
-            (FeatureExtractionLayer::Linear(layer1), FeatureExtractionLayer::Linear(layer2)) => {
-                let shape = x.shape().dims;
-                self.activation.forward(
-                    layer2.forward(
-                        self.activation
-                            .forward(layer1.forward(x.reshape([shape[0], shape[1]]))),
-                    ),
-                )
-            }
-            (FeatureExtractionLayer::Conv2d(layer1), FeatureExtractionLayer::Conv2d(layer2)) => {
-                let shape = x.shape().dims;
-                let x = self
-                    .activation
-                    .forward(layer1.forward(x.reshape([shape[0], shape[1], shape[2], shape[3]])));
-                let x = self.activation.forward(layer2.forward(x));
-                x.flatten(1, 3)
-            }
-            _ => unimplemented!(),
-        };
+        let x = self.extract_features(observation);
         match &self.output_layer {
             OutputLayer::Expectation(layer) => match layer {
                 ValueLayer::Linear(layer) => layer.forward(x),
@@ -433,37 +1024,578 @@ impl<B: Backend> Estimator<B> for DeepQNetworkModel<B> {
             },
             OutputLayer::CategoricalDistribution(layer) => layer.forward(x),
             OutputLayer::QuantileRegression(layer) => layer.forward(x),
+            OutputLayer::ImplicitQuantile(layer) => layer.forward(x),
+            OutputLayer::FullyParameterizedQuantile(layer) => layer.forward(x),
         }
     }
 }
 
 impl<B: Backend> Distributional<B> for DeepQNetworkModel<B> {
     fn get_distribution<const D: usize>(&self, observation: Tensor<B, D>) -> Tensor<B, 3> {
-        let x = observation;
-        let x = match (&self.layer1, &self.layer2) {
-            (FeatureExtractionLayer::Linear(layer1), FeatureExtractionLayer::Linear(layer2)) => {
-                let shape = x.shape().dims;
-                self.activation.forward(
-                    layer2.forward(
-                        self.activation
-                            .forward(layer1.forward(x.reshape([shape[0], shape[1]]))),
-                    ),
-                )
-            }
-            (FeatureExtractionLayer::Conv2d(layer1), FeatureExtractionLayer::Conv2d(layer2)) => {
-                let shape = x.shape().dims;
-                let x = self
-                    .activation
-                    .forward(layer1.forward(x.reshape([shape[0], shape[1], shape[2], shape[3]])));
-                let x = self.activation.forward(layer2.forward(x));
-                x.flatten(1, 3)
-            }
-            _ => unimplemented!(),
-        };
+        let x = self.extract_features(observation);
         match &self.output_layer {
             OutputLayer::Expectation(_) => unimplemented!("Expectation not supported"),
             OutputLayer::CategoricalDistribution(layer) => layer.forward_distribution(x),
             OutputLayer::QuantileRegression(layer) => layer.forward_distribution(x),
+            OutputLayer::ImplicitQuantile(layer) => layer.forward_distribution(x),
+            OutputLayer::FullyParameterizedQuantile(layer) => layer.forward_distribution(x),
+        }
+    }
+}
+
+impl<B: Backend> ImplicitQuantile<B> for DeepQNetworkModel<B> {
+    fn get_quantiles<const D: usize>(
+        &self,
+        observation: Tensor<B, D>,
+        taus: Tensor<B, 2>,
+    ) -> Tensor<B, 3> {
+        let x = self.extract_features(observation);
+        match &self.output_layer {
+            OutputLayer::ImplicitQuantile(layer) => layer.forward_quantiles(x, taus),
+            OutputLayer::FullyParameterizedQuantile(layer) => {
+                layer.quantile_layer.forward_quantiles(x, taus)
+            }
+            _ => unimplemented!("only the implicit quantile layers take taus"),
+        }
+    }
+}
+
+impl<B: Backend> FractionProposal<B> for DeepQNetworkModel<B> {
+    fn propose_fractions<const D: usize>(&self, observation: Tensor<B, D>) -> Tensor<B, 2> {
+        let x = self.extract_features(observation);
+        match &self.output_layer {
+            OutputLayer::FullyParameterizedQuantile(layer) => layer.forward_fractions(x),
+            _ => unimplemented!("only the fully parameterized quantile layer proposes fractions"),
         }
     }
 }
+
+impl<B: Backend> Noisy<B> for OutputLayer<B> {
+    fn map_noisy<F: FnMut(NoisyLinear<B>) -> NoisyLinear<B>>(self, f: &mut F) -> Self {
+        match self {
+            OutputLayer::Expectation(layer) => OutputLayer::Expectation(layer.map_noisy(f)),
+            OutputLayer::CategoricalDistribution(layer) => {
+                OutputLayer::CategoricalDistribution(CategoricalDistributionLayer {
+                    value_layer: layer.value_layer.map_noisy(f),
+                    ..layer
+                })
+            }
+            OutputLayer::QuantileRegression(layer) => {
+                OutputLayer::QuantileRegression(QuantileRegressionLayer {
+                    value_layer: layer.value_layer.map_noisy(f),
+                    ..layer
+                })
+            }
+            OutputLayer::ImplicitQuantile(layer) => {
+                OutputLayer::ImplicitQuantile(ImplicitQuantileLayer {
+                    value_layer: layer.value_layer.map_noisy(f),
+                    ..layer
+                })
+            }
+            OutputLayer::FullyParameterizedQuantile(layer) => {
+                OutputLayer::FullyParameterizedQuantile(FullyParameterizedQuantileLayer {
+                    quantile_layer: ImplicitQuantileLayer {
+                        value_layer: layer.quantile_layer.value_layer.map_noisy(f),
+                        ..layer.quantile_layer
+                    },
+                    ..layer
+                })
+            }
+        }
+    }
+}
+
+impl<B: Backend> Noisy<B> for DeepQNetworkModel<B> {
+    fn map_noisy<F: FnMut(NoisyLinear<B>) -> NoisyLinear<B>>(self, f: &mut F) -> Self {
+        Self {
+            output_layer: self.output_layer.map_noisy(f),
+            ..self
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Serialize, Deserialize)]
+pub enum RecurrentCell {
+    Lstm,
+    Gru,
+}
+
+#[derive(Module, Debug)]
+pub enum RecurrentLayer<B: Backend> {
+    Lstm(Lstm<B>),
+    Gru(Gru<B>),
+}
+
+#[derive(Module, Debug)]
+pub struct RecurrentDeepQNetworkModel<B: Backend> {
+    torso: Torso<B>,
+    recurrent_layer: RecurrentLayer<B>,
+    value_layer: ValueLayer<B>,
+    hidden_size: usize,
+}
+
+impl<B: Backend> RecurrentDeepQNetworkModel<B> {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new<const D: usize>(
+        device: &B::Device,
+        observation_space: &ObservationSpace<D>,
+        action_space: &ActionSpace,
+        torso_config: &TorsoConfig,
+        head_config: &HeadConfig,
+        cell: RecurrentCell,
+        hidden_size: usize,
+        dueling: bool,
+        noisy: bool,
+    ) -> anyhow::Result<Self> {
+        let torso = torso_config.init(device, observation_space)?;
+        let feature_dim = torso.output_dim();
+        let recurrent_layer = match cell {
+            RecurrentCell::Lstm => {
+                RecurrentLayer::Lstm(LstmConfig::new(feature_dim, hidden_size, true).init(device))
+            }
+            RecurrentCell::Gru => {
+                RecurrentLayer::Gru(GruConfig::new(feature_dim, hidden_size, true).init(device))
+            }
+        };
+        let value_layer = if dueling {
+            ValueLayer::Dueling(DuelingLayer::new(
+                device,
+                hidden_size,
+                1,
+                action_space.size(),
+                head_config,
+                noisy,
+            ))
+        } else {
+            ValueLayer::Linear(LinearValueLayer::new(
+                device,
+                hidden_size,
+                action_space.size(),
+                head_config,
+                noisy,
+            ))
+        };
+        Ok(Self {
+            torso,
+            recurrent_layer,
+            value_layer,
+            hidden_size,
+        })
+    }
+}
+
+impl<B: Backend> Recurrent<B> for RecurrentDeepQNetworkModel<B> {
+    // the lstm state is the hidden state followed by the cell state
+    fn recurrent_state_size(&self) -> usize {
+        match self.recurrent_layer {
+            RecurrentLayer::Lstm(_) => 2 * self.hidden_size,
+            RecurrentLayer::Gru(_) => self.hidden_size,
+        }
+    }
+
+    fn predict_sequence<const D: usize>(
+        &self,
+        observation: Tensor<B, D>,
+        sequence_length: usize,
+        recurrent_state: Tensor<B, 2>,
+    ) -> (Tensor<B, 3>, Tensor<B, 2>) {
+        let x = self.torso.forward(observation);
+        let [total, feature_dim] = x.dims();
+        let batch_size = total / sequence_length;
+        let x = x.reshape([batch_size, sequence_length, feature_dim]);
+        let hidden_size = self.hidden_size;
+        let (x, recurrent_state) = match &self.recurrent_layer {
+            RecurrentLayer::Lstm(layer) => {
+                let hidden = recurrent_state
+                    .clone()
+                    .slice([0..batch_size, 0..hidden_size]);
+                let cell = recurrent_state.slice([0..batch_size, hidden_size..2 * hidden_size]);
+                let (x, state) = layer.forward(x, Some(LstmState::new(cell, hidden)));
+                (x, Tensor::cat(vec![state.hidden, state.cell], 1))
+            }
+            RecurrentLayer::Gru(layer) => {
+                let x = layer.forward(x, Some(recurrent_state));
+                let hidden = x
+                    .clone()
+                    .slice([
+                        0..batch_size,
+                        sequence_length - 1..sequence_length,
+                        0..hidden_size,
+                    ])
+                    .reshape([batch_size, hidden_size]);
+                (x, hidden)
+            }
+        };
+        let q_value = self
+            .value_layer
+            .forward(x.reshape([batch_size * sequence_length, hidden_size]));
+        let action_num = q_value.dims()[1];
+        (
+            q_value.reshape([batch_size, sequence_length, action_num]),
+            recurrent_state,
+        )
+    }
+}
+
+impl<B: Backend> Noisy<B> for RecurrentDeepQNetworkModel<B> {
+    fn map_noisy<F: FnMut(NoisyLinear<B>) -> NoisyLinear<B>>(self, f: &mut F) -> Self {
+        Self {
+            value_layer: self.value_layer.map_noisy(f),
+            ..self
+        }
+    }
+}
+
+const LOG_STD_MIN: f32 = -20.0;
+const LOG_STD_MAX: f32 = 2.0;
+
+#[derive(Module, Debug)]
+pub struct GaussianPolicyModel<B: Backend> {
+    layer1: Linear<B>,
+    layer2: Linear<B>,
+    mean: Linear<B>,
+    log_std: Linear<B>,
+    activation: Relu,
+}
+
+impl<B: Backend> GaussianPolicyModel<B> {
+    pub fn new<const D: usize>(
+        device: &B::Device,
+        observation_space: &ObservationSpace<D>,
+        action_space: &ActionSpace,
+    ) -> Self {
+        if D != 2 {
+            unimplemented!()
+        }
+        Self {
+            layer1: LinearConfig::new(observation_space.shape()[1], 256).init(device),
+            layer2: LinearConfig::new(256, 256).init(device),
+            mean: LinearConfig::new(256, action_space.size()).init(device),
+            log_std: LinearConfig::new(256, action_space.size()).init(device),
+            activation: Relu::new(),
+        }
+    }
+}
+
+impl<B: Backend> GaussianPolicy<B> for GaussianPolicyModel<B> {
+    fn gaussian<const D: usize>(&self, observation: Tensor<B, D>) -> (Tensor<B, 2>, Tensor<B, 2>) {
+        let shape = observation.shape().dims;
+        let x = observation.reshape([shape[0], shape[1]]);
+        let x = self.activation.forward(self.layer1.forward(x));
+        let x = self.activation.forward(self.layer2.forward(x));
+        let mean = self.mean.forward(x.clone());
+        let log_std = self.log_std.forward(x).clamp(LOG_STD_MIN, LOG_STD_MAX);
+        (mean, log_std)
+    }
+}
+
+#[derive(Module, Debug)]
+pub struct ActionValueModel<B: Backend> {
+    layer1: Linear<B>,
+    layer2: Linear<B>,
+    output: Linear<B>,
+    activation: Relu,
+}
+
+impl<B: Backend> ActionValueModel<B> {
+    pub fn new<const D: usize>(
+        device: &B::Device,
+        observation_space: &ObservationSpace<D>,
+        action_space: &ActionSpace,
+    ) -> Self {
+        if D != 2 {
+            unimplemented!()
+        }
+        let input_size = observation_space.shape()[1] + action_space.size();
+        Self {
+            layer1: LinearConfig::new(input_size, 256).init(device),
+            layer2: LinearConfig::new(256, 256).init(device),
+            output: LinearConfig::new(256, 1).init(device),
+            activation: Relu::new(),
+        }
+    }
+}
+
+impl<B: Backend> ActionValue<B> for ActionValueModel<B> {
+    fn action_value<const D: usize>(
+        &self,
+        observation: Tensor<B, D>,
+        action: Tensor<B, 2>,
+    ) -> Tensor<B, 2> {
+        let shape = observation.shape().dims;
+        let x = Tensor::cat(vec![observation.reshape([shape[0], shape[1]]), action], 1);
+        let x = self.activation.forward(self.layer1.forward(x));
+        let x = self.activation.forward(self.layer2.forward(x));
+        self.output.forward(x)
+    }
+}
+
+#[derive(Module, Debug)]
+pub struct ActorCriticModel<B: Backend> {
+    actor1: Linear<B>,
+    actor2: Linear<B>,
+    actor_output: Linear<B>,
+    critic1: Linear<B>,
+    critic2: Linear<B>,
+    critic_output: Linear<B>,
+    // state independent log std of the gaussian policy, only for box action spaces
+    log_std: Option<Param<Tensor<B, 1>>>,
+}
+
+impl<B: Backend> ActorCriticModel<B> {
+    pub fn new<const D: usize>(
+        device: &B::Device,
+        observation_space: &ObservationSpace<D>,
+        action_space: &ActionSpace,
+    ) -> Self {
+        if D != 2 {
+            unimplemented!()
+        }
+        let input_size = observation_space.shape()[1];
+        let log_std = match action_space {
+            ActionSpace::Discrete(..) => None,
+            ActionSpace::Box { .. } => Some(Param::from_tensor(Tensor::zeros(
+                [action_space.size()],
+                device,
+            ))),
+        };
+        Self {
+            actor1: LinearConfig::new(input_size, 64).init(device),
+            actor2: LinearConfig::new(64, 64).init(device),
+            actor_output: LinearConfig::new(64, action_space.size()).init(device),
+            critic1: LinearConfig::new(input_size, 64).init(device),
+            critic2: LinearConfig::new(64, 64).init(device),
+            critic_output: LinearConfig::new(64, 1).init(device),
+            log_std,
+        }
+    }
+}
+
+impl<B: Backend> ActorCritic<B> for ActorCriticModel<B> {
+    fn actor_critic<const D: usize>(
+        &self,
+        observation: Tensor<B, D>,
+    ) -> (Tensor<B, 2>, Tensor<B, 2>) {
+        let shape = observation.shape().dims;
+        let x = observation.reshape([shape[0], shape[1]]);
+
+        let policy = self.actor1.forward(x.clone()).tanh();
+        let policy = self.actor2.forward(policy).tanh();
+        let policy = self.actor_output.forward(policy);
+        let policy = match &self.log_std {
+            Some(log_std) => {
+                let log_std = log_std.val().unsqueeze::<2>().repeat_dim(0, shape[0]);
+                Tensor::cat(vec![policy, log_std], 1)
+            }
+            None => policy,
+        };
+
+        let value = self.critic1.forward(x).tanh();
+        let value = self.critic2.forward(value).tanh();
+        let value = self.critic_output.forward(value);
+        (policy, value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use burn::backend::{libtorch::LibTorchDevice, LibTorch};
+    use rand::{rngs::StdRng, SeedableRng as _};
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_torso_config() -> anyhow::Result<()> {
+        let torso_config: TorsoConfig = "conv:8:3:2, relu, layer_norm, linear:32, tanh".parse()?;
+        assert_eq!(
+            torso_config.layers,
+            vec![
+                TorsoLayerConfig::Conv {
+                    channels: 8,
+                    kernel_size: 3,
+                    stride: 2
+                },
+                TorsoLayerConfig::Relu,
+                TorsoLayerConfig::LayerNorm,
+                TorsoLayerConfig::Linear { width: 32 },
+                TorsoLayerConfig::Tanh,
+            ]
+        );
+        assert!("linear".parse::<TorsoConfig>().is_err());
+
+        let artifacts_dir = TempDir::new()?;
+        let path = artifacts_dir.path().join("torso.json");
+        torso_config.save(&path)?;
+        assert_eq!(
+            TorsoConfig::load(&path).map_err(|err| anyhow::anyhow!("{:?}", err))?,
+            torso_config
+        );
+
+        let device = LibTorchDevice::Cpu;
+        let torso =
+            torso_config.init::<LibTorch, 3>(&device, &ObservationSpace::unbounded([1, 5, 11]))?;
+        assert_eq!(torso.output_dim(), 32);
+        let x = torso.forward(Tensor::<LibTorch, 3>::ones([2, 5, 11], &device));
+        assert_eq!(x.dims(), [2, 32]);
+
+        let torso = torso_config
+            .init::<LibTorch, 4>(&device, &ObservationSpace::unbounded([1, 2, 9, 7]))?;
+        let x = torso.forward(Tensor::<LibTorch, 4>::ones([3, 2, 9, 7], &device));
+        assert_eq!(x.dims(), [3, 32]);
+
+        let torso = TorsoConfig::default_for::<4>()
+            .init::<LibTorch, 4>(&device, &ObservationSpace::unbounded([1, 4, 84, 84]))?;
+        assert_eq!(torso.output_dim(), 32 * 5 * 5);
+
+        let observation_space = ObservationSpace::unbounded([1, 4, 84, 84]);
+        let torso = TorsoConfig::nature_dqn().init::<LibTorch, 4>(&device, &observation_space)?;
+        let x = torso.forward(Tensor::<LibTorch, 4>::ones([2, 4, 84, 84], &device));
+        assert_eq!(x.dims(), [2, 512]);
+        let torso = TorsoConfig::impala().init::<LibTorch, 4>(&device, &observation_space)?;
+        let x = torso.forward(Tensor::<LibTorch, 4>::ones([2, 4, 84, 84], &device));
+        assert_eq!(x.dims(), [2, 256]);
+        let torso = "impala:8"
+            .parse::<TorsoConfig>()?
+            .init::<LibTorch, 4>(&device, &observation_space)?;
+        assert_eq!(torso.output_dim(), 8 * 42 * 42);
+        let x = torso.forward(Tensor::<LibTorch, 4>::ones([1, 4, 84, 84], &device));
+        assert_eq!(x.dims(), [1, 8 * 42 * 42]);
+
+        let torso = "linear:16,layer_norm,relu"
+            .parse::<TorsoConfig>()?
+            .init::<LibTorch, 2>(&device, &ObservationSpace::unbounded([1, 6]))?;
+        let x = torso.forward(Tensor::<LibTorch, 2>::ones([4, 6], &device));
+        assert_eq!(x.dims(), [4, 16]);
+
+        // layers that do not fit the observations
+        for (torso_config, shape) in [
+            ("conv:8:3:1", vec![1, 6]),
+            ("linear:8,conv:8:3:1", vec![1, 2, 9, 7]),
+            ("conv:8:10:1", vec![1, 2, 9, 7]),
+            ("conv:8:3:0", vec![1, 2, 9]),
+            ("impala:8", vec![1, 2, 9]),
+        ] {
+            let torso_config = torso_config.parse::<TorsoConfig>()?;
+            let result = match shape[..] {
+                [batch, size] => torso_config
+                    .init::<LibTorch, 2>(&device, &ObservationSpace::unbounded([batch, size]))
+                    .map(|_| ()),
+                [batch, channel, length] => torso_config
+                    .init::<LibTorch, 3>(
+                        &device,
+                        &ObservationSpace::unbounded([batch, channel, length]),
+                    )
+                    .map(|_| ()),
+                [batch, channel, height, width] => torso_config
+                    .init::<LibTorch, 4>(
+                        &device,
+                        &ObservationSpace::unbounded([batch, channel, height, width]),
+                    )
+                    .map(|_| ()),
+                _ => unreachable!(),
+            };
+            assert!(result.is_err(), "{:?} {:?}", torso_config, shape);
+        }
+        assert!(TorsoConfig::default_for::<2>()
+            .init::<LibTorch, 5>(&device, &ObservationSpace::unbounded([1, 2, 3, 4, 5]))
+            .is_err());
+        assert!(DeepQNetworkModel::<LibTorch>::new(
+            &device,
+            &ObservationSpace::unbounded([1, 6]),
+            &ActionSpace::Discrete(3),
+            &TorsoConfig::nature_dqn(),
+            &HeadConfig::new(),
+            false,
+            false,
+            OutputLayerConfig::Expectation,
+        )
+        .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_value_layers() -> anyhow::Result<()> {
+        let device = LibTorchDevice::Cpu;
+        let observation_space = ObservationSpace::unbounded([1, 6]);
+        let action_space = ActionSpace::Discrete(3);
+        let torso_config = TorsoConfig::new(vec![
+            TorsoLayerConfig::Linear { width: 48 },
+            TorsoLayerConfig::Relu,
+        ]);
+        let output_layer_configs = [
+            OutputLayerConfig::Expectation,
+            OutputLayerConfig::CategoricalDistribution {
+                atoms: 5,
+                min_value: -10.0,
+                max_value: 10.0,
+            },
+            OutputLayerConfig::QuantileRegression { quantiles: 5 },
+            OutputLayerConfig::ImplicitQuantile {
+                quantiles: 5,
+                embedding_dim: 8,
+            },
+            OutputLayerConfig::FullyParameterizedQuantile {
+                quantiles: 5,
+                embedding_dim: 8,
+            },
+        ];
+        for output_layer_config in output_layer_configs {
+            for dueling in [false, true] {
+                for noisy in [false, true] {
+                    for hidden_layers in [0, 1, 2] {
+                        let head_config = HeadConfig::new()
+                            .with_hidden_width(24)
+                            .with_hidden_layers(hidden_layers);
+                        let model = DeepQNetworkModel::<LibTorch>::new(
+                            &device,
+                            &observation_space,
+                            &action_space,
+                            &torso_config,
+                            &head_config,
+                            dueling,
+                            noisy,
+                            output_layer_config.clone(),
+                        )?;
+                        let observation = Tensor::<LibTorch, 2>::ones([2, 6], &device);
+                        assert_eq!(model.predict(observation.clone()).dims(), [2, 3]);
+                        if !matches!(output_layer_config, OutputLayerConfig::Expectation) {
+                            assert_eq!(model.get_distribution(observation).dims(), [2, 3, 5]);
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_noise_mode() -> anyhow::Result<()> {
+        let device = LibTorchDevice::Cpu;
+        let model = DeepQNetworkModel::<LibTorch>::new(
+            &device,
+            &ObservationSpace::unbounded([1, 6]),
+            &ActionSpace::Discrete(3),
+            &TorsoConfig::default_for::<2>(),
+            &HeadConfig::new(),
+            true,
+            true,
+            OutputLayerConfig::Expectation,
+        )?;
+        let observation = Tensor::<LibTorch, 2>::ones([2, 6], &device);
+        let predict =
+            |model: &DeepQNetworkModel<LibTorch>| model.predict(observation.clone()).into_data();
+
+        let model = model.train(false);
+        predict(&model).assert_eq(&predict(&model), true);
+
+        let model = model.resample_noise(&mut StdRng::seed_from_u64(0));
+        let q_value = predict(&model);
+        q_value.assert_eq(&predict(&model), true);
+        let model = model.resample_noise(&mut StdRng::seed_from_u64(0));
+        q_value.assert_eq(&predict(&model), true);
+        let model = model.resample_noise(&mut StdRng::seed_from_u64(1));
+        assert_ne!(q_value, predict(&model));
+        Ok(())
+    }
+}
@@ -1,11 +1,23 @@
-use std::collections::VecDeque;
+use std::{
+    collections::VecDeque,
+    fs::{File, OpenOptions},
+    io::Write as _,
+    path::Path,
+};
 
-use anyhow::anyhow;
+use anyhow::{anyhow, bail, ensure, Context as _};
+use burn::config::Config;
+use rand::{rngs::StdRng, Rng, SeedableRng as _};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::json;
 
-use crate::{Experience, State};
+use crate::{Action, ActionSpace, Agent, Env, Experience, State};
 
 pub mod prioritized;
+pub mod replay;
+pub mod rollout;
+pub mod sequence;
+pub mod storage;
 pub mod uniform;
 
 pub struct RandomPolicy {
@@ -28,8 +40,366 @@ impl RandomPolicy {
             .max(self.final_exploration)
     }
 
-    pub fn sample(&self, epi: usize) -> bool {
-        rand::random::<f32>() < self.epsilon(epi)
+    pub fn sample(&self, epi: usize, rng: &mut impl Rng) -> bool {
+        rng.gen::<f32>() < self.epsilon(epi)
+    }
+}
+
+fn random_action(action_space: &ActionSpace, rng: &mut impl Rng) -> Action {
+    match action_space {
+        ActionSpace::Discrete(n) => Action::Discrete(rng.gen_range(0..*n)),
+        ActionSpace::Box { low, high } => Action::Continuous(
+            low.iter()
+                .zip(high)
+                .map(|(low, high)| rng.gen_range(*low..=*high))
+                .collect(),
+        ),
+    }
+}
+
+// gaussian policies sample outside of the bounds, the env only sees the clipped action
+fn clip_action(action: Action, action_space: &ActionSpace) -> Action {
+    match (action, action_space) {
+        (Action::Continuous(action), ActionSpace::Box { low, high }) => Action::Continuous(
+            action
+                .iter()
+                .zip(low.iter().zip(high))
+                .map(|(action, (low, high))| action.clamp(*low, *high))
+                .collect(),
+        ),
+        (action, _) => action,
+    }
+}
+
+fn ensure_action_space<S: State, A: Agent<S>>(
+    _agent: &A,
+    action_space: &ActionSpace,
+) -> anyhow::Result<()> {
+    ensure!(
+        A::supports_action_space(action_space),
+        "agent does not support action space {:?}",
+        action_space
+    );
+    Ok(())
+}
+
+fn create_train_logger(artifacts_dir: &Path, epi: usize) -> anyhow::Result<File> {
+    let episode_artifacts_dir = artifacts_dir.join(format!("{}", epi));
+    std::fs::create_dir_all(&episode_artifacts_dir)
+        .with_context(|| format!("create episode artifact dir {:?}", episode_artifacts_dir))?;
+    let train_log_path = episode_artifacts_dir.join("train.jsonl");
+    File::create(&train_log_path).with_context(|| "create train log file")
+}
+
+// the progress of a trainer saved next to each agent checkpoint, a restored run continues
+// its episodes, steps, teacher sync and exploration from it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TrainingState {
+    pub finished_episode: usize,
+    pub total_step: usize,
+    pub update_counter: usize,
+    // the exploration rng is reseeded with it at every checkpoint
+    pub seed: u64,
+    // the agent noise is reseeded apart from the exploration
+    pub noise_seed: u64,
+    // the evaluations done and their best mean score
+    pub evaluated: usize,
+    pub best_score: Option<f32>,
+}
+
+impl TrainingState {
+    pub fn save(&self, artifacts_dir: &Path) -> anyhow::Result<()> {
+        let file = File::create(artifacts_dir.join("training_state.json"))
+            .with_context(|| "create training state file")?;
+        serde_json::to_writer(file, self).with_context(|| "write training state")?;
+        Ok(())
+    }
+
+    // none for a checkpoint without a training state
+    pub fn load(restore_dir: &Path) -> anyhow::Result<Option<Self>> {
+        let path = restore_dir.join("training_state.json");
+        if !path.exists() {
+            return Ok(None);
+        }
+        let file = File::open(path).with_context(|| "open training state file")?;
+        let state = serde_json::from_reader(file).with_context(|| "read training state")?;
+        Ok(Some(state))
+    }
+
+    // the exploration rng of the trainer, the agent resumes its updates and noise and the
+    // evaluation its schedule and best score
+    // a fresh run seeds the rng with `seed`, or from the entropy without one
+    fn restore<S: State, const D: usize>(
+        training_state: &Option<Self>,
+        seed: Option<u64>,
+        agent: &mut impl Agent<S>,
+        evaluation: &mut Option<Evaluation<'_, D>>,
+    ) -> (usize, usize, StdRng) {
+        let Some(state) = training_state else {
+            let rng = seed.map_or_else(StdRng::from_entropy, StdRng::seed_from_u64);
+            return (0, 0, rng);
+        };
+        agent.set_update_counter(state.update_counter);
+        agent.set_noise_seed(state.noise_seed);
+        if let Some(evaluation) = evaluation {
+            evaluation.evaluated = state.evaluated;
+            evaluation.best_score = state.best_score;
+        }
+        (
+            state.finished_episode,
+            state.total_step,
+            StdRng::seed_from_u64(state.seed),
+        )
+    }
+
+    // saves the agent and the state, the rng and the agent noise continue from the saved seeds
+    fn checkpoint<S: State, const D: usize>(
+        agent: &mut impl Agent<S>,
+        evaluation: &Option<Evaluation<'_, D>>,
+        artifacts_dir: &Path,
+        finished_episode: usize,
+        total_step: usize,
+        rng: &mut StdRng,
+    ) -> anyhow::Result<()> {
+        agent.save(artifacts_dir)?;
+        let seed = rng.gen();
+        let noise_seed = rng.gen();
+        *rng = StdRng::seed_from_u64(seed);
+        agent.set_noise_seed(noise_seed);
+        let state = TrainingState {
+            finished_episode,
+            total_step,
+            update_counter: agent.update_counter(),
+            seed,
+            noise_seed,
+            evaluated: evaluation
+                .as_ref()
+                .map_or(0, |evaluation| evaluation.evaluated),
+            best_score: evaluation
+                .as_ref()
+                .and_then(|evaluation| evaluation.best_score),
+        };
+        state.save(artifacts_dir)
+    }
+}
+
+// experiences, or sequences of them for the recurrent agents
+pub struct ReplayBatch<T> {
+    pub indexes: Vec<usize>,
+    pub experiences: Vec<T>,
+    // importance sampling weights of the experiences, all 1 for uniform sampling
+    pub weights: Vec<f32>,
+}
+
+// the storage and sampling of the experiences replayed by `ReplayTrainer`, or of the
+// sequences replayed by `SequenceReplayTrainer`
+pub trait ReplayMemory<T> {
+    fn push(&mut self, experience: T) -> anyhow::Result<()>;
+    // fails while the memory holds less than a batch
+    fn sample(&mut self) -> anyhow::Result<ReplayBatch<T>>;
+    fn batch_size(&self) -> usize;
+    fn update_priorities(&mut self, _indexes: Vec<usize>, _td_errors: Vec<f32>) {}
+    // memories sampling by priority get the td errors of their batches after each update
+    fn is_prioritized(&self) -> bool {
+        false
+    }
+    // the fraction of the training done, from 0 to 1
+    fn set_progress(&mut self, _progress: f32) {}
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    fn capacity(&self) -> usize;
+    // the sampling state beside the experiences, for a memory on a persistent storage
+    fn save(&self, _path: &Path) -> anyhow::Result<()> {
+        bail!("the replay memory does not support persistence")
+    }
+    fn load(&mut self, _path: &Path) -> anyhow::Result<()> {
+        bail!("the replay memory does not support persistence")
+    }
+}
+
+// when the replay trainers update the agent, counted in env steps across all envs
+#[derive(Debug, Config)]
+pub struct TrainingScheduleConfig {
+    // env steps collected before the first update
+    #[config(default = 0)]
+    learning_starts: usize,
+    // env steps between two updates
+    #[config(default = 1)]
+    train_frequency: usize,
+    // agent updates on each of them
+    #[config(default = 1)]
+    gradient_steps: usize,
+    // training stops after this many env steps, or after the episode count
+    total_timesteps: Option<usize>,
+}
+
+impl TrainingScheduleConfig {
+    // agent updates done over `env_steps`, e.g. to sync the target network in env steps
+    pub fn updates_in(&self, env_steps: usize) -> usize {
+        env_steps / self.train_frequency.max(1) * self.gradient_steps
+    }
+
+    // by env steps with a timestep budget, by episodes otherwise
+    fn progress(&self, finished_episode: usize, episode: usize, total_step: usize) -> f32 {
+        match self.total_timesteps {
+            Some(total_timesteps) => total_step as f32 / total_timesteps.max(1) as f32,
+            None => finished_episode as f32 / episode.max(1) as f32,
+        }
+    }
+
+    fn is_finished(&self, total_step: usize) -> bool {
+        self.total_timesteps
+            .is_some_and(|total_timesteps| total_step >= total_timesteps)
+    }
+
+    // agent updates due once the env steps went from `previous_step` to `total_step`
+    fn updates(&self, previous_step: usize, total_step: usize) -> usize {
+        if total_step < self.learning_starts {
+            return 0;
+        }
+        let train_frequency = self.train_frequency.max(1);
+        let previous_step = previous_step.max(self.learning_starts.saturating_sub(1));
+        (total_step / train_frequency - previous_step / train_frequency) * self.gradient_steps
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvaluationInterval {
+    Episode(usize),
+    Step(usize),
+}
+
+// greedy episodes on a separate env, logged to `eval.jsonl` with the best agent kept in `best/`
+pub struct Evaluation<'a, const D: usize> {
+    env: Box<dyn Env<D> + 'a>,
+    interval: EvaluationInterval,
+    episode: usize,
+    // cuts the episodes of an agent stuck in a game that does not end
+    max_steps: Option<usize>,
+    evaluated: usize,
+    best_score: Option<f32>,
+}
+
+impl<'a, const D: usize> Evaluation<'a, D> {
+    pub fn new(env: impl Env<D> + 'a, interval: EvaluationInterval, episode: usize) -> Self {
+        Self {
+            env: Box::new(env),
+            interval,
+            episode,
+            max_steps: None,
+            evaluated: 0,
+            best_score: None,
+        }
+    }
+
+    pub fn with_max_steps(mut self, max_steps: usize) -> Self {
+        self.max_steps = Some(max_steps);
+        self
+    }
+
+    pub fn best_score(&self) -> Option<f32> {
+        self.best_score
+    }
+
+    // true once per interval crossed, several envs may finish episodes in the same step
+    fn is_due(&mut self, finished_episode: usize, total_step: usize) -> bool {
+        let count = match self.interval {
+            EvaluationInterval::Episode(episode) => finished_episode / episode.max(1),
+            EvaluationInterval::Step(step) => total_step / step.max(1),
+        };
+        if count > self.evaluated {
+            self.evaluated = count;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn run_episode<S: State>(&mut self, agent: &impl Agent<S>) -> anyhow::Result<f32> {
+        let mut observation = self.env.reset()?;
+        let mut state = S::new(observation.clone());
+        let mut cumulative_reward = 0.0;
+        for step in 1.. {
+            let action = agent
+                .batch_state_policy(
+                    std::slice::from_ref(&observation),
+                    std::slice::from_ref(&state),
+                )
+                .remove(0);
+            let result = self.env.step(&action)?;
+            cumulative_reward += result.reward;
+            if result.is_done() || self.max_steps.is_some_and(|max_steps| step >= max_steps) {
+                break;
+            }
+            state = agent.make_state(&result.observation, &state);
+            observation = result.observation;
+        }
+        Ok(cumulative_reward)
+    }
+
+    pub fn evaluate<S: State>(
+        &mut self,
+        agent: &mut impl Agent<S>,
+        artifacts_dir: &Path,
+        finished_episode: usize,
+        total_step: usize,
+    ) -> anyhow::Result<()> {
+        agent.set_training(false);
+        let scores = (0..self.episode)
+            .map(|_| self.run_episode(agent))
+            .collect::<anyhow::Result<Vec<_>>>();
+        agent.set_training(true);
+        let scores = scores?;
+        if scores.is_empty() {
+            return Ok(());
+        }
+
+        let mean = scores.iter().sum::<f32>() / scores.len() as f32;
+        let std = (scores
+            .iter()
+            .map(|score| (score - mean).powi(2))
+            .sum::<f32>()
+            / scores.len() as f32)
+            .sqrt();
+        let min = scores.iter().copied().fold(f32::INFINITY, f32::min);
+        let max = scores.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        let log = json!({
+            "episode": finished_episode,
+            "step": total_step,
+            "mean": mean,
+            "std": std,
+            "min": min,
+            "max": max,
+        });
+        let mut eval_logger = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(artifacts_dir.join("eval.jsonl"))
+            .with_context(|| "open eval log file")?;
+        writeln!(eval_logger, "{}", log).with_context(|| "write eval log")?;
+
+        if self.best_score.is_none_or(|best_score| mean > best_score) {
+            agent
+                .save(artifacts_dir.join("best"))
+                .with_context(|| "save best agent")?;
+            self.best_score = Some(mean);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn evaluate_if_due<S: State>(
+        &mut self,
+        agent: &mut impl Agent<S>,
+        artifacts_dir: &Path,
+        finished_episode: usize,
+        total_step: usize,
+    ) -> anyhow::Result<()> {
+        if self.is_due(finished_episode, total_step) {
+            self.evaluate(agent, artifacts_dir, finished_episode, total_step)?;
+        }
+        Ok(())
     }
 }
 
@@ -41,6 +411,19 @@ pub enum RewardMapping {
     SymLog,
 }
 
+impl RewardMapping {
+    pub fn apply(&self, reward: f32) -> f32 {
+        match *self {
+            RewardMapping::Identity => reward,
+            RewardMapping::Clip { min, max } => reward.clamp(min, max),
+            RewardMapping::Rescaling { epsilon } => {
+                reward.signum() * ((1.0 + reward.abs()).sqrt() - 1.0) + epsilon * reward
+            }
+            RewardMapping::SymLog => reward.signum() * (1.0 + reward.abs()).ln(),
+        }
+    }
+}
+
 pub struct NStepExperience<S: State + Serialize + DeserializeOwned + 'static> {
     n_step: usize,
     n_step_buffer: VecDeque<Experience<S>>,
@@ -65,13 +448,15 @@ impl<S: State + Serialize + DeserializeOwned + 'static> NStepExperience<S> {
         }
 
         let mut total_reward = 0.0;
-        let mut finally_done = false;
+        let mut terminated = false;
+        let mut truncated = false;
 
+        // the rewards up to the end of the episode, whichever way it ended
         for (i, exp) in self.n_step_buffer.iter().enumerate() {
-            let mask = if exp.is_done() { 0.0 } else { 1.0 };
-            total_reward += self.gamma.powi(i as i32) * mask * exp.reward;
+            total_reward += self.gamma.powi(i as i32) * exp.reward;
             if exp.is_done() {
-                finally_done = true;
+                terminated = exp.terminated;
+                truncated = exp.truncated;
                 break;
             }
         }
@@ -81,22 +466,91 @@ impl<S: State + Serialize + DeserializeOwned + 'static> NStepExperience<S> {
             .pop_front()
             .ok_or(anyhow!("n_step_buffer is empty"))?;
 
-        let total_reward = match self.reward_mapping {
-            RewardMapping::Identity => total_reward,
-            RewardMapping::Clip { min, max } => total_reward.clamp(min, max),
-            RewardMapping::Rescaling { epsilon } => {
-                total_reward.signum() * ((1.0 + total_reward.abs()).sqrt() - 1.0)
-                    + epsilon * total_reward
-            }
-            RewardMapping::SymLog => total_reward.signum() * (1.0 + total_reward.abs()).ln(),
-        };
+        let total_reward = self.reward_mapping.apply(total_reward);
 
         let experience = Experience {
             state: front.state().clone(),
             action: front.action().clone(),
             reward: total_reward,
-            is_done: finally_done,
+            terminated,
+            truncated,
         };
         Ok(Some(experience))
     }
+
+    // the experiences still waiting for their n-step return
+    pub fn pending(&self) -> &VecDeque<Experience<S>> {
+        &self.n_step_buffer
+    }
+
+    // the pending experiences of an interrupted run, the restart cut off their episode
+    pub fn restore(&mut self, mut pending: VecDeque<Experience<S>>) {
+        if let Some(last) = pending.back_mut() {
+            if !last.is_done() {
+                last.truncated = true;
+            }
+        }
+        self.n_step_buffer = pending;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::DeepQNetworkState;
+
+    use super::*;
+
+    fn experience(reward: f32, terminated: bool, truncated: bool) -> Experience<DeepQNetworkState> {
+        Experience {
+            state: DeepQNetworkState::default(),
+            action: Action::Discrete(0),
+            reward,
+            terminated,
+            truncated,
+        }
+    }
+
+    #[test]
+    fn test_n_step_experience() -> anyhow::Result<()> {
+        let mut n_step = NStepExperience::new(3, 0.5, RewardMapping::Identity);
+        assert!(n_step.push(experience(1.0, false, false))?.is_none());
+        assert!(n_step.push(experience(2.0, false, false))?.is_none());
+        let result = n_step.push(experience(4.0, false, true))?.unwrap();
+        assert_eq!(result.reward, 1.0 + 0.5 * 2.0 + 0.25 * 4.0);
+        assert!(result.is_truncated() && !result.is_terminated());
+        let result = n_step.push(experience(8.0, true, false))?.unwrap();
+        assert_eq!(result.reward, 2.0 + 0.5 * 4.0);
+        assert!(result.is_truncated());
+
+        let mut restored = NStepExperience::new(3, 0.5, RewardMapping::Identity);
+        restored.restore(VecDeque::from([experience(1.0, false, false)]));
+        assert!(restored.push(experience(2.0, false, false))?.is_none());
+        let result = restored.push(experience(4.0, false, false))?.unwrap();
+        assert_eq!(result.reward, 1.0);
+        assert!(result.is_truncated());
+        assert_eq!(restored.pending().len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_training_schedule() {
+        let schedule = TrainingScheduleConfig::new()
+            .with_learning_starts(10)
+            .with_train_frequency(4)
+            .with_gradient_steps(2)
+            .with_total_timesteps(Some(100));
+        let updates = (0..40)
+            .map(|step| schedule.updates(step, step + 1))
+            .collect::<Vec<_>>();
+        assert_eq!(updates.iter().sum::<usize>(), 2 * 8);
+        assert_eq!(updates.iter().position(|x| *x > 0), Some(11));
+        // several envs stepping at once
+        assert_eq!(schedule.updates(0, 8), 0);
+        assert_eq!(schedule.updates(8, 16), 2 * 2);
+        assert_eq!(schedule.updates(16, 24), 2 * 2);
+        assert!(!schedule.is_finished(99));
+        assert!(schedule.is_finished(100));
+        assert_eq!(schedule.updates_in(1000), 500);
+        assert!(!TrainingScheduleConfig::new().is_finished(usize::MAX));
+    }
 }
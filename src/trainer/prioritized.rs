@@ -1,286 +1,292 @@
 use std::{
     fs::File,
-    io::{Cursor, Write as _},
-    path::PathBuf,
+    io::{BufReader, BufWriter},
+    path::Path,
     sync::{
-        atomic::{AtomicUsize, Ordering},
+        atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
         mpsc::Receiver,
         Arc,
     },
     thread::JoinHandle,
     time::Duration,
-    vec,
 };
 
-use crate::{Action, ActionSpace, Env, Experience, PrioritizedReplayAgent, State};
+use crate::{Experience, FlatState};
 
-use anyhow::Context as _;
-use itertools::Itertools;
+use anyhow::{ensure, Context as _};
+use burn::config::Config;
 use parking_lot::RwLock;
 use rand::Rng as _;
-use rocksdb::{DBCompressionType, DBWithThreadMode, MultiThreaded, Options};
-use serde::{de::DeserializeOwned, Serialize};
-use serde_json::json;
-use tempfile::TempDir;
-
-use super::{NStepExperience, RandomPolicy, RewardMapping};
-
-pub struct PrioritizedReplayTrainer {
-    episode: usize,
-    gamma: f32,
-    n_step: usize,
-    rewards_mapping: RewardMapping,
-    artifacts_dir: PathBuf,
-    render: bool,
-}
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
-impl PrioritizedReplayTrainer {
-    pub fn new(
-        episode: usize,
-        gamma: f32,
-        n_step: usize,
-        rewards_mapping: RewardMapping,
-        artifacts_dir: PathBuf,
-        render: bool,
-    ) -> anyhow::Result<Self> {
-        std::fs::create_dir_all(&artifacts_dir).with_context(|| "create artifact dir")?;
-        Ok(Self {
-            episode,
-            gamma,
-            n_step,
-            rewards_mapping,
-            artifacts_dir,
-            render,
-        })
-    }
-
-    pub fn train_loop<S: State + Serialize + DeserializeOwned + 'static, const D: usize>(
-        &self,
-        agent: &mut impl PrioritizedReplayAgent<S>,
-        env: &mut impl Env<D>,
-        memory: &mut PrioritizedReplayMemory<S>,
-        random_policy: &Option<RandomPolicy>,
-    ) -> anyhow::Result<()> {
-        let mut n_step_experiences =
-            NStepExperience::new(self.n_step, self.gamma, self.rewards_mapping.clone());
-
-        for epi in 0..self.episode {
-            let mut step = 0;
-            let mut cumulative_reward = 0.0;
-            let mut observation = env.reset()?;
-            let mut reward;
-            let mut is_done = false;
-            let mut state = S::new(observation.clone());
-            let episode_artifacts_dir = self.artifacts_dir.join(format!("{}", epi));
-            std::fs::create_dir_all(&episode_artifacts_dir).with_context(|| {
-                format!("create episode artifact dir {:?}", episode_artifacts_dir)
-            })?;
-            let train_log_path = episode_artifacts_dir.join("train.jsonl");
-            let mut train_logger =
-                File::create(&train_log_path).with_context(|| "create train log file")?;
-            while !is_done {
-                step += 1;
-
-                if self.render {
-                    env.render()?;
-                }
+use super::{
+    storage::{ExperienceStorage, StorageBackend},
+    ReplayBatch, ReplayMemory,
+};
 
-                let action = if let Some(policy) = random_policy {
-                    if policy.sample(epi) {
-                        let action_space = env.action_space();
-                        match action_space {
-                            ActionSpace::Discrete(n) => {
-                                let action = rand::thread_rng().gen_range(0..*n);
-                                Action::Discrete(action)
-                            }
-                        }
-                    } else {
-                        agent.policy(&observation)
-                    }
-                } else {
-                    agent.policy(&observation)
-                };
-
-                (observation, reward, is_done) = env.step(&action)?;
-                state = agent.make_state(&observation, &state);
-                cumulative_reward += reward;
-                let log = json!({
-                    "episode": epi,
-                    "step": step,
-                    "action": action,
-                    "reward": reward,
-                    "cumulative_reward": cumulative_reward
-                });
-                writeln!(&mut train_logger, "{}", log).with_context(|| "write train log")?;
-
-                let experience = Experience {
-                    state: state.clone(),
-                    action,
-                    reward,
-                    is_done,
-                };
-
-                if let Some(experience) = n_step_experiences.push(experience)? {
-                    let experiences = vec![experience.clone()];
-                    let td_errors = agent.temporaral_difference_error(self.gamma, &experiences)?;
-                    memory.push(experience, td_errors[0])?;
-                }
+#[derive(Debug, Config)]
+pub struct PrioritizedReplayConfig {
+    // how much the td errors shape the sampling, 0 is uniform
+    #[config(default = 0.6)]
+    alpha: f32,
+    // importance sampling exponent, annealed linearly over the training
+    #[config(default = 0.4)]
+    beta_start: f32,
+    #[config(default = 1.0)]
+    beta_end: f32,
+    // keeps transitions with a zero td error in the samples, must be positive
+    #[config(default = 0.001)]
+    priority_epsilon: f32,
+}
 
-                if epi == 0 {
-                    continue;
-                }
-                if let Ok(batch) = memory.sample() {
-                    agent.update(self.gamma, &batch.experiences, &batch.weights)?;
-                    let td_errors =
-                        agent.temporaral_difference_error(self.gamma, &batch.experiences)?;
-                    let (indexes, td_errors) = batch
-                        .indexes
-                        .iter()
-                        .zip(td_errors)
-                        .filter(|(_, x)| !x.is_nan())
-                        .unzip();
-                    memory.update_priorities(indexes, td_errors);
-                }
-            }
-            agent.save(&episode_artifacts_dir)?;
-        }
+impl PrioritizedReplayConfig {
+    pub(crate) fn ensure_valid(&self) -> anyhow::Result<()> {
+        // a zero priority has an infinite importance sampling weight
+        ensure!(
+            self.priority_epsilon > 0.0,
+            "priority epsilon must be positive, got {}",
+            self.priority_epsilon
+        );
         Ok(())
     }
-}
 
-pub struct PrioritizedBatch<S: State> {
-    indexes: Vec<usize>,
-    experiences: Vec<Experience<S>>,
-    weights: Vec<f32>,
+    pub(crate) fn beta(&self, progress: f32) -> f32 {
+        self.beta_start + (self.beta_end - self.beta_start) * progress.clamp(0.0, 1.0)
+    }
+
+    pub(crate) fn priority(&self, td_error: f32) -> f32 {
+        (td_error + self.priority_epsilon).powf(self.alpha)
+    }
 }
 
-pub struct PrioritizedReplayMemory<S: State + Serialize + DeserializeOwned + 'static> {
-    _buffer_dir: TempDir,
-    buffer: Arc<DBWithThreadMode<MultiThreaded>>,
+pub struct PrioritizedReplayMemory<S: FlatState + Serialize + DeserializeOwned + Sync + 'static> {
+    storage: Arc<dyn ExperienceStorage<S>>,
     priorities: Arc<RwLock<SumTree>>,
     max_buffer_size: usize,
+    batch_size: usize,
     counter: Arc<AtomicUsize>,
-    alpha: f32,
-
-    batch_channel: Receiver<PrioritizedBatch<S>>,
-    _samplers: Vec<JoinHandle<()>>,
-}
-
-impl<S: State + Serialize + DeserializeOwned + 'static> Drop for PrioritizedReplayMemory<S> {
-    fn drop(&mut self) {
-        let _ = DBWithThreadMode::<MultiThreaded>::destroy(&Options::default(), self.buffer.path());
-    }
+    config: PrioritizedReplayConfig,
+    // new transitions get the highest priority so far, to be replayed at least once
+    max_priority: f32,
+    // training progress as f32 bits, read by the samplers for beta
+    progress: Arc<AtomicU32>,
+
+    batch_channel: Receiver<anyhow::Result<ReplayBatch<Experience<S>>>>,
+    samplers: Vec<JoinHandle<()>>,
+    stop: Arc<AtomicBool>,
 }
 
-impl<S: State + Serialize + DeserializeOwned + 'static> PrioritizedReplayMemory<S> {
-    pub fn new(max_buffer_size: usize, batch_size: usize, alpha: f32) -> anyhow::Result<Self> {
-        let dir = TempDir::new()?;
-        let mut opts = Options::default();
-        opts.create_if_missing(true);
-        opts.optimize_for_point_lookup(4 * 1024);
-        opts.set_compression_type(DBCompressionType::Zstd);
-        let buffer = Arc::new(DBWithThreadMode::<MultiThreaded>::open(&opts, dir.path())?);
+impl<S: FlatState + Serialize + DeserializeOwned + Sync + 'static> PrioritizedReplayMemory<S> {
+    pub fn new(
+        max_buffer_size: usize,
+        batch_size: usize,
+        config: PrioritizedReplayConfig,
+        backend: StorageBackend,
+    ) -> anyhow::Result<Self> {
+        config.ensure_valid()?;
+        let storage = backend.create::<S>()?;
 
         let counter = Arc::new(AtomicUsize::new(0));
         let priorities = Arc::new(RwLock::new(SumTree::new(max_buffer_size)));
+        let progress = Arc::new(AtomicU32::new(0.0f32.to_bits()));
 
         let (tx, rx) = std::sync::mpsc::sync_channel(4);
 
         let sampler_num = 4;
-        let mut _samplers = Vec::with_capacity(sampler_num);
+        let mut samplers = Vec::with_capacity(sampler_num);
+        let stop = Arc::new(AtomicBool::new(false));
 
         for _ in 0..sampler_num {
             let tx_clone = tx.clone();
-            let buffer_clone = buffer.clone();
+            // the samplers stop once the memory is dropped
+            let storage_clone = Arc::downgrade(&storage);
             let priorities_clone = priorities.clone();
             let counter_clone = counter.clone();
+            let stop_clone = stop.clone();
+            let progress_clone = progress.clone();
+            let config_clone = config.clone();
 
-            let _sampler = std::thread::spawn(move || {
+            let sampler = std::thread::spawn(move || {
                 while counter_clone.load(Ordering::Relaxed) < batch_size {
+                    if stop_clone.load(Ordering::Relaxed) {
+                        return;
+                    }
                     std::thread::sleep(Duration::from_millis(100));
                 }
-                loop {
-                    let (indexes, experiences, weights) = {
-                        let priorities: Vec<(usize, f32)> = {
-                            let priorities = priorities_clone.read();
-                            (0..batch_size).map(|_| priorities.sample()).collect()
-                        };
-                        let mut indexes = Vec::with_capacity(batch_size);
-                        let mut experiences = Vec::with_capacity(batch_size);
-                        let mut weights = Vec::with_capacity(batch_size);
-                        let counter = counter_clone.load(Ordering::Relaxed);
-                        for (index, weight) in priorities {
-                            let experience =
-                                if let Some(v) = buffer_clone.get(index.to_le_bytes()).unwrap() {
-                                    rmp_serde::from_read(Cursor::new(v)).unwrap()
-                                } else {
-                                    println!("invalid index: {}", index);
-                                    continue;
-                                };
-                            indexes.push(index);
-                            experiences.push(experience);
-                            weights.push(weight);
-                        }
-                        let weights = weights
-                            .into_iter()
-                            .map(|f| {
-                                (1.0 / f).powf((counter as f32 / max_buffer_size as f32).tanh())
-                            })
-                            .collect_vec();
-                        let max_weight = weights.iter().copied().fold(0.0, f32::max);
-                        let weights = weights.into_iter().map(|x| x / max_weight).collect();
-                        (indexes, experiences, weights)
+                while !stop_clone.load(Ordering::Relaxed) {
+                    let Some(storage_clone) = storage_clone.upgrade() else {
+                        return;
                     };
-                    tx_clone
-                        .send(PrioritizedBatch {
-                            indexes,
-                            experiences,
-                            weights,
-                        })
-                        .unwrap();
+                    let priorities: Vec<(usize, f32)> = {
+                        let priorities = priorities_clone.read();
+                        (0..batch_size).map(|_| priorities.sample()).collect()
+                    };
+                    let beta =
+                        config_clone.beta(f32::from_bits(progress_clone.load(Ordering::Relaxed)));
+                    let batch = sample_batch(&*storage_clone, priorities, beta);
+                    // a failed sampler reports the error and stops
+                    let failed = batch.is_err();
+                    if tx_clone.send(batch).is_err() || failed {
+                        return;
+                    }
                 }
             });
-            _samplers.push(_sampler);
+            samplers.push(sampler);
         }
 
         Ok(Self {
-            _buffer_dir: dir,
-            buffer,
+            storage,
             priorities,
             max_buffer_size,
+            batch_size,
             counter,
-            alpha,
+            config,
+            max_priority: 1.0,
+            progress,
             batch_channel: rx,
-            _samplers,
+            samplers,
+            stop,
         })
     }
+}
+
+// the samplers are joined before the storage is dropped, so a persistent one can be reopened
+impl<S: FlatState + Serialize + DeserializeOwned + Sync + 'static> Drop
+    for PrioritizedReplayMemory<S>
+{
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        // a closed channel wakes the samplers waiting to send a batch
+        self.batch_channel = std::sync::mpsc::sync_channel(1).1;
+        for sampler in self.samplers.drain(..) {
+            let _ = sampler.join();
+        }
+    }
+}
 
-    pub fn update_priorities(&mut self, indexes: Vec<usize>, td_errors: Vec<f32>) {
+impl<S: FlatState + Serialize + DeserializeOwned + Sync + 'static> ReplayMemory<Experience<S>>
+    for PrioritizedReplayMemory<S>
+{
+    fn update_priorities(&mut self, indexes: Vec<usize>, td_errors: Vec<f32>) {
         for (index, td_error) in indexes.iter().zip(td_errors) {
-            let priority = (td_error + 0.001).powf(self.alpha);
+            let priority = self.config.priority(td_error);
+            self.max_priority = self.max_priority.max(priority);
             let mut priorities = self.priorities.write();
             priorities.set(*index, priority);
         }
     }
 
-    fn push(&mut self, experience: Experience<S>, td_error: f32) -> anyhow::Result<()> {
-        let priority = (td_error + 0.001).powf(self.alpha);
+    fn is_prioritized(&self) -> bool {
+        true
+    }
+
+    fn set_progress(&mut self, progress: f32) {
+        self.progress.store(progress.to_bits(), Ordering::Relaxed);
+    }
+
+    fn push(&mut self, experience: Experience<S>) -> anyhow::Result<()> {
+        let priority = self.max_priority;
         let index = self.counter.fetch_add(1, Ordering::Relaxed) % self.max_buffer_size;
-        let value = rmp_serde::to_vec(&experience)?;
 
         let mut priorities = self.priorities.write();
-        self.buffer.put(index.to_le_bytes(), value)?;
+        self.storage.put(index, &experience)?;
         priorities.set(index, priority);
 
         Ok(())
     }
 
-    pub fn sample(&self) -> anyhow::Result<PrioritizedBatch<S>> {
-        self.batch_channel.try_recv().with_context(|| "recv batch")
+    fn sample(&mut self) -> anyhow::Result<ReplayBatch<Experience<S>>> {
+        // the samplers start once the memory holds a batch, then every call waits for one
+        ensure!(
+            self.counter.load(Ordering::Relaxed) >= self.batch_size,
+            "not enough experiences to sample a batch"
+        );
+        self.batch_channel.recv().with_context(|| "recv batch")?
+    }
+
+    fn batch_size(&self) -> usize {
+        self.batch_size
+    }
+
+    fn len(&self) -> usize {
+        self.counter
+            .load(Ordering::Relaxed)
+            .min(self.max_buffer_size)
+    }
+
+    fn capacity(&self) -> usize {
+        self.max_buffer_size
+    }
+
+    fn save(&self, path: &Path) -> anyhow::Result<()> {
+        ensure!(
+            self.storage.is_persistent(),
+            "the experiences are not persistent"
+        );
+        let priorities = self.priorities.read();
+        let record = PrioritizedReplayRecord {
+            counter: self.counter.load(Ordering::Relaxed),
+            max_priority: self.max_priority,
+            priorities: &*priorities,
+        };
+        let mut writer = BufWriter::new(File::create(path).with_context(|| "create memory")?);
+        rmp_serde::encode::write(&mut writer, &record).with_context(|| "write memory")?;
+        Ok(())
+    }
+
+    fn load(&mut self, path: &Path) -> anyhow::Result<()> {
+        let reader = BufReader::new(File::open(path).with_context(|| "open memory")?);
+        let record: PrioritizedReplayRecord<SumTree> =
+            rmp_serde::from_read(reader).with_context(|| "read memory")?;
+        ensure!(
+            record.priorities.capacity == self.max_buffer_size,
+            "memory size mismatch: {} != {}",
+            record.priorities.capacity,
+            self.max_buffer_size
+        );
+        *self.priorities.write() = record.priorities;
+        self.max_priority = record.max_priority;
+        self.counter.store(record.counter, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+// the sampled priorities turn into importance sampling weights normalized by their max
+fn sample_batch<S: FlatState>(
+    storage: &dyn ExperienceStorage<S>,
+    priorities: Vec<(usize, f32)>,
+    beta: f32,
+) -> anyhow::Result<ReplayBatch<Experience<S>>> {
+    let mut indexes = Vec::with_capacity(priorities.len());
+    let mut experiences = Vec::with_capacity(priorities.len());
+    let mut weights = Vec::with_capacity(priorities.len());
+    for (index, priority) in priorities {
+        // every sampled index has been pushed, a missing one is a broken storage
+        let experience = storage
+            .get(index)?
+            .with_context(|| format!("missing experience at index {}", index))?;
+        indexes.push(index);
+        experiences.push(experience);
+        weights.push((1.0 / priority).powf(beta));
     }
+    let max_weight = weights.iter().copied().fold(0.0, f32::max);
+    let weights = weights.into_iter().map(|x| x / max_weight).collect();
+    Ok(ReplayBatch {
+        indexes,
+        experiences,
+        weights,
+    })
+}
+
+#[derive(Serialize, Deserialize)]
+struct PrioritizedReplayRecord<T> {
+    counter: usize,
+    max_priority: f32,
+    priorities: T,
 }
 
-struct SumTree {
+#[derive(Serialize, Deserialize)]
+pub(crate) struct SumTree {
     data: Vec<f32>,
     capacity: usize,
 }
@@ -322,7 +328,8 @@ impl SumTree {
         while cur < self.capacity {
             let left = 2 * cur;
             let right = 2 * cur + 1;
-            if value > self.data[left] {
+            // ties go right, so a leaf with a zero priority is never sampled
+            if value >= self.data[left] {
                 cur = right;
                 value -= self.data[left];
             } else {
@@ -338,8 +345,59 @@ impl SumTree {
 
 #[cfg(test)]
 mod tests {
+    use crate::{Action, DeepQNetworkState};
+
     use super::*;
 
+    #[test]
+    fn test_prioritized_replay_memory() -> anyhow::Result<()> {
+        let config = PrioritizedReplayConfig::new()
+            .with_alpha(0.5)
+            .with_beta_start(0.4)
+            .with_beta_end(1.0)
+            .with_priority_epsilon(0.0);
+        assert_eq!(config.beta(-1.0), 0.4);
+        assert!((config.beta(0.5) - 0.7).abs() < 1e-6);
+        assert_eq!(config.beta(2.0), 1.0);
+        assert_eq!(config.priority(4.0), 2.0);
+        assert!(PrioritizedReplayMemory::<DeepQNetworkState>::new(
+            8,
+            2,
+            config.clone(),
+            StorageBackend::InMemory,
+        )
+        .is_err());
+
+        let mut memory = PrioritizedReplayMemory::<DeepQNetworkState>::new(
+            8,
+            2,
+            config.with_priority_epsilon(1e-4),
+            StorageBackend::InMemory,
+        )?;
+        let experience = Experience {
+            state: DeepQNetworkState::default(),
+            action: Action::Discrete(0),
+            reward: 1.0,
+            terminated: false,
+            truncated: false,
+        };
+        for _ in 0..4 {
+            memory.push(experience.clone())?;
+        }
+        assert_eq!(memory.priorities.read().total(), 4.0);
+
+        memory.update_priorities(vec![0, 1], vec![9.0, 0.0]);
+        memory.push(experience.clone())?;
+        // 3 + 0.01 for the updated ones, 1 + 1 for the others and 3 for the new one
+        assert!((memory.priorities.read().total() - 8.01).abs() < 1e-3);
+
+        memory.set_progress(1.0);
+        let batch = memory.sample()?;
+        assert_eq!(batch.experiences.len(), 2);
+        assert!(batch.weights.iter().all(|w| *w > 0.0 && *w <= 1.0));
+        Ok(())
+    }
+
     #[test]
     fn test_sum_tree() {
         let sample_num = 10000;
@@ -361,5 +419,12 @@ mod tests {
                     < sample_num as f32 / 10.0
             );
         }
+
+        sum_tree.set(0, 0.0);
+        sum_tree.set(2, 0.0);
+        for _ in 0..sample_num {
+            let (index, _) = sum_tree.sample();
+            assert!(index == 1 || index == 3);
+        }
     }
 }
@@ -0,0 +1,845 @@
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{BufReader, Write as _},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{ensure, Context as _};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::json;
+
+use crate::{Agent, Env, Experience, PrioritizedReplayAgent, State, VecEnv};
+
+use super::{
+    create_train_logger, ensure_action_space, random_action, Evaluation, NStepExperience,
+    RandomPolicy, ReplayBatch, ReplayMemory, RewardMapping, TrainingScheduleConfig, TrainingState,
+};
+
+// the off-policy trainer of the agents learning from a `ReplayMemory`
+pub struct ReplayTrainer {
+    episode: usize,
+    gamma: f32,
+    n_step: usize,
+    rewards_mapping: RewardMapping,
+    artifacts_dir: PathBuf,
+    render: bool,
+    schedule: TrainingScheduleConfig,
+    // where the memory and the n-step buffers are checkpointed and resumed from
+    replay_checkpoint: Option<PathBuf>,
+    training_state: Option<TrainingState>,
+    // seeds the exploration of a fresh run
+    seed: Option<u64>,
+}
+
+impl ReplayTrainer {
+    pub fn new(
+        episode: usize,
+        gamma: f32,
+        n_step: usize,
+        rewards_mapping: RewardMapping,
+        artifacts_dir: PathBuf,
+        render: bool,
+    ) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(&artifacts_dir).with_context(|| "create artifact dir")?;
+        Ok(Self {
+            episode,
+            gamma,
+            n_step,
+            rewards_mapping,
+            artifacts_dir,
+            render,
+            schedule: TrainingScheduleConfig::new(),
+            replay_checkpoint: None,
+            training_state: None,
+            seed: None,
+        })
+    }
+
+    pub fn with_schedule(mut self, schedule: TrainingScheduleConfig) -> Self {
+        self.schedule = schedule;
+        self
+    }
+
+    // resumes the episodes, steps and exploration of a restored checkpoint
+    pub fn with_training_state(mut self, training_state: Option<TrainingState>) -> Self {
+        self.training_state = training_state;
+        self
+    }
+
+    pub fn with_seed(mut self, seed: Option<u64>) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    pub fn with_replay_checkpoint(mut self, replay_checkpoint: Option<PathBuf>) -> Self {
+        self.replay_checkpoint = replay_checkpoint;
+        self
+    }
+
+    pub fn train_loop<S: State + Serialize + DeserializeOwned + 'static, const D: usize>(
+        &self,
+        agent: &mut impl Agent<S>,
+        env: &mut impl Env<D>,
+        memory: &mut impl ReplayMemory<Experience<S>>,
+        random_policy: &Option<RandomPolicy>,
+        evaluation: &mut Option<Evaluation<'_, D>>,
+    ) -> anyhow::Result<()> {
+        let mut envs = VecEnv::new(vec![env])?;
+        self.vec_train_loop(agent, &mut envs, memory, random_policy, evaluation)
+    }
+
+    pub fn vec_train_loop<
+        S: State + Serialize + DeserializeOwned + 'static,
+        const D: usize,
+        E: Env<D>,
+    >(
+        &self,
+        agent: &mut impl Agent<S>,
+        envs: &mut VecEnv<D, E>,
+        memory: &mut impl ReplayMemory<Experience<S>>,
+        random_policy: &Option<RandomPolicy>,
+        evaluation: &mut Option<Evaluation<'_, D>>,
+    ) -> anyhow::Result<()> {
+        ensure!(
+            !memory.is_prioritized(),
+            "a prioritized memory needs the td errors of the agent, use the prioritized train loop"
+        );
+        self.run(agent, envs, memory, random_policy, evaluation, |_, _, _| {
+            Ok(())
+        })
+    }
+
+    pub fn prioritized_train_loop<
+        S: State + Serialize + DeserializeOwned + 'static,
+        const D: usize,
+    >(
+        &self,
+        agent: &mut impl PrioritizedReplayAgent<S>,
+        env: &mut impl Env<D>,
+        memory: &mut impl ReplayMemory<Experience<S>>,
+        random_policy: &Option<RandomPolicy>,
+        evaluation: &mut Option<Evaluation<'_, D>>,
+    ) -> anyhow::Result<()> {
+        let mut envs = VecEnv::new(vec![env])?;
+        self.vec_prioritized_train_loop(agent, &mut envs, memory, random_policy, evaluation)
+    }
+
+    // updates the priorities of a prioritized memory with the td errors of each batch
+    pub fn vec_prioritized_train_loop<
+        S: State + Serialize + DeserializeOwned + 'static,
+        const D: usize,
+        E: Env<D>,
+    >(
+        &self,
+        agent: &mut impl PrioritizedReplayAgent<S>,
+        envs: &mut VecEnv<D, E>,
+        memory: &mut impl ReplayMemory<Experience<S>>,
+        random_policy: &Option<RandomPolicy>,
+        evaluation: &mut Option<Evaluation<'_, D>>,
+    ) -> anyhow::Result<()> {
+        self.run(
+            agent,
+            envs,
+            memory,
+            random_policy,
+            evaluation,
+            |agent, memory, batch| {
+                if !memory.is_prioritized() {
+                    return Ok(());
+                }
+                let td_errors =
+                    agent.temporaral_difference_error(self.gamma, &batch.experiences)?;
+                let (indexes, td_errors) = batch
+                    .indexes
+                    .iter()
+                    .zip(td_errors)
+                    .filter(|(_, x)| !x.is_nan())
+                    .unzip();
+                memory.update_priorities(indexes, td_errors);
+                Ok(())
+            },
+        )
+    }
+
+    fn run<
+        S: State + Serialize + DeserializeOwned + 'static,
+        const D: usize,
+        E: Env<D>,
+        A: Agent<S>,
+        M: ReplayMemory<Experience<S>>,
+    >(
+        &self,
+        agent: &mut A,
+        envs: &mut VecEnv<D, E>,
+        memory: &mut M,
+        random_policy: &Option<RandomPolicy>,
+        evaluation: &mut Option<Evaluation<'_, D>>,
+        mut after_update: impl FnMut(&A, &mut M, &ReplayBatch<Experience<S>>) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        ensure_action_space(agent, envs.action_space())?;
+        let env_num = envs.len();
+        let mut n_step_experiences = (0..env_num)
+            .map(|_| NStepExperience::new(self.n_step, self.gamma, self.rewards_mapping.clone()))
+            .collect::<Vec<_>>();
+        if let Some(dir) = &self.replay_checkpoint {
+            if dir.join("memory.mpk").exists() {
+                Self::load_replay(dir, memory, &mut n_step_experiences)?;
+            }
+        }
+
+        let mut observations = envs.reset()?;
+        let mut states = observations
+            .iter()
+            .map(|observation| S::new(observation.clone()))
+            .collect::<Vec<_>>();
+        // the episodes running at the checkpoint start over
+        let (mut finished_episode, mut total_step, mut rng) =
+            TrainingState::restore(&self.training_state, self.seed, agent, evaluation);
+        let mut episodes = (finished_episode..finished_episode + env_num).collect::<Vec<_>>();
+        let mut next_episode = finished_episode + env_num;
+        let mut steps = vec![0; env_num];
+        let mut cumulative_rewards = vec![0.0; env_num];
+        let mut train_loggers = episodes
+            .iter()
+            .map(|epi| self.train_logger(*epi))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        while finished_episode < self.episode && !self.schedule.is_finished(total_step) {
+            if self.render {
+                envs.render()?;
+            }
+
+            agent.resample_noise();
+            let mut actions = agent.batch_state_policy(&observations, &states);
+            if let Some(policy) = random_policy {
+                for (action, epi) in actions.iter_mut().zip(episodes.iter()) {
+                    if policy.sample(*epi, &mut rng) {
+                        *action = random_action(envs.action_space(), &mut rng);
+                    }
+                }
+            }
+
+            let results = envs.step(&actions)?;
+            total_step += env_num;
+            let previous_finished_episode = finished_episode;
+            for (i, (action, result)) in actions.into_iter().zip(results).enumerate() {
+                steps[i] += 1;
+                states[i] = agent.make_state(&result.observation, &states[i]);
+                cumulative_rewards[i] += result.reward;
+                if let Some(train_logger) = train_loggers[i].as_mut() {
+                    let log = json!({
+                        "episode": episodes[i],
+                        "step": steps[i],
+                        "action": action,
+                        "reward": result.reward,
+                        "cumulative_reward": cumulative_rewards[i]
+                    });
+                    writeln!(train_logger, "{}", log).with_context(|| "write train log")?;
+                }
+
+                let experience = Experience {
+                    state: states[i].clone(),
+                    action,
+                    reward: result.reward,
+                    terminated: result.terminated,
+                    truncated: result.truncated,
+                };
+
+                if let Some(experience) = n_step_experiences[i].push(experience)? {
+                    memory.push(experience)?;
+                }
+
+                observations[i] = result.observation;
+                if let Some(observation) = result.reset_observation {
+                    if episodes[i] < self.episode {
+                        finished_episode += 1;
+                        TrainingState::checkpoint(
+                            agent,
+                            evaluation,
+                            &self.artifacts_dir.join(format!("{}", episodes[i])),
+                            finished_episode,
+                            total_step,
+                            &mut rng,
+                        )?;
+                    }
+                    episodes[i] = next_episode;
+                    next_episode += 1;
+                    steps[i] = 0;
+                    cumulative_rewards[i] = 0.0;
+                    states[i] = S::new(observation.clone());
+                    observations[i] = observation;
+                    train_loggers[i] = self.train_logger(episodes[i])?;
+                }
+            }
+            if finished_episode > previous_finished_episode {
+                if let Some(dir) = &self.replay_checkpoint {
+                    Self::save_replay(dir, memory, &n_step_experiences)?;
+                }
+            }
+
+            if let Some(evaluation) = evaluation {
+                evaluation.evaluate_if_due(
+                    agent,
+                    &self.artifacts_dir,
+                    finished_episode,
+                    total_step,
+                )?;
+            }
+
+            memory.set_progress(
+                self.schedule
+                    .progress(finished_episode, self.episode, total_step),
+            );
+            for _ in 0..self.schedule.updates(total_step - env_num, total_step) {
+                // the updates start once the memory holds a batch
+                if memory.len() < memory.batch_size() {
+                    break;
+                }
+                let batch = memory.sample()?;
+                agent.update(self.gamma, &batch.experiences, &batch.weights)?;
+                after_update(agent, memory, &batch)?;
+            }
+        }
+        Ok(())
+    }
+
+    // written next to a temporary file first, a preemption leaves the previous checkpoint
+    fn save_replay<S: State + Serialize + DeserializeOwned + 'static>(
+        dir: &Path,
+        memory: &impl ReplayMemory<Experience<S>>,
+        n_step_experiences: &[NStepExperience<S>],
+    ) -> anyhow::Result<()> {
+        std::fs::create_dir_all(dir).with_context(|| "create replay checkpoint dir")?;
+        memory.save(&dir.join("memory.mpk.tmp"))?;
+        let pending = n_step_experiences
+            .iter()
+            .map(NStepExperience::pending)
+            .collect::<Vec<_>>();
+        let pending = rmp_serde::to_vec(&pending).with_context(|| "encode n-step buffers")?;
+        std::fs::write(dir.join("n_step.mpk.tmp"), pending)
+            .with_context(|| "write n-step buffers")?;
+        for name in ["memory.mpk", "n_step.mpk"] {
+            std::fs::rename(dir.join(format!("{}.tmp", name)), dir.join(name))
+                .with_context(|| format!("replace {}", name))?;
+        }
+        Ok(())
+    }
+
+    // pending experiences of envs beyond the current number are dropped
+    fn load_replay<S: State + Serialize + DeserializeOwned + 'static>(
+        dir: &Path,
+        memory: &mut impl ReplayMemory<Experience<S>>,
+        n_step_experiences: &mut [NStepExperience<S>],
+    ) -> anyhow::Result<()> {
+        memory
+            .load(&dir.join("memory.mpk"))
+            .with_context(|| "load replay memory")?;
+        let reader =
+            BufReader::new(File::open(dir.join("n_step.mpk")).with_context(|| "open n-step")?);
+        let pending: Vec<VecDeque<Experience<S>>> =
+            rmp_serde::from_read(reader).with_context(|| "read n-step buffers")?;
+        for (n_step_experience, pending) in n_step_experiences.iter_mut().zip(pending) {
+            n_step_experience.restore(pending);
+        }
+        Ok(())
+    }
+
+    fn train_logger(&self, epi: usize) -> anyhow::Result<Option<File>> {
+        if epi < self.episode {
+            Ok(Some(create_train_logger(&self.artifacts_dir, epi)?))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use burn::{
+        backend::{libtorch::LibTorchDevice, Autodiff, LibTorch},
+        lr_scheduler::constant::ConstantLr,
+        optim::AdamConfig,
+    };
+
+    use crate::{
+        agent::{
+            expectation::{DeepQNetworkAgent, DeepQNetworkAgentConfig},
+            LossFunction,
+        },
+        env::classic::{CartPole, Pendulum},
+        model::{DeepQNetworkModel, HeadConfig, OutputLayerConfig, TorsoConfig},
+        trainer::{
+            prioritized::{PrioritizedReplayConfig, PrioritizedReplayMemory},
+            storage::StorageBackend,
+            uniform::UniformReplayMemory,
+            EvaluationInterval,
+        },
+        Agent as _, DeepQNetworkState, FrameStackState, ObservationState,
+    };
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    type Backend = Autodiff<LibTorch>;
+
+    fn cart_pole_agent(
+        env: &impl Env<2>,
+    ) -> anyhow::Result<impl PrioritizedReplayAgent<DeepQNetworkState>> {
+        let device = LibTorchDevice::Cpu;
+        let model = DeepQNetworkModel::<Backend>::new(
+            &device,
+            env.observation_space(),
+            env.action_space(),
+            &TorsoConfig::default_for::<2>(),
+            &HeadConfig::new(),
+            false,
+            false,
+            OutputLayerConfig::Expectation,
+        )?;
+        DeepQNetworkAgent::new(
+            model,
+            AdamConfig::new().init(),
+            ConstantLr::new(0.00025),
+            env.observation_space().clone(),
+            env.action_space().clone(),
+            device,
+            DeepQNetworkAgentConfig::new(100, 1, false, LossFunction::Huber),
+        )
+    }
+
+    #[test]
+    fn test_agent_rejects_action_space() -> anyhow::Result<()> {
+        let env = CartPole::new();
+        let device = LibTorchDevice::Cpu;
+        let model = DeepQNetworkModel::<Backend>::new(
+            &device,
+            env.observation_space(),
+            env.action_space(),
+            &TorsoConfig::default_for::<2>(),
+            &HeadConfig::new(),
+            false,
+            false,
+            OutputLayerConfig::Expectation,
+        )?;
+        let agent = DeepQNetworkAgent::new(
+            model,
+            AdamConfig::new().init(),
+            ConstantLr::new(0.00025),
+            env.observation_space().clone(),
+            Pendulum::new().action_space().clone(),
+            device,
+            DeepQNetworkAgentConfig::new(100, 1, false, LossFunction::Huber),
+        );
+        assert!(agent.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_train_loop_cart_pole() -> anyhow::Result<()> {
+        let episode = 3;
+        let mut env = CartPole::with_seed(0);
+        let mut agent = cart_pole_agent(&env)?;
+        let mut memory =
+            UniformReplayMemory::<DeepQNetworkState>::new(1024, 8, StorageBackend::InMemory)?;
+
+        let artifacts_dir = TempDir::new()?;
+        let trainer = ReplayTrainer::new(
+            episode,
+            0.99,
+            1,
+            RewardMapping::Identity,
+            artifacts_dir.path().to_path_buf(),
+            false,
+        )?;
+        let random_policy = Some(RandomPolicy::new(1.0, 0.01, 0.99));
+        trainer.train_loop(&mut agent, &mut env, &mut memory, &random_policy, &mut None)?;
+
+        for epi in 0..episode {
+            let episode_artifacts_dir = artifacts_dir.path().join(format!("{}", epi));
+            assert!(episode_artifacts_dir.join("train.jsonl").exists());
+            assert!(episode_artifacts_dir.join("model.mpk").exists());
+            assert!(episode_artifacts_dir.join("optimizer.mpk").exists());
+        }
+        assert!(!artifacts_dir.path().join(format!("{}", episode)).exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_prioritized_train_loop_cart_pole() -> anyhow::Result<()> {
+        let episode = 3;
+        let mut env = CartPole::with_seed(0);
+        let mut agent = cart_pole_agent(&env)?;
+        let mut memory = PrioritizedReplayMemory::<DeepQNetworkState>::new(
+            1024,
+            8,
+            PrioritizedReplayConfig::new(),
+            StorageBackend::InMemory,
+        )?;
+
+        let artifacts_dir = TempDir::new()?;
+        let trainer = ReplayTrainer::new(
+            episode,
+            0.99,
+            3,
+            RewardMapping::Identity,
+            artifacts_dir.path().to_path_buf(),
+            false,
+        )?;
+        let random_policy = Some(RandomPolicy::new(1.0, 0.01, 0.99));
+        // the plain loop would never update the priorities
+        assert!(trainer
+            .train_loop(&mut agent, &mut env, &mut memory, &random_policy, &mut None)
+            .is_err());
+        trainer.prioritized_train_loop(
+            &mut agent,
+            &mut env,
+            &mut memory,
+            &random_policy,
+            &mut None,
+        )?;
+
+        assert!(memory.len() > 0 && memory.len() <= memory.capacity());
+        for epi in 0..episode {
+            let episode_artifacts_dir = artifacts_dir.path().join(format!("{}", epi));
+            assert!(episode_artifacts_dir.join("model.mpk").exists());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_training_state_resume() -> anyhow::Result<()> {
+        let mut env = CartPole::with_seed(0);
+        let mut agent = cart_pole_agent(&env)?;
+        let mut memory =
+            UniformReplayMemory::<DeepQNetworkState>::new(1024, 8, StorageBackend::InMemory)?;
+        let random_policy = Some(RandomPolicy::new(1.0, 0.01, 0.99));
+
+        let artifacts_dir = TempDir::new()?;
+        ReplayTrainer::new(
+            2,
+            0.99,
+            1,
+            RewardMapping::Identity,
+            artifacts_dir.path().to_path_buf(),
+            false,
+        )?
+        .train_loop(&mut agent, &mut env, &mut memory, &random_policy, &mut None)?;
+        let checkpoint = artifacts_dir.path().join("1");
+        assert!(checkpoint.join("teacher_model.mpk").exists());
+        let state = TrainingState::load(&checkpoint)?.unwrap();
+        assert_eq!(state.finished_episode, 2);
+        assert!(state.total_step > 0);
+        // the updates of the last step come after the checkpoint
+        assert!(0 < state.update_counter && state.update_counter <= agent.update_counter());
+
+        // a restored run picks up from the third episode with the saved update counter
+        let mut restored_agent = cart_pole_agent(&env)?;
+        restored_agent.load(&checkpoint)?;
+        let restored_dir = TempDir::new()?;
+        ReplayTrainer::new(
+            3,
+            0.99,
+            1,
+            RewardMapping::Identity,
+            restored_dir.path().to_path_buf(),
+            false,
+        )?
+        .with_training_state(Some(state.clone()))
+        .train_loop(
+            &mut restored_agent,
+            &mut env,
+            &mut memory,
+            &random_policy,
+            &mut None,
+        )?;
+        assert!(!restored_dir.path().join("0").exists());
+        let resumed = TrainingState::load(&restored_dir.path().join("2"))?.unwrap();
+        assert_eq!(resumed.finished_episode, 3);
+        assert!(resumed.total_step > state.total_step);
+        assert!(resumed.update_counter > state.update_counter);
+        assert!(TrainingState::load(restored_dir.path())?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_seeded_exploration() -> anyhow::Result<()> {
+        // the random actions alone decide the episodes, so the seeded runs play the same ones
+        let random_policy = Some(RandomPolicy::new(1.0, 1.0, 1.0));
+        let mut states = Vec::new();
+        for _ in 0..2 {
+            let mut env = CartPole::with_seed(0);
+            let mut agent = cart_pole_agent(&env)?;
+            let mut memory =
+                UniformReplayMemory::<DeepQNetworkState>::new(1024, 8, StorageBackend::InMemory)?;
+            let artifacts_dir = TempDir::new()?;
+            ReplayTrainer::new(
+                2,
+                0.99,
+                1,
+                RewardMapping::Identity,
+                artifacts_dir.path().to_path_buf(),
+                false,
+            )?
+            .with_seed(Some(42))
+            .train_loop(
+                &mut agent,
+                &mut env,
+                &mut memory,
+                &random_policy,
+                &mut None,
+            )?;
+            states.push(TrainingState::load(&artifacts_dir.path().join("1"))?.unwrap());
+        }
+        assert_eq!(states[0].total_step, states[1].total_step);
+        assert_eq!(states[0].seed, states[1].seed);
+        Ok(())
+    }
+
+    #[test]
+    fn test_replay_checkpoint() -> anyhow::Result<()> {
+        let mut env = CartPole::with_seed(0);
+        let mut agent = cart_pole_agent(&env)?;
+        let artifacts_dir = TempDir::new()?;
+        let replay_dir = artifacts_dir.path().join("replay");
+        let backend = StorageBackend::PersistentRocksDb(replay_dir.join("experiences"));
+        let memory = |backend: StorageBackend| {
+            PrioritizedReplayMemory::<DeepQNetworkState>::new(
+                1024,
+                8,
+                PrioritizedReplayConfig::new(),
+                backend,
+            )
+        };
+        let trainer = |episode| {
+            ReplayTrainer::new(
+                episode,
+                0.99,
+                3,
+                RewardMapping::Identity,
+                artifacts_dir.path().to_path_buf(),
+                false,
+            )
+            .map(|trainer| trainer.with_replay_checkpoint(Some(replay_dir.clone())))
+        };
+
+        let mut trained_memory = memory(backend.clone())?;
+        trainer(2)?.prioritized_train_loop(
+            &mut agent,
+            &mut env,
+            &mut trained_memory,
+            &None,
+            &mut None,
+        )?;
+        assert!(replay_dir.join("memory.mpk").exists());
+        assert!(replay_dir.join("n_step.mpk").exists());
+        let trained_len = trained_memory.len();
+        // the storage is released with the memory
+        drop(trained_memory);
+        let trained = {
+            let storage = backend.create::<DeepQNetworkState>()?;
+            (0..trained_len)
+                .map(|index| {
+                    storage
+                        .get(index)
+                        .map(|experience| format!("{:?}", experience))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?
+        };
+
+        // a new memory on the same dir is resumed before the first step and keeps growing
+        let mut resumed_memory = memory(backend)?;
+        trainer(1)?.prioritized_train_loop(
+            &mut agent,
+            &mut env,
+            &mut resumed_memory,
+            &None,
+            &mut None,
+        )?;
+        assert!(resumed_memory.len() > trained_len);
+        let batches = (0..10)
+            .map(|_| resumed_memory.sample())
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let mut sampled_trained = 0;
+        for batch in &batches {
+            for (index, experience) in batch.indexes.iter().zip(&batch.experiences) {
+                if *index < trained_len {
+                    assert_eq!(format!("{:?}", Some(experience)), trained[*index]);
+                    sampled_trained += 1;
+                }
+            }
+        }
+        assert!(sampled_trained > 0);
+
+        // the sampling state is not saved without the experiences
+        let memory = memory(StorageBackend::InMemory)?;
+        assert!(memory
+            .save(&artifacts_dir.path().join("memory.mpk"))
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_vec_train_loop_cart_pole() -> anyhow::Result<()> {
+        let episode = 6;
+        let mut envs = VecEnv::new((0..4).map(CartPole::with_seed).collect())?;
+        let mut agent = cart_pole_agent(&CartPole::new())?;
+        let mut memory =
+            UniformReplayMemory::<DeepQNetworkState>::new(1024, 8, StorageBackend::InMemory)?;
+
+        let artifacts_dir = TempDir::new()?;
+        let trainer = ReplayTrainer::new(
+            episode,
+            0.99,
+            1,
+            RewardMapping::Identity,
+            artifacts_dir.path().to_path_buf(),
+            false,
+        )?;
+        trainer.vec_train_loop(&mut agent, &mut envs, &mut memory, &None, &mut None)?;
+
+        for epi in 0..episode {
+            let episode_artifacts_dir = artifacts_dir.path().join(format!("{}", epi));
+            assert!(episode_artifacts_dir.join("train.jsonl").exists());
+            assert!(episode_artifacts_dir.join("model.mpk").exists());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_train_loop_evaluation() -> anyhow::Result<()> {
+        let episode = 4;
+        let mut env = CartPole::with_seed(0);
+        let mut agent = cart_pole_agent(&env)?;
+        let mut memory =
+            UniformReplayMemory::<DeepQNetworkState>::new(1024, 8, StorageBackend::InMemory)?;
+
+        let artifacts_dir = TempDir::new()?;
+        let trainer = ReplayTrainer::new(
+            episode,
+            0.99,
+            1,
+            RewardMapping::Identity,
+            artifacts_dir.path().to_path_buf(),
+            false,
+        )?;
+        let mut evaluation = Some(Evaluation::new(
+            CartPole::with_seed(1),
+            EvaluationInterval::Episode(2),
+            3,
+        ));
+        trainer.train_loop(&mut agent, &mut env, &mut memory, &None, &mut evaluation)?;
+
+        let eval_log = std::fs::read_to_string(artifacts_dir.path().join("eval.jsonl"))?;
+        let logs = eval_log
+            .lines()
+            .map(serde_json::from_str::<serde_json::Value>)
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(logs.len(), 2);
+        let best_score = logs
+            .iter()
+            .map(|log| log["mean"].as_f64().unwrap() as f32)
+            .fold(f32::NEG_INFINITY, f32::max);
+        assert_eq!(evaluation.unwrap().best_score(), Some(best_score));
+        let first_score = logs[0]["mean"].as_f64().map(|mean| mean as f32);
+        for log in logs {
+            assert!(log["min"].as_f64() <= log["mean"].as_f64());
+            assert!(log["mean"].as_f64() <= log["max"].as_f64());
+        }
+        assert!(artifacts_dir.path().join("best").join("model.mpk").exists());
+
+        // the checkpoint of the third episode comes after the first evaluation only
+        let checkpoint = artifacts_dir.path().join("2");
+        let state = TrainingState::load(&checkpoint)?.unwrap();
+        assert_eq!(state.evaluated, 1);
+        assert_eq!(state.best_score, first_score);
+        assert_ne!(state.seed, state.noise_seed);
+
+        // a restored run evaluates again at the fourth episode, not as soon as it starts
+        let mut restored_agent = cart_pole_agent(&env)?;
+        restored_agent.load(&checkpoint)?;
+        let restored_dir = TempDir::new()?;
+        let mut evaluation = Some(Evaluation::new(
+            CartPole::with_seed(1),
+            EvaluationInterval::Episode(2),
+            3,
+        ));
+        ReplayTrainer::new(
+            episode,
+            0.99,
+            1,
+            RewardMapping::Identity,
+            restored_dir.path().to_path_buf(),
+            false,
+        )?
+        .with_training_state(Some(state.clone()))
+        .train_loop(
+            &mut restored_agent,
+            &mut env,
+            &mut memory,
+            &None,
+            &mut evaluation,
+        )?;
+        let eval_log = std::fs::read_to_string(restored_dir.path().join("eval.jsonl"))?;
+        assert_eq!(eval_log.lines().count(), 1);
+        assert!(evaluation.unwrap().best_score() >= state.best_score);
+
+        // cart pole pays 1 per step, the capped episodes score at most the cap
+        let capped_dir = TempDir::new()?;
+        let mut evaluation =
+            Evaluation::new(CartPole::with_seed(1), EvaluationInterval::Episode(1), 2)
+                .with_max_steps(5);
+        evaluation.evaluate(&mut restored_agent, capped_dir.path(), 0, 0)?;
+        assert!(evaluation.best_score() <= Some(5.0));
+        Ok(())
+    }
+
+    #[test]
+    fn test_train_loop_frame_stack() -> anyhow::Result<()> {
+        let episode = 2;
+        let mut env = CartPole::with_seed(0);
+        let device = LibTorchDevice::Cpu;
+        let observation_space = FrameStackState::<4>::observation_space(env.observation_space());
+        assert_eq!(observation_space.shape(), &[1, 16]);
+        let model = DeepQNetworkModel::<Backend>::new(
+            &device,
+            &observation_space,
+            env.action_space(),
+            &TorsoConfig::default_for::<2>(),
+            &HeadConfig::new(),
+            false,
+            false,
+            OutputLayerConfig::Expectation,
+        )?;
+        let mut agent = DeepQNetworkAgent::new(
+            model,
+            AdamConfig::new().init(),
+            ConstantLr::new(0.00025),
+            observation_space,
+            env.action_space().clone(),
+            device,
+            DeepQNetworkAgentConfig::new(100, 1, false, LossFunction::Huber),
+        )?;
+        let mut memory =
+            UniformReplayMemory::<FrameStackState<4>>::new(1024, 8, StorageBackend::RocksDb)?;
+
+        let artifacts_dir = TempDir::new()?;
+        let trainer = ReplayTrainer::new(
+            episode,
+            0.99,
+            1,
+            RewardMapping::Identity,
+            artifacts_dir.path().to_path_buf(),
+            false,
+        )?;
+        let random_policy = Some(RandomPolicy::new(1.0, 0.01, 0.99));
+        trainer.train_loop(&mut agent, &mut env, &mut memory, &random_policy, &mut None)?;
+
+        for epi in 0..episode {
+            let episode_artifacts_dir = artifacts_dir.path().join(format!("{}", epi));
+            assert!(episode_artifacts_dir.join("model.mpk").exists());
+        }
+        Ok(())
+    }
+}
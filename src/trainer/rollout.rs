@@ -0,0 +1,305 @@
+use std::{fs::File, io::Write, path::PathBuf};
+
+use anyhow::{ensure, Context as _};
+use rand::seq::SliceRandom as _;
+use serde_json::json;
+
+use crate::{Action, Env, OnPolicyAgent, RolloutSample, State, VecEnv};
+
+use super::{clip_action, create_train_logger, ensure_action_space, RewardMapping, TrainingState};
+
+#[derive(Debug, Clone)]
+pub struct RolloutStep {
+    pub observation: Vec<f32>,
+    pub action: Action,
+    pub reward: f32,
+    pub is_done: bool,
+    pub log_prob: f32,
+    pub value: f32,
+}
+
+pub struct RolloutBuffer {
+    rollout_length: usize,
+    gamma: f32,
+    gae_lambda: f32,
+    steps: Vec<Vec<RolloutStep>>,
+}
+
+impl RolloutBuffer {
+    pub fn new(env_num: usize, rollout_length: usize, gamma: f32, gae_lambda: f32) -> Self {
+        Self {
+            rollout_length,
+            gamma,
+            gae_lambda,
+            steps: (0..env_num)
+                .map(|_| Vec::with_capacity(rollout_length))
+                .collect(),
+        }
+    }
+
+    pub fn push(&mut self, env_index: usize, step: RolloutStep) {
+        self.steps[env_index].push(step);
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.steps
+            .iter()
+            .all(|steps| steps.len() >= self.rollout_length)
+    }
+
+    // generalized advantage estimation, `last_values` bootstraps the unfinished episodes
+    pub fn finish(&mut self, last_values: &[f32]) -> anyhow::Result<Vec<RolloutSample>> {
+        ensure!(
+            last_values.len() == self.steps.len(),
+            "expected {} last values, got {}",
+            self.steps.len(),
+            last_values.len()
+        );
+        let mut samples = Vec::with_capacity(self.steps.len() * self.rollout_length);
+        for (steps, last_value) in self.steps.iter_mut().zip(last_values) {
+            let mut advantage = 0.0;
+            let mut next_value = *last_value;
+            let mut env_samples = Vec::with_capacity(steps.len());
+            for step in steps.drain(..).rev() {
+                let mask = if step.is_done { 0.0 } else { 1.0 };
+                let delta = step.reward + self.gamma * next_value * mask - step.value;
+                advantage = delta + self.gamma * self.gae_lambda * mask * advantage;
+                next_value = step.value;
+                env_samples.push(RolloutSample {
+                    observation: step.observation,
+                    action: step.action,
+                    log_prob: step.log_prob,
+                    advantage,
+                    value_target: advantage + step.value,
+                });
+            }
+            env_samples.reverse();
+            samples.extend(env_samples);
+        }
+        Ok(samples)
+    }
+}
+
+pub struct RolloutTrainer {
+    episode: usize,
+    gamma: f32,
+    gae_lambda: f32,
+    rollout_length: usize,
+    epochs: usize,
+    minibatch_size: usize,
+    rewards_mapping: RewardMapping,
+    artifacts_dir: PathBuf,
+    render: bool,
+    training_state: Option<TrainingState>,
+    // seeds the minibatch shuffling of a fresh run
+    seed: Option<u64>,
+}
+
+impl RolloutTrainer {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        episode: usize,
+        gamma: f32,
+        gae_lambda: f32,
+        rollout_length: usize,
+        epochs: usize,
+        minibatch_size: usize,
+        rewards_mapping: RewardMapping,
+        artifacts_dir: PathBuf,
+        render: bool,
+    ) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(&artifacts_dir).with_context(|| "create artifact dir")?;
+        Ok(Self {
+            episode,
+            gamma,
+            gae_lambda,
+            rollout_length,
+            epochs,
+            minibatch_size,
+            rewards_mapping,
+            artifacts_dir,
+            render,
+            training_state: None,
+            seed: None,
+        })
+    }
+
+    // resumes the episodes and steps of a restored checkpoint, the rollout in progress is lost
+    pub fn with_training_state(mut self, training_state: Option<TrainingState>) -> Self {
+        self.training_state = training_state;
+        self
+    }
+
+    pub fn with_seed(mut self, seed: Option<u64>) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    pub fn train_loop<S: State, const D: usize>(
+        &self,
+        agent: &mut impl OnPolicyAgent<S>,
+        env: &mut impl Env<D>,
+    ) -> anyhow::Result<()> {
+        let mut envs = VecEnv::new(vec![env])?;
+        self.vec_train_loop(agent, &mut envs)
+    }
+
+    pub fn vec_train_loop<S: State, const D: usize, E: Env<D>>(
+        &self,
+        agent: &mut impl OnPolicyAgent<S>,
+        envs: &mut VecEnv<D, E>,
+    ) -> anyhow::Result<()> {
+        ensure_action_space(agent, envs.action_space())?;
+        let env_num = envs.len();
+        let mut buffer =
+            RolloutBuffer::new(env_num, self.rollout_length, self.gamma, self.gae_lambda);
+
+        let mut observations = envs.reset()?;
+        // the episodes running at the checkpoint start over
+        let (mut finished_episode, mut total_step, mut rng) =
+            TrainingState::restore::<_, D>(&self.training_state, self.seed, agent, &mut None);
+        let mut episodes = (finished_episode..finished_episode + env_num).collect::<Vec<_>>();
+        let mut next_episode = finished_episode + env_num;
+        let mut steps = vec![0; env_num];
+        let mut cumulative_rewards = vec![0.0; env_num];
+        let mut train_loggers = episodes
+            .iter()
+            .map(|epi| self.train_logger(*epi))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        while finished_episode < self.episode {
+            if self.render {
+                envs.render()?;
+            }
+
+            let evaluations = agent.evaluate(&observations);
+            let actions = evaluations
+                .iter()
+                .map(|(action, _, _)| clip_action(action.clone(), envs.action_space()))
+                .collect::<Vec<_>>();
+
+            let results = envs.step(&actions)?;
+            total_step += env_num;
+            for (i, ((action, log_prob, value), result)) in
+                evaluations.into_iter().zip(results).enumerate()
+            {
+                steps[i] += 1;
+                cumulative_rewards[i] += result.reward;
+                if let Some(train_logger) = train_loggers[i].as_mut() {
+                    let log = json!({
+                        "episode": episodes[i],
+                        "step": steps[i],
+                        "action": actions[i],
+                        "reward": result.reward,
+                        "cumulative_reward": cumulative_rewards[i]
+                    });
+                    writeln!(train_logger, "{}", log).with_context(|| "write train log")?;
+                }
+
+                let mut reward = self.rewards_mapping.apply(result.reward);
+                // a truncated episode still bootstraps from its last observation
+                if result.truncated && !result.terminated {
+                    reward +=
+                        self.gamma * agent.value(std::slice::from_ref(&result.observation))[0];
+                }
+                let is_done = result.is_done();
+                let observation = std::mem::replace(&mut observations[i], result.observation);
+                buffer.push(
+                    i,
+                    RolloutStep {
+                        observation,
+                        action,
+                        reward,
+                        is_done,
+                        log_prob,
+                        value,
+                    },
+                );
+
+                if let Some(observation) = result.reset_observation {
+                    if episodes[i] < self.episode {
+                        finished_episode += 1;
+                        TrainingState::checkpoint::<_, D>(
+                            agent,
+                            &None,
+                            &self.artifacts_dir.join(format!("{}", episodes[i])),
+                            finished_episode,
+                            total_step,
+                            &mut rng,
+                        )?;
+                    }
+                    episodes[i] = next_episode;
+                    next_episode += 1;
+                    steps[i] = 0;
+                    cumulative_rewards[i] = 0.0;
+                    observations[i] = observation;
+                    train_loggers[i] = self.train_logger(episodes[i])?;
+                }
+            }
+
+            if buffer.is_full() {
+                let last_values = agent.value(&observations);
+                let mut samples = buffer.finish(&last_values)?;
+                for _ in 0..self.epochs {
+                    samples.shuffle(&mut rng);
+                    for minibatch in samples.chunks(self.minibatch_size) {
+                        agent.update_rollout(minibatch)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn train_logger(&self, epi: usize) -> anyhow::Result<Option<File>> {
+        if epi < self.episode {
+            Ok(Some(create_train_logger(&self.artifacts_dir, epi)?))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn step(reward: f32, is_done: bool, value: f32) -> RolloutStep {
+        RolloutStep {
+            observation: vec![0.0],
+            action: Action::Discrete(0),
+            reward,
+            is_done,
+            log_prob: 0.0,
+            value,
+        }
+    }
+
+    #[test]
+    fn test_rollout_buffer_gae() -> anyhow::Result<()> {
+        let gamma = 0.9;
+        let gae_lambda = 0.5;
+        let mut buffer = RolloutBuffer::new(1, 3, gamma, gae_lambda);
+        buffer.push(0, step(1.0, false, 0.5));
+        assert!(!buffer.is_full());
+        buffer.push(0, step(2.0, true, 1.0));
+        buffer.push(0, step(3.0, false, 2.0));
+        assert!(buffer.is_full());
+
+        let samples = buffer.finish(&[4.0])?;
+        assert!(!buffer.is_full());
+
+        // the episode boundary after the second step cuts the bootstrap and the trace
+        let delta3 = 3.0 + gamma * 4.0 - 2.0;
+        let delta2 = 2.0 - 1.0;
+        let delta1 = 1.0 + gamma * 1.0 - 0.5;
+        let expected = [delta1 + gamma * gae_lambda * delta2, delta2, delta3];
+        for (sample, (advantage, value)) in samples.iter().zip(expected.iter().zip([0.5, 1.0, 2.0]))
+        {
+            assert!((sample.advantage - advantage).abs() < 1e-6);
+            assert!((sample.value_target - (advantage + value)).abs() < 1e-6);
+        }
+        assert!(buffer.finish(&[]).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,621 @@
+use std::{
+    fs::File,
+    io::Write as _,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
+        mpsc::Receiver,
+        Arc,
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use anyhow::{ensure, Context as _};
+use parking_lot::RwLock;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::json;
+
+use crate::{Env, Experience, FlatState, Sequence, SequenceReplayAgent, State, VecEnv};
+
+use super::{
+    create_train_logger, ensure_action_space,
+    prioritized::{PrioritizedReplayConfig, SumTree},
+    random_action,
+    storage::{ExperienceStorage, StorageBackend},
+    RandomPolicy, ReplayBatch, ReplayMemory, RewardMapping, TrainingState,
+};
+
+pub struct SequenceReplayTrainer {
+    episode: usize,
+    gamma: f32,
+    sequence_length: usize,
+    burn_in: usize,
+    sequence_period: usize,
+    rewards_mapping: RewardMapping,
+    artifacts_dir: PathBuf,
+    render: bool,
+    training_state: Option<TrainingState>,
+    // seeds the exploration of a fresh run
+    seed: Option<u64>,
+}
+
+impl SequenceReplayTrainer {
+    // sequences of `sequence_length` experiences start every `sequence_period` steps,
+    // the first `burn_in` experiences of each sequence are not trained on
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        episode: usize,
+        gamma: f32,
+        sequence_length: usize,
+        burn_in: usize,
+        sequence_period: usize,
+        rewards_mapping: RewardMapping,
+        artifacts_dir: PathBuf,
+        render: bool,
+    ) -> anyhow::Result<Self> {
+        ensure!(
+            burn_in < sequence_length,
+            "burn in {} must be shorter than the sequence length {}",
+            burn_in,
+            sequence_length
+        );
+        ensure!(
+            0 < sequence_period && sequence_period <= sequence_length - burn_in,
+            "sequence period {} must be in 1..={}",
+            sequence_period,
+            sequence_length - burn_in
+        );
+        std::fs::create_dir_all(&artifacts_dir).with_context(|| "create artifact dir")?;
+        Ok(Self {
+            episode,
+            gamma,
+            sequence_length,
+            burn_in,
+            sequence_period,
+            rewards_mapping,
+            artifacts_dir,
+            render,
+            training_state: None,
+            seed: None,
+        })
+    }
+
+    // resumes the episodes and exploration of a restored checkpoint
+    pub fn with_training_state(mut self, training_state: Option<TrainingState>) -> Self {
+        self.training_state = training_state;
+        self
+    }
+
+    pub fn with_seed(mut self, seed: Option<u64>) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    pub fn train_loop<S: State + Serialize + DeserializeOwned + 'static, const D: usize>(
+        &self,
+        agent: &mut impl SequenceReplayAgent<S>,
+        env: &mut impl Env<D>,
+        memory: &mut impl ReplayMemory<Sequence<S>>,
+        random_policy: &Option<RandomPolicy>,
+    ) -> anyhow::Result<()> {
+        let mut envs = VecEnv::new(vec![env])?;
+        self.vec_train_loop(agent, &mut envs, memory, random_policy)
+    }
+
+    pub fn vec_train_loop<
+        S: State + Serialize + DeserializeOwned + 'static,
+        const D: usize,
+        E: Env<D>,
+    >(
+        &self,
+        agent: &mut impl SequenceReplayAgent<S>,
+        envs: &mut VecEnv<D, E>,
+        memory: &mut impl ReplayMemory<Sequence<S>>,
+        random_policy: &Option<RandomPolicy>,
+    ) -> anyhow::Result<()> {
+        ensure_action_space(agent, envs.action_space())?;
+        let env_num = envs.len();
+        let mut sequence_buffers = (0..env_num)
+            .map(|_| SequenceBuffer::new(self.sequence_length, self.burn_in, self.sequence_period))
+            .collect::<Vec<_>>();
+
+        let mut observations = envs.reset()?;
+        let mut states = observations
+            .iter()
+            .map(|observation| S::new(observation.clone()))
+            .collect::<Vec<_>>();
+        // the episodes running at the checkpoint start over
+        let (mut finished_episode, mut total_step, mut rng) =
+            TrainingState::restore::<_, D>(&self.training_state, self.seed, agent, &mut None);
+        let mut episodes = (finished_episode..finished_episode + env_num).collect::<Vec<_>>();
+        let mut next_episode = finished_episode + env_num;
+        let mut steps = vec![0; env_num];
+        let mut cumulative_rewards = vec![0.0; env_num];
+        let mut train_loggers = episodes
+            .iter()
+            .map(|epi| self.train_logger(*epi))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        while finished_episode < self.episode {
+            if self.render {
+                envs.render()?;
+            }
+
+            agent.resample_noise();
+            let mut actions = agent.batch_state_policy(&observations, &states);
+            if let Some(policy) = random_policy {
+                for (action, epi) in actions.iter_mut().zip(episodes.iter()) {
+                    if policy.sample(*epi, &mut rng) {
+                        *action = random_action(envs.action_space(), &mut rng);
+                    }
+                }
+            }
+
+            let results = envs.step(&actions)?;
+            total_step += env_num;
+            for (i, (action, result)) in actions.into_iter().zip(results).enumerate() {
+                steps[i] += 1;
+                states[i] = agent.make_state(&result.observation, &states[i]);
+                cumulative_rewards[i] += result.reward;
+                if let Some(train_logger) = train_loggers[i].as_mut() {
+                    let log = json!({
+                        "episode": episodes[i],
+                        "step": steps[i],
+                        "action": action,
+                        "reward": result.reward,
+                        "cumulative_reward": cumulative_rewards[i]
+                    });
+                    writeln!(train_logger, "{}", log).with_context(|| "write train log")?;
+                }
+
+                let experience = Experience {
+                    state: states[i].clone(),
+                    action,
+                    reward: self.rewards_mapping.apply(result.reward),
+                    terminated: result.terminated,
+                    truncated: result.truncated,
+                };
+
+                if let Some(sequence) = sequence_buffers[i].push(experience) {
+                    memory.push(sequence)?;
+                }
+
+                observations[i] = result.observation;
+                if let Some(observation) = result.reset_observation {
+                    if let Some(sequence) = sequence_buffers[i].finish() {
+                        memory.push(sequence)?;
+                    }
+                    if episodes[i] < self.episode {
+                        finished_episode += 1;
+                        TrainingState::checkpoint::<_, D>(
+                            agent,
+                            &None,
+                            &self.artifacts_dir.join(format!("{}", episodes[i])),
+                            finished_episode,
+                            total_step,
+                            &mut rng,
+                        )?;
+                    }
+                    episodes[i] = next_episode;
+                    next_episode += 1;
+                    steps[i] = 0;
+                    cumulative_rewards[i] = 0.0;
+                    states[i] = S::new(observation.clone());
+                    observations[i] = observation;
+                    train_loggers[i] = self.train_logger(episodes[i])?;
+                }
+            }
+
+            if finished_episode == 0 || memory.len() < memory.batch_size() {
+                continue;
+            }
+            memory.set_progress(finished_episode as f32 / self.episode.max(1) as f32);
+            let batch = memory.sample()?;
+            let priorities =
+                agent.update_sequences(self.gamma, &batch.experiences, &batch.weights)?;
+            let (indexes, priorities) = batch
+                .indexes
+                .iter()
+                .zip(priorities)
+                .filter(|(_, x)| !x.is_nan())
+                .unzip();
+            memory.update_priorities(indexes, priorities);
+        }
+        Ok(())
+    }
+
+    fn train_logger(&self, epi: usize) -> anyhow::Result<Option<File>> {
+        if epi < self.episode {
+            Ok(Some(create_train_logger(&self.artifacts_dir, epi)?))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+// cuts the experiences of an episode into overlapping sequences
+pub struct SequenceBuffer<S: State> {
+    sequence_length: usize,
+    burn_in: usize,
+    sequence_period: usize,
+    experiences: Vec<Experience<S>>,
+    // leading experiences already trained on as part of the previous sequence
+    trained: usize,
+}
+
+impl<S: State> SequenceBuffer<S> {
+    pub fn new(sequence_length: usize, burn_in: usize, sequence_period: usize) -> Self {
+        Self {
+            sequence_length,
+            burn_in,
+            sequence_period,
+            experiences: Vec::with_capacity(sequence_length),
+            trained: 0,
+        }
+    }
+
+    pub fn push(&mut self, experience: Experience<S>) -> Option<Sequence<S>> {
+        self.experiences.push(experience);
+        if self.experiences.len() < self.sequence_length {
+            return None;
+        }
+        let sequence = Sequence {
+            experiences: self.experiences.clone(),
+            burn_in: self.burn_in,
+        };
+        self.experiences.drain(..self.sequence_period);
+        self.trained = self.sequence_length - self.sequence_period;
+        Some(sequence)
+    }
+
+    // the shorter tail of a finished episode with only its untrained experiences after the
+    // burn in, the agent pads and masks it to the sequence length
+    pub fn finish(&mut self) -> Option<Sequence<S>> {
+        let mut experiences = std::mem::take(&mut self.experiences);
+        let trained = std::mem::replace(&mut self.trained, 0);
+        experiences.drain(..trained.saturating_sub(self.burn_in));
+        if experiences.len() <= self.burn_in {
+            return None;
+        }
+        Some(Sequence {
+            experiences,
+            burn_in: self.burn_in,
+        })
+    }
+}
+
+pub struct SequenceReplayMemory<S: FlatState + Serialize + DeserializeOwned + Sync + 'static> {
+    storage: Arc<dyn ExperienceStorage<S>>,
+    // the length and burn in of the sequence in each slot, 0 long while empty, its experiences
+    // are stored at `slot * sequence_length + i`
+    slots: Arc<RwLock<Vec<(usize, usize)>>>,
+    priorities: Arc<RwLock<SumTree>>,
+    max_buffer_size: usize,
+    batch_size: usize,
+    sequence_length: usize,
+    counter: Arc<AtomicUsize>,
+    config: PrioritizedReplayConfig,
+    // new sequences have not been trained on yet, they get the highest priority so far
+    max_priority: f32,
+    // training progress as f32 bits, read by the samplers for beta
+    progress: Arc<AtomicU32>,
+
+    batch_channel: Receiver<anyhow::Result<ReplayBatch<Sequence<S>>>>,
+    samplers: Vec<JoinHandle<()>>,
+    stop: Arc<AtomicBool>,
+}
+
+impl<S: FlatState + Serialize + DeserializeOwned + Sync + 'static> SequenceReplayMemory<S> {
+    // an `alpha` of 0 samples the sequences uniformly
+    pub fn new(
+        max_buffer_size: usize,
+        batch_size: usize,
+        sequence_length: usize,
+        config: PrioritizedReplayConfig,
+        backend: StorageBackend,
+    ) -> anyhow::Result<Self> {
+        config.ensure_valid()?;
+        let storage = backend.create::<S>()?;
+
+        let slots = Arc::new(RwLock::new(vec![(0, 0); max_buffer_size]));
+        let counter = Arc::new(AtomicUsize::new(0));
+        let priorities = Arc::new(RwLock::new(SumTree::new(max_buffer_size)));
+        let progress = Arc::new(AtomicU32::new(0.0f32.to_bits()));
+
+        let (tx, rx) = std::sync::mpsc::sync_channel(4);
+
+        let sampler_num = 4;
+        let mut samplers = Vec::with_capacity(sampler_num);
+        let stop = Arc::new(AtomicBool::new(false));
+
+        for _ in 0..sampler_num {
+            let tx_clone = tx.clone();
+            // the samplers stop once the memory is dropped
+            let storage_clone = Arc::downgrade(&storage);
+            let slots_clone = slots.clone();
+            let priorities_clone = priorities.clone();
+            let counter_clone = counter.clone();
+            let stop_clone = stop.clone();
+            let progress_clone = progress.clone();
+            let config_clone = config.clone();
+
+            let sampler = std::thread::spawn(move || {
+                while counter_clone.load(Ordering::Relaxed) < batch_size {
+                    if stop_clone.load(Ordering::Relaxed) {
+                        return;
+                    }
+                    std::thread::sleep(Duration::from_millis(100));
+                }
+                while !stop_clone.load(Ordering::Relaxed) {
+                    let Some(storage_clone) = storage_clone.upgrade() else {
+                        return;
+                    };
+                    let priorities: Vec<(usize, f32)> = {
+                        let priorities = priorities_clone.read();
+                        (0..batch_size).map(|_| priorities.sample()).collect()
+                    };
+                    let beta =
+                        config_clone.beta(f32::from_bits(progress_clone.load(Ordering::Relaxed)));
+                    let batch = sample_batch(
+                        &*storage_clone,
+                        &slots_clone.read(),
+                        sequence_length,
+                        priorities,
+                        beta,
+                    );
+                    // a failed sampler reports the error and stops
+                    let failed = batch.is_err();
+                    if tx_clone.send(batch).is_err() || failed {
+                        return;
+                    }
+                }
+            });
+            samplers.push(sampler);
+        }
+
+        Ok(Self {
+            storage,
+            slots,
+            priorities,
+            max_buffer_size,
+            batch_size,
+            sequence_length,
+            counter,
+            config,
+            max_priority: 1.0,
+            progress,
+            batch_channel: rx,
+            samplers,
+            stop,
+        })
+    }
+}
+
+// the samplers are joined before the storage is dropped
+impl<S: FlatState + Serialize + DeserializeOwned + Sync + 'static> Drop
+    for SequenceReplayMemory<S>
+{
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        // a closed channel wakes the samplers waiting to send a batch
+        self.batch_channel = std::sync::mpsc::sync_channel(1).1;
+        for sampler in self.samplers.drain(..) {
+            let _ = sampler.join();
+        }
+    }
+}
+
+impl<S: FlatState + Serialize + DeserializeOwned + Sync + 'static> ReplayMemory<Sequence<S>>
+    for SequenceReplayMemory<S>
+{
+    fn update_priorities(&mut self, indexes: Vec<usize>, td_errors: Vec<f32>) {
+        for (index, td_error) in indexes.iter().zip(td_errors) {
+            let priority = self.config.priority(td_error);
+            self.max_priority = self.max_priority.max(priority);
+            let mut priorities = self.priorities.write();
+            priorities.set(*index, priority);
+        }
+    }
+
+    fn is_prioritized(&self) -> bool {
+        true
+    }
+
+    fn set_progress(&mut self, progress: f32) {
+        self.progress.store(progress.to_bits(), Ordering::Relaxed);
+    }
+
+    fn push(&mut self, sequence: Sequence<S>) -> anyhow::Result<()> {
+        ensure!(
+            sequence.experiences.len() <= self.sequence_length,
+            "sequence of {} experiences is longer than {}",
+            sequence.experiences.len(),
+            self.sequence_length
+        );
+        let priority = self.max_priority;
+        let index = self.counter.load(Ordering::Relaxed) % self.max_buffer_size;
+
+        // the samplers never see a partly written sequence
+        let mut slots = self.slots.write();
+        for (i, experience) in sequence.experiences.iter().enumerate() {
+            self.storage
+                .put(index * self.sequence_length + i, experience)?;
+        }
+        slots[index] = (sequence.experiences.len(), sequence.burn_in);
+        self.priorities.write().set(index, priority);
+        self.counter.fetch_add(1, Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    fn sample(&mut self) -> anyhow::Result<ReplayBatch<Sequence<S>>> {
+        // the samplers start once the memory holds a batch, then every call waits for one
+        ensure!(
+            self.counter.load(Ordering::Relaxed) >= self.batch_size,
+            "not enough sequences to sample a batch"
+        );
+        self.batch_channel.recv().with_context(|| "recv batch")?
+    }
+
+    fn batch_size(&self) -> usize {
+        self.batch_size
+    }
+
+    fn len(&self) -> usize {
+        self.counter
+            .load(Ordering::Relaxed)
+            .min(self.max_buffer_size)
+    }
+
+    fn capacity(&self) -> usize {
+        self.max_buffer_size
+    }
+}
+
+// the sampled priorities turn into importance sampling weights normalized by their max
+fn sample_batch<S: FlatState>(
+    storage: &dyn ExperienceStorage<S>,
+    slots: &[(usize, usize)],
+    sequence_length: usize,
+    priorities: Vec<(usize, f32)>,
+    beta: f32,
+) -> anyhow::Result<ReplayBatch<Sequence<S>>> {
+    let mut indexes = Vec::with_capacity(priorities.len());
+    let mut sequences = Vec::with_capacity(priorities.len());
+    let mut weights = Vec::with_capacity(priorities.len());
+    for (index, priority) in priorities {
+        // every sampled index has been pushed, a missing one is a broken storage
+        let (len, burn_in) = slots[index];
+        ensure!(len > 0, "missing sequence at index {}", index);
+        let experiences = (0..len)
+            .map(|i| {
+                storage
+                    .get(index * sequence_length + i)?
+                    .with_context(|| format!("missing experience {} of sequence {}", i, index))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        indexes.push(index);
+        sequences.push(Sequence {
+            experiences,
+            burn_in,
+        });
+        weights.push((1.0 / priority).powf(beta));
+    }
+    let max_weight = weights.iter().copied().fold(0.0, f32::max);
+    let weights = weights.into_iter().map(|x| x / max_weight).collect();
+    Ok(ReplayBatch {
+        indexes,
+        experiences: sequences,
+        weights,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::DeepQNetworkState;
+
+    use super::*;
+
+    fn experience(reward: f32, terminated: bool) -> Experience<DeepQNetworkState> {
+        Experience {
+            state: DeepQNetworkState::default(),
+            action: crate::Action::Discrete(0),
+            reward,
+            terminated,
+            truncated: false,
+        }
+    }
+
+    fn rewards(sequence: &Sequence<DeepQNetworkState>) -> Vec<f32> {
+        sequence.experiences.iter().map(|e| e.reward).collect()
+    }
+
+    #[test]
+    fn test_sequence_buffer() {
+        let mut buffer = SequenceBuffer::new(4, 1, 2);
+        let sequences = (0..7)
+            .filter_map(|i| buffer.push(experience(i as f32, i == 6)))
+            .collect::<Vec<_>>();
+        // the sequences overlap by `sequence_length - sequence_period` experiences
+        assert_eq!(sequences.len(), 2);
+        assert_eq!(rewards(&sequences[0]), vec![0.0, 1.0, 2.0, 3.0]);
+        assert_eq!(rewards(&sequences[1]), vec![2.0, 3.0, 4.0, 5.0]);
+        assert!(sequences.iter().all(|s| s.burn_in == 1));
+
+        // the tail trains only on the last experience, the one before it is its burn in
+        let tail = buffer.finish().unwrap();
+        assert_eq!(rewards(&tail), vec![5.0, 6.0]);
+        assert_eq!(tail.burn_in, 1);
+        assert!(buffer.finish().is_none());
+
+        // an episode shorter than a sequence is sent whole
+        let mut buffer = SequenceBuffer::new(4, 1, 2);
+        assert!((0..3)
+            .filter_map(|i| buffer.push(experience(i as f32, i == 2)))
+            .next()
+            .is_none());
+        assert_eq!(rewards(&buffer.finish().unwrap()), vec![0.0, 1.0, 2.0]);
+
+        // nothing new after the last full sequence
+        let mut buffer = SequenceBuffer::new(4, 1, 2);
+        assert!((0..4)
+            .filter_map(|i| buffer.push(experience(i as f32, i == 3)))
+            .next()
+            .is_some());
+        assert!(buffer.finish().is_none());
+
+        // too short to train on after the burn in
+        let mut buffer = SequenceBuffer::new(4, 1, 2);
+        assert!(buffer.push(experience(0.0, true)).is_none());
+        assert!(buffer.finish().is_none());
+    }
+
+    #[test]
+    fn test_sequence_replay_memory() -> anyhow::Result<()> {
+        let config = PrioritizedReplayConfig::new().with_alpha(0.5);
+        assert!(SequenceReplayMemory::<DeepQNetworkState>::new(
+            8,
+            2,
+            4,
+            config.clone().with_priority_epsilon(0.0),
+            StorageBackend::InMemory,
+        )
+        .is_err());
+
+        // the samplers start after the third sequence
+        let mut memory = SequenceReplayMemory::<DeepQNetworkState>::new(
+            2,
+            3,
+            4,
+            config,
+            StorageBackend::InMemory,
+        )?;
+        let sequence = |rewards: &[f32]| Sequence {
+            experiences: rewards.iter().map(|r| experience(*r, false)).collect(),
+            burn_in: 1,
+        };
+        assert!(memory.push(sequence(&[0.0; 5])).is_err());
+        assert!(memory.sample().is_err());
+
+        // the shorter sequence replacing a longer one keeps only its own experiences
+        memory.push(sequence(&[0.0, 1.0, 2.0, 3.0]))?;
+        memory.push(sequence(&[4.0, 5.0]))?;
+        memory.update_priorities(vec![0], vec![0.0]);
+        memory.push(sequence(&[6.0, 7.0]))?;
+        assert_eq!(memory.len(), 2);
+        let batch = memory.sample()?;
+        assert_eq!(batch.experiences.len(), 3);
+        for (index, sequence) in batch.indexes.iter().zip(&batch.experiences) {
+            let expected = if *index == 0 {
+                vec![6.0, 7.0]
+            } else {
+                vec![4.0, 5.0]
+            };
+            assert_eq!(rewards(sequence), expected);
+            assert_eq!(sequence.burn_in, 1);
+        }
+        assert!(batch.weights.iter().all(|w| *w > 0.0 && *w <= 1.0));
+        Ok(())
+    }
+}
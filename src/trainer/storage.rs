@@ -0,0 +1,531 @@
+use std::{
+    collections::HashMap,
+    io::Cursor,
+    marker::PhantomData,
+    mem::size_of,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::Context as _;
+use parking_lot::RwLock;
+use rocksdb::{DBCompressionType, DBWithThreadMode, IteratorMode, MultiThreaded, Options};
+use serde::{de::DeserializeOwned, Serialize};
+use tempfile::TempDir;
+
+use crate::{Action, Experience, FlatState, State};
+
+// observations up to this many floats are kept in memory by default
+const IN_MEMORY_OBSERVATION_SIZE: usize = 4096;
+
+// where the replay memories keep their experiences, indexed by ring buffer slot
+pub trait ExperienceStorage<S: State>: Send + Sync {
+    fn put(&self, index: usize, experience: &Experience<S>) -> anyhow::Result<()>;
+    fn get(&self, index: usize) -> anyhow::Result<Option<Experience<S>>>;
+    // the experiences outlive the memory and can be reopened
+    fn is_persistent(&self) -> bool {
+        false
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StorageBackend {
+    InMemory,
+    RocksDb,
+    // kept at the path after the training, reopened when it already exists
+    PersistentRocksDb(PathBuf),
+}
+
+impl StorageBackend {
+    // in memory for vector observations, on disk for pixels
+    pub fn for_observation_size(observation_size: usize) -> Self {
+        if observation_size <= IN_MEMORY_OBSERVATION_SIZE {
+            StorageBackend::InMemory
+        } else {
+            StorageBackend::RocksDb
+        }
+    }
+
+    pub fn create<S: FlatState + Serialize + DeserializeOwned + Sync + 'static>(
+        &self,
+    ) -> anyhow::Result<Arc<dyn ExperienceStorage<S>>> {
+        Ok(match self {
+            StorageBackend::InMemory => Arc::new(InMemoryStorage::new()),
+            StorageBackend::RocksDb => Arc::new(RocksDbStorage::new()?),
+            StorageBackend::PersistentRocksDb(path) => Arc::new(RocksDbStorage::open(path)?),
+        })
+    }
+}
+
+// experiences serialized with rmp_serde into a zstd-compressed rocksdb,
+// in a temporary dir unless it is opened at a path
+pub struct RocksDbStorage<S> {
+    temp_dir: Option<TempDir>,
+    db: DBWithThreadMode<MultiThreaded>,
+    // references to the shared frames, rebuilt from the experiences when reopened
+    references: RwLock<FrameReferences>,
+    _state: PhantomData<fn() -> S>,
+}
+
+impl<S: FlatState + DeserializeOwned> RocksDbStorage<S> {
+    pub fn new() -> anyhow::Result<Self> {
+        let dir = TempDir::new()?;
+        let db = Self::open_db(dir.path())?;
+        Ok(Self {
+            temp_dir: Some(dir),
+            db,
+            references: RwLock::new(FrameReferences::default()),
+            _state: PhantomData,
+        })
+    }
+
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(path).with_context(|| "create replay storage dir")?;
+        let db = Self::open_db(path).with_context(|| "open replay storage")?;
+        let mut references = FrameReferences::default();
+        for item in db.iterator(IteratorMode::Start) {
+            let (key, value) = item?;
+            if key.len() != size_of::<usize>() {
+                continue;
+            }
+            let experience: Experience<S> =
+                rmp_serde::from_read(Cursor::new(value)).with_context(|| "decode experience")?;
+            let keys = experience.state.frame_keys();
+            // states without shared frames need no references
+            if keys.is_empty() {
+                break;
+            }
+            references.acquire(&keys);
+        }
+        Ok(Self {
+            temp_dir: None,
+            db,
+            references: RwLock::new(references),
+            _state: PhantomData,
+        })
+    }
+
+    fn get_experience(&self, index: usize) -> anyhow::Result<Option<Experience<S>>> {
+        let Some(value) = self.db.get(index.to_le_bytes())? else {
+            return Ok(None);
+        };
+        let experience =
+            rmp_serde::from_read(Cursor::new(value)).with_context(|| "decode experience")?;
+        Ok(Some(experience))
+    }
+}
+
+impl<S> RocksDbStorage<S> {
+    fn open_db(path: &Path) -> anyhow::Result<DBWithThreadMode<MultiThreaded>> {
+        let mut opts = Options::default();
+        opts.create_if_missing(true);
+        opts.optimize_for_point_lookup(4 * 1024);
+        opts.set_compression_type(DBCompressionType::Zstd);
+        Ok(DBWithThreadMode::<MultiThreaded>::open(&opts, path)?)
+    }
+}
+
+// the frames are keyed apart from the experience indexes
+fn frame_key(key: u64) -> [u8; 13] {
+    let mut frame_key = [0; 13];
+    frame_key[..5].copy_from_slice(b"frame");
+    frame_key[5..].copy_from_slice(&key.to_le_bytes());
+    frame_key
+}
+
+impl<S> Drop for RocksDbStorage<S> {
+    fn drop(&mut self) {
+        if self.temp_dir.is_some() {
+            let _ = DBWithThreadMode::<MultiThreaded>::destroy(&Options::default(), self.db.path());
+        }
+    }
+}
+
+impl<S: FlatState + Serialize + DeserializeOwned + Sync> ExperienceStorage<S>
+    for RocksDbStorage<S>
+{
+    fn put(&self, index: usize, experience: &Experience<S>) -> anyhow::Result<()> {
+        let keys = experience.state.frame_keys();
+        let mut references = self.references.write();
+        let replaced = if keys.is_empty() {
+            Vec::new()
+        } else {
+            self.get_experience(index)?
+                .map(|replaced| replaced.state.frame_keys())
+                .unwrap_or_default()
+        };
+        let acquired = references.acquire(&keys);
+        for ((key, frame), acquired) in keys.iter().zip(experience.state.frames()).zip(acquired) {
+            if acquired {
+                let frame = frame
+                    .iter()
+                    .flat_map(|x| x.to_le_bytes())
+                    .collect::<Vec<_>>();
+                self.db.put(frame_key(*key), frame)?;
+            }
+        }
+        let value = rmp_serde::to_vec(&experience.without_frames())?;
+        self.db.put(index.to_le_bytes(), value)?;
+        for key in references.release(&replaced) {
+            self.db.delete(frame_key(key))?;
+        }
+        Ok(())
+    }
+
+    fn get(&self, index: usize) -> anyhow::Result<Option<Experience<S>>> {
+        let _references = self.references.read();
+        let Some(experience) = self.get_experience(index)? else {
+            return Ok(None);
+        };
+        let frames = experience
+            .state
+            .frame_keys()
+            .into_iter()
+            .map(|key| {
+                let frame = self
+                    .db
+                    .get(frame_key(key))?
+                    .with_context(|| format!("missing frame {}", key))?;
+                Ok(frame
+                    .chunks_exact(4)
+                    .map(|x| f32::from_le_bytes([x[0], x[1], x[2], x[3]]))
+                    .collect())
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(Some(experience.with_frames(frames)))
+    }
+
+    fn is_persistent(&self) -> bool {
+        self.temp_dir.is_none()
+    }
+}
+
+// how many stored experiences reference each shared frame
+#[derive(Default)]
+struct FrameReferences {
+    counts: HashMap<u64, usize>,
+}
+
+impl FrameReferences {
+    // whether each key is newly referenced and its frame is to be stored
+    fn acquire(&mut self, keys: &[u64]) -> Vec<bool> {
+        keys.iter()
+            .map(|key| {
+                let count = self.counts.entry(*key).or_default();
+                *count += 1;
+                *count == 1
+            })
+            .collect()
+    }
+
+    // the keys no experience references anymore
+    fn release(&mut self, keys: &[u64]) -> Vec<u64> {
+        keys.iter()
+            .filter(|key| {
+                let count = self
+                    .counts
+                    .get_mut(key)
+                    .expect("released frame is referenced");
+                *count -= 1;
+                if *count == 0 {
+                    self.counts.remove(key);
+                    true
+                } else {
+                    false
+                }
+            })
+            .copied()
+            .collect()
+    }
+}
+
+// experiences as rows of f32 in one contiguous buffer, the row width grows to the longest one
+pub struct InMemoryStorage<S> {
+    rows: RwLock<Rows>,
+    _state: PhantomData<fn() -> S>,
+}
+
+#[derive(Default)]
+struct Rows {
+    data: Vec<f32>,
+    // used width of each slot, 0 while empty
+    lengths: Vec<usize>,
+    stride: usize,
+    frames: HashMap<u64, Vec<f32>>,
+    references: FrameReferences,
+}
+
+impl Rows {
+    fn restride(&mut self, stride: usize) {
+        let mut data = vec![0.0; self.lengths.len() * stride];
+        for (index, len) in self.lengths.iter().enumerate() {
+            data[index * stride..index * stride + len]
+                .copy_from_slice(&self.data[index * self.stride..index * self.stride + len]);
+        }
+        self.data = data;
+        self.stride = stride;
+    }
+
+    fn put(&mut self, index: usize, row: &[f32]) {
+        if row.len() > self.stride {
+            self.restride(row.len());
+        }
+        if index >= self.lengths.len() {
+            self.lengths.resize(index + 1, 0);
+            self.data.resize((index + 1) * self.stride, 0.0);
+        }
+        let start = index * self.stride;
+        self.data[start..start + row.len()].copy_from_slice(row);
+        self.lengths[index] = row.len();
+    }
+
+    fn get(&self, index: usize) -> Option<&[f32]> {
+        let len = *self.lengths.get(index).filter(|len| **len > 0)?;
+        let start = index * self.stride;
+        Some(&self.data[start..start + len])
+    }
+}
+
+impl<S> InMemoryStorage<S> {
+    pub fn new() -> Self {
+        Self {
+            rows: RwLock::new(Rows::default()),
+            _state: PhantomData,
+        }
+    }
+}
+
+impl<S> Default for InMemoryStorage<S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// [reward, terminated, truncated, action, state], discrete actions as -1 and the index,
+// continuous ones as their length and values
+fn experience_to_row<S: FlatState>(experience: &Experience<S>) -> Vec<f32> {
+    let mut row = vec![
+        experience.reward,
+        experience.terminated as u8 as f32,
+        experience.truncated as u8 as f32,
+    ];
+    match &experience.action {
+        Action::Discrete(action) => row.extend([-1.0, *action as f32]),
+        Action::Continuous(action) => {
+            row.push(action.len() as f32);
+            row.extend_from_slice(action);
+        }
+    }
+    row.extend(experience.state.without_frames().to_flat());
+    row
+}
+
+fn experience_from_row<S: FlatState>(row: &[f32]) -> Experience<S> {
+    let (action, state) = if row[3] < 0.0 {
+        (Action::Discrete(row[4] as i64), &row[5..])
+    } else {
+        let len = row[3] as usize;
+        (
+            Action::Continuous(row[4..4 + len].to_vec()),
+            &row[4 + len..],
+        )
+    };
+    Experience {
+        state: S::from_flat(state),
+        action,
+        reward: row[0],
+        terminated: row[1] > 0.0,
+        truncated: row[2] > 0.0,
+    }
+}
+
+impl<S: FlatState + Sync> ExperienceStorage<S> for InMemoryStorage<S> {
+    fn put(&self, index: usize, experience: &Experience<S>) -> anyhow::Result<()> {
+        let keys = experience.state.frame_keys();
+        let row = experience_to_row(experience);
+        let mut rows = self.rows.write();
+        let replaced = if keys.is_empty() {
+            Vec::new()
+        } else {
+            rows.get(index)
+                .map(|row| experience_from_row::<S>(row).state.frame_keys())
+                .unwrap_or_default()
+        };
+        let acquired = rows.references.acquire(&keys);
+        for ((key, frame), acquired) in keys.iter().zip(experience.state.frames()).zip(acquired) {
+            if acquired {
+                rows.frames.insert(*key, frame.to_vec());
+            }
+        }
+        rows.put(index, &row);
+        for key in rows.references.release(&replaced) {
+            rows.frames.remove(&key);
+        }
+        Ok(())
+    }
+
+    fn get(&self, index: usize) -> anyhow::Result<Option<Experience<S>>> {
+        let rows = self.rows.read();
+        let Some(experience) = rows.get(index).map(experience_from_row::<S>) else {
+            return Ok(None);
+        };
+        let frames = experience
+            .state
+            .frame_keys()
+            .into_iter()
+            .map(|key| {
+                rows.frames
+                    .get(&key)
+                    .cloned()
+                    .with_context(|| format!("missing frame {}", key))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(Some(experience.with_frames(frames)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{DeepQNetworkState, FrameStackState, ObservationState as _, RecurrentState};
+
+    use super::*;
+
+    fn roundtrip<S: FlatState + Serialize + DeserializeOwned + Sync + 'static>(
+        backend: StorageBackend,
+        experiences: &[Experience<S>],
+    ) -> anyhow::Result<()> {
+        let storage = backend.create::<S>()?;
+        assert!(storage.get(0)?.is_none());
+        for (index, experience) in experiences.iter().enumerate() {
+            storage.put(index, experience)?;
+        }
+        for (index, experience) in experiences.iter().enumerate() {
+            let stored = storage.get(index)?.unwrap();
+            assert_eq!(format!("{:?}", stored), format!("{:?}", experience));
+        }
+        assert!(storage.get(experiences.len())?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_experience_storage() -> anyhow::Result<()> {
+        let experiences = (0..5)
+            .map(|i| Experience {
+                state: DeepQNetworkState {
+                    observation: vec![i as f32; 4],
+                    next_observation: vec![i as f32 + 0.5; 4],
+                },
+                action: Action::Discrete(i % 2),
+                reward: i as f32,
+                terminated: i == 4,
+                truncated: i == 3,
+            })
+            .collect::<Vec<_>>();
+        // the recurrent state is empty at the episode start, the rows get wider after it
+        let recurrent_experiences = (0..3)
+            .map(|i| Experience {
+                state: RecurrentState {
+                    observation: vec![i as f32; 3],
+                    next_observation: vec![i as f32; 3],
+                    recurrent_state: vec![0.25; 2 * i],
+                    next_recurrent_state: vec![0.5; 2],
+                },
+                action: Action::Continuous(vec![-1.0, i as f32]),
+                reward: -1.0,
+                terminated: false,
+                truncated: false,
+            })
+            .collect::<Vec<_>>();
+        for backend in [StorageBackend::InMemory, StorageBackend::RocksDb] {
+            roundtrip(backend.clone(), &experiences)?;
+            roundtrip(backend, &recurrent_experiences)?;
+        }
+
+        // a persistent storage keeps the experiences for the next one opened at the path
+        let dir = TempDir::new()?;
+        let backend = StorageBackend::PersistentRocksDb(dir.path().join("replay"));
+        {
+            let storage = backend.create::<DeepQNetworkState>()?;
+            assert!(storage.is_persistent());
+            storage.put(0, &experiences[1])?;
+        }
+        let storage = backend.create::<DeepQNetworkState>()?;
+        let stored = storage.get(0)?.unwrap();
+        assert_eq!(format!("{:?}", stored), format!("{:?}", experiences[1]));
+        assert!(!StorageBackend::InMemory
+            .create::<DeepQNetworkState>()?
+            .is_persistent());
+
+        assert_eq!(
+            StorageBackend::for_observation_size(4),
+            StorageBackend::InMemory
+        );
+        assert_eq!(
+            StorageBackend::for_observation_size(4 * 84 * 84),
+            StorageBackend::RocksDb
+        );
+        Ok(())
+    }
+
+    // the experiences of one episode, each a frame further
+    fn frame_stack_experiences(len: usize) -> Vec<Experience<FrameStackState<4>>> {
+        let mut state = FrameStackState::<4>::new(vec![0.0; 3]);
+        (1..=len)
+            .map(|i| {
+                state = state.next_state(&[i as f32; 3]);
+                Experience {
+                    state: state.clone(),
+                    action: Action::Discrete(0),
+                    reward: 0.0,
+                    terminated: false,
+                    truncated: false,
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_shared_frames() -> anyhow::Result<()> {
+        let experiences = frame_stack_experiences(10);
+        let assert_stored = |storage: &dyn ExperienceStorage<FrameStackState<4>>| {
+            for index in 0..4 {
+                let stored = storage.get(index)?.unwrap();
+                let experience = &experiences[6 + (index + 2) % 4];
+                assert_eq!(format!("{:?}", stored), format!("{:?}", experience));
+            }
+            anyhow::Ok(())
+        };
+
+        // the last 4 experiences of a ring of 4 slots keep their 4 + 4 frames once each
+        let storage = InMemoryStorage::new();
+        for (index, experience) in experiences.iter().enumerate() {
+            storage.put(index % 4, experience)?;
+        }
+        assert_stored(&storage)?;
+        assert_eq!(storage.rows.read().frames.len(), 8);
+
+        let dir = TempDir::new()?;
+        let path = dir.path().join("replay");
+        let first_frame = frame_key(experiences[0].state.frame_keys()[0]);
+        {
+            let storage = RocksDbStorage::open(&path)?;
+            for (index, experience) in experiences.iter().enumerate() {
+                storage.put(index % 4, experience)?;
+            }
+            assert_stored(&storage)?;
+            assert!(storage.db.get(first_frame)?.is_none());
+        }
+
+        // the references are rebuilt when reopened, a new episode releases all the old frames
+        let storage = RocksDbStorage::open(&path)?;
+        assert_stored(&storage)?;
+        let old_frames = experiences[9].state.frame_keys();
+        for (index, experience) in frame_stack_experiences(4).iter().enumerate() {
+            storage.put(index, experience)?;
+        }
+        for key in old_frames {
+            assert!(storage.db.get(frame_key(key))?.is_none());
+        }
+        Ok(())
+    }
+}
@@ -1,227 +1,194 @@
 use std::{
     fs::File,
-    io::{Cursor, Write},
-    path::PathBuf,
+    io::{BufReader, BufWriter},
+    path::Path,
     sync::{
-        atomic::{AtomicUsize, Ordering},
+        atomic::{AtomicBool, AtomicUsize, Ordering},
         mpsc::Receiver,
         Arc,
     },
     thread::JoinHandle,
     time::Duration,
-}; // Add the Write trait import
+};
 
-use anyhow::Context as _;
+use anyhow::{ensure, Context as _};
 use rand::Rng as _;
-use rocksdb::{DBCompressionType, DBWithThreadMode, MultiThreaded, Options};
-use serde::{de::DeserializeOwned, Serialize};
-use serde_json::json;
-use tempfile::TempDir;
-
-use crate::{Action, ActionSpace, Agent, Env, Experience, State};
-
-use super::{NStepExperience, RandomPolicy, RewardMapping};
-
-pub struct UniformReplayTrainer {
-    episode: usize,
-    gamma: f32,
-    n_step: usize,
-    rewards_mapping: RewardMapping,
-    artifacts_dir: PathBuf,
-    render: bool,
-}
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
-impl UniformReplayTrainer {
-    pub fn new(
-        episode: usize,
-        gamma: f32,
-        n_step: usize,
-        rewards_mapping: RewardMapping,
-        artifacts_dir: PathBuf,
-        render: bool,
-    ) -> anyhow::Result<Self> {
-        std::fs::create_dir_all(&artifacts_dir).with_context(|| "create artifact dir")?;
-        Ok(Self {
-            episode,
-            gamma,
-            n_step,
-            rewards_mapping,
-            artifacts_dir,
-            render,
-        })
-    }
+use crate::{Experience, FlatState};
 
-    pub fn train_loop<S: State + Serialize + DeserializeOwned + 'static, const D: usize>(
-        &self,
-        agent: &mut impl Agent<S>,
-        env: &mut impl Env<D>,
-        memory: &mut UniformReplayMemory<S>,
-        random_policy: &Option<RandomPolicy>,
-    ) -> anyhow::Result<()> {
-        let mut n_step_experiences =
-            NStepExperience::new(self.n_step, self.gamma, self.rewards_mapping.clone());
-
-        for epi in 0..self.episode {
-            let mut step = 0;
-            let mut cumulative_reward = 0.0;
-            let mut observation = env.reset()?;
-            let mut reward;
-            let mut is_done = false;
-            let mut state = S::new(observation.clone());
-            let episode_artifacts_dir = self.artifacts_dir.join(format!("{}", epi));
-            std::fs::create_dir_all(&episode_artifacts_dir).with_context(|| {
-                format!("create episode artifact dir {:?}", episode_artifacts_dir)
-            })?;
-            let train_log_path = episode_artifacts_dir.join("train.jsonl");
-            let mut train_logger =
-                File::create(&train_log_path).with_context(|| "create train log file")?;
-            while !is_done {
-                step += 1;
-
-                if self.render {
-                    env.render()?;
-                }
+use super::{
+    storage::{ExperienceStorage, StorageBackend},
+    ReplayBatch, ReplayMemory,
+};
 
-                let action = if let Some(policy) = random_policy {
-                    if policy.sample(epi) {
-                        let action_space = env.action_space();
-                        match action_space {
-                            ActionSpace::Discrete(n) => {
-                                let action = rand::thread_rng().gen_range(0..*n);
-                                Action::Discrete(action)
-                            }
-                        }
-                    } else {
-                        agent.policy(&observation)
-                    }
-                } else {
-                    agent.policy(&observation)
-                };
-
-                (observation, reward, is_done) = env.step(&action)?;
-                state = agent.make_state(&observation, &state);
-                cumulative_reward += reward;
-                let log = json!({
-                    "episode": epi,
-                    "step": step,
-                    "action": action,
-                    "reward": reward,
-                    "cumulative_reward": cumulative_reward
-                });
-                writeln!(&mut train_logger, "{}", log).with_context(|| "write train log")?;
-
-                let experience = Experience {
-                    state: state.clone(),
-                    action,
-                    reward,
-                    is_done,
-                };
-
-                if let Some(experience) = n_step_experiences.push(experience)? {
-                    memory.push(experience)?;
-                }
-
-                if epi == 0 {
-                    continue;
-                }
-                if let Ok(batch) = memory.sample() {
-                    let weights = vec![1.0; batch.len()];
-                    agent.update(self.gamma, &batch, &weights)?;
-                }
-            }
-            agent.save(&episode_artifacts_dir)?;
-        }
-        Ok(())
-    }
-}
-
-pub struct UniformReplayMemory<S: State + Serialize + DeserializeOwned + 'static> {
-    _buffer_dir: TempDir,
-    buffer: Arc<DBWithThreadMode<MultiThreaded>>,
+pub struct UniformReplayMemory<S: FlatState + Serialize + DeserializeOwned + Sync + 'static> {
+    storage: Arc<dyn ExperienceStorage<S>>,
     max_buffer_size: usize,
+    batch_size: usize,
     counter: Arc<AtomicUsize>,
-    batch_channel: Receiver<Vec<Experience<S>>>,
-    _samplers: Vec<JoinHandle<()>>,
-}
-
-impl<S: State + Serialize + DeserializeOwned + 'static> Drop for UniformReplayMemory<S> {
-    fn drop(&mut self) {
-        let _ = DBWithThreadMode::<MultiThreaded>::destroy(&Options::default(), self.buffer.path());
-    }
+    batch_channel: Receiver<anyhow::Result<ReplayBatch<Experience<S>>>>,
+    samplers: Vec<JoinHandle<()>>,
+    stop: Arc<AtomicBool>,
 }
 
-impl<S: State + Serialize + DeserializeOwned + 'static> UniformReplayMemory<S> {
-    pub fn new(max_buffer_size: usize, batch_size: usize) -> anyhow::Result<Self> {
-        let dir = TempDir::new()?;
-        let mut opts = Options::default();
-        opts.create_if_missing(true);
-        opts.optimize_for_point_lookup(4 * 1024);
-        opts.set_compression_type(DBCompressionType::Zstd);
-        let buffer = Arc::new(DBWithThreadMode::<MultiThreaded>::open(&opts, dir.path())?);
+impl<S: FlatState + Serialize + DeserializeOwned + Sync + 'static> UniformReplayMemory<S> {
+    pub fn new(
+        max_buffer_size: usize,
+        batch_size: usize,
+        backend: StorageBackend,
+    ) -> anyhow::Result<Self> {
+        let storage = backend.create::<S>()?;
         let counter = Arc::new(AtomicUsize::new(0));
 
         let (tx, rx) = std::sync::mpsc::sync_channel(4);
         let sampler_num = 4;
-        let mut _samplers = Vec::with_capacity(sampler_num);
+        let mut samplers = Vec::with_capacity(sampler_num);
+        let stop = Arc::new(AtomicBool::new(false));
 
         for _ in 0..sampler_num {
-            let buffer_clone = buffer.clone();
+            // the samplers stop once the memory is dropped
+            let storage_clone = Arc::downgrade(&storage);
             let counter_clone = counter.clone();
+            let stop_clone = stop.clone();
 
             let tx_clone = tx.clone();
-            let _sampler = std::thread::spawn(move || {
+            let sampler = std::thread::spawn(move || {
                 while counter_clone.load(Ordering::Relaxed) < batch_size {
+                    if stop_clone.load(Ordering::Relaxed) {
+                        return;
+                    }
                     std::thread::sleep(Duration::from_millis(100));
                 }
-                loop {
-                    let batch = {
-                        let mut batch = Vec::new();
-                        let counter = counter_clone.load(Ordering::Relaxed);
-
-                        let len = if counter > max_buffer_size {
-                            max_buffer_size
-                        } else {
-                            counter
-                        };
-                        let indexes = (0..batch_size)
-                            .map(|_| rand::thread_rng().gen_range(0..len))
-                            .collect::<Vec<_>>();
-                        for index in indexes {
-                            let experience =
-                                if let Some(v) = buffer_clone.get(index.to_le_bytes()).unwrap() {
-                                    rmp_serde::from_read(Cursor::new(v)).unwrap()
-                                } else {
-                                    println!("invalid index: {}", index);
-                                    continue;
-                                };
-                            batch.push(experience);
-                        }
-                        batch
+                while !stop_clone.load(Ordering::Relaxed) {
+                    let Some(storage_clone) = storage_clone.upgrade() else {
+                        return;
                     };
-                    tx_clone.send(batch).unwrap();
+                    let counter = counter_clone.load(Ordering::Relaxed);
+                    let batch =
+                        sample_batch(&*storage_clone, counter.min(max_buffer_size), batch_size);
+                    // a failed sampler reports the error and stops
+                    let failed = batch.is_err();
+                    if tx_clone.send(batch).is_err() || failed {
+                        return;
+                    }
                 }
             });
+            samplers.push(sampler);
         }
 
         Ok(Self {
-            _buffer_dir: dir,
-            buffer,
+            storage,
             max_buffer_size,
+            batch_size,
             counter,
             batch_channel: rx,
-            _samplers,
+            samplers,
+            stop,
         })
     }
+}
+
+// the samplers are joined before the storage is dropped, so a persistent one can be reopened
+impl<S: FlatState + Serialize + DeserializeOwned + Sync + 'static> Drop for UniformReplayMemory<S> {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        // a closed channel wakes the samplers waiting to send a batch
+        self.batch_channel = std::sync::mpsc::sync_channel(1).1;
+        for sampler in self.samplers.drain(..) {
+            let _ = sampler.join();
+        }
+    }
+}
 
+impl<S: FlatState + Serialize + DeserializeOwned + Sync + 'static> ReplayMemory<Experience<S>>
+    for UniformReplayMemory<S>
+{
     fn push(&mut self, experience: Experience<S>) -> anyhow::Result<()> {
-        let value = rmp_serde::to_vec(&experience)?;
-        let index = self.counter.fetch_add(1, Ordering::Relaxed) % self.max_buffer_size;
-        self.buffer.put(index.to_le_bytes(), value)?;
+        let index = self.counter.load(Ordering::Relaxed) % self.max_buffer_size;
+        self.storage.put(index, &experience)?;
+        self.counter.fetch_add(1, Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    fn sample(&mut self) -> anyhow::Result<ReplayBatch<Experience<S>>> {
+        // the samplers start once the memory holds a batch, then every call waits for one
+        ensure!(
+            self.counter.load(Ordering::Relaxed) >= self.batch_size,
+            "not enough experiences to sample a batch"
+        );
+        self.batch_channel.recv().with_context(|| "recv batch")?
+    }
+
+    fn batch_size(&self) -> usize {
+        self.batch_size
+    }
+
+    fn len(&self) -> usize {
+        self.counter
+            .load(Ordering::Relaxed)
+            .min(self.max_buffer_size)
+    }
+
+    fn capacity(&self) -> usize {
+        self.max_buffer_size
+    }
+
+    fn save(&self, path: &Path) -> anyhow::Result<()> {
+        ensure!(
+            self.storage.is_persistent(),
+            "the experiences are not persistent"
+        );
+        let record = UniformReplayRecord {
+            counter: self.counter.load(Ordering::Relaxed),
+            max_buffer_size: self.max_buffer_size,
+        };
+        let mut writer = BufWriter::new(File::create(path).with_context(|| "create memory")?);
+        rmp_serde::encode::write(&mut writer, &record).with_context(|| "write memory")?;
+        Ok(())
+    }
 
+    fn load(&mut self, path: &Path) -> anyhow::Result<()> {
+        let reader = BufReader::new(File::open(path).with_context(|| "open memory")?);
+        let record: UniformReplayRecord =
+            rmp_serde::from_read(reader).with_context(|| "read memory")?;
+        ensure!(
+            record.max_buffer_size == self.max_buffer_size,
+            "memory size mismatch: {} != {}",
+            record.max_buffer_size,
+            self.max_buffer_size
+        );
+        self.counter.store(record.counter, Ordering::Relaxed);
         Ok(())
     }
+}
 
-    fn sample(&self) -> anyhow::Result<Vec<Experience<S>>> {
-        self.batch_channel.try_recv().with_context(|| "recv batch")
+fn sample_batch<S: FlatState>(
+    storage: &dyn ExperienceStorage<S>,
+    len: usize,
+    batch_size: usize,
+) -> anyhow::Result<ReplayBatch<Experience<S>>> {
+    let mut indexes = Vec::with_capacity(batch_size);
+    let mut experiences = Vec::with_capacity(batch_size);
+    for _ in 0..batch_size {
+        let index = rand::thread_rng().gen_range(0..len);
+        // every sampled index has been pushed, a missing one is a broken storage
+        let experience = storage
+            .get(index)?
+            .with_context(|| format!("missing experience at index {}", index))?;
+        indexes.push(index);
+        experiences.push(experience);
     }
+    Ok(ReplayBatch {
+        indexes,
+        weights: vec![1.0; experiences.len()],
+        experiences,
+    })
+}
+
+#[derive(Serialize, Deserialize)]
+struct UniformReplayRecord {
+    counter: usize,
+    max_buffer_size: usize,
 }